@@ -1,5 +1,7 @@
 use libcommon_sys as sys;
-use std::os::raw::c_void;
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
 
 // {{{ Element
 
@@ -49,6 +51,15 @@ impl Timer {
             Self(el)
         }
     }
+
+    /// Unregister a timer which did not fire yet, dropping its callback.
+    pub fn cancel(&mut self) {
+        unsafe {
+            let data = sys::el_unregister(&mut self.0);
+
+            drop(Box::from_raw(data.ptr as *mut Box<dyn FnOnce(Timer)>));
+        }
+    }
 }
 
 impl Element for Timer {
@@ -74,6 +85,195 @@ impl Element for Blocker {
     }
 }
 
+// }}}
+// {{{ Before
+
+/// Element calling its callback before every iteration of the event loop.
+pub struct Before(sys::el_t);
+
+impl Before {
+    extern "C" fn call_cb(_el: sys::el_t, data: sys::data_t) {
+        let cb = unsafe { &mut *(data.ptr as *mut Box<dyn FnMut()>) };
+
+        (cb)();
+    }
+
+    pub fn new<F>(cb: F) -> Self
+    where
+        F: FnMut(),
+        F: 'static,
+    {
+        let cb: Box<Box<dyn FnMut()>> = Box::new(Box::new(cb));
+        let data = sys::data_t {
+            ptr: Box::into_raw(cb) as *mut c_void,
+        };
+
+        let cb_f = Before::call_cb as unsafe extern "C" fn(sys::el_t, sys::data_t);
+
+        unsafe { Self(sys::el_before_register_d(Some(cb_f), data)) }
+    }
+}
+
+impl Element for Before {
+    fn get_el(&self) -> sys::el_t {
+        self.0
+    }
+}
+
+// }}}
+// {{{ Fd
+
+type FdCb = Box<dyn FnMut(i16) -> i16>;
+
+/// Element calling its callback with the events polled on a file
+/// descriptor, which it does not own.
+///
+/// The callback returns the events to poll next, 0 stopping the polling
+/// until `set_mask` is called.
+pub struct Fd(sys::el_t);
+
+impl Fd {
+    extern "C" fn call_cb(el: sys::el_t, _fd: c_int, events: i16, data: sys::data_t) -> c_int {
+        let cb = unsafe { &mut *(data.ptr as *mut FdCb) };
+        let mask = (cb)(events);
+
+        unsafe {
+            sys::el_fd_set_mask(el, mask);
+        }
+        0
+    }
+
+    pub fn new<F>(fd: RawFd, events: i16, cb: F) -> Self
+    where
+        F: FnMut(i16) -> i16,
+        F: 'static,
+    {
+        let cb: Box<FdCb> = Box::new(Box::new(cb));
+        let data = sys::data_t {
+            ptr: Box::into_raw(cb) as *mut c_void,
+        };
+
+        let cb_f = Fd::call_cb as unsafe extern "C" fn(sys::el_t, c_int, i16, sys::data_t) -> c_int;
+
+        unsafe { Self(sys::el_fd_register_d(fd, false, events, Some(cb_f), data)) }
+    }
+
+    /// Poll the descriptor for `events`.
+    pub fn set_mask(&mut self, events: i16) {
+        unsafe {
+            sys::el_fd_set_mask(self.0, events);
+        }
+    }
+
+    /// Do not keep the event loop running for this element.
+    pub fn unref(&mut self) {
+        unsafe {
+            sys::el_unref(self.0);
+        }
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe {
+            let data = sys::el_unregister(&mut self.0);
+
+            drop(Box::from_raw(data.ptr as *mut FdCb));
+        }
+    }
+}
+
+// }}}
+// {{{ Wake
+
+/// Element calling its callback on the thread of the event loop, every
+/// time it is woken from any thread with a `WakeHandle`.
+///
+/// The wakeups done before the callback runs are coalesced.
+pub struct Wake {
+    el: Arc<Mutex<Option<ElPtr>>>,
+}
+
+/// Handle waking a `Wake` element, from any thread.
+#[derive(Clone)]
+pub struct WakeHandle {
+    el: Arc<Mutex<Option<ElPtr>>>,
+}
+
+struct ElPtr(sys::el_t);
+
+// The element is only fired from other threads, under the lock released
+// when it is unregistered.
+unsafe impl Send for ElPtr {}
+
+impl Wake {
+    extern "C" fn call_cb(_el: sys::el_t, data: sys::data_t) {
+        let cb = unsafe { &mut *(data.ptr as *mut Box<dyn FnMut()>) };
+
+        (cb)();
+    }
+
+    pub fn new<F>(cb: F) -> Self
+    where
+        F: FnMut(),
+        F: 'static,
+    {
+        let cb: Box<Box<dyn FnMut()>> = Box::new(Box::new(cb));
+        let data = sys::data_t {
+            ptr: Box::into_raw(cb) as *mut c_void,
+        };
+
+        let cb_f = Wake::call_cb as unsafe extern "C" fn(sys::el_t, sys::data_t);
+        let el = unsafe { sys::el_wake_register_d(Some(cb_f), data) };
+
+        Self {
+            el: Arc::new(Mutex::new(Some(ElPtr(el)))),
+        }
+    }
+
+    pub fn handle(&self) -> WakeHandle {
+        WakeHandle {
+            el: self.el.clone(),
+        }
+    }
+
+    /// Do not keep the event loop running for this element.
+    pub fn unref(&mut self) {
+        if let Some(el) = &*self.el.lock().unwrap() {
+            unsafe {
+                sys::el_unref(el.0);
+            }
+        }
+    }
+}
+
+impl Drop for Wake {
+    fn drop(&mut self) {
+        if let Some(mut el) = self.el.lock().unwrap().take() {
+            unsafe {
+                let data = sys::el_unregister(&mut el.0);
+
+                drop(Box::from_raw(data.ptr as *mut Box<dyn FnMut()>));
+            }
+        }
+    }
+}
+
+impl WakeHandle {
+    /// Wake the element, returning false if it was dropped.
+    pub fn wake(&self) -> bool {
+        match &*self.el.lock().unwrap() {
+            Some(el) => {
+                unsafe {
+                    sys::el_wake_fire(el.0);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
 // }}}
 // {{{ API
 
@@ -112,4 +312,49 @@ mod tests {
         super::el_loop();
         assert_eq!(*cnt.borrow(), 1);
     }
+
+    #[test]
+    fn test_wake() {
+        let blocker = Rc::new(RefCell::new(super::Blocker::new()));
+
+        let cnt = Rc::new(RefCell::new(0));
+        let wake = {
+            let cnt = cnt.clone();
+            super::Wake::new(move || {
+                cnt.replace_with(|&mut v| v + 1);
+                blocker.borrow_mut().unregister();
+            })
+        };
+        let handle = wake.handle();
+        std::thread::spawn(move || assert!(handle.wake()))
+            .join()
+            .unwrap();
+        super::el_loop();
+        assert_eq!(*cnt.borrow(), 1);
+
+        let handle = wake.handle();
+        drop(wake);
+        assert!(!handle.wake());
+    }
+
+    #[test]
+    fn test_fd() {
+        use std::io::Write;
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::UnixStream;
+
+        let (mut writer, reader) = UnixStream::pair().unwrap();
+
+        let events = Rc::new(RefCell::new(0));
+        let _fd = {
+            let events = events.clone();
+            super::Fd::new(reader.as_raw_fd(), libc::POLLIN, move |ev| {
+                events.replace_with(|&mut v| v | ev);
+                0
+            })
+        };
+        writer.write_all(b"a").unwrap();
+        super::el_loop_timeout(1000);
+        assert_eq!(*events.borrow() & libc::POLLIN, libc::POLLIN);
+    }
 }
@@ -1,10 +1,12 @@
 use crate::el;
-use futures::executor::LocalPool;
+use futures::executor::{LocalPool, LocalSpawner};
 use futures::future::Future;
-use futures::task::LocalSpawnExt;
+use futures::task::{waker, ArcWake, LocalSpawnExt};
 use libcommon_sys as sys;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::os::unix::io::RawFd;
 use std::pin::Pin;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
 
@@ -17,6 +19,8 @@ pub struct TimerState {
 
 pub struct Timer {
     state: Arc<Mutex<TimerState>>,
+    // unregistered if the timer is dropped before firing
+    el: el::Timer,
 }
 
 impl Future for Timer {
@@ -41,7 +45,7 @@ impl Timer {
         };
         let state = Arc::new(Mutex::new(state));
 
-        {
+        let el = {
             let state = state.clone();
             el::Timer::new(next, 0, flags, move |_t| {
                 let mut state = state.lock().unwrap();
@@ -49,38 +53,190 @@ impl Timer {
                 if let Some(waker) = state.waker.take() {
                     waker.wake();
                 }
-            });
+            })
+        };
+        Timer { state, el }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        // fired timers are unregistered by the event loop
+        if !self.state.lock().unwrap().fired {
+            self.el.cancel();
+        }
+    }
+}
+
+// }}}
+// {{{ Fd
+
+struct FdState {
+    // events polled since the last time they were awaited
+    ready: i16,
+    waker: Option<Waker>,
+}
+
+/// Readiness of a non-blocking file descriptor, polled by the event loop.
+///
+/// The descriptor is only polled while a task waits on it, so that it does
+/// not wake the event loop up while its events are not handled.
+pub struct Fd {
+    el: el::Fd,
+    state: Rc<RefCell<FdState>>,
+}
+
+impl Fd {
+    /// Poll `fd`, which must outlive the returned value.
+    pub fn new(fd: RawFd) -> Self {
+        let state = Rc::new(RefCell::new(FdState {
+            ready: 0,
+            waker: None,
+        }));
+        let el = {
+            let state = state.clone();
+
+            el::Fd::new(fd, 0, move |events| {
+                let mut state = state.borrow_mut();
+
+                state.ready |= events;
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+                // polled again once awaited
+                0
+            })
+        };
+
+        Fd { el, state }
+    }
+
+    /// Wait for one of `events`, after an operation on the descriptor
+    /// failed with `WouldBlock`.
+    ///
+    /// Errors and hangups make the descriptor ready for all the events, so
+    /// that the next operation fails.
+    pub fn poll_ready(&mut self, cx: &mut Context, events: i16) -> Poll<()> {
+        let mut state = self.state.borrow_mut();
+
+        if state.ready & (events | libc::POLLERR | libc::POLLHUP) != 0 {
+            state.ready &= !events;
+            return Poll::Ready(());
+        }
+        state.waker = Some(cx.waker().clone());
+        drop(state);
+        self.el.set_mask(events);
+        Poll::Pending
+    }
+}
+
+// }}}
+// {{{ Wakers
+
+thread_local! {
+    // Element of the running `block_on`, fired when its future or a task of
+    // the pool is woken.
+    static BLOCK_ON_WAKE: RefCell<Option<el::WakeHandle>> = const { RefCell::new(None) };
+}
+
+/// Waker also firing the element of `block_on`, so that its event loop
+/// returns to poll the woken future, even if woken from another thread.
+struct ElWaker {
+    waker: Option<Waker>,
+    el: el::WakeHandle,
+}
+
+impl ArcWake for ElWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        if let Some(waker) = &arc_self.waker {
+            waker.wake_by_ref();
+        }
+        arc_self.el.wake();
+    }
+}
+
+/// Task spawned on the pool, waking the event loop of `block_on` along with
+/// the pool.
+struct Task<F> {
+    fut: Pin<Box<F>>,
+}
+
+impl<F: Future<Output = ()>> Future for Task<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match BLOCK_ON_WAKE.with(|el| el.borrow().clone()) {
+            Some(el) => {
+                let waker = waker(Arc::new(ElWaker {
+                    waker: Some(cx.waker().clone()),
+                    el,
+                }));
+
+                self.fut.as_mut().poll(&mut Context::from_waker(&waker))
+            }
+            None => self.fut.as_mut().poll(cx),
         }
-        Timer { state }
     }
 }
 
 // }}}
 
 struct ElPool {
-    pool: LocalPool,
+    // The pool is only borrowed while running the tasks, spawning goes through `spawner` so that
+    // the tasks can spawn other tasks.
+    pool: RefCell<LocalPool>,
+    spawner: LocalSpawner,
     // FIXME: this is required because it is impossible to know if the local pool is empty
     // otherwise... Using FuturesUnordered directly could solve this.
-    nb_tasks: u32,
+    nb_tasks: Cell<u32>,
+}
+
+impl ElPool {
+    fn new() -> Self {
+        let pool = LocalPool::new();
+        let spawner = pool.spawner();
+
+        ElPool {
+            pool: RefCell::new(pool),
+            spawner,
+            nb_tasks: Cell::new(0),
+        }
+    }
+
+    /// Run one task of the pool, and return the number of remaining tasks.
+    fn run_one(&self) -> u32 {
+        if self.pool.borrow_mut().try_run_one() {
+            self.nb_tasks.set(self.nb_tasks.get() - 1);
+        }
+        self.nb_tasks.get()
+    }
+
+    /// Run the tasks of the pool until they all wait.
+    fn run_until_stalled(&self) {
+        while self.pool.borrow_mut().try_run_one() {
+            self.nb_tasks.set(self.nb_tasks.get() - 1);
+        }
+    }
 }
 
 // XXX: There isn't really a way around this thread local as long as rust code depends on async C
 // code (for example ichannel comms).
 thread_local! {
-    static POOL: RefCell<ElPool> = RefCell::new(ElPool { pool: LocalPool::new(), nb_tasks: 0 });
+    static POOL: ElPool = ElPool::new();
 }
 
+/// Spawn a future on the pool of the event loop.
+///
+/// It can be called from a spawned future.
 pub fn spawn<F>(fun: F)
 where
     F: Future<Output = ()> + 'static,
 {
     POOL.with(|pool| {
-        let mut pool = pool.borrow_mut();
-
-        pool.nb_tasks += 1;
-
-        let spawner = pool.pool.spawner();
-        spawner.spawn_local(fun).unwrap();
+        pool.nb_tasks.set(pool.nb_tasks.get() + 1);
+        pool.spawner
+            .spawn_local(Task { fut: Box::pin(fun) })
+            .unwrap();
     });
 }
 
@@ -91,14 +247,7 @@ where
     spawn(fun);
 
     loop {
-        let nb_tasks = POOL.with(|pool| {
-            let mut pool = pool.borrow_mut();
-
-            if pool.pool.try_run_one() {
-                pool.nb_tasks -= 1;
-            }
-            pool.nb_tasks
-        });
+        let nb_tasks = POOL.with(|pool| pool.run_one());
         if nb_tasks == 0 {
             break;
         }
@@ -106,6 +255,40 @@ where
     }
 }
 
+/// Run the event loop and the spawned futures until `fun` resolves,
+/// returning its output.
+///
+/// It must not be called from a spawned future.
+pub fn block_on<F>(fun: F) -> F::Output
+where
+    F: Future,
+{
+    let mut fun = Box::pin(fun);
+    // the element makes the event loop return once the future or a task is
+    // woken
+    let wake = el::Wake::new(|| ());
+    let waker = waker(Arc::new(ElWaker {
+        waker: None,
+        el: wake.handle(),
+    }));
+    let mut cx = Context::from_waker(&waker);
+    let prev = BLOCK_ON_WAKE.with(|el| el.replace(Some(wake.handle())));
+
+    let res = loop {
+        if let Poll::Ready(res) = fun.as_mut().poll(&mut cx) {
+            break res;
+        }
+        POOL.with(|pool| pool.run_until_stalled());
+        el::el_loop_timeout(BLOCK_ON_TIMEOUT);
+    };
+    BLOCK_ON_WAKE.with(|el| el.replace(prev));
+    res
+}
+
+/// Longest wait of an iteration of the event loop in `block_on`, which
+/// returns as soon as an event is handled.
+const BLOCK_ON_TIMEOUT: i32 = 60_000;
+
 #[cfg(test)]
 mod tests {
     use std::cell::RefCell;
@@ -131,4 +314,59 @@ mod tests {
             assert!(*g.borrow());
         });
     }
+
+    #[test]
+    fn test_spawn_from_task() {
+        GUARD.with(|g| {
+            g.replace_with(|&mut _g| false);
+        });
+
+        super::exec_test_async(async {
+            super::spawn(async {
+                super::Timer::new(10, 0).await.await;
+                GUARD.with(|g| {
+                    g.replace_with(|&mut _g| true);
+                });
+            });
+        });
+
+        GUARD.with(|g| {
+            assert!(*g.borrow());
+        });
+    }
+
+    #[test]
+    fn test_block_on() {
+        let res = super::block_on(async {
+            super::Timer::new(10, 0).await.await;
+            42
+        });
+        assert_eq!(res, 42);
+    }
+
+    #[test]
+    fn test_block_on_woken_from_thread() {
+        use futures::channel::oneshot;
+        use std::time::{Duration, Instant};
+
+        // the future and the task are only woken from other threads, which
+        // must wake the event loop
+        let (tx, rx) = oneshot::channel();
+        let (task_tx, task_rx) = oneshot::channel();
+        let (done_tx, done_rx) = oneshot::channel();
+        super::spawn(async move {
+            let _ = done_tx.send(task_rx.await.unwrap() + 1);
+        });
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            let _ = task_tx.send(1);
+            std::thread::sleep(Duration::from_millis(10));
+            let _ = tx.send(40);
+        });
+
+        let start = Instant::now();
+        let res = super::block_on(async move { rx.await.unwrap() + done_rx.await.unwrap() });
+        assert_eq!(res, 42);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
 }
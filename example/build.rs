@@ -0,0 +1,10 @@
+use std::env;
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+
+    println!("cargo:rerun-if-changed=src/iop/course.iop");
+    if let Err(e) = iop_codegen::compile("src/iop/course.iop", &out_dir) {
+        panic!("cannot compile src/iop/course.iop: {}", e);
+    }
+}
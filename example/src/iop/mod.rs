@@ -1 +1,3 @@
-pub mod course;
+pub mod course {
+    include!(concat!(env!("OUT_DIR"), "/course.rs"));
+}
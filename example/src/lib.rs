@@ -1,11 +1,10 @@
-use futures;
-use lazy_static::lazy_static;
 use libcommon_ic::error;
 use libcommon_ic::ic::{Channel, RpcRegister};
-use libcommon_ic::types::Rpc;
+use libcommon_ic::service;
+use libcommon_ic::types::{Rpc, Service};
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::rc::Rc;
 
 mod iop;
 use iop::course::rpcs::{custom as custom_rpc, user as rpc};
@@ -41,18 +40,12 @@ impl PartialEq for CourseType {
 // }}}
 // {{{ User management
 
+#[derive(Default)]
 struct State {
     users: HashMap<u64, User>,
     next_id: u64,
 }
 
-lazy_static! {
-    static ref STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
-        users: HashMap::new(),
-        next_id: 0,
-    }));
-}
-
 impl State {
     fn find_user(&self, user_id: u64) -> Result<&User, error::Error<()>> {
         self.users
@@ -98,30 +91,6 @@ impl State {
 // }}}
 // {{{ User interface
 
-async fn rpc_get_user(
-    _ic: Channel,
-    arg: rpc::GetArgs,
-) -> Result<rpc::GetRes, error::Error<rpc::GetExn>> {
-    let state = STATE.lock().unwrap();
-    let state = state.borrow();
-
-    state
-        .find_user(arg.id)
-        .map(|user| rpc::GetRes { user: user.clone() })
-}
-
-async fn rpc_set_progress(
-    _ic: Channel,
-    arg: rpc::SetProgressArgs,
-) -> Result<rpc::SetProgressRes, error::Error<rpc::SetProgressExn>> {
-    let state = STATE.lock().unwrap();
-    let mut state = state.borrow_mut();
-
-    state
-        .set_user_progress(arg.id, arg.progress)
-        .map_err(|v| error::Error::Generic(v))
-}
-
 async fn course_type_get_nb_total_steps(
     ic: &mut Channel,
     typ: &CourseType,
@@ -136,76 +105,109 @@ async fn course_type_get_nb_total_steps(
     }
 }
 
-async fn rpc_get_completion_rate(
-    mut ic: Channel,
-    arg: rpc::GetCompletionRateArgs,
-) -> Result<rpc::GetCompletionRateRes, error::Error<rpc::GetCompletionRateExn>> {
-    let state = STATE.lock().unwrap();
-    let state = state.borrow();
-    let user = state.find_user(arg.id)?;
-
-    let mut done_steps = 0;
-    let mut total_steps: u32 = 0;
-
-    // naive way of waiting for multiple futures
-    for course in &user.courses {
-        done_steps += course.completed_steps;
-        total_steps += course_type_get_nb_total_steps(&mut ic, &course.r#type).await?;
-    }
-    done_steps = 0;
-    total_steps = 0;
-
-    // better way to wait concurrently for all futures
-    let mut futs = Vec::new();
-    for course in &user.courses {
-        done_steps += course.completed_steps;
-        match &course.r#type {
-            CourseType::Std(t) => {
-                total_steps += std_course_get_nb_total_steps(t);
-            }
-            CourseType::CustomId(id) => {
-                let args = custom_rpc::GetNbTotalStepsArgs { id: *id };
-                let fut = custom_rpc::GetNbTotalSteps::call(&mut ic, course_mod::CUSTOM, args);
-                futs.push(fut);
-            }
-        }
-    }
-    if futs.len() > 0 {
-        total_steps += futures::future::try_join_all(futs)
-            .await?
-            .iter()
-            .map(|res| res.nb_total_steps)
-            .sum::<u32>();
-    }
-
-    let percent = if total_steps == 0 {
-        0.
-    } else if total_steps < done_steps {
-        100.
-    } else {
-        let val = done_steps as f64 / total_steps as f64;
-        // keep only two decimal digits of precision
-        (val * 10000.).round() / 100.
-    };
-
-    Ok(rpc::GetCompletionRateRes { percent })
+/// Implementation of the user interface, sharing its state between its
+/// rpcs.
+#[derive(Default)]
+struct UserService {
+    state: RefCell<State>,
 }
 
-pub fn register_user_rpcs(reg: &mut RpcRegister) {
-    // closure can be registered directly
-    rpc::Create::implement(reg, course_mod::USER, |_ic, arg| async {
-        let state = STATE.lock().unwrap();
-        let mut state = state.borrow_mut();
+#[service]
+impl UserService {
+    #[rpc(rpc::Create)]
+    async fn create(
+        &self,
+        _ic: Channel,
+        arg: rpc::CreateArgs,
+    ) -> Result<rpc::CreateRes, error::Error<rpc::CreateExn>> {
+        let mut state = self.state.borrow_mut();
 
         Ok(rpc::CreateRes {
             id: state.create_user(&arg.name, arg.email),
         })
-    });
+    }
+
+    #[rpc(rpc::Get)]
+    async fn get(
+        &self,
+        _ic: Channel,
+        arg: rpc::GetArgs,
+    ) -> Result<rpc::GetRes, error::Error<rpc::GetExn>> {
+        let state = self.state.borrow();
+
+        state
+            .find_user(arg.id)
+            .map(|user| rpc::GetRes { user: user.clone() })
+    }
+
+    #[rpc(rpc::SetProgress)]
+    async fn set_progress(
+        &self,
+        _ic: Channel,
+        arg: rpc::SetProgressArgs,
+    ) -> Result<rpc::SetProgressRes, error::Error<rpc::SetProgressExn>> {
+        let mut state = self.state.borrow_mut();
+
+        state
+            .set_user_progress(arg.id, arg.progress)
+            .map_err(error::Error::Generic)
+    }
+
+    #[rpc(rpc::GetCompletionRate)]
+    async fn get_completion_rate(
+        &self,
+        mut ic: Channel,
+        arg: rpc::GetCompletionRateArgs,
+    ) -> Result<rpc::GetCompletionRateRes, error::Error<rpc::GetCompletionRateExn>> {
+        // the state cannot stay borrowed while waiting for the queries, as
+        // other rpcs may be handled meanwhile
+        let courses = self.state.borrow().find_user(arg.id)?.courses.clone();
+
+        let done_steps: u32 = courses.iter().map(|course| course.completed_steps).sum();
+
+        // naive way of waiting for multiple futures
+        let mut naive_total_steps = 0;
+        for course in &courses {
+            naive_total_steps += course_type_get_nb_total_steps(&mut ic, &course.r#type).await?;
+        }
+
+        // better way to wait concurrently for all futures, with at most 8
+        // queries waiting for an answer at once
+        let mut total_steps = 0;
+        let mut batch = ic.batch::<custom_rpc::GetNbTotalSteps>(course_mod::CUSTOM);
+        batch.set_limit(8);
+        for course in &courses {
+            match &course.r#type {
+                CourseType::Std(t) => {
+                    total_steps += std_course_get_nb_total_steps(t);
+                }
+                CourseType::CustomId(id) => {
+                    batch.push(custom_rpc::GetNbTotalStepsArgs { id: *id });
+                }
+            }
+        }
+        for res in batch.run().await {
+            total_steps += res?.nb_total_steps;
+        }
+        debug_assert_eq!(naive_total_steps, total_steps);
+
+        let percent = if total_steps == 0 {
+            0.
+        } else if total_steps < done_steps {
+            100.
+        } else {
+            let val = done_steps as f64 / total_steps as f64;
+            // keep only two decimal digits of precision
+            (val * 10000.).round() / 100.
+        };
+
+        Ok(rpc::GetCompletionRateRes { percent })
+    }
+}
 
-    // a top level function can be registered as well
-    rpc::Get::implement(reg, course_mod::USER, rpc_get_user);
-    rpc::SetProgress::implement(reg, course_mod::USER, rpc_set_progress);
-    rpc::GetCompletionRate::implement(reg, course_mod::USER, rpc_get_completion_rate);
+pub fn register_user_rpcs(reg: &mut RpcRegister) {
+    // all the rpcs of the service are registered at once
+    Rc::new(UserService::default()).implement(reg, course_mod::USER);
 }
 
 // }}}
@@ -233,7 +235,7 @@ mod tests {
     use super::*;
     use libcommon_el;
     use libcommon_ic::ic::{Client, Server};
-    use std::rc::Rc;
+    use libcommon_ic::testing::MockChannel;
 
     async fn set_progress(ic: &mut Channel, id: u64, typ: CourseType, completed_steps: u32) {
         rpc::SetProgress::call(
@@ -264,14 +266,17 @@ mod tests {
 
         libcommon_el::exec_test_async(async {
             // start server serving user rpcs
-            let _server = Server::new("127.0.0.1", Some(server_reg));
+            let server = Server::new("127.0.0.1:0", Some(server_reg.build()), None).unwrap();
 
             // start client serving custom rpcs
-            let client_reg = Rc::new(client_reg);
+            let client_reg = client_reg.build();
             let mut client = Client::new(Some(&client_reg));
 
             // wait for both to be connected
-            let connected = client.connect_once("127.0.0.1").await;
+            let connected = client
+                .connect_once(&server.local_addr().to_string(), None)
+                .unwrap()
+                .await;
             assert!(connected);
             let mut ic = client.get_channel();
 
@@ -332,4 +337,41 @@ mod tests {
             assert_eq!(rate, 30.77);
         });
     }
+
+    #[test]
+    fn test_completion_rate_with_mock() {
+        let service = UserService::default();
+        let id = {
+            let mut state = service.state.borrow_mut();
+            let id = state.create_user("Diego Brando", None);
+
+            for (typ, completed_steps) in [
+                (CourseType::Std(StdCourseType::C), 12),
+                (CourseType::CustomId(7), 6),
+            ] {
+                let progress = CourseProgress {
+                    r#type: typ,
+                    completed_steps,
+                };
+                state.set_user_progress(id, progress).unwrap();
+            }
+            id
+        };
+
+        // the custom course is answered by the mock, without a server
+        let mock = MockChannel::new();
+        mock.respond::<custom_rpc::GetNbTotalSteps, _>(course_mod::CUSTOM, |_arg| {
+            Ok(custom_rpc::GetNbTotalStepsRes { nb_total_steps: 24 })
+        });
+
+        let args = rpc::GetCompletionRateArgs { id };
+        let fut = service.get_completion_rate(mock.channel(), args);
+        let res = futures::executor::block_on(fut);
+        assert_eq!(res.unwrap().percent, 37.5);
+
+        // it is queried once by each way of waiting for the futures
+        let calls = mock.calls_to::<custom_rpc::GetNbTotalSteps>(course_mod::CUSTOM);
+        assert_eq!(calls.len(), 2);
+        assert!(calls.iter().all(|arg| arg.id == 7));
+    }
 }
@@ -0,0 +1,141 @@
+//! Attribute macro implementing RPCs with the methods of a type.
+//!
+//! The methods of an impl block marked with `#[rpc(...)]` implement the
+//! given RPC, and are registered at once with `Service::implement`:
+//!
+//! ```ignore
+//! #[libcommon_ic::service]
+//! impl UserService {
+//!     #[rpc(rpc::Get)]
+//!     async fn get(&self, _ic: Channel, arg: rpc::GetArgs) -> Result<rpc::GetRes, ...> {
+//!         ...
+//!     }
+//!
+//!     #[rpc(rpc::SetProgress)]
+//!     async fn set_progress(&self, _ic: Channel, hdr: QueryHeader, arg: ...) -> ... {
+//!         ...
+//!     }
+//! }
+//!
+//! Rc::new(UserService::default()).implement(&mut reg, course_mod::USER);
+//! ```
+//!
+//! The methods take `&self`, the channel of the query, optionally its
+//! header, and its arguments, and return a future of the result of the RPC.
+//! The service is shared by the implementations, so its state must be
+//! mutated through cells. The other methods are left untouched.
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::{parse_macro_input, Attribute, Error, FnArg, ImplItem, ItemImpl, Path, Result};
+
+#[proc_macro_attribute]
+pub fn service(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        let err = Error::new(Span::call_site(), "service does not take arguments");
+
+        return err.to_compile_error().into();
+    }
+    let mut input = parse_macro_input!(input as ItemImpl);
+
+    match expand(&mut input) {
+        Ok(service) => quote!(#input #service).into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Remove the `#[rpc(...)]` attributes of the methods, and implement
+/// `Service` with the methods having one.
+fn expand(input: &mut ItemImpl) -> Result<TokenStream2> {
+    if let Some((_, path, _)) = &input.trait_ {
+        return Err(Error::new_spanned(
+            path,
+            "service must be placed on an inherent impl block",
+        ));
+    }
+
+    let mut impls = Vec::new();
+    for item in input.items.iter_mut() {
+        let method = match item {
+            ImplItem::Method(method) => method,
+            _ => continue,
+        };
+        let rpc = match take_rpc_attr(&mut method.attrs)? {
+            Some(rpc) => rpc,
+            None => continue,
+        };
+        let sig = &method.sig;
+        let name = &sig.ident;
+
+        match sig.inputs.first() {
+            Some(FnArg::Receiver(recv))
+                if recv.reference.is_some() && recv.mutability.is_none() => {}
+            _ => return Err(Error::new_spanned(sig, "rpc methods must take &self")),
+        }
+        let implement = match sig.inputs.len() {
+            3 => quote! {
+                <#rpc as ::libcommon_ic::types::Rpc>::implement(reg, iface_tag, move |ic, arg| {
+                    let service = service.clone();
+
+                    async move { service.#name(ic, arg).await }
+                });
+            },
+            4 => quote! {
+                <#rpc as ::libcommon_ic::types::Rpc>::implement_with_hdr(
+                    reg,
+                    iface_tag,
+                    move |ic, hdr, arg| {
+                        let service = service.clone();
+
+                        async move { service.#name(ic, hdr, arg).await }
+                    },
+                );
+            },
+            _ => {
+                return Err(Error::new_spanned(
+                    &sig.inputs,
+                    "rpc methods take the channel, optionally the header, and the arguments \
+                     of the query",
+                ))
+            }
+        };
+        impls.push(quote! {
+            {
+                let service = self.clone();
+
+                #implement
+            }
+        });
+    }
+
+    let (impl_generics, _, where_clause) = input.generics.split_for_impl();
+    let self_ty = &input.self_ty;
+
+    Ok(quote! {
+        impl #impl_generics ::libcommon_ic::types::Service for #self_ty #where_clause {
+            #[allow(unused_variables)]
+            fn implement(
+                self: ::std::rc::Rc<Self>,
+                reg: &mut ::libcommon_ic::ic::RpcRegister,
+                iface_tag: u16,
+            ) {
+                #(#impls)*
+            }
+        }
+    })
+}
+
+/// Remove the `#[rpc(...)]` attribute of a method, returning the path of
+/// its RPC.
+fn take_rpc_attr(attrs: &mut Vec<Attribute>) -> Result<Option<Path>> {
+    let (taken, kept): (Vec<_>, _) = attrs.drain(..).partition(|a| a.path.is_ident("rpc"));
+
+    *attrs = kept;
+    match taken.as_slice() {
+        [] => Ok(None),
+        [attr] => attr.parse_args().map(Some),
+        [_, attr, ..] => Err(Error::new_spanned(attr, "duplicated rpc attribute")),
+    }
+}
@@ -0,0 +1,94 @@
+use crate::types::Rpc;
+use libcommon_sys as sys;
+
+// {{{ ACL
+
+/// Status of the queries rejected by an `Acl`.
+///
+/// lib-common has no status for it, so peers get `Error::Abort`.
+pub const FORBIDDEN_STATUS: sys::ic_status_t = sys::ic_status_t_IC_MSG_ABORT;
+
+/// Rule of an `Acl`, allowing the queries of a group to an interface, or to
+/// one of its RPCs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AclRule {
+    /// Group of the header of the queries, every caller being allowed if
+    /// missing.
+    pub group: Option<String>,
+    pub iface_tag: u16,
+    /// RPC of the interface, every RPC being allowed if missing.
+    pub rpc_tag: Option<u16>,
+}
+
+impl AclRule {
+    fn matches(&self, group: Option<&str>, cmd: i32) -> bool {
+        let group_ok = match &self.group {
+            Some(allowed) => group == Some(allowed.as_str()),
+            None => true,
+        };
+        let iface_tag = (cmd >> 16) as u16;
+        let rpc_tag = (cmd & 0xffff) as u16;
+
+        group_ok && self.iface_tag == iface_tag && self.rpc_tag.is_none_or(|t| t == rpc_tag)
+    }
+}
+
+/// Commands the groups of the callers are allowed to query, checked by
+/// servers after their authenticator with `Server::set_acl`.
+///
+/// Queries not allowed by a rule are rejected with `FORBIDDEN_STATUS`, as
+/// when their implementation returns `Error::Forbidden`:
+///
+/// ```ignore
+/// let mut acl = Acl::new();
+///
+/// acl.allow_iface(None, modules::admin::USERS)
+///     .allow::<CreateUser>(Some("admin"), modules::admin::USERS_ADMIN);
+/// server.set_acl(acl);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+}
+
+impl Acl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_rule(&mut self, rule: AclRule) -> &mut Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Allow `group`, or every caller if `None`, to query every RPC of the
+    /// interface `iface_tag`.
+    pub fn allow_iface(&mut self, group: Option<&str>, iface_tag: u16) -> &mut Self {
+        self.add_rule(AclRule {
+            group: group.map(str::to_owned),
+            iface_tag,
+            rpc_tag: None,
+        })
+    }
+
+    /// Allow `group`, or every caller if `None`, to query the RPC `R` of the
+    /// interface `iface_tag`.
+    pub fn allow<R: Rpc>(&mut self, group: Option<&str>, iface_tag: u16) -> &mut Self {
+        self.add_rule(AclRule {
+            group: group.map(str::to_owned),
+            iface_tag,
+            rpc_tag: Some(R::TAG),
+        })
+    }
+
+    pub fn rules(&self) -> &[AclRule] {
+        &self.rules
+    }
+
+    /// Whether a caller of `group` can query `cmd`.
+    pub fn is_allowed(&self, group: Option<&str>, cmd: i32) -> bool {
+        self.rules.iter().any(|rule| rule.matches(group, cmd))
+    }
+}
+
+// }}}
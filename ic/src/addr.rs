@@ -0,0 +1,215 @@
+use futures::channel::oneshot;
+use libcommon_sys as sys;
+use std::error;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
+use std::os::raw::c_void;
+use std::ptr;
+use std::thread;
+
+// {{{ Error
+
+#[derive(Debug, PartialEq)]
+pub enum AddrError {
+    /// The address is not of the form `host:port`.
+    Parse(String),
+    /// The host cannot be resolved.
+    Resolve(String),
+    /// The server cannot listen on the address.
+    Listen(String),
+    /// The TLS configuration differs from the one of the other channels of
+    /// the thread.
+    Tls(String),
+}
+
+impl fmt::Display for AddrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddrError::Parse(addr) => write!(f, "invalid address `{}`", addr),
+            AddrError::Resolve(addr) => write!(f, "cannot resolve address `{}`", addr),
+            AddrError::Listen(addr) => write!(f, "cannot listen on address `{}`", addr),
+            AddrError::Tls(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl error::Error for AddrError {}
+
+// }}}
+// {{{ Socket address
+
+/// Address of a server, resolved from a `host:port` string.
+///
+/// IPv6 literals are written between brackets, as in `[::1]:1234`.
+#[derive(Clone, Copy)]
+pub struct SockAddr(sys::sockunion_t);
+
+impl SockAddr {
+    /// Parse the address of a server to connect to.
+    pub fn parse(addr: &str) -> Result<Self, AddrError> {
+        Self::parse_minport(addr, 1)
+    }
+
+    /// Parse an address to listen on, where port 0 picks an ephemeral port.
+    pub fn parse_listen(addr: &str) -> Result<Self, AddrError> {
+        Self::parse_minport(addr, 0)
+    }
+
+    fn parse_minport(addr: &str, minport: i32) -> Result<Self, AddrError> {
+        unsafe {
+            let mut su: sys::sockunion_t = mem::zeroed();
+            let mut host: sys::pstream_t = mem::zeroed();
+            let mut port: sys::in_port_t = mem::zeroed();
+
+            let ps = sys::ps_init(addr.as_ptr() as *const c_void, addr.len());
+            if sys::addr_parse_minport(ps, &mut host, &mut port, minport, -1) < 0 {
+                return Err(AddrError::Parse(addr.to_owned()));
+            }
+
+            // brackets are removed from IPv6 literals, which are the only
+            // hosts containing colons
+            let start = host.__bindgen_anon_1.b;
+            let len = host.__bindgen_anon_2.b_end as usize - start as usize;
+            let family = if std::slice::from_raw_parts(start, len).contains(&b':') {
+                libc::AF_INET6
+            } else {
+                libc::AF_INET
+            };
+
+            if sys::addr_info(&mut su, family as u16, host, port) < 0 {
+                return Err(AddrError::Resolve(addr.to_owned()));
+            }
+            Ok(Self(su))
+        }
+    }
+
+    /// Resolve the address of a server to connect to, without blocking the
+    /// event loop.
+    ///
+    /// Unlike `parse`, hostnames are resolved on a thread, the first
+    /// address found being used.
+    pub async fn resolve(addr: &str) -> Result<Self, AddrError> {
+        if let Ok(sa) = addr.parse::<SocketAddr>() {
+            return Self::check_port(sa, addr);
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let host = addr.to_owned();
+        thread::spawn(move || {
+            let _ = tx.send(host.to_socket_addrs().map(|mut addrs| addrs.next()));
+        });
+
+        match rx.await {
+            Ok(Ok(Some(sa))) => Self::check_port(sa, addr),
+            Ok(Err(e)) if e.kind() == io::ErrorKind::InvalidInput => {
+                Err(AddrError::Parse(addr.to_owned()))
+            }
+            _ => Err(AddrError::Resolve(addr.to_owned())),
+        }
+    }
+
+    fn check_port(sa: SocketAddr, addr: &str) -> Result<Self, AddrError> {
+        if sa.port() == 0 {
+            return Err(AddrError::Parse(addr.to_owned()));
+        }
+        Ok(sa.into())
+    }
+
+    pub(crate) fn to_raw(self) -> sys::sockunion_t {
+        self.0
+    }
+}
+
+impl From<SocketAddr> for SockAddr {
+    fn from(addr: SocketAddr) -> Self {
+        unsafe {
+            let mut su: sys::sockunion_t = mem::zeroed();
+
+            match addr {
+                SocketAddr::V4(addr) => {
+                    let mut sin: libc::sockaddr_in = mem::zeroed();
+
+                    sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                    sin.sin_port = addr.port().to_be();
+                    sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                    copy_sockaddr(&sin, &mut su);
+                }
+                SocketAddr::V6(addr) => {
+                    let mut sin6: libc::sockaddr_in6 = mem::zeroed();
+
+                    sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                    sin6.sin6_port = addr.port().to_be();
+                    sin6.sin6_flowinfo = addr.flowinfo();
+                    sin6.sin6_addr.s6_addr = addr.ip().octets();
+                    sin6.sin6_scope_id = addr.scope_id();
+                    copy_sockaddr(&sin6, &mut su);
+                }
+            }
+            Self(su)
+        }
+    }
+}
+
+unsafe fn copy_sockaddr<T>(sa: &T, su: &mut sys::sockunion_t) {
+    ptr::copy_nonoverlapping(
+        sa as *const T as *const u8,
+        su as *mut sys::sockunion_t as *mut u8,
+        mem::size_of::<T>(),
+    );
+}
+
+/// Address a socket is bound to.
+pub(crate) fn local_addr(fd: i32) -> Option<SocketAddr> {
+    unsafe {
+        let mut ss: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of_val(&ss) as libc::socklen_t;
+
+        if libc::getsockname(fd, &mut ss as *mut _ as *mut libc::sockaddr, &mut len) < 0 {
+            return None;
+        }
+        to_socket_addr(&ss)
+    }
+}
+
+/// Address of the peer of a connected socket.
+pub(crate) fn peer_addr(fd: i32) -> Option<SocketAddr> {
+    unsafe {
+        let mut ss: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of_val(&ss) as libc::socklen_t;
+
+        if libc::getpeername(fd, &mut ss as *mut _ as *mut libc::sockaddr, &mut len) < 0 {
+            return None;
+        }
+        to_socket_addr(&ss)
+    }
+}
+
+unsafe fn to_socket_addr(ss: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match ss.ss_family as i32 {
+        libc::AF_INET => {
+            let sin = &*(ss as *const _ as *const libc::sockaddr_in);
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+
+            Some(SocketAddrV4::new(ip, u16::from_be(sin.sin_port)).into())
+        }
+        libc::AF_INET6 => {
+            let sin6 = &*(ss as *const _ as *const libc::sockaddr_in6);
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+
+            Some(
+                SocketAddrV6::new(
+                    ip,
+                    u16::from_be(sin6.sin6_port),
+                    sin6.sin6_flowinfo,
+                    sin6.sin6_scope_id,
+                )
+                .into(),
+            )
+        }
+        _ => None,
+    }
+}
+
+// }}}
@@ -0,0 +1,39 @@
+use crate::hdr::QueryHeader;
+use crate::ic::Channel;
+use libcommon_sys as sys;
+
+// {{{ Authenticator
+
+/// Check of the queries received by a server, given their channel, header
+/// and command, before they are handled.
+///
+/// Rejected queries are answered with the returned status, without going
+/// through the middlewares nor the RPC implementations. The header is empty
+/// for queries sent without one.
+///
+/// On TLS channels, the certificate of the client can replace its login and
+/// password: its identity is given by `Channel::peer_identity`.
+pub trait Authenticator {
+    fn authenticate(
+        &self,
+        ic: &Channel,
+        hdr: &QueryHeader,
+        cmd: i32,
+    ) -> Result<(), sys::ic_status_t>;
+}
+
+impl<F> Authenticator for F
+where
+    F: Fn(&Channel, &QueryHeader, i32) -> Result<(), sys::ic_status_t>,
+{
+    fn authenticate(
+        &self,
+        ic: &Channel,
+        hdr: &QueryHeader,
+        cmd: i32,
+    ) -> Result<(), sys::ic_status_t> {
+        self(ic, hdr, cmd)
+    }
+}
+
+// }}}
@@ -0,0 +1,98 @@
+use crate::error;
+use crate::ic::Channel;
+use crate::types::Rpc;
+use futures::future::Future;
+use futures::stream::{self, StreamExt};
+
+// {{{ Batch
+
+/// Queries of the same RPC, sent concurrently on a channel with at most
+/// `limit` of them waiting for an answer at once.
+///
+/// ```ignore
+/// let mut batch = ic.batch::<GetNbTotalSteps>(course_mod::CUSTOM);
+/// for id in ids {
+///     batch.push(GetNbTotalStepsArgs { id });
+/// }
+/// let results = batch.run().await;
+/// ```
+pub struct Batch<R: Rpc> {
+    ic: Channel,
+    iface_tag: u16,
+    args: Vec<R::Input>,
+    limit: usize,
+}
+
+impl<R: Rpc> Batch<R> {
+    /// Default number of queries of a batch waiting for an answer at once.
+    pub const DEFAULT_LIMIT: usize = 32;
+
+    pub(crate) fn new(ic: Channel, iface_tag: u16) -> Self {
+        Self {
+            ic,
+            iface_tag,
+            args: Vec::new(),
+            limit: Self::DEFAULT_LIMIT,
+        }
+    }
+
+    /// Add a query to the batch.
+    pub fn push(&mut self, arg: R::Input) -> &mut Self {
+        self.args.push(arg);
+        self
+    }
+
+    /// Set the number of queries waiting for an answer at once, at least
+    /// one.
+    pub fn set_limit(&mut self, limit: usize) -> &mut Self {
+        self.limit = limit.max(1);
+        self
+    }
+
+    /// Number of queries in the batch.
+    pub fn len(&self) -> usize {
+        self.args.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+
+    /// Send the queries, resolving with their results in the order they
+    /// were pushed.
+    ///
+    /// A query is only sent once one of the previous ones is answered, if
+    /// `limit` of them are waiting for their answer.
+    pub fn run(self) -> impl Future<Output = Vec<Result<R::Output, error::Error<R::Exception>>>> {
+        call_all::<R, _>(&self.ic, self.iface_tag, self.args, self.limit)
+    }
+}
+
+// }}}
+// {{{ Call all
+
+/// Call the RPC `R` with each of `args` on `ic`, with at most `limit`
+/// queries waiting for an answer at once, resolving with their results in
+/// the order of `args`.
+///
+/// The queries are polled by the returned future, so they can be sent on a
+/// loopback channel as well as on the event loop.
+pub fn call_all<R, I>(
+    ic: &Channel,
+    iface_tag: u16,
+    args: I,
+    limit: usize,
+) -> impl Future<Output = Vec<Result<R::Output, error::Error<R::Exception>>>>
+where
+    R: Rpc,
+    I: IntoIterator<Item = R::Input>,
+{
+    let mut ic = ic.dup();
+
+    stream::iter(args)
+        .map(move |arg| R::call(&mut ic, iface_tag, arg))
+        .buffered(limit.max(1))
+        .collect()
+}
+
+// }}}
@@ -0,0 +1,63 @@
+use crate::connect::{ConnectError, ConnectOptions};
+use crate::error;
+use crate::ic::{Client, RpcDispatcher};
+use crate::tls::TlsConfig;
+use crate::types::Rpc;
+use libcommon_el::el_future;
+use std::time::{Duration, Instant};
+
+// {{{ Sync client
+
+/// Client whose queries block until answered, for the programs not using
+/// async, such as command line tools.
+///
+/// The event loop and the spawned futures are run while waiting, so it must
+/// not be used from a spawned future or an RPC implementation.
+///
+/// ```ignore
+/// let mut client = SyncClient::new(None);
+/// client.connect("127.0.0.1:1234", None, ConnectOptions::default())?;
+/// let user = client.call::<GetUser>(IFACE, GetUserArg { id }, Duration::from_secs(5))?;
+/// ```
+pub struct SyncClient {
+    client: Client,
+}
+
+impl SyncClient {
+    pub fn new(register: Option<&RpcDispatcher>) -> Self {
+        Self {
+            client: Client::new(register),
+        }
+    }
+
+    /// Connect to `hostname`, retrying as `Client::connect_with`.
+    pub fn connect(
+        &mut self,
+        hostname: &str,
+        tls: Option<&TlsConfig>,
+        options: ConnectOptions,
+    ) -> Result<(), ConnectError> {
+        el_future::block_on(self.client.connect_with(hostname, tls, options))
+    }
+
+    /// Call the RPC `R`, failing with `Error::TimedOut` if it is not
+    /// answered within `timeout`.
+    pub fn call<R: Rpc>(
+        &mut self,
+        iface_tag: u16,
+        arg: R::Input,
+        timeout: Duration,
+    ) -> Result<R::Output, error::Error<R::Exception>> {
+        let mut ic = self.client.get_channel();
+
+        ic.set_deadline(Some(Instant::now() + timeout));
+        el_future::block_on(R::call(&mut ic, iface_tag, arg))
+    }
+
+    /// The underlying client, to configure it.
+    pub fn client_mut(&mut self) -> &mut Client {
+        &mut self.client
+    }
+}
+
+// }}}
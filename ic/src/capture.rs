@@ -0,0 +1,238 @@
+use crate::compress;
+use crate::hdr::QueryHeader;
+use crate::ic::Direction;
+use crate::types::Rpc;
+use libcommon_sys as sys;
+use serde_iop::desc::{describe, Schema};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// {{{ Config
+
+/// Files of a capture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptureConfig {
+    pub path: PathBuf,
+    /// Size above which the file is rotated, as `<path>.1`, the previous
+    /// ones being shifted.
+    pub max_size: u64,
+    /// Number of rotated files kept.
+    pub max_files: usize,
+}
+
+impl CaptureConfig {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            max_size: 64 << 20,
+            max_files: 4,
+        }
+    }
+}
+
+// }}}
+// {{{ Schemas
+
+struct RpcSchemas {
+    name: Option<&'static str>,
+    input: Option<Schema>,
+    output: Option<Schema>,
+    exception: Option<Schema>,
+}
+
+// schemas of the RPCs, by command
+static SCHEMAS: Mutex<BTreeMap<i32, RpcSchemas>> = Mutex::new(BTreeMap::new());
+
+/// Decode the frames of the RPC `R` with its types, instead of guessing
+/// their structure.
+pub fn register_rpc<R: Rpc>(iface_tag: u16) {
+    let schemas = RpcSchemas {
+        name: R::NAME,
+        input: describe::<R::Input>().ok(),
+        output: describe::<R::Output>().ok(),
+        exception: describe::<R::Exception>().ok(),
+    };
+
+    SCHEMAS
+        .lock()
+        .unwrap()
+        .insert(R::get_cmd(iface_tag), schemas);
+}
+
+// }}}
+// {{{ Capture
+
+struct Capture {
+    config: CaptureConfig,
+    // written by whole frames, so that the file can be followed
+    out: File,
+    size: u64,
+}
+
+impl Capture {
+    fn open(config: CaptureConfig) -> io::Result<Self> {
+        let out = File::create(&config.path)?;
+
+        Ok(Self {
+            config,
+            out,
+            size: 0,
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..self.config.max_files).rev() {
+            let from = self.rotated_path(n);
+
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(n + 1))?;
+            }
+        }
+        if self.config.max_files > 0 {
+            fs::rename(&self.config.path, self.rotated_path(1))?;
+        }
+        self.out = File::create(&self.config.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, frame: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + frame.len() as u64 > self.config.max_size {
+            self.rotate()?;
+        }
+        self.out.write_all(frame.as_bytes())?;
+        self.size += frame.len() as u64;
+        Ok(())
+    }
+}
+
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+
+// checked before taking the lock, for every message
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Start dumping every message sent or received by the channels of the
+/// process in a text file, with the header of queries and the structure of
+/// their payload, replacing the capture in progress if any.
+///
+/// The payloads are decoded with the types given to `register_rpc`, or
+/// guessed as by `iop-dump`. Passwords are never dumped.
+pub fn start(config: CaptureConfig) -> io::Result<()> {
+    let capture = Capture::open(config)?;
+
+    *CAPTURE.lock().unwrap() = Some(capture);
+    ACTIVE.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Stop the capture in progress, if any.
+pub fn stop() {
+    let mut current = CAPTURE.lock().unwrap();
+
+    ACTIVE.store(false, Ordering::Relaxed);
+    *current = None;
+}
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Dump a message sent or received on a channel, if a capture is in
+/// progress.
+pub(crate) fn frame(
+    direction: Direction,
+    cmd: i32,
+    status: Option<sys::ic_status_t>,
+    slot: u64,
+    peer: Option<SocketAddr>,
+    hdr: *const sys::ic__hdr__t,
+    data: &[u8],
+) {
+    if !is_active() {
+        return;
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut out = format!(
+        "[{}.{:06}] {} {} {:#010x}",
+        timestamp.as_secs(),
+        timestamp.subsec_micros(),
+        match direction {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        },
+        if status.is_some() { "answer" } else { "query" },
+        cmd,
+    );
+
+    let schemas = SCHEMAS.lock().unwrap();
+    let rpc = schemas.get(&cmd);
+    if let Some(name) = rpc.and_then(|rpc| rpc.name) {
+        let _ = write!(out, " {}", name);
+    }
+    let _ = write!(out, " slot {}", slot);
+    if let Some(status) = status {
+        let _ = write!(out, " status {}", status);
+    }
+    if let Some(peer) = peer {
+        let _ = write!(out, " peer {}", peer);
+    }
+    out.push('\n');
+
+    let hdr = unsafe { QueryHeader::from_raw(hdr) };
+    if hdr != QueryHeader::default() {
+        let _ = writeln!(out, "  {:?}", hdr);
+    }
+
+    let schema = rpc.and_then(|rpc| match status {
+        None => rpc.input.as_ref(),
+        Some(sys::ic_status_t_IC_MSG_OK) => rpc.output.as_ref(),
+        Some(sys::ic_status_t_IC_MSG_EXN) => rpc.exception.as_ref(),
+        Some(_) => None,
+    });
+    let dump = compress::decompress(data, None)
+        .and_then(|data| iop_dump::dump(&data, schema).map_err(|e| e.to_string()));
+    match dump {
+        Ok(dump) => {
+            for line in dump.lines() {
+                let _ = writeln!(out, "  {}", line);
+            }
+        }
+        Err(e) => {
+            let _ = writeln!(
+                out,
+                "  cannot decode payload of {} bytes: {}",
+                data.len(),
+                e
+            );
+        }
+    }
+    drop(schemas);
+
+    let mut capture = CAPTURE.lock().unwrap();
+    if let Some(current) = capture.as_mut() {
+        if let Err(e) = current.write(&out) {
+            log::error!(cmd, slot; "cannot write capture, capture stopped: {}", e);
+            ACTIVE.store(false, Ordering::Relaxed);
+            *capture = None;
+        }
+    }
+}
+
+// }}}
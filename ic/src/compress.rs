@@ -0,0 +1,91 @@
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+use std::borrow::Cow;
+
+// {{{ Config
+
+/// Compression of the payloads of a channel.
+///
+/// It is only used once the peer accepts it, which is negotiated when the
+/// client connects. Payloads are still received uncompressed from peers not
+/// supporting it, such as C services.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compression {
+    /// Payloads up to this size are sent as is.
+    pub threshold: usize,
+    /// Deflate level, from 1 to 10.
+    pub level: u8,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            threshold: 4096,
+            level: 6,
+        }
+    }
+}
+
+/// Command of the query negotiating the compression, sent by clients when
+/// they connect, and answered by servers accepting compressed payloads.
+pub(crate) const NEGOTIATE_CMD: i32 = 0x7fff_ff00;
+
+// }}}
+// {{{ Payloads
+
+// IOP never packs a field with tag 0, so packed arguments and answers never
+// start with this byte, which marks compressed payloads.
+const COMPRESSED_MARK: u8 = 0;
+
+/// Compress a payload if it is above the threshold, and compressing it
+/// makes it smaller.
+///
+/// Compressed payloads are the mark, the size of the payload as a u32 LE,
+/// and the deflated payload.
+pub(crate) fn compress(data: &[u8], config: &Compression) -> Option<Vec<u8>> {
+    if data.len() <= config.threshold || data.len() > u32::MAX as usize {
+        return None;
+    }
+
+    let deflated = compress_to_vec(data, config.level);
+    if deflated.len() + 5 >= data.len() {
+        return None;
+    }
+
+    let mut res = Vec::with_capacity(deflated.len() + 5);
+    res.push(COMPRESSED_MARK);
+    res.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    res.extend_from_slice(&deflated);
+    Some(res)
+}
+
+/// Decompress a payload if it is compressed, refusing payloads bigger than
+/// `max` once decompressed.
+pub(crate) fn decompress(data: &[u8], max: Option<usize>) -> Result<Cow<'_, [u8]>, String> {
+    if data.first() != Some(&COMPRESSED_MARK) {
+        return Ok(Cow::Borrowed(data));
+    }
+    if data.len() < 5 {
+        return Err("truncated compressed payload".to_owned());
+    }
+
+    let mut len = [0; 4];
+    len.copy_from_slice(&data[1..5]);
+    let len = u32::from_le_bytes(len) as usize;
+    if let Some(max) = max {
+        if len > max {
+            return Err(format!(
+                "compressed payload of {} bytes exceeds the limit of {}",
+                len, max
+            ));
+        }
+    }
+
+    match decompress_to_vec_with_limit(&data[5..], len) {
+        Ok(res) if res.len() == len => Ok(Cow::Owned(res)),
+        Ok(_) => Err("invalid size of compressed payload".to_owned()),
+        Err(e) => Err(format!("cannot decompress payload: {:?}", e.status)),
+    }
+}
+
+// }}}
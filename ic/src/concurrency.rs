@@ -0,0 +1,140 @@
+use futures::channel::oneshot;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+// {{{ Config
+
+/// Limits of the queries handled at once by a server, all its channels
+/// included, so that the implementations waiting on a slow dependency do
+/// not pile up.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConcurrencyLimits {
+    /// Limit of all the queries being handled.
+    pub global: Option<usize>,
+    /// Limits of the queries to given commands.
+    pub cmds: HashMap<i32, usize>,
+    /// Queries waiting for the end of others when a limit is reached, the
+    /// next ones being answered with `IC_MSG_RETRY`. 0 rejects them at once.
+    pub max_queued: usize,
+}
+
+// }}}
+// {{{ Limiter
+
+pub(crate) enum Acquired {
+    Now(Permit),
+    /// Resolved once the query can be handled, canceled if the limiter is
+    /// dropped.
+    Queued(oneshot::Receiver<Permit>),
+    Rejected,
+}
+
+struct Waiter {
+    cmd: i32,
+    sender: oneshot::Sender<Permit>,
+}
+
+/// Counts of the queries being handled by the channels of a server.
+pub(crate) struct ConcurrencyLimiter {
+    limits: ConcurrencyLimits,
+    running: Cell<usize>,
+    running_cmds: RefCell<HashMap<i32, usize>>,
+    queue: RefCell<VecDeque<Waiter>>,
+}
+
+impl ConcurrencyLimiter {
+    pub(crate) fn new(limits: ConcurrencyLimits) -> Rc<Self> {
+        Rc::new(Self {
+            limits,
+            running: Cell::new(0),
+            running_cmds: RefCell::new(HashMap::new()),
+            queue: RefCell::new(VecDeque::new()),
+        })
+    }
+
+    /// Take a slot for a query to `cmd`, released when the permit is
+    /// dropped.
+    pub(crate) fn acquire(self: &Rc<Self>, cmd: i32) -> Acquired {
+        if self.has_room(cmd) {
+            return Acquired::Now(self.take(cmd));
+        }
+
+        let mut queue = self.queue.borrow_mut();
+        // the queries abandoned by their channel do not take room
+        queue.retain(|waiter| !waiter.sender.is_canceled());
+        if queue.len() >= self.limits.max_queued {
+            return Acquired::Rejected;
+        }
+        let (sender, receiver) = oneshot::channel();
+        queue.push_back(Waiter { cmd, sender });
+        Acquired::Queued(receiver)
+    }
+
+    fn has_room(&self, cmd: i32) -> bool {
+        if let Some(max) = self.limits.global {
+            if self.running.get() >= max {
+                return false;
+            }
+        }
+        match self.limits.cmds.get(&cmd) {
+            Some(max) => self.running_cmds.borrow().get(&cmd).copied().unwrap_or(0) < *max,
+            None => true,
+        }
+    }
+
+    fn take(self: &Rc<Self>, cmd: i32) -> Permit {
+        self.running.set(self.running.get() + 1);
+        if self.limits.cmds.contains_key(&cmd) {
+            *self.running_cmds.borrow_mut().entry(cmd).or_default() += 1;
+        }
+        Permit {
+            limiter: self.clone(),
+            cmd,
+        }
+    }
+
+    fn release(self: &Rc<Self>, cmd: i32) {
+        self.running.set(self.running.get() - 1);
+        if let Some(running) = self.running_cmds.borrow_mut().get_mut(&cmd) {
+            *running -= 1;
+        }
+
+        // the first waiter which fits is given the slot, the queries to the
+        // commands still at their limit keep waiting
+        loop {
+            let waiter = {
+                let mut queue = self.queue.borrow_mut();
+                let pos = queue
+                    .iter()
+                    .position(|waiter| waiter.sender.is_canceled() || self.has_room(waiter.cmd));
+
+                match pos.and_then(|pos| queue.remove(pos)) {
+                    Some(waiter) => waiter,
+                    None => return,
+                }
+            };
+            if waiter.sender.is_canceled() {
+                continue;
+            }
+
+            // a permit not received is released again when dropped
+            let _ = waiter.sender.send(self.take(waiter.cmd));
+            return;
+        }
+    }
+}
+
+/// Slot of a query being handled.
+pub(crate) struct Permit {
+    limiter: Rc<ConcurrencyLimiter>,
+    cmd: i32,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.release(self.cmd);
+    }
+}
+
+// }}}
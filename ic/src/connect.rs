@@ -0,0 +1,71 @@
+use crate::addr::AddrError;
+use std::error;
+use std::fmt;
+use std::time::Duration;
+
+// {{{ Options
+
+/// Retries of `Client::connect_with`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectOptions {
+    /// Time after which connecting is given up.
+    pub deadline: Duration,
+    /// Delay between two attempts, also used by lib-common to reconnect the
+    /// channel once connected.
+    pub retry_interval: Duration,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            deadline: Duration::from_secs(10),
+            retry_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+// }}}
+// {{{ Error
+
+#[derive(Debug, PartialEq)]
+pub enum ConnectError {
+    /// The address is not of the form `host:port`.
+    InvalidAddr(String),
+    /// The host cannot be resolved.
+    Dns(String),
+    /// The TLS configuration differs from the one of the other channels of
+    /// the thread.
+    Tls(String),
+    /// The last attempt before the deadline failed, the server refusing the
+    /// connection or being unreachable.
+    Refused,
+    /// The last attempt was still in progress at the deadline, or the host
+    /// was not resolved yet.
+    Timeout,
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectError::InvalidAddr(addr) => write!(f, "invalid address `{}`", addr),
+            ConnectError::Dns(addr) => write!(f, "cannot resolve address `{}`", addr),
+            ConnectError::Tls(msg) => write!(f, "{}", msg),
+            ConnectError::Refused => write!(f, "connection refused"),
+            ConnectError::Timeout => write!(f, "connection timed out"),
+        }
+    }
+}
+
+impl error::Error for ConnectError {}
+
+impl From<AddrError> for ConnectError {
+    fn from(e: AddrError) -> Self {
+        match e {
+            AddrError::Resolve(addr) => ConnectError::Dns(addr),
+            AddrError::Parse(addr) | AddrError::Listen(addr) => ConnectError::InvalidAddr(addr),
+            AddrError::Tls(msg) => ConnectError::Tls(msg),
+        }
+    }
+}
+
+// }}}
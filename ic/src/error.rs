@@ -1,7 +1,20 @@
+use crate::acl::FORBIDDEN_STATUS;
 use libcommon_sys as sys;
+use std::any::Any;
 use std::error;
 use std::fmt;
 
+// {{{ Error
+
+/// Error of a query, with the exception type `T` of its RPC.
+///
+/// `AnyError` can be used instead to handle the errors of different RPCs
+/// together.
+///
+/// Errors returned by RPC implementations are answered with their `status`,
+/// so that peers get the same error, the exception being packed in the
+/// answer. The message of generic errors is only logged, peers getting a
+/// `ServerError`.
 #[derive(Debug)]
 pub enum Error<T> {
     Exn(T),
@@ -14,6 +27,13 @@ pub enum Error<T> {
     ProxyError,
     TimedOut,
     Canceled,
+    /// The packed arguments of the query exceed the payload limit of the
+    /// channel, given with the size of the payload.
+    PayloadTooLarge(usize),
+    /// The caller is not allowed to query the RPC, as rejected by an `Acl`.
+    ///
+    /// It is answered with `acl::FORBIDDEN_STATUS`.
+    Forbidden,
 }
 
 impl<T> fmt::Display for Error<T> {
@@ -32,6 +52,8 @@ impl<T> fmt::Display for Error<T> {
                 Error::ProxyError => "proxy error",
                 Error::TimedOut => "timed out",
                 Error::Canceled => "canceled",
+                Error::PayloadTooLarge(_) => "payload too large",
+                Error::Forbidden => "forbidden",
             }
         )
     }
@@ -50,16 +72,16 @@ impl<T> From<sys::ic_status_t> for Error<T> {
             sys::ic_status_t_IC_MSG_PROXY_ERROR => Self::ProxyError,
             sys::ic_status_t_IC_MSG_TIMEDOUT => Self::TimedOut,
             sys::ic_status_t_IC_MSG_CANCELED => Self::Canceled,
-            _ => {
-                unreachable!();
-            }
+            // a peer cannot be trusted to answer with a known status
+            _ => Self::Generic(format!("unexpected answer status {}", status)),
         }
     }
 }
 
-impl<T> From<Error<T>> for sys::ic_status_t {
-    fn from(status: Error<T>) -> Self {
-        match status {
+impl<T> Error<T> {
+    /// Status of the answer of a query failing with this error.
+    pub fn status(&self) -> sys::ic_status_t {
+        match self {
             Error::Retry => sys::ic_status_t_IC_MSG_RETRY,
             Error::Abort => sys::ic_status_t_IC_MSG_ABORT,
             Error::Invalid => sys::ic_status_t_IC_MSG_INVALID,
@@ -69,11 +91,180 @@ impl<T> From<Error<T>> for sys::ic_status_t {
             Error::TimedOut => sys::ic_status_t_IC_MSG_TIMEDOUT,
             Error::Canceled => sys::ic_status_t_IC_MSG_CANCELED,
             Error::Exn(_) => sys::ic_status_t_IC_MSG_EXN,
-            /* TODO: this isn't what we want to do */
-            Error::Generic(s) => {
-                println!("generic error: {}", s);
-                sys::ic_status_t_IC_MSG_SERVER_ERROR
+            Error::PayloadTooLarge(_) => sys::ic_status_t_IC_MSG_INVALID,
+            Error::Forbidden => FORBIDDEN_STATUS,
+            Error::Generic(_) => sys::ic_status_t_IC_MSG_SERVER_ERROR,
+        }
+    }
+}
+
+impl<T> Error<T> {
+    /// Kind of the error, regardless of its exception.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Exn(_) => ErrorKind::Exn,
+            Error::Generic(_) => ErrorKind::Generic,
+            Error::Retry => ErrorKind::Retry,
+            Error::Abort => ErrorKind::Abort,
+            Error::Invalid => ErrorKind::Invalid,
+            Error::Unimplemented => ErrorKind::Unimplemented,
+            Error::ServerError => ErrorKind::ServerError,
+            Error::ProxyError => ErrorKind::ProxyError,
+            Error::TimedOut => ErrorKind::TimedOut,
+            Error::Canceled => ErrorKind::Canceled,
+            Error::PayloadTooLarge(_) => ErrorKind::PayloadTooLarge,
+            Error::Forbidden => ErrorKind::Forbidden,
+        }
+    }
+
+    /// Convert the exception of the error, keeping the other variants.
+    pub fn map_exn<U, F>(self, f: F) -> Error<U>
+    where
+        F: FnOnce(T) -> U,
+    {
+        match self {
+            Error::Exn(exn) => Error::Exn(f(exn)),
+            Error::Generic(s) => Error::Generic(s),
+            Error::Retry => Error::Retry,
+            Error::Abort => Error::Abort,
+            Error::Invalid => Error::Invalid,
+            Error::Unimplemented => Error::Unimplemented,
+            Error::ServerError => Error::ServerError,
+            Error::ProxyError => Error::ProxyError,
+            Error::TimedOut => Error::TimedOut,
+            Error::Canceled => Error::Canceled,
+            Error::PayloadTooLarge(size) => Error::PayloadTooLarge(size),
+            Error::Forbidden => Error::Forbidden,
+        }
+    }
+}
+
+impl<T> From<Error<T>> for sys::ic_status_t {
+    fn from(status: Error<T>) -> Self {
+        status.status()
+    }
+}
+
+// }}}
+// {{{ Error Kind
+
+/// Kind of an `Error`, without its exception nor details.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    Exn,
+    Generic,
+    Retry,
+    Abort,
+    Invalid,
+    Unimplemented,
+    ServerError,
+    ProxyError,
+    TimedOut,
+    Canceled,
+    PayloadTooLarge,
+    Forbidden,
+}
+
+// }}}
+// {{{ Any Error
+
+/// Exceptions that can be stored in an `AnyError`.
+trait Exception: Any + fmt::Debug + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: Any + fmt::Debug + Send + Sync> Exception for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+/// Error of a query, whatever its RPC.
+///
+/// Any `Error<T>` converts into it, so that `?` can be used on queries of
+/// different RPCs in the same function. The exception is kept, and can be
+/// retrieved with `exn` or `downcast` given its type:
+///
+/// ```ignore
+/// async fn get_name(ic: &mut Channel, id: u32) -> Result<String, AnyError> {
+///     let user = GetUser::call(ic, IFACE, GetUserArg { user_id: id }).await?;
+///     let res = SayHello::call(ic, IFACE, SayHelloArg { user_id: id }).await?;
+///     ...
+/// }
+///
+/// match get_name(&mut ic, 1).await {
+///     Err(e) if e.kind() == ErrorKind::Exn => e.exn::<GetUserExn>(),
+///     ...
+/// }
+/// ```
+#[derive(Debug)]
+pub struct AnyError(Error<Box<dyn Exception>>);
+
+impl AnyError {
+    /// Kind of the error.
+    pub fn kind(&self) -> ErrorKind {
+        self.0.kind()
+    }
+
+    /// Status of the answer of a query failing with this error.
+    pub fn status(&self) -> sys::ic_status_t {
+        self.0.status()
+    }
+
+    /// Exception of the error, if it is one of type `T`.
+    pub fn exn<T: Any>(&self) -> Option<&T> {
+        match &self.0 {
+            Error::Exn(exn) => exn.as_any().downcast_ref(),
+            _ => None,
+        }
+    }
+
+    /// Get back the typed error, failing if the error is an exception of
+    /// another type than `T`.
+    pub fn downcast<T: Any>(self) -> Result<Error<T>, Self> {
+        match self.0 {
+            Error::Exn(exn) if exn.as_any().is::<T>() => {
+                // The type was just checked, so this cannot fail.
+                Ok(Error::Exn(*exn.into_any().downcast().unwrap()))
             }
+            Error::Exn(exn) => Err(AnyError(Error::Exn(exn))),
+            e => Ok(e.map_exn(|_| unreachable!())),
         }
     }
 }
+
+impl fmt::Display for AnyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl error::Error for AnyError {}
+
+impl<T> From<Error<T>> for AnyError
+where
+    T: Any + fmt::Debug + Send + Sync,
+{
+    fn from(err: Error<T>) -> Self {
+        AnyError(err.map_exn(|exn| Box::new(exn) as Box<dyn Exception>))
+    }
+}
+
+impl From<sys::ic_status_t> for AnyError {
+    fn from(status: sys::ic_status_t) -> Self {
+        AnyError(Error::from(status))
+    }
+}
+
+impl From<AnyError> for sys::ic_status_t {
+    fn from(err: AnyError) -> Self {
+        err.status()
+    }
+}
+
+// }}}
@@ -0,0 +1,130 @@
+use crate::error;
+use crate::ic::Channel;
+use crate::types::Rpc;
+use futures::channel::oneshot;
+use futures::future::{Future, FutureExt};
+use libcommon_el::el::{Wake, WakeHandle};
+use libcommon_el::el_future;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+// {{{ Channel handle
+
+type Job = Box<dyn FnOnce(&Channel) + Send>;
+
+// jobs posted by the handles, run on the thread of the event loop
+#[derive(Default)]
+struct Queue {
+    jobs: Mutex<Vec<Job>>,
+    // set once all the handles are dropped
+    closed: AtomicBool,
+}
+
+struct Shared {
+    queue: Arc<Queue>,
+    wake: WakeHandle,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // the element is dropped by the thread of the event loop
+        self.queue.closed.store(true, Ordering::Release);
+        self.wake.wake();
+    }
+}
+
+/// Handle to send queries on a channel from any thread, built with
+/// `Channel::handle`.
+///
+/// The queries are posted to the thread of the event loop of the channel,
+/// woken for them, and their results are sent back to the futures returned
+/// by `call`, which can be awaited by any executor.
+///
+/// ```ignore
+/// let handle = client.get_channel().handle();
+/// std::thread::spawn(move || {
+///     let res = block_on(handle.call::<GetUser>(IFACE, GetUserArg { id }));
+/// });
+/// ```
+#[derive(Clone)]
+pub struct ChannelHandle {
+    shared: Arc<Shared>,
+}
+
+impl ChannelHandle {
+    pub(crate) fn new(ic: Channel) -> Self {
+        let queue = Arc::new(Queue::default());
+        let wake: Rc<RefCell<Option<Wake>>> = Rc::default();
+        let mut element = {
+            let queue = queue.clone();
+            let wake = wake.clone();
+
+            Wake::new(move || {
+                let jobs = std::mem::take(&mut *queue.jobs.lock().unwrap());
+
+                for job in jobs {
+                    (job)(&ic);
+                }
+                if queue.closed.load(Ordering::Acquire) {
+                    // the element cannot be dropped from its own callback
+                    let wake = wake.borrow_mut().take();
+
+                    el_future::spawn(async move { drop(wake) });
+                }
+            })
+        };
+
+        // the handles do not keep the event loop running
+        element.unref();
+        let handle = element.handle();
+        *wake.borrow_mut() = Some(element);
+
+        Self {
+            shared: Arc::new(Shared {
+                queue,
+                wake: handle,
+            }),
+        }
+    }
+
+    /// Call the RPC `R` on the channel, from any thread.
+    ///
+    /// The query fails with `Error::Canceled` if the channel is dropped, or
+    /// if its event loop stopped.
+    pub fn call<R>(
+        &self,
+        iface_tag: u16,
+        arg: R::Input,
+    ) -> impl Future<Output = Result<R::Output, error::Error<R::Exception>>> + Send
+    where
+        R: Rpc + 'static,
+        R::Input: Send,
+        R::Output: Send,
+        R::Exception: Send,
+    {
+        let (tx, rx) = oneshot::channel();
+
+        self.post(Box::new(move |ic: &Channel| {
+            let query = R::call(&mut ic.dup(), iface_tag, arg);
+
+            el_future::spawn(async move {
+                let _ = tx.send(query.await);
+            });
+        }));
+        rx.map(|res| res.unwrap_or(Err(error::Error::Canceled)))
+    }
+
+    fn post(&self, job: Job) {
+        let queue = &self.shared.queue;
+
+        queue.jobs.lock().unwrap().push(job);
+        if !self.shared.wake.wake() {
+            // the senders of the results are dropped with the jobs
+            queue.jobs.lock().unwrap().clear();
+        }
+    }
+}
+
+// }}}
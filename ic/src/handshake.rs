@@ -0,0 +1,62 @@
+use serde_iop::{Deserialize, Serialize};
+
+// {{{ Capabilities
+
+/// Version of an interface supported by a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IfaceVersion {
+    pub iface_tag: u16,
+    pub version: u32,
+}
+
+/// Interfaces versions and feature flags advertised by a peer, exchanged by
+/// the handshake sent when a client connects.
+///
+/// It lets the two sides of a rolling upgrade gate the fields and RPCs the
+/// other side does not know yet. Peers not doing the handshake, such as C
+/// services, advertise nothing, and should be treated as the oldest
+/// version.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub ifaces: Vec<IfaceVersion>,
+    pub features: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advertise `version` of the interface `iface_tag`, replacing the
+    /// previous one.
+    pub fn with_iface(mut self, iface_tag: u16, version: u32) -> Self {
+        self.ifaces.retain(|iface| iface.iface_tag != iface_tag);
+        self.ifaces.push(IfaceVersion { iface_tag, version });
+        self
+    }
+
+    pub fn with_feature(mut self, feature: &str) -> Self {
+        if !self.has_feature(feature) {
+            self.features.push(feature.to_owned());
+        }
+        self
+    }
+
+    /// Version of the interface `iface_tag`, if advertised.
+    pub fn iface_version(&self, iface_tag: u16) -> Option<u32> {
+        self.ifaces
+            .iter()
+            .find(|iface| iface.iface_tag == iface_tag)
+            .map(|iface| iface.version)
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// Command of the handshake, sent by clients with their capabilities when
+/// they connect, and answered by servers with theirs.
+pub(crate) const HANDSHAKE_CMD: i32 = 0x7fff_ff02;
+
+// }}}
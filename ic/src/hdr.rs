@@ -0,0 +1,180 @@
+use libcommon_sys as sys;
+use serde_iop::{to_bytes, Serialize, Wire};
+use std::fmt;
+use std::mem;
+use std::time::Instant;
+
+// {{{ Query header
+
+/// Header of a query, identifying its caller.
+///
+/// This is a copy of the simple `ic.Hdr` of the query, so it can be kept by
+/// the RPC implementation after the query is received. Routed queries give
+/// the header of their original query.
+///
+/// Clients attach it to their queries with `Rpc::call_with_hdr`, to
+/// authenticate against services checking the login of their callers.
+#[derive(Clone, Default, PartialEq)]
+pub struct QueryHeader {
+    pub login: Option<String>,
+    pub password: Option<String>,
+    pub group: Option<String>,
+    pub host: Option<String>,
+    pub source: Option<String>,
+    pub kind: Option<String>,
+    pub payload: i32,
+    /// Deadline of the query, after which it times out.
+    ///
+    /// `ic.SimpleHdr` has no field for it, so it is not sent to the peer:
+    /// it is only known by the process which sets it.
+    pub deadline: Option<Instant>,
+}
+
+impl fmt::Debug for QueryHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QueryHeader")
+            .field("login", &self.login)
+            .field("password", &self.password.as_ref().map(|_| "..."))
+            .field("group", &self.group)
+            .field("host", &self.host)
+            .field("source", &self.source)
+            .field("kind", &self.kind)
+            .field("payload", &self.payload)
+            .field("deadline", &self.deadline)
+            .finish()
+    }
+}
+
+impl QueryHeader {
+    /// Header authenticating queries with a login and a password.
+    pub fn with_login(login: &str, password: &str) -> Self {
+        Self {
+            login: Some(login.to_owned()),
+            password: Some(password.to_owned()),
+            ..Self::default()
+        }
+    }
+
+    /// Copy the header of a received query, which may be null.
+    ///
+    /// # Safety
+    ///
+    /// `hdr` must be null or point to a valid header.
+    pub(crate) unsafe fn from_raw(mut hdr: *const sys::ic__hdr__t) -> Self {
+        while !hdr.is_null() && (*hdr).iop_tag == sys::ic__hdr__tag_t_ic__hdr__routing__ft {
+            hdr = (*hdr).__bindgen_anon_1.routing.original_hdr;
+        }
+        if hdr.is_null() || (*hdr).iop_tag != sys::ic__hdr__tag_t_ic__hdr__simple__ft {
+            return Self::default();
+        }
+
+        let simple = &(*hdr).__bindgen_anon_1.simple;
+        Self {
+            login: lstr_to_string(&simple.login),
+            password: lstr_to_string(&simple.password),
+            group: lstr_to_string(&simple.group),
+            host: lstr_to_string(&simple.host),
+            source: lstr_to_string(&simple.source),
+            kind: lstr_to_string(&simple.kind),
+            payload: simple.payload,
+            deadline: None,
+        }
+    }
+
+    /// Pack the header as an `ic.Hdr`, in front of the arguments of a query.
+    pub(crate) fn pack(&self) -> Vec<u8> {
+        let simple = SimpleHdr {
+            login: self.login.as_deref(),
+            password: self.password.as_deref(),
+            kind: self.kind.as_deref(),
+            payload: self.payload,
+            dealias: None,
+            host: self.host.as_deref(),
+            group: self.group.as_deref(),
+            source: self.source.as_deref(),
+            workspace_id: None,
+        };
+        let content = to_bytes(&simple).unwrap();
+
+        // the header is a union, whose simple variant has tag 1
+        let mut res = Vec::with_capacity(content.len() + 5);
+        res.push(Wire::BLK4 as u8 | 1);
+        res.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        res.extend_from_slice(&content);
+        res
+    }
+
+    /// Build the C header, borrowing the strings of `self`.
+    pub(crate) fn to_raw(&self) -> sys::ic__hdr__t {
+        unsafe {
+            let mut hdr: sys::ic__hdr__t = mem::zeroed();
+            let simple = &mut hdr.__bindgen_anon_1.simple;
+
+            hdr.iop_tag = sys::ic__hdr__tag_t_ic__hdr__simple__ft;
+            simple.login = opt_lstr(&self.login);
+            simple.password = opt_lstr(&self.password);
+            simple.kind = opt_lstr(&self.kind);
+            simple.payload = self.payload;
+            simple.host = opt_lstr(&self.host);
+            simple.group = opt_lstr(&self.group);
+            simple.source = opt_lstr(&self.source);
+            hdr
+        }
+    }
+}
+
+/// `ic.SimpleHdr`, as packed by the C library.
+#[derive(Serialize)]
+struct SimpleHdr<'a> {
+    login: Option<&'a str>,
+    password: Option<&'a str>,
+    kind: Option<&'a str>,
+    payload: i32,
+    dealias: Option<bool>,
+    host: Option<&'a str>,
+    group: Option<&'a str>,
+    source: Option<&'a str>,
+    workspace_id: Option<u64>,
+}
+
+/// Header attached to a query, with the strings it points to.
+pub(crate) struct RawHeader {
+    raw: sys::ic__hdr__t,
+    _hdr: Box<QueryHeader>,
+}
+
+// The pointers of the C header only reference the strings of the header.
+unsafe impl Send for RawHeader {}
+
+impl RawHeader {
+    pub(crate) fn new(hdr: &QueryHeader) -> Box<Self> {
+        let hdr = Box::new(hdr.clone());
+
+        Box::new(Self {
+            raw: hdr.to_raw(),
+            _hdr: hdr,
+        })
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const sys::ic__hdr__t {
+        &self.raw
+    }
+}
+
+fn opt_lstr(s: &Option<String>) -> sys::lstr_t {
+    match s {
+        Some(s) => sys::to_lstr(s).as_raw(),
+        None => unsafe { mem::zeroed() },
+    }
+}
+
+/// Copy an optional string of a header, null when absent.
+unsafe fn lstr_to_string(s: &sys::lstr_t) -> Option<String> {
+    if s.__bindgen_anon_1.s.is_null() {
+        None
+    } else {
+        Some(String::from_utf8_lossy(sys::from_lstr(s)).into_owned())
+    }
+}
+
+// }}}
@@ -0,0 +1,933 @@
+use crate::addr::AddrError;
+use crate::error;
+use crate::hdr::QueryHeader;
+use crate::ic::{loopback_query, unpack_answer, RpcDispatcher};
+use crate::types::Rpc;
+use futures::channel::oneshot;
+use futures::future::{self, AbortHandle, Abortable, Either, Future, FutureExt, LocalBoxFuture};
+use futures::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use futures::{pin_mut, ready};
+use libcommon_el::{el, el_future};
+use libcommon_sys as sys;
+use serde_iop::to_bytes;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+
+// Queries are sent as packed IOP over HTTP, for the peers and load balancers
+// speaking HTTP. This is not the ichttp transport of lib-common, whose
+// bodies are JSON or XML: the servers of this module are only queried by its
+// `HttpClient`, or by peers following the same conventions.
+//
+// Each query is a `POST` to `<prefix>/<package>.<Interface>/<rpc>`, the RPC
+// being named by its description. The bodies are the packed arguments and
+// answers, with the status of the answer given by the `X-Ic-Status` header,
+// so that peers get the same errors as on a channel. The login, password
+// and group of the header of the queries are given by the `X-Ic-Login`,
+// `X-Ic-Password` and `X-Ic-Group` headers.
+
+/// Header giving the status of an answer.
+const STATUS_HEADER: &str = "X-Ic-Status";
+
+/// Headers giving the fields of the header of a query.
+const LOGIN_HEADER: &str = "X-Ic-Login";
+const PASSWORD_HEADER: &str = "X-Ic-Password";
+const GROUP_HEADER: &str = "X-Ic-Group";
+
+/// Content type of the bodies, packed IOP.
+const CONTENT_TYPE: &str = "application/x-iop";
+
+/// Maximum size of the request or status line and of the headers.
+const MAX_HEAD_SIZE: u64 = 16 << 10;
+
+/// Maximum size of the bodies.
+const MAX_BODY_SIZE: usize = 64 << 20;
+
+// {{{ Messages
+
+/// Request or response, without its body.
+struct Head {
+    start_line: String,
+    // names are lowercased
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    /// Read the head of a message, `None` if the peer closed the connection
+    /// before sending one.
+    async fn read<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut reader = reader.take(MAX_HEAD_SIZE);
+        let mut lines = Vec::new();
+
+        loop {
+            let mut line = String::new();
+
+            if reader.read_line(&mut line).await? == 0 {
+                if lines.is_empty() {
+                    return Ok(None);
+                }
+                return Err(invalid_data("truncated HTTP head"));
+            }
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+            if line.is_empty() {
+                break;
+            }
+            lines.push(line.to_owned());
+        }
+
+        let mut lines = lines.into_iter();
+        let start_line = lines
+            .next()
+            .ok_or_else(|| invalid_data("empty HTTP head"))?;
+        let headers = lines
+            .map(|line| match line.split_once(':') {
+                Some((name, value)) => Ok((name.trim().to_lowercase(), value.trim().to_owned())),
+                None => Err(invalid_data("invalid HTTP header")),
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Some(Self {
+            start_line,
+            headers,
+        }))
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn content_length(&self) -> io::Result<Option<usize>> {
+        match self.header("content-length") {
+            Some(len) => match len.parse() {
+                Ok(len) if len <= MAX_BODY_SIZE => Ok(Some(len)),
+                _ => Err(invalid_data("invalid HTTP content length")),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read a body of `len` bytes, the buffer growing as they are received.
+async fn read_body<R: AsyncRead + Unpin>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+
+    reader.take(len as u64).read_to_end(&mut body).await?;
+    if body.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(body)
+}
+
+/// HTTP code of the answers with `status`, for the proxies and load
+/// balancers in front of the servers.
+fn http_code(status: sys::ic_status_t) -> (u16, &'static str) {
+    match status {
+        sys::ic_status_t_IC_MSG_OK => (200, "OK"),
+        sys::ic_status_t_IC_MSG_INVALID => (400, "Bad Request"),
+        sys::ic_status_t_IC_MSG_UNIMPLEMENTED => (404, "Not Found"),
+        sys::ic_status_t_IC_MSG_PROXY_ERROR => (502, "Bad Gateway"),
+        sys::ic_status_t_IC_MSG_RETRY | sys::ic_status_t_IC_MSG_CANCELED => {
+            (503, "Service Unavailable")
+        }
+        sys::ic_status_t_IC_MSG_TIMEDOUT => (504, "Gateway Timeout"),
+        _ => (500, "Internal Server Error"),
+    }
+}
+
+/// Status of an answer not given by an ic server, such as the errors of a
+/// proxy.
+fn ic_status(code: u16) -> Option<sys::ic_status_t> {
+    match code {
+        404 => Some(sys::ic_status_t_IC_MSG_UNIMPLEMENTED),
+        502 => Some(sys::ic_status_t_IC_MSG_PROXY_ERROR),
+        503 => Some(sys::ic_status_t_IC_MSG_RETRY),
+        504 => Some(sys::ic_status_t_IC_MSG_TIMEDOUT),
+        _ => None,
+    }
+}
+
+// }}}
+// {{{ Streams
+
+/// Non-blocking TCP stream, whose I/O is done on the event loop.
+struct Stream {
+    // unregistered before the socket is closed
+    fd: el_future::Fd,
+    tcp: TcpStream,
+}
+
+impl Stream {
+    fn new(tcp: TcpStream) -> io::Result<Self> {
+        tcp.set_nonblocking(true)?;
+
+        Ok(Self {
+            fd: el_future::Fd::new(tcp.as_raw_fd()),
+            tcp,
+        })
+    }
+
+    /// Connect to `addr`, without blocking the event loop.
+    async fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let mut stream = Self::new(connect_nonblocking(addr)?)?;
+
+        future::poll_fn(|cx| stream.fd.poll_ready(cx, libc::POLLOUT)).await;
+        match stream.tcp.take_error()? {
+            Some(e) => Err(e),
+            None => Ok(stream),
+        }
+    }
+
+    /// Resolve once the peer closes or resets the connection.
+    ///
+    /// The data received in the meantime is left for the next reads, and
+    /// stops the watch: the peer sending more requests did not close it.
+    async fn closed(&mut self) {
+        let mut buf = [0; 1];
+
+        loop {
+            match self.tcp.peek(&mut buf) {
+                Ok(0) => return,
+                Ok(_) => return future::pending().await,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    future::poll_fn(|cx| self.fd.poll_ready(cx, libc::POLLIN)).await
+                }
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        loop {
+            match (&this.tcp).read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    ready!(this.fd.poll_ready(cx, libc::POLLIN))
+                }
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        loop {
+            match (&this.tcp).write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    ready!(this.fd.poll_ready(cx, libc::POLLOUT))
+                }
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.tcp.shutdown(Shutdown::Write))
+    }
+}
+
+/// Open a non-blocking socket connecting to `addr`, the connection being
+/// established once it is writable.
+fn connect_nonblocking(addr: SocketAddr) -> io::Result<TcpStream> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    let fd = unsafe {
+        libc::socket(
+            storage.ss_family as libc::c_int,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // closes the socket on errors
+    let tcp = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let res = unsafe {
+        libc::connect(
+            fd,
+            &storage as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        let e = io::Error::last_os_error();
+
+        if e.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(e);
+        }
+    }
+    Ok(tcp)
+}
+
+/// Fail with `TimedOut` if `fut` does not resolve within `timeout`.
+async fn with_timeout<T, F>(timeout: Duration, fut: F) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    let timer = el_future::Timer::new(timeout.as_millis() as i64, 0).await;
+
+    pin_mut!(fut);
+    match future::select(fut, timer).await {
+        Either::Left((res, _)) => res,
+        Either::Right(_) => Err(io::ErrorKind::TimedOut.into()),
+    }
+}
+
+// }}}
+// {{{ Server
+
+/// Limits of the connections of an `HttpServer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HttpLimits {
+    /// Connections handled at once, the others being answered with a `503`
+    /// and closed. 1024 by default.
+    pub max_connections: usize,
+    /// Time given to the peers to send a request once started, and to read
+    /// its answer, after which the connection is closed. 30 seconds by
+    /// default.
+    pub timeout: Duration,
+    /// Time after which the connections without request are closed. One
+    /// minute by default.
+    pub idle_timeout: Duration,
+}
+
+impl Default for HttpLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Error answering an invalid request, before closing its connection.
+type HttpError = (u16, &'static str);
+
+/// Answer of a query, given the name of its RPC, its header and its packed
+/// arguments.
+pub(crate) type Handler =
+    Box<dyn Fn(&str, QueryHeader, Vec<u8>) -> LocalBoxFuture<'static, (sys::ic_status_t, Vec<u8>)>>;
+
+/// Query read from a connection.
+struct Request {
+    name: String,
+    hdr: QueryHeader,
+    data: Vec<u8>,
+    keep_alive: bool,
+}
+
+/// State of an `HttpServer`, shared with its connections.
+struct ServerState {
+    prefix: String,
+    handler: Handler,
+    // size of the largest bodies accepted
+    max_body: usize,
+    limits: Cell<HttpLimits>,
+    next_id: Cell<u64>,
+    // tasks serving the connections, aborted when the server is dropped
+    connections: RefCell<HashMap<u64, AbortHandle>>,
+}
+
+/// Server answering queries sent over HTTP with the implementations of a
+/// register, for the peers and load balancers speaking HTTP.
+///
+/// The connections are served by tasks of the event loop, where the
+/// implementations are called as for the queries of a loopback channel.
+/// Only the RPCs with a name, given by their description, can be queried.
+///
+/// The queries are not checked as those of a `Server`: servers authenticating
+/// or limiting their queries answer them over HTTP with
+/// `Server::listen_http`.
+///
+/// ```ignore
+/// let server = HttpServer::new("127.0.0.1:8080", "/iop", &reg.build())?;
+/// ```
+pub struct HttpServer {
+    local_addr: SocketAddr,
+    // accepts the connections, closing the listener once dropped
+    _el: el::Fd,
+    state: Rc<ServerState>,
+}
+
+impl HttpServer {
+    /// Listen on `hostname`, answering the queries whose path starts with
+    /// `prefix`.
+    ///
+    /// Hostnames are resolved synchronously, as with `Server::new`.
+    pub fn new(hostname: &str, prefix: &str, register: &RpcDispatcher) -> Result<Self, AddrError> {
+        let register = register.register.clone();
+        let handler: Handler = Box::new(move |name, hdr, data| match register.named_cmd(name) {
+            Some(cmd) => loopback_query(register.clone(), Some(&hdr), cmd, &data).boxed_local(),
+            None => {
+                future::ready((sys::ic_status_t_IC_MSG_UNIMPLEMENTED, Vec::new())).boxed_local()
+            }
+        });
+
+        Self::with_handler(hostname, prefix, None, handler)
+    }
+
+    /// Same as `new`, answering the queries with `handler`, and refusing the
+    /// bodies larger than `max_body`.
+    pub(crate) fn with_handler(
+        hostname: &str,
+        prefix: &str,
+        max_body: Option<usize>,
+        handler: Handler,
+    ) -> Result<Self, AddrError> {
+        let addr = hostname
+            .to_socket_addrs()
+            .map_err(|_| AddrError::Resolve(hostname.to_owned()))?
+            .next()
+            .ok_or_else(|| AddrError::Resolve(hostname.to_owned()))?;
+        let listen_error = |_| AddrError::Listen(hostname.to_owned());
+        let listener = TcpListener::bind(addr).map_err(listen_error)?;
+        let local_addr = listener.local_addr().map_err(listen_error)?;
+        listener.set_nonblocking(true).map_err(listen_error)?;
+
+        let state = Rc::new(ServerState {
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            handler,
+            max_body: max_body.map_or(MAX_BODY_SIZE, |max| max.min(MAX_BODY_SIZE)),
+            limits: Cell::new(HttpLimits::default()),
+            next_id: Cell::new(0),
+            connections: RefCell::new(HashMap::new()),
+        });
+        let mut el = {
+            let state = state.clone();
+            let fd = listener.as_raw_fd();
+
+            el::Fd::new(fd, libc::POLLIN, move |_events| {
+                loop {
+                    match listener.accept() {
+                        Ok((tcp, _)) => Self::accept(&state, tcp),
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            log::warn!("cannot accept HTTP connection: {}", e);
+                            break;
+                        }
+                    }
+                }
+                libc::POLLIN
+            })
+        };
+        // the server does not keep the event loop running
+        el.unref();
+
+        Ok(Self {
+            local_addr,
+            _el: el,
+            state,
+        })
+    }
+
+    /// Address the server listens on, giving the port picked when listening
+    /// on port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Limits of the connections, applied to the requests read from now on.
+    pub fn set_limits(&mut self, limits: HttpLimits) {
+        self.state.limits.set(limits);
+    }
+
+    fn accept(state: &Rc<ServerState>, tcp: TcpStream) {
+        if state.connections.borrow().len() >= state.limits.get().max_connections {
+            // best effort, the send buffer of the socket is empty
+            let _ = tcp.set_nonblocking(true);
+            let _ = (&tcp).write_all(&error_message((503, "Service Unavailable")));
+            return;
+        }
+        let stream = match Stream::new(tcp) {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+
+        let id = state.next_id.get();
+        state.next_id.set(id + 1);
+        let (handle, registration) = AbortHandle::new_pair();
+        state.connections.borrow_mut().insert(id, handle);
+
+        let conn = Connection {
+            state: state.clone(),
+            id,
+        };
+        el_future::spawn(Abortable::new(conn.serve(stream), registration).map(|_| ()));
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        // the queries being handled are canceled with their connection
+        for (_, handle) in self.state.connections.borrow().iter() {
+            handle.abort();
+        }
+    }
+}
+
+/// Connection accepted by an `HttpServer`, served by a task of the event
+/// loop.
+struct Connection {
+    state: Rc<ServerState>,
+    id: u64,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.state.connections.borrow_mut().remove(&self.id);
+    }
+}
+
+impl Connection {
+    async fn serve(self, stream: Stream) {
+        let mut reader = BufReader::new(stream);
+
+        // requests are answered in order until the peer closes the
+        // connection, or asks for it to be closed
+        loop {
+            let limits = self.state.limits.get();
+
+            // wait for the next request
+            let wait = future::poll_fn(|cx| {
+                Pin::new(&mut reader)
+                    .poll_fill_buf(cx)
+                    .map_ok(|buf| !buf.is_empty())
+            });
+            match with_timeout(limits.idle_timeout, wait).await {
+                Ok(true) => (),
+                // closed by the peer, idle or failed
+                _ => return,
+            }
+
+            let read = async { Ok(self.read_request(&mut reader).await) };
+            let request = match with_timeout(limits.timeout, read).await {
+                Ok(Ok(request)) => request,
+                Ok(Err(e)) => {
+                    // the body may not have been read
+                    let _ = send(reader.get_mut(), &error_message(e), limits.timeout).await;
+                    return;
+                }
+                Err(_) => {
+                    let error = error_message((408, "Request Timeout"));
+
+                    let _ = send(reader.get_mut(), &error, limits.timeout).await;
+                    return;
+                }
+            };
+
+            let answer = {
+                let query = (self.state.handler)(&request.name, request.hdr, request.data);
+                // more requests can be sent before the answer
+                let pipelined = !reader.buffer().is_empty();
+                let closed = async {
+                    if pipelined {
+                        future::pending().await
+                    } else {
+                        reader.get_mut().closed().await
+                    }
+                };
+
+                pin_mut!(query, closed);
+                match future::select(query, closed).await {
+                    Either::Left((answer, _)) => answer,
+                    // the query is canceled with its connection
+                    Either::Right(_) => return,
+                }
+            };
+
+            let (status, data) = answer;
+            let message = answer_message(status, &data, request.keep_alive);
+            if send(reader.get_mut(), &message, limits.timeout)
+                .await
+                .is_err()
+                || !request.keep_alive
+            {
+                return;
+            }
+        }
+    }
+
+    /// Read a query, or give the HTTP error of an invalid request.
+    async fn read_request<R>(&self, reader: &mut R) -> Result<Request, HttpError>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let head = match Head::read(reader).await {
+            Ok(Some(head)) => head,
+            _ => return Err((400, "Bad Request")),
+        };
+        let keep_alive = !head.start_line.ends_with("HTTP/1.0")
+            && !head
+                .header("connection")
+                .is_some_and(|c| c.eq_ignore_ascii_case("close"));
+
+        let mut start_line = head.start_line.split(' ');
+        let (method, path) = match (start_line.next(), start_line.next()) {
+            (Some(method), Some(path)) => (method, path),
+            _ => return Err((400, "Bad Request")),
+        };
+        if method != "POST" {
+            return Err((405, "Method Not Allowed"));
+        }
+        if head.header("transfer-encoding").is_some() {
+            return Err((501, "Not Implemented"));
+        }
+        let len = match head.content_length() {
+            Ok(Some(len)) if len <= self.state.max_body => len,
+            Ok(None) => return Err((411, "Length Required")),
+            _ => return Err((413, "Payload Too Large")),
+        };
+        let hdr = QueryHeader {
+            login: head.header(&LOGIN_HEADER.to_lowercase()).map(str::to_owned),
+            password: head
+                .header(&PASSWORD_HEADER.to_lowercase())
+                .map(str::to_owned),
+            group: head.header(&GROUP_HEADER.to_lowercase()).map(str::to_owned),
+            ..Default::default()
+        };
+
+        // `<prefix>/<package>.<Interface>/<rpc>` for `package.Interface.rpc`
+        let name = match path.strip_prefix(&*self.state.prefix) {
+            Some(rpc) => match rpc.strip_prefix('/').and_then(|rpc| rpc.split_once('/')) {
+                Some((iface, rpc)) if !rpc.contains('/') => format!("{}.{}", iface, rpc),
+                _ => return Err((404, "Not Found")),
+            },
+            None => return Err((404, "Not Found")),
+        };
+        let data = read_body(reader, len)
+            .await
+            .map_err(|_| (400, "Bad Request"))?;
+
+        Ok(Request {
+            name,
+            hdr,
+            data,
+            keep_alive,
+        })
+    }
+}
+
+fn answer_message(status: sys::ic_status_t, answer: &[u8], keep_alive: bool) -> Vec<u8> {
+    let (code, reason) = http_code(status);
+    let mut message = format!(
+        "HTTP/1.1 {} {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         {}: {}\r\n\
+         Connection: {}\r\n\r\n",
+        code,
+        reason,
+        CONTENT_TYPE,
+        answer.len(),
+        STATUS_HEADER,
+        status,
+        if keep_alive { "keep-alive" } else { "close" },
+    )
+    .into_bytes();
+
+    message.extend_from_slice(answer);
+    message
+}
+
+fn error_message((code, reason): HttpError) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        code, reason
+    )
+    .into_bytes()
+}
+
+/// Send a message, failing if the peer does not read it within `timeout`.
+async fn send(stream: &mut Stream, message: &[u8], timeout: Duration) -> io::Result<()> {
+    with_timeout(timeout, stream.write_all(message)).await
+}
+
+// }}}
+// {{{ Client
+
+/// Headers giving the fields of `hdr` sent to the servers, `None` if one
+/// of them cannot be sent.
+fn hdr_lines(hdr: &QueryHeader) -> Option<String> {
+    let mut lines = String::new();
+
+    for (name, value) in [
+        (LOGIN_HEADER, &hdr.login),
+        (PASSWORD_HEADER, &hdr.password),
+        (GROUP_HEADER, &hdr.group),
+    ] {
+        if let Some(value) = value {
+            if value.contains(&['\r', '\n'][..]) {
+                return None;
+            }
+            lines.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    Some(lines)
+}
+
+/// Client sending queries over HTTP, to an `HttpServer` or to a server
+/// behind an HTTP proxy or load balancer.
+///
+/// Each query is sent on its own connection, by the event loop: the
+/// queries must be awaited by its tasks, or with `el_future::block_on`.
+/// Only the RPCs with a name, given by their description, can be queried.
+///
+/// ```ignore
+/// let client = HttpClient::new("http://127.0.0.1:8080/iop")?;
+/// let user = client.call::<GetUser>(GetUserArg { id }).await?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    // `host:port`, sent as the host of the requests
+    host: String,
+    prefix: String,
+    timeout: Duration,
+    // address of the host, resolved by the first query, and again after a
+    // connection failure
+    addr: Arc<Mutex<Option<SocketAddr>>>,
+}
+
+impl HttpClient {
+    /// Client of the server at `url`, of the form
+    /// `http://host[:port][/prefix]`, the port being 80 by default.
+    pub fn new(url: &str) -> Result<Self, AddrError> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| AddrError::Parse(url.to_owned()))?;
+        let (host, prefix) = match rest.find('/') {
+            Some(pos) => rest.split_at(pos),
+            None => (rest, ""),
+        };
+        if host.is_empty() {
+            return Err(AddrError::Parse(url.to_owned()));
+        }
+        // the colons of IPv6 literals are between brackets
+        let host_end = host.rfind(']').map_or(0, |pos| pos + 1);
+        let host = if host[host_end..].contains(':') {
+            host.to_owned()
+        } else {
+            format!("{}:80", host)
+        };
+
+        Ok(Self {
+            host,
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            timeout: Duration::from_secs(60),
+            addr: Arc::default(),
+        })
+    }
+
+    /// Time after which a query fails with `Error::TimedOut`, from the
+    /// resolution of the host to the answer, one minute by default.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Call the RPC `R`.
+    ///
+    /// RPCs without name fail with `Error::Unimplemented`.
+    pub fn call<R: Rpc + 'static>(
+        &self,
+        arg: R::Input,
+    ) -> impl Future<Output = Result<R::Output, error::Error<R::Exception>>> + 'static {
+        self.call_with_hdr::<R>(&QueryHeader::default(), arg)
+    }
+
+    /// Same as `call`, giving the login, the password and the group of
+    /// `hdr` to the server, its other fields not being sent.
+    pub fn call_with_hdr<R: Rpc + 'static>(
+        &self,
+        hdr: &QueryHeader,
+        arg: R::Input,
+    ) -> impl Future<Output = Result<R::Output, error::Error<R::Exception>>> + 'static {
+        let query = R::NAME.map(|name| {
+            // `package.Interface.rpc` is queried at `package.Interface/rpc`
+            let path = match name.rfind('.') {
+                Some(pos) => format!("{}/{}/{}", self.prefix, &name[..pos], &name[pos + 1..]),
+                None => format!("{}/{}", self.prefix, name),
+            };
+
+            (self.clone(), path, hdr_lines(hdr), to_bytes(&arg))
+        });
+
+        async move {
+            let (client, path, headers, data) = match query {
+                Some((client, path, Some(headers), Ok(data))) => (client, path, headers, data),
+                Some((_, _, None, _)) => {
+                    return Err(error::Error::Generic(
+                        "invalid query header: line breaks cannot be sent".to_owned(),
+                    ))
+                }
+                Some((_, _, _, Err(e))) => {
+                    return Err(error::Error::Generic(format!(
+                        "cannot pack rpc arguments: {}",
+                        e
+                    )))
+                }
+                None => return Err(error::Error::Unimplemented),
+            };
+
+            let query = client.send(&path, &headers, &data);
+            match with_timeout(client.timeout, query).await {
+                Ok((status, answer)) => unpack_answer(status, &answer),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => Err(error::Error::TimedOut),
+                Err(e) => Err(error::Error::Generic(format!("HTTP query failed: {}", e))),
+            }
+        }
+    }
+
+    /// Address of the host, resolved by a thread so that the event loop is
+    /// not blocked.
+    async fn resolve(&self) -> io::Result<SocketAddr> {
+        if let Some(addr) = *self.addr.lock().unwrap() {
+            return Ok(addr);
+        }
+
+        let addr = match self.host.parse() {
+            Ok(addr) => addr,
+            Err(_) => {
+                let (tx, rx) = oneshot::channel();
+                let host = self.host.clone();
+
+                thread::spawn(move || {
+                    let addr = host.to_socket_addrs().and_then(|mut addrs| {
+                        addrs.next().ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::NotFound, "cannot resolve host")
+                        })
+                    });
+                    let _ = tx.send(addr);
+                });
+                rx.await
+                    .map_err(|_| io::Error::other("host resolution aborted"))??
+            }
+        };
+        *self.addr.lock().unwrap() = Some(addr);
+        Ok(addr)
+    }
+
+    /// Send a query, resolving once answered.
+    async fn send(
+        &self,
+        path: &str,
+        headers: &str,
+        data: &[u8],
+    ) -> io::Result<(sys::ic_status_t, Vec<u8>)> {
+        let addr = self.resolve().await?;
+        let mut stream = match Stream::connect(addr).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                // the host may have moved
+                *self.addr.lock().unwrap() = None;
+                return Err(e);
+            }
+        };
+
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Content-Type: {}\r\n\
+             Content-Length: {}\r\n\
+             {}\
+             Connection: close\r\n\r\n",
+            if path.is_empty() { "/" } else { path },
+            self.host,
+            CONTENT_TYPE,
+            data.len(),
+            headers,
+        )
+        .into_bytes();
+        request.extend_from_slice(data);
+        stream.write_all(&request).await?;
+
+        let mut reader = BufReader::new(stream);
+        let head = Head::read(&mut reader)
+            .await?
+            .ok_or_else(|| invalid_data("no HTTP response"))?;
+        let code = head
+            .start_line
+            .split(' ')
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| invalid_data("invalid HTTP status line"))?;
+        let body = match head.content_length()? {
+            Some(len) => read_body(&mut reader, len).await?,
+            None => {
+                let mut body = Vec::new();
+
+                (&mut reader)
+                    .take(MAX_BODY_SIZE as u64)
+                    .read_to_end(&mut body)
+                    .await?;
+                body
+            }
+        };
+
+        let status = match head.header(&STATUS_HEADER.to_lowercase()) {
+            Some(status) => status
+                .parse()
+                .map_err(|_| invalid_data("invalid answer status"))?,
+            None => match ic_status(code) {
+                Some(status) => status,
+                None => return Err(io::Error::other(format!("unexpected HTTP status {}", code))),
+            },
+        };
+        Ok((status, body))
+    }
+}
+
+// }}}
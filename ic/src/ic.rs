@@ -1,25 +1,88 @@
+use crate::acl::{Acl, FORBIDDEN_STATUS};
+use crate::addr::{local_addr, peer_addr, AddrError, SockAddr};
+use crate::auth::Authenticator;
+use crate::batch::Batch;
+use crate::capture;
+use crate::compress::{self, Compression, NEGOTIATE_CMD};
+use crate::concurrency::{Acquired, ConcurrencyLimiter, ConcurrencyLimits};
+use crate::connect::{ConnectError, ConnectOptions};
 use crate::error;
-use futures::future::{Future, FutureExt};
+use crate::extensions::Extensions;
+use crate::handle::ChannelHandle;
+use crate::handshake::{Capabilities, HANDSHAKE_CMD};
+use crate::hdr::{QueryHeader, RawHeader};
+use crate::http::{Handler, HttpLimits, HttpServer};
+use crate::keepalive::{Keepalive, PING_CMD};
+use crate::metrics::{Metrics, MetricsSnapshot, QueryMetrics};
+use crate::middleware::{self, Call, Middleware, Next};
+use crate::msgbuf::MsgBuffer;
+use crate::ratelimit::{RateLimiter, RateLimits};
+use crate::record::Recorder;
+use crate::reflect::{ListRpcs, ListRpcsRes, RpcDesc};
+use crate::socket::{ClientOptions, ServerOptions};
+use crate::tls::{self, PeerIdentity, TlsConfig};
+#[cfg(feature = "tracing")]
+use crate::trace;
+use crate::types::Rpc;
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, join_all, Either, Future, FutureExt, LocalBoxFuture};
+use futures::stream::Stream;
+use futures::task::noop_waker;
 use libc;
+use libcommon_el::el::{self, Element};
 use libcommon_el::el_future;
 use libcommon_sys as sys;
-use serde_iop::{from_bytes, to_bytes, DeserializeOwned, Serialize};
-use std::collections::HashMap;
+use serde_iop::{from_bytes, to_buffer, DeserializeOwned, Output, Serialize};
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::marker::PhantomData;
 use std::mem;
+use std::net::SocketAddr;
+use std::ops::Deref;
 use std::os::raw::{c_uchar, c_void};
 use std::pin::Pin;
 use std::rc::Rc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
 use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
 // {{{ RPC Implementation register
 
 pub struct RpcRegister {
     map: sys::qm_ic_cbs_t,
 
-    impls: HashMap<i32, Box<dyn Fn(Channel, &[u8], u64)>>,
+    // commands registered in the map, which cannot be removed from it
+    cmds: HashSet<i32>,
+
+    impls: RefCell<HashMap<i32, RpcImpl>>,
+
+    // names of the implemented RPCs, and commands forwarded by `proxy`,
+    // listed by `rpcs`
+    names: HashMap<i32, &'static str>,
+    proxied: HashSet<i32>,
+
+    // implementation of the commands without one, for loopback channels
+    pub(crate) fallback: Option<RpcImpl>,
+
+    // registers of the instances served by the register, by key of the
+    // header of their queries, the host by default
+    routes: HashMap<String, RpcDispatcher>,
+    route_key: Option<RouteKey>,
+
+    // middlewares of the RPC implementations, and of the queries sent on the
+    // channels using the register
+    middlewares: Rc<[Middleware]>,
+    call_middlewares: Rc<[Middleware]>,
+
+    metrics: Arc<Metrics>,
 }
 
+pub(crate) type RpcImpl =
+    Rc<dyn Fn(Channel, QueryHeader, i32, &[u8], u64) -> LocalBoxFuture<'static, sys::ic_status_t>>;
+
+type RouteKey = Box<dyn Fn(&QueryHeader) -> Option<&str>>;
+
 impl RpcRegister {
     pub fn new() -> Self {
         let map = unsafe {
@@ -34,11 +97,33 @@ impl RpcRegister {
             );
             map
         };
-
-        Self {
+        let mut reg = Self {
             map,
-            impls: HashMap::new(),
+            cmds: HashSet::new(),
+            impls: RefCell::new(HashMap::new()),
+            names: HashMap::new(),
+            proxied: HashSet::new(),
+            fallback: None,
+            routes: HashMap::new(),
+            route_key: None,
+            middlewares: Rc::new([]),
+            call_middlewares: Rc::new([]),
+            metrics: Arc::new(Metrics::default()),
+        };
+
+        // the negotiation of the compression, the handshake and the pings
+        // are answered by every register
+        for cmd in [NEGOTIATE_CMD, HANDSHAKE_CMD, PING_CMD] {
+            unsafe {
+                let mut entry: sys::ic_cb_entry_t = mem::zeroed();
+
+                entry.cb_type = sys::ic_cb_entry_type_t_IC_CB_NORMAL_RAW;
+                entry.u.cbr.cb = Some(RpcRegister::call_rpc_impl);
+
+                sys::_ic_register(&mut reg.map, cmd, &mut entry);
+            }
         }
+        reg
     }
 
     pub fn register<'b, I, O, E, F>(&mut self, cmd: i32, fun: impl Fn(Channel, I) -> F + 'static)
@@ -48,43 +133,392 @@ impl RpcRegister {
         E: Serialize + 'static,
         F: Future<Output = Result<O, error::Error<E>>> + 'static,
     {
-        self.impls.insert(
-            cmd,
-            Box::new(move |channel: Channel, data: &[u8], slot: u64| {
-                let input: I = from_bytes(data).unwrap();
-
-                let promise = fun(channel, input).then(move |result| async move {
-                    match result {
-                        Ok(res) => {
-                            let res = to_bytes(&res).unwrap();
-
-                            send_reply(&res, slot, sys::ic_status_t_IC_MSG_OK);
-                        }
-                        Err(e) => {
-                            match &e {
-                                error::Error::Exn(iop) => {
-                                    let exn = to_bytes(&iop).unwrap();
-
-                                    send_reply(&exn, slot, sys::ic_status_t::from(e));
-                                }
-                                _ => {
-                                    send_reply(&[], slot, sys::ic_status_t::from(e));
-                                }
-                            };
-                        }
+        self.register_with_hdr(cmd, move |channel, _hdr, input| fun(channel, input));
+    }
+
+    /// Same as `register`, giving the header of the queries to `fun`.
+    pub fn register_with_hdr<I, O, E, F>(
+        &mut self,
+        cmd: i32,
+        fun: impl Fn(Channel, QueryHeader, I) -> F + 'static,
+    ) where
+        I: DeserializeOwned,
+        O: Serialize + 'static,
+        E: Serialize + 'static,
+        F: Future<Output = Result<O, error::Error<E>>> + 'static,
+    {
+        self.register_impl(cmd, Self::make_impl(fun));
+    }
+
+    /// Register a handler of the raw queries of `cmd`, given their packed
+    /// arguments, to build gateways or custom codecs without serde types.
+    ///
+    /// The handler answers the query with `Channel::reply_raw`, possibly
+    /// later, and resolves with the status it returned. The middlewares, the
+    /// metrics and the wire hooks of the register are applied as for the
+    /// other implementations.
+    ///
+    /// ```ignore
+    /// reg.register_raw(cmd, move |ic, cmd, data, slot| {
+    ///     let query = backend.forward(cmd, data.to_vec());
+    ///     async move {
+    ///         let (status, answer) = query.await;
+    ///         ic.reply_raw(slot, status, &answer)
+    ///     }
+    /// });
+    /// ```
+    pub fn register_raw<F>(
+        &mut self,
+        cmd: i32,
+        fun: impl Fn(Channel, i32, &[u8], Slot) -> F + 'static,
+    ) where
+        F: Future<Output = sys::ic_status_t> + 'static,
+    {
+        let fun: RpcImpl = Rc::new(move |channel, _hdr, cmd, data, slot| {
+            fun(channel, cmd, data, Slot { cmd, slot }).boxed_local()
+        });
+
+        self.register_impl(cmd, fun);
+    }
+
+    fn register_impl(&mut self, cmd: i32, fun: RpcImpl) {
+        self.impls.get_mut().insert(cmd, fun);
+
+        if self.cmds.insert(cmd) {
+            unsafe {
+                let mut entry: sys::ic_cb_entry_t = mem::zeroed();
+
+                entry.cb_type = sys::ic_cb_entry_type_t_IC_CB_NORMAL_RAW;
+                entry.u.cbr.cb = Some(RpcRegister::call_rpc_impl);
+
+                sys::_ic_register(&mut self.map, cmd, &mut entry);
+            }
+        }
+    }
+
+    /// Register RPC implementations sharing `state`, which they are given
+    /// when called, instead of using a global.
+    ///
+    /// ```ignore
+    /// let state = Rc::new(RefCell::new(State::default()));
+    /// let mut reg = reg.with_state(state);
+    ///
+    /// Get::implement_with_state(&mut reg, IFACE, |state, _ic, arg| async move {
+    ///     state.borrow().find_user(arg.id)
+    /// });
+    /// ```
+    pub fn with_state<S: 'static>(&mut self, state: Rc<S>) -> StateRegister<'_, S> {
+        StateRegister {
+            register: self,
+            state,
+        }
+    }
+
+    /// Freeze the register, to give it to servers and clients.
+    pub fn build(self) -> RpcDispatcher {
+        RpcDispatcher {
+            register: Rc::new(self),
+        }
+    }
+
+    /// Replace the implementation of a registered RPC, while it is used by
+    /// servers and clients.
+    ///
+    /// Returns false if the RPC was never registered, as new RPCs cannot be
+    /// added once the register is built.
+    pub fn replace<I, O, E, F>(&self, cmd: i32, fun: impl Fn(Channel, I) -> F + 'static) -> bool
+    where
+        I: DeserializeOwned,
+        O: Serialize + 'static,
+        E: Serialize + 'static,
+        F: Future<Output = Result<O, error::Error<E>>> + 'static,
+    {
+        if !self.cmds.contains(&cmd) {
+            return false;
+        }
+        let fun = Self::make_impl(move |channel, _hdr, input| fun(channel, input));
+
+        self.impls.borrow_mut().insert(cmd, fun);
+        true
+    }
+
+    /// Remove the implementation of an RPC, which is then answered as
+    /// unimplemented.
+    ///
+    /// Returns false if the RPC was not implemented.
+    pub fn unregister(&self, cmd: i32) -> bool {
+        self.impls.borrow_mut().remove(&cmd).is_some()
+    }
+
+    /// Forward the queries of the commands `cmds` to the channel `target`,
+    /// without unpacking them, and relay its answers.
+    ///
+    /// The forwarding is done by lib-common, so the middlewares, metrics and
+    /// wire hooks of the register are not applied to these queries. The
+    /// client of `target` must outlive the servers and clients using the
+    /// register.
+    ///
+    /// Returns false, without registering any of them, if one of the
+    /// commands is already registered, or if `target` is a loopback or
+    /// dropped channel.
+    ///
+    /// ```ignore
+    /// reg.proxy((IFACE << 16)..((IFACE + 1) << 16), &backend.get_channel());
+    /// ```
+    pub fn proxy(&mut self, cmds: impl IntoIterator<Item = i32>, target: &Channel) -> bool {
+        let cmds: Vec<i32> = cmds.into_iter().collect();
+        let target = match target.live_raw() {
+            Some(raw_ic) => raw_ic,
+            None => return false,
+        };
+
+        if cmds.iter().any(|cmd| {
+            [NEGOTIATE_CMD, HANDSHAKE_CMD, PING_CMD].contains(cmd) || self.cmds.contains(cmd)
+        }) {
+            return false;
+        }
+        for cmd in cmds {
+            if !self.cmds.insert(cmd) {
+                continue;
+            }
+            self.proxied.insert(cmd);
+            unsafe {
+                let mut entry: sys::ic_cb_entry_t = mem::zeroed();
+
+                entry.cb_type = sys::ic_cb_entry_type_t_IC_CB_PROXY_P;
+                entry.u.proxy_p.ic_p = target;
+
+                sys::_ic_register(&mut self.map, cmd, &mut entry);
+            }
+        }
+        true
+    }
+
+    /// Route the queries whose header has the host `key` to the register of
+    /// another instance, so that a single server can serve several of them.
+    ///
+    /// The queries are routed after the authentication, the ACL and the
+    /// limits of the server, and then go through the middlewares and the
+    /// metrics of the register they are routed to only. The queries of the
+    /// hosts without a route are handled by this register, and the commands
+    /// it proxies are not routed.
+    ///
+    /// ```ignore
+    /// let mut reg = RpcRegister::new();
+    /// reg.route("tenant-a", &tenant_a_reg.build());
+    /// reg.route("tenant-b", &tenant_b_reg.build());
+    /// let server = Server::new(addr, Some(reg.build()), None)?;
+    /// ```
+    pub fn route(&mut self, key: &str, dispatcher: &RpcDispatcher) {
+        for cmd in &dispatcher.cmds {
+            if self.cmds.insert(*cmd) {
+                unsafe {
+                    let mut entry: sys::ic_cb_entry_t = mem::zeroed();
+
+                    entry.cb_type = sys::ic_cb_entry_type_t_IC_CB_NORMAL_RAW;
+                    entry.u.cbr.cb = Some(RpcRegister::call_rpc_impl);
+
+                    sys::_ic_register(&mut self.map, *cmd, &mut entry);
+                }
+            }
+        }
+        self.routes.insert(key.to_owned(), dispatcher.clone());
+    }
+
+    /// Route the queries on another field of their header than the host.
+    ///
+    /// ```ignore
+    /// reg.route_on(|hdr| hdr.group.as_deref());
+    /// ```
+    pub fn route_on(&mut self, key: impl Fn(&QueryHeader) -> Option<&str> + 'static) {
+        self.route_key = Some(Box::new(key));
+    }
+
+    /// Register of the instance a query is routed to, if any.
+    fn routed(&self, hdr: &QueryHeader) -> Option<&RpcDispatcher> {
+        let key = match &self.route_key {
+            Some(route_key) => route_key(hdr),
+            None => hdr.host.as_deref(),
+        };
+
+        self.routes.get(key?)
+    }
+
+    /// Wrap the RPC implementations with a middleware, called before the
+    /// middlewares already registered.
+    ///
+    /// ```ignore
+    /// reg.wrap(|next, _ic, hdr, cmd, _data| {
+    ///     if hdr.login.is_none() {
+    ///         return future::ready(sys::ic_status_t_IC_MSG_INVALID).boxed_local();
+    ///     }
+    ///     let start = Instant::now();
+    ///     next().inspect(move |status| log(cmd, *status, start.elapsed())).boxed_local()
+    /// });
+    /// ```
+    pub fn wrap<F>(&mut self, middleware: F)
+    where
+        F: Fn(
+                Next,
+                &Channel,
+                &QueryHeader,
+                i32,
+                &[u8],
+            ) -> LocalBoxFuture<'static, sys::ic_status_t>
+            + 'static,
+    {
+        let mut middlewares = self.middlewares.to_vec();
+
+        middlewares.push(Rc::new(middleware));
+        self.middlewares = middlewares.into();
+    }
+
+    /// Same as `wrap`, for the queries sent on the channels using this
+    /// register.
+    ///
+    /// A middleware not calling `next` cancels the query, which resolves with
+    /// the error matching the returned status.
+    pub fn wrap_calls<F>(&mut self, middleware: F)
+    where
+        F: Fn(
+                Next,
+                &Channel,
+                &QueryHeader,
+                i32,
+                &[u8],
+            ) -> LocalBoxFuture<'static, sys::ic_status_t>
+            + 'static,
+    {
+        let mut middlewares = self.call_middlewares.to_vec();
+
+        middlewares.push(Rc::new(middleware));
+        self.call_middlewares = middlewares.into();
+    }
+
+    /// Metrics of the queries handled by the RPC implementations, and of the
+    /// queries sent on the channels using this register.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Commands handled by the register, implemented or forwarded, sorted
+    /// by command.
+    ///
+    /// RPCs are named when implemented through `Rpc::implement` and its
+    /// variants, if their description gives a name.
+    pub fn rpcs(&self) -> Vec<RpcDesc> {
+        let impls = self.impls.borrow();
+        let mut cmds: Vec<i32> = impls.keys().chain(self.proxied.iter()).copied().collect();
+
+        cmds.sort_unstable();
+        cmds.into_iter()
+            .map(|cmd| RpcDesc {
+                iface_tag: (cmd >> 16) as u16,
+                rpc_tag: (cmd & 0xffff) as u16,
+                name: self.names.get(&cmd).map(|name| (*name).to_owned()),
+                proxied: self.proxied.contains(&cmd),
+            })
+            .collect()
+    }
+
+    /// Implement the `ListRpcs` RPC in the interface `iface_tag`, answering
+    /// with the commands handled by the register of the channel the query
+    /// is received on, so that they can be discovered by its peers.
+    pub fn expose_reflection(&mut self, iface_tag: u16) {
+        ListRpcs::implement(self, iface_tag, |ic, _arg: ()| {
+            future::ready(match ic.register() {
+                Some(reg) => Ok(ListRpcsRes { rpcs: reg.rpcs() }),
+                None => Err(error::Error::Unimplemented),
+            })
+        });
+    }
+
+    pub(crate) fn set_name(&mut self, cmd: i32, name: Option<&'static str>) {
+        match name {
+            Some(name) => self.names.insert(cmd, name),
+            None => self.names.remove(&cmd),
+        };
+    }
+
+    /// Command of the RPC implemented under `name`.
+    pub(crate) fn named_cmd(&self, name: &str) -> Option<i32> {
+        self.names
+            .iter()
+            .find(|(_, n)| **n == name)
+            .map(|(cmd, _)| *cmd)
+    }
+
+    pub(crate) fn make_impl<I, O, E, F>(
+        fun: impl Fn(Channel, QueryHeader, I) -> F + 'static,
+    ) -> RpcImpl
+    where
+        I: DeserializeOwned,
+        O: Serialize + 'static,
+        E: Serialize + 'static,
+        F: Future<Output = Result<O, error::Error<E>>> + 'static,
+    {
+        Rc::new(
+            move |mut channel: Channel, hdr: QueryHeader, cmd: i32, data: &[u8], slot: u64| {
+                let peer = channel.peer_addr();
+                let input: I = match from_bytes(data) {
+                    Ok(input) => input,
+                    Err(e) => {
+                        log::warn!(cmd, slot, peer:?; "cannot unpack rpc arguments: {}", e);
+                        send_reply(&[], cmd, slot, sys::ic_status_t_IC_MSG_INVALID);
+                        return future::ready(sys::ic_status_t_IC_MSG_INVALID).boxed_local();
                     }
+                };
+
+                let reply = Rc::new(PendingReply {
+                    cmd,
+                    slot,
+                    peer,
+                    taken: Cell::new(false),
+                    deferred: Cell::new(None),
                 });
-                el_future::spawn(promise);
-            }),
-        );
-
-        unsafe {
-            let mut entry: sys::ic_cb_entry_t = mem::zeroed();
+                channel.reply = Some(reply.clone());
+
+                fun(channel, hdr, input)
+                    .then(move |result| match reply.deferred.take() {
+                        // the reply is sent with the token taken by the
+                        // implementation, which also gives its status
+                        Some(deferred) => deferred
+                            .map(|status| status.unwrap_or(sys::ic_status_t_IC_MSG_SERVER_ERROR))
+                            .boxed_local(),
+                        None => future::ready(send_result(result, cmd, slot, peer)).boxed_local(),
+                    })
+                    .boxed_local()
+            },
+        )
+    }
 
-            entry.cb_type = sys::ic_cb_entry_type_t_IC_CB_NORMAL_RAW;
-            entry.u.cbr.cb = Some(RpcRegister::call_rpc_impl);
+    /// Keep the capabilities of the client, and answer with the ones of the
+    /// channel, if it has any.
+    fn answer_handshake(ic: &mut InnerClient, data: &[u8], slot: u64) {
+        let caps = match &ic.capabilities {
+            Some(caps) => caps,
+            None => {
+                send_reply(
+                    &[],
+                    HANDSHAKE_CMD,
+                    slot,
+                    sys::ic_status_t_IC_MSG_UNIMPLEMENTED,
+                );
+                return;
+            }
+        };
+        let peer_caps = compress::decompress(data, ic.payload_limits.incoming)
+            .and_then(|data| from_bytes::<Capabilities>(&data).map_err(|e| e.to_string()));
 
-            sys::_ic_register(&mut self.map, cmd, &mut entry);
+        match peer_caps {
+            Ok(peer_caps) => {
+                send_packed_reply(&**caps, HANDSHAKE_CMD, slot, sys::ic_status_t_IC_MSG_OK);
+                ic.peer_capabilities = Some(Rc::new(peer_caps));
+            }
+            Err(e) => {
+                log::warn!(slot, peer:? = ic.peer_addr; "invalid handshake: {}", e);
+                send_reply(&[], HANDSHAKE_CMD, slot, sys::ic_status_t_IC_MSG_INVALID);
+            }
         }
     }
 
@@ -93,93 +527,433 @@ impl RpcRegister {
         slot: u64,
         cmd: i32,
         data: sys::lstr_t,
-        _hdr: *const sys::ic__hdr__t,
+        hdr: *const sys::ic__hdr__t,
     ) {
-        let ic = InnerClient::from_raw(raw_ic);
+        let ic = unsafe { InnerClient::from_raw(raw_ic) };
+
+        let data = sys::from_lstr(&data);
+        call_wire_hook(raw_ic, Direction::Received, cmd, None, slot, hdr, data);
+
+        if cmd == NEGOTIATE_CMD {
+            let status = if ic.compression.is_some() {
+                ic.peer_compression = true;
+                sys::ic_status_t_IC_MSG_OK
+            } else {
+                sys::ic_status_t_IC_MSG_UNIMPLEMENTED
+            };
+
+            send_reply(&[], cmd, slot, status);
+            return;
+        }
+        if cmd == HANDSHAKE_CMD {
+            Self::answer_handshake(ic, data, slot);
+            return;
+        }
+        if cmd == PING_CMD {
+            send_reply(data, cmd, slot, sys::ic_status_t_IC_MSG_OK);
+            return;
+        }
+        if !ic.server.is_null() && (*ic.server).shutting_down {
+            // the client can send it again to another server
+            send_reply(&[], cmd, slot, sys::ic_status_t_IC_MSG_RETRY);
+            return;
+        }
 
-        let cb = match ic.register.as_mut().and_then(|reg| reg.impls.get(&cmd)) {
+        let reg = match ic.register.as_ref() {
+            Some(reg) => reg,
+            None => {
+                log::warn!(
+                    cmd, slot, peer:? = ic.peer_addr;
+                    "query received without rpc register"
+                );
+                send_reply(&[], cmd, slot, sys::ic_status_t_IC_MSG_UNIMPLEMENTED);
+                return;
+            }
+        };
+
+        if let Some(max) = ic.payload_limits.incoming {
+            if data.len() > max {
+                let status = sys::ic_status_t_IC_MSG_INVALID;
+
+                log::warn!(
+                    cmd, slot, peer:? = ic.peer_addr;
+                    "query payload of {} bytes exceeds the limit of {}", data.len(), max
+                );
+                reg.metrics
+                    .record_handled(cmd, status, Duration::default(), data.len());
+                send_reply(&[], cmd, slot, status);
+                return;
+            }
+        }
+
+        if let Some(limiter) = &mut ic.rate_limiter {
+            if !limiter.try_acquire(cmd) {
+                let status = sys::ic_status_t_IC_MSG_RETRY;
+
+                // clients looping on their queries would flood the logs with warnings
+                log::debug!(cmd, slot, peer:? = ic.peer_addr; "query rate limit exceeded");
+                reg.metrics
+                    .record_handled(cmd, status, Duration::default(), data.len());
+                send_reply(&[], cmd, slot, status);
+                return;
+            }
+        }
+
+        let hdr = QueryHeader::from_raw(hdr);
+        if let Some(server) = ic.server.as_ref() {
+            if let Err(status) = server.check_query(&Channel::from_raw(raw_ic), &hdr, cmd) {
+                log::warn!(cmd, slot, peer:? = ic.peer_addr; "query not authorized");
+                reg.metrics
+                    .record_handled(cmd, status, Duration::default(), data.len());
+                send_reply(&[], cmd, slot, status);
+                return;
+            }
+        }
+
+        let wire_len = data.len();
+        let data = match compress::decompress(data, ic.payload_limits.incoming) {
+            Ok(data) => data,
+            Err(e) => {
+                let status = sys::ic_status_t_IC_MSG_INVALID;
+
+                log::warn!(cmd, slot, peer:? = ic.peer_addr; "invalid query payload: {}", e);
+                reg.metrics
+                    .record_handled(cmd, status, Duration::default(), wire_len);
+                send_reply(&[], cmd, slot, status);
+                return;
+            }
+        };
+
+        let mut reg = reg;
+        while let Some(routed) = reg.routed(&hdr) {
+            reg = &routed.register;
+        }
+
+        // the implementation is cloned, so that it can replace itself
+        let cb = match reg.impls.borrow().get(&cmd).cloned() {
             Some(cb) => cb,
             None => {
-                let err: error::Error<()> =
-                    error::Error::Generic(format!("unimplemented RPC with cmd {}", cmd));
-                // FIXME: reply error
-                println!("error: {}", err);
+                let status = sys::ic_status_t_IC_MSG_UNIMPLEMENTED;
+
+                log::warn!(cmd, slot, peer:? = ic.peer_addr; "unimplemented rpc");
+                reg.metrics
+                    .record_handled(cmd, status, Duration::default(), wire_len);
+                send_reply(&[], cmd, slot, status);
                 return;
             }
         };
 
-        let data = std::slice::from_raw_parts(
-            data.__bindgen_anon_1.s as *const c_void as *const u8,
-            data.len as usize,
-        );
+        #[cfg(feature = "tracing")]
+        let peer = ic.peer_addr;
+
+        let fut = match ic.concurrency.as_ref().map(|limiter| limiter.acquire(cmd)) {
+            None => Self::dispatch(reg, cb, Channel::from_raw(raw_ic), hdr, cmd, &data, slot),
+            Some(Acquired::Now(permit)) => {
+                Self::dispatch(reg, cb, Channel::from_raw(raw_ic), hdr, cmd, &data, slot)
+                    .map(move |status| {
+                        drop(permit);
+                        status
+                    })
+                    .boxed_local()
+            }
+            Some(Acquired::Queued(permit)) => {
+                let reg = reg.clone();
+                let alive = ic.alive.clone();
+                let data = data.into_owned();
+
+                async move {
+                    let permit = match permit.await {
+                        Ok(permit) => permit,
+                        Err(_) => return sys::ic_status_t_IC_MSG_CANCELED,
+                    };
+                    // the channel was destroyed while the query waited
+                    if !alive.get() {
+                        return sys::ic_status_t_IC_MSG_CANCELED;
+                    }
+                    let ic = unsafe { Channel::from_raw(raw_ic) };
+                    let status = Self::dispatch(&reg, cb, ic, hdr, cmd, &data, slot).await;
 
-        let ic = Channel::from_raw(raw_ic);
-        (cb)(ic, &data, slot);
-        // match ic.register.as_ref().and_then(|reg| reg.impls.get(&cmd)) {
-        //     Some(cb) => {
-        //         let data = std::slice::from_raw_parts(
-        //             data.__bindgen_anon_1.s as *const c_void as *const u8,
-        //             data.len as usize,
-        //         );
+                    drop(permit);
+                    status
+                }
+                .boxed_local()
+            }
+            Some(Acquired::Rejected) => {
+                let status = sys::ic_status_t_IC_MSG_RETRY;
+
+                log::debug!(cmd, slot, peer:? = ic.peer_addr; "too many queries being handled");
+                reg.metrics
+                    .record_handled(cmd, status, Duration::default(), wire_len);
+                send_reply(&[], cmd, slot, status);
+                return;
+            }
+        };
+
+        let fut = {
+            let metrics = reg.metrics.clone();
+            let start = Instant::now();
+
+            fut.inspect(move |status| {
+                metrics.record_handled(cmd, *status, start.elapsed(), wire_len)
+            })
+        };
+
+        #[cfg(feature = "tracing")]
+        let fut = trace::dispatch(fut.boxed_local(), peer, cmd, slot);
+
+        // the queries being handled are awaited by `Server::shutdown`
+        let in_flight = ic.in_flight.clone();
+        in_flight.begin();
+
+        let mut fut = fut.map(move |_| in_flight.end()).boxed_local();
+
+        // the implementations answering at once are not left to the
+        // executor, which is not run by the callbacks of `ic_sync`
+        let waker = noop_waker();
+        if fut
+            .poll_unpin(&mut Context::from_waker(&waker))
+            .is_pending()
+        {
+            el_future::spawn(fut);
+        }
+    }
 
-        //         (cb)(ic, &data, slot);
-        //     }
-        //     None => {
-        //         let err = error::Error::Generic(format!("unimplemented RPC with cmd {}", cmd));
-        //         // FIXME: reply error
-        //         println!("error: {}", err);
-        //     }
-        // };
+    /// Give a query to its implementation, through the middlewares of the
+    /// register.
+    fn dispatch(
+        reg: &RpcRegister,
+        cb: RpcImpl,
+        mut ic: Channel,
+        hdr: QueryHeader,
+        cmd: i32,
+        data: &[u8],
+        slot: u64,
+    ) -> LocalBoxFuture<'static, sys::ic_status_t> {
+        ic.set_deadline(hdr.deadline);
+        if reg.middlewares.is_empty() {
+            (cb)(ic, hdr, cmd, data, slot)
+        } else {
+            Self::run_middlewares(reg, cb, ic, hdr, cmd, data, slot)
+        }
+    }
+
+    fn run_middlewares(
+        reg: &RpcRegister,
+        cb: RpcImpl,
+        ic: Channel,
+        hdr: QueryHeader,
+        cmd: i32,
+        data: &[u8],
+        slot: u64,
+    ) -> LocalBoxFuture<'static, sys::ic_status_t> {
+        let call = Call {
+            ic: Rc::new(ic),
+            hdr: Rc::new(hdr),
+            cmd,
+            data: data.into(),
+        };
+        let replied = Rc::new(Cell::new(false));
+        let terminal: Next = {
+            let call = call.clone();
+            let replied = replied.clone();
+
+            Box::new(move || {
+                replied.set(true);
+                (cb)(
+                    call.ic.dup(),
+                    (*call.hdr).clone(),
+                    call.cmd,
+                    &call.data,
+                    slot,
+                )
+            })
+        };
+        let chain = middleware::run(reg.middlewares.clone(), terminal, call);
+
+        async move {
+            let status = chain.await;
+
+            // the query was rejected by a middleware
+            if !replied.get() {
+                send_reply(&[], cmd, slot, status);
+            }
+            status
+        }
+        .boxed_local()
     }
 }
 
-// }}}
-// {{{ Helpers
+/// Register built by `RpcRegister::build`, given to servers and clients.
+///
+/// Its RPCs cannot be registered anymore, as lib-common looks them up while
+/// dispatching the queries, but their implementations can still be replaced
+/// with `RpcRegister::replace` or removed with `RpcRegister::unregister`.
+#[derive(Clone)]
+pub struct RpcDispatcher {
+    pub(crate) register: Rc<RpcRegister>,
+}
 
-unsafe fn hostname_to_su(hostname: &str) -> sys::sockunion_t {
-    let mut su: sys::sockunion_t = mem::zeroed();
-    let mut host: sys::pstream_t = mem::zeroed();
-    let mut port: sys::in_port_t = mem::zeroed();
+impl Deref for RpcDispatcher {
+    type Target = RpcRegister;
 
-    let hostname: Vec<u8> = hostname.bytes().collect();
-    let ps = sys::ps_init(hostname.as_ptr() as *const c_void, hostname.len());
+    fn deref(&self) -> &RpcRegister {
+        &self.register
+    }
+}
+
+/// Register giving a shared state to the RPC implementations, built with
+/// `RpcRegister::with_state`.
+pub struct StateRegister<'a, S> {
+    register: &'a mut RpcRegister,
+    state: Rc<S>,
+}
+
+impl<S: 'static> StateRegister<'_, S> {
+    pub fn state(&self) -> &Rc<S> {
+        &self.state
+    }
+
+    pub fn register<I, O, E, F>(&mut self, cmd: i32, fun: impl Fn(Rc<S>, Channel, I) -> F + 'static)
+    where
+        I: DeserializeOwned,
+        O: Serialize + 'static,
+        E: Serialize + 'static,
+        F: Future<Output = Result<O, error::Error<E>>> + 'static,
+    {
+        let state = self.state.clone();
+
+        self.register.register(cmd, move |channel, input| {
+            fun(state.clone(), channel, input)
+        });
+    }
 
-    sys::addr_parse_minport(ps, &mut host, &mut port, 1, -1);
-    sys::addr_info(&mut su, libc::AF_INET as u16, host, port);
-    su
+    /// Same as `register`, giving the header of the queries to `fun`.
+    pub fn register_with_hdr<I, O, E, F>(
+        &mut self,
+        cmd: i32,
+        fun: impl Fn(Rc<S>, Channel, QueryHeader, I) -> F + 'static,
+    ) where
+        I: DeserializeOwned,
+        O: Serialize + 'static,
+        E: Serialize + 'static,
+        F: Future<Output = Result<O, error::Error<E>>> + 'static,
+    {
+        let state = self.state.clone();
+
+        self.register
+            .register_with_hdr(cmd, move |channel, hdr, input| {
+                fun(state.clone(), channel, hdr, input)
+            });
+    }
+
+    pub(crate) fn set_name(&mut self, cmd: i32, name: Option<&'static str>) {
+        self.register.set_name(cmd, name);
+    }
 }
 
 // }}}
 // {{{ Server
 
+/// Identifier of a channel accepted by a server, not reused by the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerId(u64);
+
 struct InnerServer {
     el: sys::el_t,
 
-    register: Option<Rc<RpcRegister>>,
+    register: Option<RpcDispatcher>,
 
+    // address the server listens on, set once listening
+    local_addr: SocketAddr,
+
+    // whether the accepted channels require TLS
+    tls: bool,
+
+    on_connected: Option<PeerCallback>,
+    on_disconnected: Option<PeerCallback>,
+
+    next_peer_id: u64,
     clients: Vec<Client>,
+    // timer dropping the channels closed by their clients, if scheduled
+    collect_timer: sys::el_t,
+
+    wire_hook: Option<WireHook>,
+    recorder: Option<Recorder>,
+    watermarks: Watermarks,
+    payload_limits: PayloadLimits,
+    compression: Option<Compression>,
+    capabilities: Option<Rc<Capabilities>>,
+    keepalive: Option<Keepalive>,
+    socket_options: Option<ClientOptions>,
+    authenticator: Option<Box<dyn Authenticator>>,
+    acl: Option<Acl>,
+    rate_limits: Option<RateLimits>,
+    // shared by the accepted channels
+    concurrency: Option<Rc<ConcurrencyLimiter>>,
+
+    // server of the queries sent over HTTP, if listening
+    http: Option<HttpServer>,
+    // shared by the HTTP queries, counted as those of one channel
+    http_rate_limiter: Option<RateLimiter>,
+    http_in_flight: Rc<InFlight>,
+
+    // set by `shutdown`, the queries received then being rejected
+    shutting_down: bool,
 }
 
+type PeerCallback = Box<dyn Fn(PeerId, Channel, Option<SocketAddr>)>;
+
+/// Result of a query sent to a peer by `Server::broadcast`.
+type PeerResult<T, E> = (PeerId, Result<T, error::Error<E>>);
+
 pub struct Server {
     _inner: Box<InnerServer>,
 }
 
 impl Server {
-    pub fn new(hostname: &str, register: Option<RpcRegister>) -> Self {
-        let register = match register {
-            Some(r) => Some(Rc::new(r)),
-            None => None,
-        };
-
+    /// Listen on `hostname`, encrypting the channels if `tls` is given.
+    ///
+    /// Servers without register still answer the pings of their clients.
+    pub fn new(
+        hostname: &str,
+        register: Option<RpcDispatcher>,
+        tls: Option<&TlsConfig>,
+    ) -> Result<Self, AddrError> {
+        let su = SockAddr::parse_listen(hostname)?.to_raw();
+        let register = register.unwrap_or_else(|| RpcRegister::new().build());
+
+        if let Some(tls) = tls {
+            tls.install()?;
+        }
         let mut inner = Box::new(InnerServer {
             el: std::ptr::null_mut(),
-            register,
+            register: Some(register),
+            local_addr: ([0, 0, 0, 0], 0).into(),
+            tls: tls.is_some(),
+            on_connected: None,
+            on_disconnected: None,
+            next_peer_id: 0,
             clients: Vec::new(),
+            collect_timer: std::ptr::null_mut(),
+            wire_hook: None,
+            recorder: None,
+            watermarks: Watermarks::default(),
+            payload_limits: PayloadLimits::default(),
+            compression: None,
+            capabilities: None,
+            keepalive: None,
+            socket_options: None,
+            authenticator: None,
+            acl: None,
+            rate_limits: None,
+            concurrency: None,
+            http: None,
+            http_rate_limiter: None,
+            http_in_flight: Rc::default(),
+            shutting_down: false,
         });
 
         inner.el = unsafe {
-            let su = hostname_to_su(hostname);
-
             sys::ic_listento(
                 &su,
                 libc::SOCK_STREAM,
@@ -188,38 +962,647 @@ impl Server {
                 Some(Server::on_accept),
             )
         };
+        if inner.el.is_null() {
+            return Err(AddrError::Listen(hostname.to_owned()));
+        }
+        inner.local_addr = match local_addr(unsafe { sys::el_fd_get_fd(inner.el) }) {
+            Some(addr) => addr,
+            None => return Err(AddrError::Listen(hostname.to_owned())),
+        };
 
-        Self { _inner: inner }
+        Ok(Self { _inner: inner })
     }
 
-    unsafe extern "C" fn on_accept(_ev: sys::el_t, fd: i32, data: *mut c_void) -> i32 {
-        let inner: &mut InnerServer = &mut *(data as *mut InnerServer);
-        let mut client = Client::new(inner.register.as_ref());
+    /// Register of the RPCs implemented by the server.
+    pub fn rpc_register(&self) -> Option<&RpcRegister> {
+        self._inner.register.as_deref()
+    }
 
-        client.spawn(fd);
+    /// Address the server listens on, giving the port picked when listening
+    /// on port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self._inner.local_addr
+    }
 
-        inner.clients.push(client);
-        0
+    /// Ids of the connected peers, in the order of their connection.
+    pub fn peers(&self) -> Vec<PeerId> {
+        self._inner
+            .clients
+            .iter()
+            .filter(|c| c.inner.connected)
+            .map(|c| c.inner.peer_id)
+            .collect()
     }
-}
 
-impl Drop for InnerServer {
-    fn drop(&mut self) {
-        unsafe {
-            sys::el_unregister(&mut self.el);
-        }
+    /// Number of connected peers.
+    pub fn connection_count(&self) -> usize {
+        self._inner
+            .clients
+            .iter()
+            .filter(|c| c.inner.connected)
+            .count()
     }
-}
 
-// }}}
-// {{{ Client
+    /// Channel of a connected peer, to send queries to it.
+    pub fn peer_channel(&mut self, id: PeerId) -> Option<Channel> {
+        self._inner
+            .clients
+            .iter_mut()
+            .find(|c| c.inner.connected && c.inner.peer_id == id)
+            .map(|c| c.get_channel())
+    }
 
-struct InnerClient {
-    raw_ic: sys::ichannel_t,
+    /// Address of a connected peer.
+    pub fn peer_addr(&self, id: PeerId) -> Option<SocketAddr> {
+        self._inner
+            .clients
+            .iter()
+            .find(|c| c.inner.connected && c.inner.peer_id == id)
+            .and_then(|c| c.inner.peer_addr)
+    }
 
-    connect_state: Option<Arc<Mutex<ConnectState>>>,
+    /// Same as `Client::on_wire`, for the channels accepted from now on.
+    pub fn on_wire<F>(&mut self, f: F)
+    where
+        F: Fn(Direction, i32, u64, &[u8]) + 'static,
+    {
+        self._inner.wire_hook = Some(Rc::new(f));
+    }
+
+    /// Same as `Client::set_recorder`, for the channels accepted from now
+    /// on, sharing the recorder.
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self._inner.recorder = recorder;
+    }
+
+    /// Watermarks of the send queues of the channels accepted from now on.
+    pub fn set_watermarks(&mut self, watermarks: Watermarks) {
+        self._inner.watermarks = watermarks;
+    }
+
+    /// Payload limits of the channels accepted from now on.
+    pub fn set_payload_limits(&mut self, limits: PayloadLimits) {
+        self._inner.payload_limits = limits;
+    }
+
+    /// Compression of the payloads sent to the clients accepted from now on,
+    /// if they support it.
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self._inner.compression = compression;
+    }
+
+    /// Capabilities advertised to the clients accepted from now on, in
+    /// answer to their handshake.
+    ///
+    /// Without them, the handshakes are answered as unimplemented, and the
+    /// capabilities of the clients are not kept.
+    pub fn set_capabilities(&mut self, capabilities: Option<Capabilities>) {
+        self._inner.capabilities = capabilities.map(Rc::new);
+    }
+
+    /// Keepalive of the channels accepted from now on, disconnected when
+    /// their client stops answering.
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self._inner.keepalive = keepalive;
+    }
+
+    /// Set the options of the listening socket, and of the sockets of the
+    /// channels accepted from now on.
+    pub fn set_socket_options(&mut self, options: ServerOptions) -> io::Result<()> {
+        let inner = &mut *self._inner;
+
+        if !inner.el.is_null() {
+            options.apply(unsafe { sys::el_fd_get_fd(inner.el) })?;
+        }
+        inner.socket_options = Some(options.clients);
+        Ok(())
+    }
+
+    /// Check the queries received by the server with `authenticator`, before
+    /// they go through the middlewares of its register.
+    pub fn set_authenticator<A: Authenticator + 'static>(&mut self, authenticator: A) {
+        self._inner.authenticator = Some(Box::new(authenticator));
+    }
+
+    /// Rate limits of the queries received on the channels accepted from now
+    /// on.
+    pub fn set_rate_limits(&mut self, limits: Option<RateLimits>) {
+        self._inner.rate_limits = limits;
+    }
+
+    /// Limits of the queries handled at once by the channels accepted from
+    /// now on, counted across them.
+    pub fn set_concurrency_limits(&mut self, limits: Option<ConcurrencyLimits>) {
+        self._inner.concurrency = limits.map(ConcurrencyLimiter::new);
+    }
+
+    /// Only accept the queries allowed by `acl` for the group of their
+    /// header, once accepted by the authenticator.
+    pub fn set_acl(&mut self, acl: Option<Acl>) {
+        self._inner.acl = acl;
+    }
+
+    /// Also answer the queries sent over HTTP on `hostname`, whose path
+    /// starts with `prefix`, returning the address listened on.
+    ///
+    /// The queries are admitted as the queries of the accepted channels: they
+    /// go through the payload limits, the authenticator, the ACL, the
+    /// concurrency limits and the middlewares set so far, and all share the
+    /// rate limits of one channel. Their header is given by the
+    /// `X-Ic-Login`, `X-Ic-Password` and `X-Ic-Group` HTTP headers, as sent
+    /// by `HttpClient::call_with_hdr`.
+    pub fn listen_http(
+        &mut self,
+        hostname: &str,
+        prefix: &str,
+        limits: HttpLimits,
+    ) -> Result<SocketAddr, AddrError> {
+        let inner = &mut *self._inner;
+        let server = inner as *mut InnerServer;
+        // the connections, and their queries, are dropped with the server
+        let handler: Handler =
+            Box::new(move |name, hdr, data| unsafe { (*server).http_query(name, hdr, data) });
+        let mut http =
+            HttpServer::with_handler(hostname, prefix, inner.payload_limits.incoming, handler)?;
+        let addr = http.local_addr();
+
+        http.set_limits(limits);
+        inner.http = Some(http);
+        inner.http_rate_limiter = inner.rate_limits.clone().map(RateLimiter::new);
+        Ok(addr)
+    }
+
+    /// Send a query to every connected peer, and collect their results.
+    pub fn broadcast<R: Rpc>(
+        &mut self,
+        iface_tag: u16,
+        arg: R::Input,
+    ) -> impl Future<Output = Vec<PeerResult<R::Output, R::Exception>>> {
+        let queries: Vec<_> = self
+            ._inner
+            .clients
+            .iter_mut()
+            .filter(|c| c.inner.connected)
+            .map(|c| {
+                let id = c.inner.peer_id;
+                let mut ic = c.get_channel();
+
+                QueryFuture::<R::Output, R::Exception>::new(
+                    &mut ic,
+                    &arg,
+                    R::get_cmd(iface_tag),
+                    R::ASYNC,
+                )
+                .map(move |res| (id, res))
+            })
+            .collect();
+
+        join_all(queries)
+    }
+
+    /// Call `f` with the id, the channel and the address of the peer, once a
+    /// client is connected.
+    ///
+    /// With TLS, the client is connected after the handshake.
+    pub fn on_client_connected<F>(&mut self, f: F)
+    where
+        F: Fn(PeerId, Channel, Option<SocketAddr>) + 'static,
+    {
+        self._inner.on_connected = Some(Box::new(f));
+    }
+
+    /// Call `f` with the id, the channel and the address of the peer, when a
+    /// client is disconnected.
+    ///
+    /// Queries can no longer be sent on the channel.
+    pub fn on_client_disconnected<F>(&mut self, f: F)
+    where
+        F: Fn(PeerId, Channel, Option<SocketAddr>) + 'static,
+    {
+        self._inner.on_disconnected = Some(Box::new(f));
+    }
+
+    /// Stop the server gracefully, waiting for the queries being handled to
+    /// be answered before disconnecting the clients.
+    ///
+    /// The server stops accepting clients at once, and the queries received
+    /// from then on are answered with `Error::Retry`, so that the clients
+    /// can send them to another server. The clients are disconnected once
+    /// the queries are answered, or after `grace`, the answers of the
+    /// remaining queries being lost.
+    ///
+    /// Returns false if queries were still being handled after `grace`.
+    pub async fn shutdown(&mut self, grace: Duration) -> bool {
+        let inner = &mut *self._inner;
+
+        if !inner.el.is_null() {
+            unsafe {
+                sys::el_unregister(&mut inner.el);
+            }
+        }
+        inner.shutting_down = true;
+
+        // woken once the last query of a channel is answered
+        let answered = future::poll_fn(|cx| {
+            let pending = inner
+                .clients
+                .iter()
+                .filter(|c| !c.inner.in_flight.poll_answered(cx))
+                .count();
+
+            if pending == 0 && inner.http_in_flight.poll_answered(cx) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        });
+        let timer = el_future::Timer::new(grace.as_millis() as i64, 0).await;
+        future::select(answered, timer).await;
+
+        let remaining: usize = inner
+            .clients
+            .iter()
+            .map(|c| c.inner.in_flight.count.get())
+            .sum::<usize>()
+            + inner.http_in_flight.count.get();
+        if remaining > 0 {
+            log::warn!(
+                addr:? = inner.local_addr;
+                "shutting down with {} queries not answered", remaining
+            );
+        }
+        for client in inner.clients.iter_mut().filter(|c| !c.inner.closed) {
+            client.disconnect();
+        }
+        remaining == 0
+    }
+
+    unsafe extern "C" fn on_accept(_ev: sys::el_t, fd: i32, data: *mut c_void) -> i32 {
+        let inner: &mut InnerServer = &mut *(data as *mut InnerServer);
+        inner.collect_clients();
+
+        if let Some(options) = &inner.socket_options {
+            if let Err(e) = options.apply(fd) {
+                log::warn!(addr:? = inner.local_addr; "cannot set socket options: {}", e);
+            }
+        }
+
+        let mut client = Client::new(inner.register.as_ref());
+
+        client.inner.server = inner;
+        client.inner.peer_id = PeerId(inner.next_peer_id);
+        inner.next_peer_id += 1;
+        client.inner.peer_addr = peer_addr(fd);
+        client.inner.wire_hook = inner.wire_hook.clone();
+        client.inner.recorder = inner.recorder.clone();
+        client.inner.watermarks = inner.watermarks;
+        client.inner.payload_limits = inner.payload_limits;
+        client.inner.compression = inner.compression;
+        client.inner.capabilities = inner.capabilities.clone();
+        client.inner.rate_limiter = inner.rate_limits.clone().map(RateLimiter::new);
+        client.inner.concurrency = inner.concurrency.clone();
+        client.inner.raw_ic.on_event = Some(Server::on_event);
+        client.inner.raw_ic.set_tls_required(inner.tls);
+        client.spawn(fd);
+        client.set_keepalive(inner.keepalive);
+
+        inner.clients.push(client);
+        0
+    }
+
+    unsafe extern "C" fn on_event(raw_ic: *mut sys::ichannel_t, evt: sys::ic_event_t) {
+        let client = InnerClient::from_raw(raw_ic);
+        let server = &mut *(client.server as *mut InnerServer);
+
+        let hook = if evt == sys::ic_event_t_IC_EVT_CONNECTED {
+            client.connected = true;
+            client.peer_identity = tls::peer_identity(client.raw_ic.ssl).map(Rc::new);
+            client.wake_state();
+            client.send_event(ConnectionEvent::Connected);
+            &server.on_connected
+        } else if evt == sys::ic_event_t_IC_EVT_DISCONNECTED {
+            client.connected = false;
+            client.closed = true;
+            client.wake_state();
+            client.send_event(ConnectionEvent::Disconnected);
+            &server.on_disconnected
+        } else {
+            match evt {
+                sys::ic_event_t_IC_EVT_ACT => {
+                    client.active = true;
+                    client.send_event(ConnectionEvent::Active);
+                }
+                sys::ic_event_t_IC_EVT_NOACT => {
+                    client.active = false;
+                    client.send_event(ConnectionEvent::Inactive);
+                }
+                _ => (),
+            }
+            return;
+        };
+        if let Some(hook) = hook {
+            (hook)(client.peer_id, Channel::from_raw(raw_ic), client.peer_addr);
+        }
+        // the hook can still use the values attached to the connection
+        if evt == sys::ic_event_t_IC_EVT_DISCONNECTED {
+            client.extensions.borrow_mut().clear();
+            client.peer_identity = None;
+            server.schedule_collect();
+        }
+    }
+}
+
+impl InnerServer {
+    /// Check a query with the authenticator, then with the ACL.
+    fn check_query(
+        &self,
+        ic: &Channel,
+        hdr: &QueryHeader,
+        cmd: i32,
+    ) -> Result<(), sys::ic_status_t> {
+        if let Some(authenticator) = &self.authenticator {
+            authenticator.authenticate(ic, hdr, cmd)?;
+        }
+        match &self.acl {
+            Some(acl) if !acl.is_allowed(hdr.group.as_deref(), cmd) => Err(FORBIDDEN_STATUS),
+            _ => Ok(()),
+        }
+    }
+
+    /// Answer a query sent over HTTP, admitted as the queries of the
+    /// accepted channels.
+    fn http_query(
+        &mut self,
+        name: &str,
+        hdr: QueryHeader,
+        data: Vec<u8>,
+    ) -> LocalBoxFuture<'static, (sys::ic_status_t, Vec<u8>)> {
+        let reject = |status| future::ready((status, Vec::new())).boxed_local();
+
+        if self.shutting_down {
+            return reject(sys::ic_status_t_IC_MSG_RETRY);
+        }
+        let register = match &self.register {
+            Some(register) => register.clone(),
+            None => return reject(sys::ic_status_t_IC_MSG_UNIMPLEMENTED),
+        };
+        let cmd = match register.named_cmd(name) {
+            Some(cmd) => cmd,
+            None => return reject(sys::ic_status_t_IC_MSG_UNIMPLEMENTED),
+        };
+
+        if let Some(limiter) = &mut self.http_rate_limiter {
+            if !limiter.try_acquire(cmd) {
+                let status = sys::ic_status_t_IC_MSG_RETRY;
+
+                log::debug!(cmd; "HTTP query rate limit exceeded");
+                register
+                    .metrics
+                    .record_handled(cmd, status, Duration::default(), data.len());
+                return reject(status);
+            }
+        }
+
+        let ic = Channel::loopback(&register);
+        if let Err(status) = self.check_query(&ic, &hdr, cmd) {
+            log::warn!(cmd; "HTTP query not authorized");
+            register
+                .metrics
+                .record_handled(cmd, status, Duration::default(), data.len());
+            return reject(status);
+        }
+
+        let mut reg = register.register.clone();
+        while let Some(routed) = reg.routed(&hdr).map(|routed| routed.register.clone()) {
+            reg = routed;
+        }
+
+        // the implementation is cloned, so that it can replace itself
+        let cb = match reg.impls.borrow().get(&cmd).cloned() {
+            Some(cb) => cb,
+            None => {
+                let status = sys::ic_status_t_IC_MSG_UNIMPLEMENTED;
+
+                log::warn!(cmd; "unimplemented rpc queried over HTTP");
+                reg.metrics
+                    .record_handled(cmd, status, Duration::default(), data.len());
+                return reject(status);
+            }
+        };
+
+        let permit = match self
+            .concurrency
+            .as_ref()
+            .map(|limiter| limiter.acquire(cmd))
+        {
+            None => None,
+            Some(Acquired::Now(permit)) => Some(future::ready(Ok(permit)).left_future()),
+            Some(Acquired::Queued(permit)) => Some(permit.right_future()),
+            Some(Acquired::Rejected) => {
+                let status = sys::ic_status_t_IC_MSG_RETRY;
+
+                log::debug!(cmd; "too many queries being handled");
+                reg.metrics
+                    .record_handled(cmd, status, Duration::default(), data.len());
+                return reject(status);
+            }
+        };
+
+        // the queries being handled are awaited by `Server::shutdown`, until
+        // answered or dropped with their connection
+        let in_flight = InFlightQuery::new(&self.http_in_flight);
+
+        async move {
+            let permit = match permit {
+                Some(permit) => match permit.await {
+                    Ok(permit) => Some(permit),
+                    Err(_) => return (sys::ic_status_t_IC_MSG_CANCELED, Vec::new()),
+                },
+                None => None,
+            };
+            let start = Instant::now();
+            let (slot, rx) = loopback_slot();
+            let dispatch = RpcRegister::dispatch(&reg, cb, ic, hdr, cmd, &data, slot);
+            let (status, answer) = loopback_answer(slot, rx, Some(dispatch)).await;
+
+            reg.metrics
+                .record_handled(cmd, status, start.elapsed(), data.len());
+            drop((permit, in_flight));
+            (status, answer)
+        }
+        .boxed_local()
+    }
+
+    /// Drop the closed channels, once their queries are answered.
+    fn collect_clients(&mut self) {
+        self.clients
+            .retain(|c| !c.inner.closed || c.inner.in_flight.count.get() > 0);
+    }
+
+    /// Drop the closed channels on the next tick of the event loop, as
+    /// channels cannot be wiped from their own callbacks.
+    fn schedule_collect(&mut self) {
+        if !self.collect_timer.is_null() {
+            return;
+        }
+
+        let server = self as *mut InnerServer;
+        let timer = el::Timer::new(0, 0, 0, move |_| unsafe {
+            (*server).collect_timer = std::ptr::null_mut();
+            (*server).collect_clients();
+        });
+        self.collect_timer = timer.get_el();
+    }
+}
+
+impl Drop for InnerServer {
+    fn drop(&mut self) {
+        if !self.el.is_null() {
+            unsafe {
+                sys::el_unregister(&mut self.el);
+            }
+        }
+        if !self.collect_timer.is_null() {
+            unsafe {
+                sys::el_unregister(&mut self.collect_timer);
+            }
+        }
+    }
+}
+
+// }}}
+// {{{ Client
+
+struct InnerClient {
+    raw_ic: sys::ichannel_t,
+
+    connect_state: Option<Arc<Mutex<ConnectState>>>,
 
     register: Option<Rc<RpcRegister>>,
+
+    // server which accepted the channel, null for connected clients
+    server: *const InnerServer,
+    peer_id: PeerId,
+    peer_addr: Option<SocketAddr>,
+    connected: bool,
+    closed: bool,
+    // cleared when the peer stops answering the keepalive messages
+    active: bool,
+
+    wire_hook: Option<WireHook>,
+    recorder: Option<Recorder>,
+    watermarks: Watermarks,
+    payload_limits: PayloadLimits,
+
+    // compression of the payloads sent, once accepted by the peer
+    compression: Option<Compression>,
+    peer_compression: bool,
+
+    // advertised by the handshake, and the ones of the peer once received
+    capabilities: Option<Rc<Capabilities>>,
+    peer_capabilities: Option<Rc<Capabilities>>,
+
+    // given by the certificate of the peer, for TLS channels
+    peer_identity: Option<Rc<PeerIdentity>>,
+
+    // cleared to stop the keepalive task of the channel
+    keepalive: Option<Rc<Cell<bool>>>,
+    // set on the socket at every connection
+    socket_options: Option<ClientOptions>,
+    // rate limits of the queries received, for the channels of servers
+    rate_limiter: Option<RateLimiter>,
+    // limits of the queries handled at once, shared with the other channels
+    // of the server
+    concurrency: Option<Rc<ConcurrencyLimiter>>,
+
+    // futures waiting for the channel to connect or disconnect
+    state_wakers: Vec<Waker>,
+    // streams of the events of the connection, dropped once closed
+    event_senders: Vec<mpsc::UnboundedSender<ConnectionEvent>>,
+    // called with the events of the connection, for the callbacks of
+    // `ic_sync`
+    event_hook: Option<Box<dyn Fn(ConnectionEvent)>>,
+
+    // values attached to the connection, cleared when it is lost, and
+    // shared with its channels
+    extensions: Rc<RefCell<Extensions>>,
+
+    // cleared when dropped, for the channels outliving it
+    alive: Rc<Cell<bool>>,
+
+    // queries received and not answered yet
+    in_flight: Rc<InFlight>,
+
+    // queries sent on the channel, canceled when it is destroyed
+    queries: Vec<TrackedQuery>,
+}
+
+struct TrackedQuery {
+    cmd: i32,
+    sent: Instant,
+    state: Weak<dyn CancelQuery>,
+}
+
+impl TrackedQuery {
+    fn is_pending(&self) -> bool {
+        self.state.upgrade().is_some_and(|query| query.is_pending())
+    }
+}
+
+/// Maximum sizes of the packed arguments and answers of the queries of a
+/// channel, unlimited by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PayloadLimits {
+    /// Received queries above it are rejected as invalid, before being
+    /// unpacked.
+    pub incoming: Option<usize>,
+    /// Queries above it fail with `Error::PayloadTooLarge`, and replies with
+    /// a server error.
+    pub outgoing: Option<usize>,
+}
+
+/// Direction of a message on a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+type WireHook = Rc<dyn Fn(Direction, i32, u64, &[u8])>;
+
+/// Compress a payload to send on a channel, if its peer accepts it.
+fn compress_payload(raw_ic: *mut sys::ichannel_t, data: &[u8]) -> Option<Vec<u8>> {
+    let ic = unsafe { InnerClient::from_raw(raw_ic) };
+
+    match &ic.compression {
+        Some(compression) if ic.peer_compression => compress::compress(data, compression),
+        _ => None,
+    }
+}
+
+/// Give a message sent or received on a channel to its wire hook, its
+/// recorder and the capture, if any, `status` being the one of answers and
+/// `hdr` the one of queries.
+fn call_wire_hook(
+    raw_ic: *mut sys::ichannel_t,
+    dir: Direction,
+    cmd: i32,
+    status: Option<sys::ic_status_t>,
+    slot: u64,
+    hdr: *const sys::ic__hdr__t,
+    data: &[u8],
+) {
+    let ic = unsafe { InnerClient::from_raw(raw_ic) };
+    let hook = ic.wire_hook.clone();
+
+    capture::frame(dir, cmd, status, slot, ic.peer_addr, hdr, data);
+
+    if let Some(recorder) = &ic.recorder {
+        recorder.record(dir, cmd, slot, status, data);
+    }
+    if let Some(hook) = hook {
+        (hook)(dir, status.map_or(cmd, |status| status as i32), slot, data);
+    }
 }
 
 pub struct Client {
@@ -227,17 +1610,195 @@ pub struct Client {
 }
 
 impl InnerClient {
-    pub fn from_raw<'b>(ic: *mut sys::ichannel_t) -> &'b mut Self {
-        unsafe { &mut *((*ic).priv_data as *mut Self) }
+    /// Client of the ichannel `ic`.
+    ///
+    /// # Safety
+    ///
+    /// `ic` must be the ichannel of a client or server which is not dropped
+    /// yet, and the returned reference must neither outlive it nor be used
+    /// along another one.
+    unsafe fn from_raw<'a>(ic: *mut sys::ichannel_t) -> &'a mut Self {
+        &mut *((*ic).priv_data as *mut Self)
+    }
+
+    fn state(&self) -> ConnectionState {
+        if self.closed {
+            ConnectionState::Closed
+        } else if !self.connected {
+            ConnectionState::Disconnected
+        } else if !self.active {
+            ConnectionState::Inactive
+        } else {
+            ConnectionState::Connected
+        }
+    }
+
+    fn wake_state(&mut self) {
+        for waker in self.state_wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn send_event(&mut self, event: ConnectionEvent) {
+        self.event_senders
+            .retain(|sender| sender.unbounded_send(event).is_ok());
+        if self.closed {
+            self.event_senders.clear();
+        }
+        if let Some(hook) = &self.event_hook {
+            (hook)(event);
+        }
+    }
+
+    fn track_query(&mut self, cmd: i32, state: Weak<dyn CancelQuery>) {
+        // the answered queries are dropped when the vector would grow
+        if self.queries.len() == self.queries.capacity() {
+            self.queries.retain(TrackedQuery::is_pending);
+        }
+        self.queries.push(TrackedQuery {
+            cmd,
+            sent: Instant::now(),
+            state,
+        });
+    }
+
+    fn pending_stats(&self) -> PendingStats {
+        let now = Instant::now();
+        let mut stats = PendingStats::default();
+
+        for query in self.queries.iter().filter(|query| query.is_pending()) {
+            let age = now.saturating_duration_since(query.sent);
+
+            stats.count += 1;
+            *stats.cmds.entry(query.cmd).or_default() += 1;
+            stats.oldest = stats.oldest.max(Some(age));
+        }
+        stats
+    }
+
+    fn events(&mut self) -> ConnectionEvents {
+        let (sender, receiver) = mpsc::unbounded();
+
+        if !self.closed {
+            self.event_senders.push(sender);
+        }
+        ConnectionEvents { receiver }
+    }
+}
+
+/// Queries received on a channel and not answered yet, awaited by
+/// `Server::shutdown`.
+#[derive(Default)]
+struct InFlight {
+    count: Cell<usize>,
+    // woken once the last query is answered
+    waker: RefCell<Option<Waker>>,
+}
+
+impl InFlight {
+    fn begin(&self) {
+        self.count.set(self.count.get() + 1);
+    }
+
+    /// Whether all the queries are answered, waking the task once they are
+    /// otherwise.
+    fn poll_answered(&self, cx: &mut Context) -> bool {
+        if self.count.get() == 0 {
+            return true;
+        }
+        self.waker.replace(Some(cx.waker().clone()));
+        false
+    }
+
+    fn end(&self) {
+        self.count.set(self.count.get() - 1);
+        if self.count.get() == 0 {
+            if let Some(waker) = self.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Query counted as being handled until dropped.
+struct InFlightQuery(Rc<InFlight>);
+
+impl InFlightQuery {
+    fn new(in_flight: &Rc<InFlight>) -> Self {
+        in_flight.begin();
+        Self(in_flight.clone())
+    }
+}
+
+impl Drop for InFlightQuery {
+    fn drop(&mut self) {
+        self.0.end();
+    }
+}
+
+/// State of the connection of a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Connected, and the peer answers the keepalive messages of lib-common.
+    Connected,
+    /// Connected, but the peer stopped answering the keepalive messages.
+    Inactive,
+    /// Not connected yet, or waiting to reconnect.
+    Disconnected,
+    /// Disconnected for good, as the channels accepted by a server once
+    /// their client disconnects.
+    Closed,
+}
+
+impl ConnectionState {
+    pub fn is_connected(self) -> bool {
+        matches!(self, Self::Connected | Self::Inactive)
     }
 }
 
+/// Change of the state of the connection of a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Connected,
+    Disconnected,
+    /// The peer stopped answering the keepalive messages of lib-common.
+    Inactive,
+    /// The peer answers the keepalive messages again.
+    Active,
+}
+
 impl Client {
-    pub fn new(register: Option<&Rc<RpcRegister>>) -> Self {
+    pub fn new(register: Option<&RpcDispatcher>) -> Self {
         let mut inner = Box::new(InnerClient {
             raw_ic: unsafe { mem::zeroed() },
             connect_state: None,
             register: None,
+            server: std::ptr::null(),
+            peer_id: PeerId(0),
+            peer_addr: None,
+            connected: false,
+            closed: false,
+            active: true,
+            wire_hook: None,
+            recorder: None,
+            watermarks: Watermarks::default(),
+            payload_limits: PayloadLimits::default(),
+            compression: None,
+            peer_compression: false,
+            capabilities: None,
+            peer_capabilities: None,
+            peer_identity: None,
+            keepalive: None,
+            socket_options: None,
+            rate_limiter: None,
+            concurrency: None,
+            state_wakers: Vec::new(),
+            event_senders: Vec::new(),
+            event_hook: None,
+            extensions: Rc::default(),
+            alive: Rc::new(Cell::new(true)),
+            in_flight: Rc::default(),
+            queries: Vec::new(),
         });
 
         unsafe {
@@ -250,31 +1811,157 @@ impl Client {
 
         if let Some(reg) = register {
             inner.raw_ic.impl_ = &reg.map;
-            inner.register = Some(reg.clone())
+            inner.register = Some(reg.register.clone())
         };
 
         Self { inner }
     }
 
-    pub fn connect_once(&mut self, hostname: &str) -> ConnectFuture {
+    /// Connect to `hostname`, encrypting the channel if `tls` is given.
+    ///
+    /// Hostnames are resolved synchronously, blocking the event loop, see
+    /// `connect` otherwise.
+    pub fn connect_once(
+        &mut self,
+        hostname: &str,
+        tls: Option<&TlsConfig>,
+    ) -> Result<ConnectFuture, AddrError> {
+        self.connect_addr(SockAddr::parse(hostname)?, tls)
+    }
+
+    /// Same as `connect_once`, resolving `hostname` without blocking the
+    /// event loop, and waiting for the outcome of the connection.
+    pub async fn connect(
+        &mut self,
+        hostname: &str,
+        tls: Option<&TlsConfig>,
+    ) -> Result<bool, AddrError> {
+        let addr = SockAddr::resolve(hostname).await?;
+
+        Ok(self.connect_addr(addr, tls)?.await)
+    }
+
+    fn connect_addr(
+        &mut self,
+        addr: SockAddr,
+        tls: Option<&TlsConfig>,
+    ) -> Result<ConnectFuture, AddrError> {
+        if let Some(tls) = tls {
+            tls.install()?;
+        }
+
         let state = Arc::new(Mutex::new(ConnectState {
             res: None,
             waker: None,
         }));
 
         self.inner.connect_state = Some(state.clone());
+        self.inner.raw_ic.set_tls_required(tls.is_some());
 
         unsafe {
-            self.inner.raw_ic.su = hostname_to_su(hostname);
+            self.inner.raw_ic.su = addr.to_raw();
             sys::ic_connect(&mut self.inner.raw_ic);
         }
 
-        ConnectFuture { state }
+        Ok(ConnectFuture { state })
+    }
+
+    /// Connect to `hostname`, retrying until `options.deadline`.
+    ///
+    /// As with `connect`, `hostname` is resolved without blocking the event
+    /// loop, the deadline including its resolution.
+    ///
+    /// lib-common reconnects the channel every `options.retry_interval`
+    /// after a failed attempt. The client is disconnected on errors, which
+    /// stops the attempts.
+    ///
+    /// The error depends on the last attempt: `ConnectError::Refused` if it
+    /// failed, `ConnectError::Timeout` if it was still in progress at the
+    /// deadline, or if `hostname` was not resolved yet.
+    pub async fn connect_with(
+        &mut self,
+        hostname: &str,
+        tls: Option<&TlsConfig>,
+        options: ConnectOptions,
+    ) -> Result<(), ConnectError> {
+        let timer = el_future::Timer::new(options.deadline.as_millis() as i64, 0).await;
+
+        self.inner.raw_ic.retry_delay = options.retry_interval.as_millis() as i32;
+        let (addr, mut timer) =
+            match future::select(SockAddr::resolve(hostname).boxed_local(), timer).await {
+                Either::Left((addr, timer)) => (addr?, timer),
+                Either::Right(_) => return Err(ConnectError::Timeout),
+            };
+        let mut connect = self.connect_addr(addr, tls)?;
+        loop {
+            match future::select(connect, timer).await {
+                Either::Left((true, _)) => return Ok(()),
+                Either::Left((false, t)) => {
+                    // the next attempts are reported on the same state
+                    timer = t;
+                    connect = ConnectFuture {
+                        state: self.inner.connect_state.clone().unwrap(),
+                    };
+                }
+                Either::Right(_) => break,
+            }
+        }
+
+        // lib-common closes the socket of a failed attempt, and only opens
+        // the one of the next attempt after the retry interval
+        let in_progress = !self.inner.raw_ic.elh.is_null();
+
+        self.disconnect();
+        if in_progress {
+            Err(ConnectError::Timeout)
+        } else {
+            Err(ConnectError::Refused)
+        }
     }
 
     unsafe extern "C" fn on_event(raw_ic: *mut sys::ichannel_t, evt: sys::ic_event_t) {
         let ic = InnerClient::from_raw(raw_ic);
 
+        match evt {
+            sys::ic_event_t_IC_EVT_CONNECTED => {
+                ic.connected = true;
+                ic.active = true;
+                ic.peer_identity = tls::peer_identity(ic.raw_ic.ssl).map(Rc::new);
+                // before the queries sent by the tasks woken below
+                if let Some(caps) = &ic.capabilities {
+                    Self::send_handshake(raw_ic, caps);
+                }
+                ic.wake_state();
+                ic.send_event(ConnectionEvent::Connected);
+                if let Some(options) = &ic.socket_options {
+                    if let Err(e) = options.apply(sys::el_fd_get_fd(ic.raw_ic.elh)) {
+                        log::warn!(peer:? = ic.peer_addr; "cannot set socket options: {}", e);
+                    }
+                }
+                if ic.compression.is_some() {
+                    Self::negotiate_compression(raw_ic);
+                }
+            }
+            sys::ic_event_t_IC_EVT_DISCONNECTED => {
+                ic.connected = false;
+                ic.peer_compression = false;
+                ic.peer_capabilities = None;
+                ic.peer_identity = None;
+                ic.extensions.borrow_mut().clear();
+                ic.wake_state();
+                ic.send_event(ConnectionEvent::Disconnected);
+            }
+            sys::ic_event_t_IC_EVT_ACT => {
+                ic.active = true;
+                ic.send_event(ConnectionEvent::Active);
+            }
+            sys::ic_event_t_IC_EVT_NOACT => {
+                ic.active = false;
+                ic.send_event(ConnectionEvent::Inactive);
+            }
+            _ => (),
+        }
+
         match ic.connect_state.as_ref() {
             Some(state) => {
                 let mut state = state.lock().unwrap();
@@ -292,83 +1979,1145 @@ impl Client {
         };
     }
 
-    pub fn disconnect(&mut self) {
-        unsafe {
-            sys::ic_disconnect(&mut self.inner.raw_ic);
+    /// Ask the server whether it accepts compressed payloads, without going
+    /// through the middlewares.
+    fn negotiate_compression(raw_ic: *mut sys::ichannel_t) {
+        extern "C" fn on_answer(
+            raw_ic: *mut sys::ichannel_t,
+            _msg: *mut sys::ic_msg_t,
+            status: sys::ic_status_t,
+            _res: *const c_uchar,
+            _rlen: u32,
+            _exn: *const c_uchar,
+            _elen: u32,
+        ) {
+            if !raw_ic.is_null() && status == sys::ic_status_t_IC_MSG_OK {
+                unsafe { InnerClient::from_raw(raw_ic).peer_compression = true };
+            }
         }
-    }
 
-    fn spawn(&mut self, fd: i32) {
         unsafe {
-            sys::ic_spawn(&mut self.inner.raw_ic, fd, None);
+            let msg = sys::ic_msg_new(0);
+
+            MsgBuffer::new(0).give_to(msg);
+            (*msg).cb2 = Some(on_answer);
+            (*msg).cmd = NEGOTIATE_CMD;
+
+            sys::__ic_query(raw_ic, msg);
         }
     }
 
-    pub fn get_channel(&mut self) -> Channel {
-        Channel::from_raw(&mut self.inner.raw_ic as *mut _)
-    }
-}
+    /// Send the capabilities of the client, keeping the ones the server
+    /// answers with, without going through the middlewares.
+    fn send_handshake(raw_ic: *mut sys::ichannel_t, caps: &Capabilities) {
+        extern "C" fn on_answer(
+            raw_ic: *mut sys::ichannel_t,
+            _msg: *mut sys::ic_msg_t,
+            status: sys::ic_status_t,
+            res: *const c_uchar,
+            rlen: u32,
+            _exn: *const c_uchar,
+            _elen: u32,
+        ) {
+            if raw_ic.is_null() || status != sys::ic_status_t_IC_MSG_OK {
+                return;
+            }
 
-impl Drop for InnerClient {
-    fn drop(&mut self) {
-        unsafe {
-            sys::ic_wipe(&mut self.raw_ic);
+            let ic = unsafe { InnerClient::from_raw(raw_ic) };
+            let res = unsafe { std::slice::from_raw_parts(res, rlen as usize) };
+            let peer_caps = compress::decompress(res, None)
+                .and_then(|res| from_bytes::<Capabilities>(&res).map_err(|e| e.to_string()));
+
+            match peer_caps {
+                Ok(peer_caps) => ic.peer_capabilities = Some(Rc::new(peer_caps)),
+                Err(e) => log::warn!(peer:? = ic.peer_addr; "invalid handshake answer: {}", e),
+            }
         }
-    }
-}
 
-// }}}
-// {{{ Channel
+        let mut data = MsgBuffer::new(PACK_BUFFER_SIZE);
 
-pub struct Channel(*mut sys::ichannel_t);
+        // the channel works without the capabilities of its peer, so the
+        // handshake is skipped if they cannot be packed
+        if let Err(e) = to_buffer(caps, &mut data) {
+            log::error!("cannot pack the capabilities, handshake skipped: {}", e);
+            return;
+        }
+        unsafe {
+            let msg = sys::ic_msg_new(0);
 
-impl Channel {
-    pub fn from_raw<'b>(ic: *mut sys::ichannel_t) -> Self {
-        Self(ic)
-    }
+            data.give_to(msg);
+            (*msg).cb2 = Some(on_answer);
+            (*msg).cmd = HANDSHAKE_CMD;
 
-    pub fn to_raw(&mut self) -> *mut sys::ichannel_t {
-        self.0
+            sys::__ic_query(raw_ic, msg);
+        }
     }
-}
+
+    pub fn disconnect(&mut self) {
+        unsafe {
+            sys::ic_disconnect(&mut self.inner.raw_ic);
+        }
+    }
+
+    /// Call `f` with every message sent or received on the channel, to debug
+    /// the exchanges with a peer.
+    ///
+    /// `f` is given the direction of the message, the command of queries or
+    /// the status of answers, the slot, and the packed arguments or answer,
+    /// without the ic header.
+    pub fn on_wire<F>(&mut self, f: F)
+    where
+        F: Fn(Direction, i32, u64, &[u8]) + 'static,
+    {
+        self.inner.wire_hook = Some(Rc::new(f));
+    }
+
+    /// Save the messages sent and received on the channel with `recorder`,
+    /// to replay the queries received with a `record::Replay`.
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self.inner.recorder = recorder;
+    }
+
+    /// Call `f` with the events of the connection, as they happen.
+    pub(crate) fn set_event_hook(&mut self, f: Option<Box<dyn Fn(ConnectionEvent)>>) {
+        self.inner.event_hook = f;
+    }
+
+    pub fn set_payload_limits(&mut self, limits: PayloadLimits) {
+        self.inner.payload_limits = limits;
+    }
+
+    /// Compression of the payloads sent to the server, if it supports it.
+    ///
+    /// It is negotiated when connecting, so it must be set before.
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self.inner.compression = compression;
+    }
+
+    /// Capabilities advertised to the server by the handshake sent when
+    /// connecting, which must then be set before.
+    ///
+    /// The capabilities of the server are known once it answers, after the
+    /// connection is established.
+    pub fn set_capabilities(&mut self, capabilities: Option<Capabilities>) {
+        self.inner.capabilities = capabilities.map(Rc::new);
+    }
+
+    /// Set the options of the socket of the channel, on every connection.
+    pub fn set_socket_options(&mut self, options: ClientOptions) -> io::Result<()> {
+        if self.inner.connected {
+            options.apply(unsafe { sys::el_fd_get_fd(self.inner.raw_ic.elh) })?;
+        }
+        self.inner.socket_options = Some(options);
+        Ok(())
+    }
+
+    /// Ping the server periodically, disconnecting the channel when it stops
+    /// answering, so that it reconnects.
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        if let Some(running) = self.inner.keepalive.take() {
+            running.set(false);
+        }
+        if let Some(keepalive) = keepalive {
+            let running = Rc::new(Cell::new(true));
+
+            self.inner.keepalive = Some(running.clone());
+            el_future::spawn(Self::run_keepalive(
+                &mut self.inner.raw_ic,
+                keepalive,
+                running,
+            ));
+        }
+    }
+
+    async fn run_keepalive(
+        raw_ic: *mut sys::ichannel_t,
+        keepalive: Keepalive,
+        running: Rc<Cell<bool>>,
+    ) {
+        let mut misses = 0;
+
+        loop {
+            el_future::Timer::new(keepalive.interval.as_millis() as i64, 0)
+                .await
+                .await;
+            // the client is dropped, or the channel accepted by a server
+            // is closed
+            if !running.get() || unsafe { InnerClient::from_raw(raw_ic) }.closed {
+                break;
+            }
+            if !unsafe { InnerClient::from_raw(raw_ic) }.connected {
+                misses = 0;
+                continue;
+            }
+
+            match unsafe { Channel::from_raw(raw_ic) }
+                .ping(keepalive.interval)
+                .await
+            {
+                _ if !running.get() => break,
+                Err(error::Error::TimedOut) => misses += 1,
+                _ => misses = 0,
+            }
+            if misses >= keepalive.max_misses {
+                log::warn!(
+                    peer:? = unsafe { InnerClient::from_raw(raw_ic) }.peer_addr;
+                    "peer missed {} pings, disconnecting", misses
+                );
+                misses = 0;
+                unsafe {
+                    sys::ic_disconnect(raw_ic);
+                }
+            }
+        }
+    }
+
+    fn spawn(&mut self, fd: i32) {
+        unsafe {
+            sys::ic_spawn(&mut self.inner.raw_ic, fd, None);
+        }
+    }
+
+    pub fn get_channel(&mut self) -> Channel {
+        unsafe { Channel::from_raw(&mut self.inner.raw_ic as *mut _) }
+    }
+
+    /// Whether the channel is connected to the server.
+    pub fn is_connected(&self) -> bool {
+        self.inner.connected
+    }
+
+    /// Whether the channel is connected, and the server still answers the
+    /// keepalive messages of lib-common.
+    pub fn is_healthy(&self) -> bool {
+        self.inner.connected && self.inner.active
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.inner.state()
+    }
+
+    /// Wait for the channel to be connected, for example after the
+    /// server restarted.
+    pub fn connected(&self) -> StateFuture {
+        StateFuture::new(&self.inner.raw_ic, Some(self.inner.alive.clone()), true)
+    }
+
+    /// Wait for the channel to be disconnected.
+    pub fn disconnected(&self) -> StateFuture {
+        StateFuture::new(&self.inner.raw_ic, Some(self.inner.alive.clone()), false)
+    }
+
+    /// Stream of the changes of the state of the connection, from now on,
+    /// ending when the client is dropped.
+    pub fn events(&mut self) -> ConnectionEvents {
+        self.inner.events()
+    }
+}
+
+impl Drop for InnerClient {
+    fn drop(&mut self) {
+        self.alive.set(false);
+        self.extensions.borrow_mut().clear();
+        if let Some(running) = &self.keepalive {
+            running.set(false);
+        }
+        self.wake_state();
+        for query in self
+            .queries
+            .drain(..)
+            .filter_map(|query| query.state.upgrade())
+        {
+            query.wipe();
+        }
+        unsafe {
+            sys::ic_wipe(&mut self.raw_ic);
+        }
+    }
+}
+
+// }}}
+// {{{ Channel
+
+/// Handle to send queries on a channel of a `Client` or a `Server`, or on a
+/// loopback channel.
+///
+/// The handle can outlive the client or server owning the channel: it is
+/// then closed, and the queries sent on it fail with `Error::Canceled`.
+pub struct Channel {
+    raw: *mut sys::ichannel_t,
+
+    // cleared once the client or server owning the channel is dropped, the
+    // ichannel being wiped, `None` for loopback channels
+    alive: Option<Rc<Cell<bool>>>,
+
+    // deadline applied to the queries sent on the channel
+    deadline: Option<Instant>,
+
+    // reply of the query, for the channel given to an RPC implementation
+    reply: Option<Rc<PendingReply>>,
+
+    // register answering the queries of a loopback channel
+    loopback: Option<Rc<RpcRegister>>,
+
+    // values attached to the connection, or to the loopback channel
+    extensions: Rc<RefCell<Extensions>>,
+}
+
+impl Channel {
+    /// Handle to the channel `ic` of a `Client` or a `Server`.
+    ///
+    /// # Safety
+    ///
+    /// `ic` must be the ichannel of a client or server which is not dropped
+    /// yet. The handle may outlive it, its calls then failing with
+    /// `Error::Canceled`.
+    pub(crate) unsafe fn from_raw(ic: *mut sys::ichannel_t) -> Self {
+        let inner = InnerClient::from_raw(ic);
+
+        Self {
+            raw: ic,
+            alive: Some(inner.alive.clone()),
+            deadline: None,
+            reply: None,
+            loopback: None,
+            extensions: inner.extensions.clone(),
+        }
+    }
+
+    /// Channel whose queries are answered by the implementations of
+    /// `register`, in the same process, without going through lib-common.
+    ///
+    /// The queries are handled when their future is polled, so they can be
+    /// awaited without running the event loop. The queries sent by the
+    /// implementations on their channel are looped back too.
+    ///
+    /// The middlewares of the register, the metrics and the wire hooks are
+    /// not applied to these queries, and their deadlines are not enforced.
+    /// Async queries sent with `Rpc::send` are handled by the event loop.
+    pub fn loopback(register: &RpcDispatcher) -> Self {
+        Self {
+            raw: std::ptr::null_mut(),
+            alive: None,
+            deadline: None,
+            reply: None,
+            loopback: Some(register.register.clone()),
+            extensions: Rc::default(),
+        }
+    }
+
+    /// Values attached to the connection of the channel, shared by all the
+    /// channels of the connection, including the ones given to the RPC
+    /// implementations.
+    ///
+    /// The values are dropped when the connection is lost, before a client
+    /// reconnects, or when the channel is closed. A loopback channel has its
+    /// own values, not shared with the channels given to the
+    /// implementations it calls.
+    ///
+    /// ```ignore
+    /// reg.wrap(|next, ic, hdr, _cmd, _data| {
+    ///     if let Some(login) = &hdr.login {
+    ///         ic.extensions_mut().insert(Session::new(login));
+    ///     }
+    ///     next()
+    /// });
+    /// ```
+    pub fn extensions(&self) -> Ref<'_, Extensions> {
+        self.extensions.borrow()
+    }
+
+    pub fn extensions_mut(&self) -> RefMut<'_, Extensions> {
+        self.extensions.borrow_mut()
+    }
+
+    /// Whether the queries of this channel are answered in process.
+    pub fn is_loopback(&self) -> bool {
+        self.loopback.is_some()
+    }
+
+    /// Whether the client or server owning the channel was dropped, the
+    /// channel being then closed for good.
+    pub fn is_dropped(&self) -> bool {
+        self.alive.as_ref().is_some_and(|alive| !alive.get())
+    }
+
+    /// The ichannel, null for loopback channels and once the client or
+    /// server owning the channel is dropped.
+    pub fn to_raw(&mut self) -> *mut sys::ichannel_t {
+        self.live_raw().unwrap_or(std::ptr::null_mut())
+    }
+
+    /// The ichannel, if not a loopback channel and not wiped yet.
+    fn live_raw(&self) -> Option<*mut sys::ichannel_t> {
+        match &self.alive {
+            Some(alive) if alive.get() => Some(self.raw),
+            _ => None,
+        }
+    }
+
+    /// Client of the ichannel, if not a loopback channel and not wiped yet.
+    fn live_inner(&self) -> Option<&InnerClient> {
+        // the ichannel is alive, so its client is not dropped
+        self.live_raw()
+            .map(|raw_ic| unsafe { &*InnerClient::from_raw(raw_ic) })
+    }
+
+    /// Deadline of the queries sent on this channel.
+    ///
+    /// The channel given to an RPC implementation has the deadline of the
+    /// query it answers, so that the queries it sends in turn do not outlive
+    /// it.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Batch of queries of the RPC `R`, sent concurrently on this channel.
+    pub fn batch<R: Rpc>(&self, iface_tag: u16) -> Batch<R> {
+        Batch::new(self.dup(), iface_tag)
+    }
+
+    /// Handle to send queries on this channel from other threads.
+    pub fn handle(&self) -> ChannelHandle {
+        ChannelHandle::new(self.dup())
+    }
+
+    /// Copy of the channel to send queries, without the reply of the query
+    /// it may answer.
+    pub(crate) fn dup(&self) -> Channel {
+        Self {
+            raw: self.raw,
+            alive: self.alive.clone(),
+            deadline: self.deadline,
+            reply: None,
+            loopback: self.loopback.clone(),
+            extensions: self.extensions.clone(),
+        }
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.live_inner()?.peer_addr
+    }
+
+    /// Capabilities advertised by the peer in the handshake of the current
+    /// connection, to gate the fields and RPCs it may not know.
+    ///
+    /// None until the handshake is done, if the peer did not advertise any,
+    /// and for loopback channels. On a server, the handshake of a client is
+    /// received before the queries it sends once connected.
+    ///
+    /// ```ignore
+    /// reg.register(IFACE << 16 | 1, |ic, arg: GetUserArg| async move {
+    ///     let v2 = ic.peer_capabilities().and_then(|caps| caps.iface_version(IFACE)) >= Some(2);
+    ///     ...
+    /// });
+    /// ```
+    pub fn peer_capabilities(&self) -> Option<Rc<Capabilities>> {
+        self.live_inner()?.peer_capabilities.clone()
+    }
+
+    /// Identity given by the certificate of the peer, for TLS channels whose
+    /// peer sent a certificate verified against the CA of the `TlsConfig`.
+    ///
+    /// It is known from the connection of the channel, so authenticators can
+    /// use it to check the queries of clients:
+    ///
+    /// ```ignore
+    /// server.set_authenticator(|ic: &Channel, _hdr: &QueryHeader, _cmd| {
+    ///     match ic.peer_identity() {
+    ///         Some(id) if id.common_name.as_deref() == Some("billing") => Ok(()),
+    ///         _ => Err(FORBIDDEN_STATUS),
+    ///     }
+    /// });
+    /// ```
+    pub fn peer_identity(&self) -> Option<Rc<PeerIdentity>> {
+        self.live_inner()?.peer_identity.clone()
+    }
+
+    /// Ping the peer, resolving with the round-trip time once it answers.
+    ///
+    /// Pings are answered by the registers of lib-common, without going
+    /// through the middlewares of either side. They fail with
+    /// `Error::TimedOut` if the peer does not answer within `timeout`, and
+    /// with `Error::Canceled` on a dropped channel. Loopback channels answer
+    /// at once.
+    pub fn ping(
+        &mut self,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Duration, error::Error<()>>> {
+        extern "C" fn on_answer(
+            _raw_ic: *mut sys::ichannel_t,
+            msg: *mut sys::ic_msg_t,
+            status: sys::ic_status_t,
+            _res: *const c_uchar,
+            _rlen: u32,
+            _exn: *const c_uchar,
+            _elen: u32,
+        ) {
+            let tx = unsafe {
+                Box::from_raw(*((*msg).priv_.as_ptr() as *const *mut oneshot::Sender<_>))
+            };
+
+            let _ = tx.send(status);
+        }
+
+        let start = Instant::now();
+        let raw_ic = match self.live_raw() {
+            Some(raw_ic) => raw_ic,
+            None if self.is_loopback() => {
+                return future::ready(Ok(Duration::default())).left_future()
+            }
+            None => return future::ready(Err(error::Error::Canceled)).left_future(),
+        };
+
+        let (tx, rx) = oneshot::channel::<sys::ic_status_t>();
+        unsafe {
+            let msg = sys::ic_msg_new(mem::size_of::<*const c_void>() as i32);
+            let tx = Box::into_raw(Box::new(tx));
+
+            MsgBuffer::new(0).give_to(msg);
+            (*msg).cb2 = Some(on_answer);
+            (*msg).cmd = PING_CMD;
+            // a timeout of 0 means no timeout at all
+            (*msg).timeout = (timeout.as_millis() as u32).max(1);
+            std::ptr::copy_nonoverlapping(
+                &(tx as *mut c_void),
+                (*msg).priv_.as_mut_ptr() as *mut *mut c_void,
+                1,
+            );
+
+            sys::__ic_query(raw_ic, msg);
+        }
+
+        rx.map(move |status| match status {
+            Ok(sys::ic_status_t_IC_MSG_OK) => Ok(start.elapsed()),
+            Ok(sys::ic_status_t_IC_MSG_EXN) => Err(error::Error::Invalid),
+            Ok(status) => Err(status.into()),
+            Err(_) => Err(error::Error::Canceled),
+        })
+        .right_future()
+    }
+
+    /// Register answering the queries received on the channel.
+    fn register(&self) -> Option<Rc<RpcRegister>> {
+        match self.live_inner() {
+            Some(inner) => inner.register.clone(),
+            None => self.loopback.clone(),
+        }
+    }
+
+    /// State of the connection of the channel, loopback channels being
+    /// always connected, and dropped channels closed.
+    pub fn state(&self) -> ConnectionState {
+        match self.live_inner() {
+            Some(inner) => inner.state(),
+            None if self.is_loopback() => ConnectionState::Connected,
+            None => ConnectionState::Closed,
+        }
+    }
+
+    /// Whether queries can be sent to the peer right away.
+    ///
+    /// Queries sent while a client is disconnected are only sent once it
+    /// reconnects, and fail with `Error::Retry` on the channels accepted by
+    /// a server.
+    pub fn is_connected(&self) -> bool {
+        self.state().is_connected()
+    }
+
+    /// Wait for the channel to be connected.
+    ///
+    /// Never resolves for a closed channel.
+    pub fn connected(&self) -> StateFuture {
+        StateFuture::new(self.raw, self.alive.clone(), true)
+    }
+
+    /// Wait for the channel to be disconnected.
+    ///
+    /// Never resolves for a loopback channel.
+    pub fn disconnected(&self) -> StateFuture {
+        StateFuture::new(self.raw, self.alive.clone(), false)
+    }
+
+    /// Stream of the changes of the state of the connection, from now on.
+    ///
+    /// It ends once the channel is closed, at once for loopback and dropped
+    /// channels.
+    pub fn events(&self) -> ConnectionEvents {
+        match self.live_raw() {
+            // the ichannel is alive, so its client is not dropped
+            Some(raw_ic) => unsafe { InnerClient::from_raw(raw_ic) }.events(),
+            None => {
+                let (_, receiver) = mpsc::unbounded();
+
+                ConnectionEvents { receiver }
+            }
+        }
+    }
+
+    /// State of the send queue of the channel, empty for loopback and
+    /// dropped channels.
+    pub fn queue_state(&self) -> QueueState {
+        let raw = match self.live_raw() {
+            Some(raw_ic) => unsafe { &*raw_ic },
+            None => return QueueState::default(),
+        };
+
+        QueueState {
+            queued_msgs: raw.queue_len.max(0) as usize,
+            queued_bytes: raw.iov_total_len.max(0) as usize,
+            pending_queries: raw.pending.max(0) as usize,
+        }
+    }
+
+    /// Queries sent on the channel and not answered yet, to detect a peer
+    /// which stopped answering some of them.
+    ///
+    /// The queries whose future is dropped are counted until they are
+    /// answered or time out. It is empty for loopback and dropped channels.
+    pub fn pending_stats(&self) -> PendingStats {
+        match self.live_inner() {
+            Some(inner) => inner.pending_stats(),
+            None => PendingStats::default(),
+        }
+    }
+
+    /// Watermarks of the send queue, shared by all the channels of the
+    /// connection.
+    pub fn watermarks(&self) -> Watermarks {
+        match self.live_inner() {
+            Some(inner) => inner.watermarks,
+            None => Watermarks::default(),
+        }
+    }
+
+    pub fn set_watermarks(&mut self, watermarks: Watermarks) {
+        if let Some(raw_ic) = self.live_raw() {
+            // the ichannel is alive, so its client is not dropped
+            unsafe { InnerClient::from_raw(raw_ic).watermarks = watermarks };
+        }
+    }
+
+    /// Whether the send queue is below its soft watermarks.
+    pub fn is_writable(&self) -> bool {
+        let state = self.queue_state();
+        let watermarks = self.watermarks();
+
+        state.queued_msgs < watermarks.msgs && state.queued_bytes < watermarks.bytes
+    }
+
+    /// Whether the send queue reaches its hard watermarks.
+    fn is_full(&self) -> bool {
+        self.watermarks().is_full(&self.queue_state())
+    }
+
+    /// Wait for the send queue to go below its soft watermarks, to throttle
+    /// bursts of queries or replies.
+    pub async fn writable(&self) {
+        future::poll_fn(|cx| {
+            if self.is_writable() {
+                Poll::Ready(())
+            } else {
+                wait_writable(self.dup(), cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Take the reply of the query answered by the RPC implementation given
+    /// this channel, to send it later, for example once an event occurs.
+    ///
+    /// The result of the implementation is then ignored. Returns `None` if
+    /// the channel was not given to an RPC implementation, or if the token
+    /// was already taken.
+    pub fn take_reply_token<T, E>(&mut self) -> Option<ReplyToken<T, E>>
+    where
+        T: Serialize,
+        E: Serialize,
+    {
+        let reply = self.reply.as_ref()?;
+        if reply.taken.replace(true) {
+            return None;
+        }
+        let (sender, receiver) = oneshot::channel();
+
+        reply.deferred.set(Some(receiver));
+        Some(ReplyToken {
+            cmd: reply.cmd,
+            slot: reply.slot,
+            peer: reply.peer,
+            sender: Some(sender),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Answer the query of `slot`, given to a raw handler registered with
+    /// `RpcRegister::register_raw`, with the packed answer `data`, returning
+    /// the status of the reply.
+    ///
+    /// The status is a server error if `data` exceeds the payload limit of
+    /// the channel of the query. The reply is dropped if that channel was
+    /// closed, or if the query was async.
+    pub fn reply_raw(&self, slot: Slot, status: sys::ic_status_t, data: &[u8]) -> sys::ic_status_t {
+        send_reply(data, slot.cmd, slot.slot, status)
+    }
+}
+
+/// Slot of a query given to a raw handler, identifying the query to answer
+/// with `Channel::reply_raw`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Slot {
+    cmd: i32,
+    slot: u64,
+}
+
+impl Slot {
+    /// Command of the query.
+    pub fn cmd(&self) -> i32 {
+        self.cmd
+    }
+
+    /// Whether the query is async, so expects no answer.
+    pub fn is_async(&self) -> bool {
+        self.slot == 0
+    }
+}
+
+struct PendingReply {
+    cmd: i32,
+    slot: u64,
+    peer: Option<SocketAddr>,
+    taken: Cell<bool>,
+
+    // status of the reply sent with the token
+    deferred: Cell<Option<oneshot::Receiver<sys::ic_status_t>>>,
+}
+
+/// Reply of a query, sent outside of its RPC implementation.
+///
+/// Dropping the token without replying answers the query with
+/// `IC_MSG_SERVER_ERROR`, so that the peer does not wait for it forever.
+pub struct ReplyToken<T, E> {
+    cmd: i32,
+    slot: u64,
+    peer: Option<SocketAddr>,
+    sender: Option<oneshot::Sender<sys::ic_status_t>>,
+    _marker: PhantomData<fn(T, E)>,
+}
+
+impl<T: Serialize, E: Serialize> ReplyToken<T, E> {
+    /// Send the reply, returning its status.
+    ///
+    /// The reply is dropped if the channel of the query was closed.
+    pub fn reply(mut self, result: Result<T, error::Error<E>>) -> sys::ic_status_t {
+        let status = send_result(result, self.cmd, self.slot, self.peer);
+
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(status);
+        }
+        status
+    }
+}
+
+impl<T, E> Drop for ReplyToken<T, E> {
+    fn drop(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            log::warn!(
+                cmd = self.cmd, slot = self.slot, peer:? = self.peer;
+                "reply token dropped without replying"
+            );
+            let status = send_reply(
+                &[],
+                self.cmd,
+                self.slot,
+                sys::ic_status_t_IC_MSG_SERVER_ERROR,
+            );
+            let _ = sender.send(status);
+        }
+    }
+}
+
+/// State of the send queue of a channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueState {
+    /// Messages waiting to be written.
+    pub queued_msgs: usize,
+    /// Bytes of the messages being written.
+    pub queued_bytes: usize,
+    /// Queries sent and not answered yet.
+    pub pending_queries: usize,
+}
+
+/// Queries of a channel waiting for their answer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PendingStats {
+    pub count: usize,
+    /// Number of pending queries by command.
+    pub cmds: HashMap<i32, usize>,
+    /// Time since the oldest pending query was sent.
+    pub oldest: Option<Duration>,
+}
+
+/// Limits of the send queue of a channel.
+///
+/// Above the soft limits, the channel is not writable, so that the callers
+/// awaiting `Channel::writable` slow down. Above the hard limits, the
+/// queries sent on the channel fail at once with `Error::Retry`, instead of
+/// buffering more data for a peer which does not keep up. The replies are
+/// always queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watermarks {
+    pub msgs: usize,
+    pub bytes: usize,
+    /// Hard limit of the queued messages, unlimited by default.
+    pub hard_msgs: Option<usize>,
+    /// Hard limit of the queued bytes, unlimited by default.
+    pub hard_bytes: Option<usize>,
+}
+
+impl Default for Watermarks {
+    fn default() -> Self {
+        Self {
+            msgs: 1024,
+            bytes: 1 << 20,
+            hard_msgs: None,
+            hard_bytes: None,
+        }
+    }
+}
+
+impl Watermarks {
+    /// Whether a queue in `state` reaches the hard limits.
+    fn is_full(&self, state: &QueueState) -> bool {
+        self.hard_msgs.is_some_and(|max| state.queued_msgs >= max)
+            || self.hard_bytes.is_some_and(|max| state.queued_bytes >= max)
+    }
+}
+
+/// Send the result of an RPC implementation, returning its status.
+fn send_result<O, E>(
+    result: Result<O, error::Error<E>>,
+    cmd: i32,
+    slot: u64,
+    peer: Option<SocketAddr>,
+) -> sys::ic_status_t
+where
+    O: Serialize,
+    E: Serialize,
+{
+    match result {
+        Ok(res) => send_packed_reply(&res, cmd, slot, sys::ic_status_t_IC_MSG_OK),
+        Err(error::Error::Exn(iop)) => {
+            send_packed_reply(&iop, cmd, slot, sys::ic_status_t_IC_MSG_EXN)
+        }
+        Err(e) => {
+            if let error::Error::Generic(msg) = &e {
+                log::error!(cmd, slot, peer:?; "rpc implementation failed: {}", msg);
+            }
+            let status = sys::ic_status_t::from(e);
+
+            send_reply(&[], cmd, slot, status);
+            status
+        }
+    }
+}
+
+/// Pack and send a reply, returning its status.
+fn send_packed_reply<T: Serialize>(
+    res: &T,
+    cmd: i32,
+    slot: u64,
+    status: sys::ic_status_t,
+) -> sys::ic_status_t {
+    // the reply is packed directly in the buffer of its message
+    let mut data = MsgBuffer::new(PACK_BUFFER_SIZE);
+
+    match to_buffer(res, &mut data) {
+        Ok(()) => send_reply_buffer(data, cmd, slot, status),
+        Err(e) => {
+            log::error!(cmd, slot; "cannot pack rpc reply: {}", e);
+            send_reply(&[], cmd, slot, sys::ic_status_t_IC_MSG_SERVER_ERROR)
+        }
+    }
+}
+
+/// Error of a query whose arguments cannot be packed.
+fn pack_error<E>(cmd: i32, e: serde_iop::Error) -> error::Error<E> {
+    log::error!(cmd; "cannot pack query arguments: {}", e);
+    error::Error::Generic(format!("cannot pack query arguments: {}", e))
+}
+
+/// Capacity reserved for the payloads packed in messages, enough for most
+/// of them.
+const PACK_BUFFER_SIZE: usize = 256;
 
 // TODO: by distinguishing async from std RPC impls, we could provide the ic if possible.
-fn send_reply(res: &[u8], slot: u64, status: sys::ic_status_t) {
-    let mut ic = std::ptr::null_mut();
-    let msg = unsafe { sys::ic_msg_new_for_reply(&mut ic as *mut _, slot, status as i32) };
+/// Send a reply, returning its status, which is a server error if the reply
+/// exceeds the payload limit of the channel.
+pub(crate) fn send_reply(
+    res: &[u8],
+    cmd: i32,
+    slot: u64,
+    status: sys::ic_status_t,
+) -> sys::ic_status_t {
+    if slot & LOOPBACK_SLOT == LOOPBACK_SLOT {
+        loopback_reply(slot, status, res);
+        return status;
+    }
 
-    let mut data = Vec::new();
-    data.resize(12, 0);
-    data.extend_from_slice(res);
-    let mut data = data.into_boxed_slice();
+    send_reply_buffer(MsgBuffer::from_payload(res), cmd, slot, status)
+}
 
-    unsafe {
-        (*msg).dlen = data.len() as u32;
-        (*msg).data = data.as_mut_ptr() as *mut c_void;
+/// Same as `send_reply`, with the payload packed in a message buffer, given
+/// as is to the message.
+fn send_reply_buffer(
+    mut data: MsgBuffer,
+    cmd: i32,
+    slot: u64,
+    status: sys::ic_status_t,
+) -> sys::ic_status_t {
+    if slot & LOOPBACK_SLOT == LOOPBACK_SLOT {
+        loopback_reply(slot, status, data.payload());
+        return status;
+    }
+
+    let mut ic: *mut sys::ichannel_t = std::ptr::null_mut();
+    let mut msg = unsafe { sys::ic_msg_new_for_reply(&mut ic as *mut _, slot, status as i32) };
+
+    if !ic.is_null() {
+        if let Some(max) = unsafe { InnerClient::from_raw(ic) }.payload_limits.outgoing {
+            let size = data.payload().len();
+
+            if size > max {
+                log::error!(
+                    cmd, slot;
+                    "reply payload of {} bytes exceeds the limit of {}", size, max
+                );
+                unsafe {
+                    sys::ic_msg_delete(&mut msg);
+                }
+                return send_reply(&[], cmd, slot, sys::ic_status_t_IC_MSG_SERVER_ERROR);
+            }
+        }
     }
-    std::mem::forget(data);
 
+    if !ic.is_null() {
+        if let Some(compressed) = compress_payload(ic, data.payload()) {
+            data.truncate(12);
+            data.extend_from_slice(&compressed);
+        }
+    }
+    let res = unsafe {
+        data.give_to(msg);
+        std::slice::from_raw_parts(
+            ((*msg).data as *const u8).add(12),
+            (*msg).dlen as usize - 12,
+        )
+    };
+
+    if !ic.is_null() {
+        if let Some(reg) = &unsafe { InnerClient::from_raw(ic) }.register {
+            reg.metrics.record_reply(cmd, res.len());
+        }
+        call_wire_hook(
+            ic,
+            Direction::Sent,
+            cmd,
+            Some(status),
+            slot,
+            std::ptr::null(),
+            res,
+        );
+    }
     unsafe {
         sys::ic_queue_for_reply(ic, msg);
     }
+    status
+}
+
+// }}}
+// {{{ Query Future
+
+type QueryCallback<Res, Exn> = Box<dyn FnOnce(Result<Res, error::Error<Exn>>)>;
+
+struct QueryState<Res, Exn> {
+    result: Option<Result<Res, error::Error<Exn>>>,
+    waker: Option<Waker>,
+    // called with the result instead of waking the future, see `on_result`
+    callback: Option<QueryCallback<Res, Exn>>,
+    // set when the future is dropped or the query canceled, the answer is
+    // then ignored
+    abandoned: bool,
+    // set when the channel is destroyed, the query is then not sent if it
+    // is still going through its middlewares
+    wiped: bool,
+    // message of the query, until it is answered
+    msg: MsgPtr,
+    // status of the answer, for the middlewares of the query
+    status_tx: Option<oneshot::Sender<sys::ic_status_t>>,
+    // header referenced by the message, until the query is answered
+    _hdr: Option<Box<RawHeader>>,
+    // metrics of the register of the channel, if any
+    metrics: Option<QueryMetrics>,
+    #[cfg(feature = "tracing")]
+    span: trace::QuerySpan,
+}
+
+struct MsgPtr(*mut sys::ic_msg_t);
+
+// The message is only accessed from the thread of the event loop.
+unsafe impl Send for MsgPtr {}
+
+trait CancelQuery {
+    /// Resolve the query with the error matching `status`, ignoring its
+    /// answer.
+    fn fail(&self, status: sys::ic_status_t);
+
+    /// Whether the query still waits for its answer.
+    fn is_pending(&self) -> bool;
+
+    /// Resolve the query with `Error::Canceled`, as its channel is
+    /// destroyed.
+    fn wipe(&self);
+}
+
+impl<Res, Exn> CancelQuery for Mutex<QueryState<Res, Exn>> {
+    fn fail(&self, status: sys::ic_status_t) {
+        let mut state = self.lock().unwrap();
+
+        if state.abandoned || state.result.is_some() {
+            return;
+        }
+        state.abandoned = true;
+        if let Some(metrics) = state.metrics.take() {
+            metrics.finish(status, 0);
+        }
+        #[cfg(feature = "tracing")]
+        state.span.finish(status);
+        if !state.msg.0.is_null() {
+            unsafe {
+                (*state.msg.0).set_canceled(true);
+            }
+        }
+        let res = Err(match status {
+            sys::ic_status_t_IC_MSG_OK | sys::ic_status_t_IC_MSG_EXN => error::Error::Canceled,
+            status => error::Error::from(status),
+        });
+        if let Some(callback) = state.callback.take() {
+            drop(state);
+            callback(res);
+            return;
+        }
+        state.result = Some(res);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn is_pending(&self) -> bool {
+        !self.lock().unwrap().msg.0.is_null()
+    }
+
+    fn wipe(&self) {
+        {
+            let mut state = self.lock().unwrap();
+
+            state.wiped = true;
+            // ends the middlewares of the query
+            if let Some(status_tx) = state.status_tx.take() {
+                let _ = status_tx.send(sys::ic_status_t_IC_MSG_CANCELED);
+            }
+        }
+        self.fail(sys::ic_status_t_IC_MSG_CANCELED);
+    }
+}
+
+pub struct QueryFuture<Res, Exn> {
+    state: Arc<Mutex<QueryState<Res, Exn>>>,
+
+    // handling of the query, for loopback channels
+    loopback: Option<LoopbackQuery>,
+}
+
+/// Handle canceling a query, without waiting for its answer.
+#[derive(Clone)]
+pub struct QueryHandle {
+    state: Weak<dyn CancelQuery>,
+}
+
+impl QueryHandle {
+    /// Cancel the query, its future resolving with `Error::Canceled`.
+    ///
+    /// Does nothing if the query is already answered.
+    pub fn cancel(&self) {
+        if let Some(state) = self.state.upgrade() {
+            state.fail(sys::ic_status_t_IC_MSG_CANCELED);
+        }
+    }
+}
+
+impl<Res: 'static, Exn: 'static> QueryFuture<Res, Exn> {
+    /// Handle to cancel the query.
+    pub fn handle(&self) -> QueryHandle {
+        let state: Arc<dyn CancelQuery> = self.state.clone();
+
+        QueryHandle {
+            state: Arc::downgrade(&state),
+        }
+    }
+
+    /// Call `cb` with the result of the query instead of awaiting it, so
+    /// that it does not need an executor.
+    pub(crate) fn on_result<F>(self, cb: F)
+    where
+        F: FnOnce(Result<Res, error::Error<Exn>>) + 'static,
+    {
+        if self.loopback.is_some() {
+            // the query is only handled when the future is polled
+            el_future::spawn(async move { cb(self.await) });
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(res) => {
+                drop(state);
+                cb(res);
+            }
+            None => state.callback = Some(Box::new(cb)),
+        }
+    }
 }
 
-// }}}
-// {{{ Query Future
-
-struct QueryState<Res, Exn> {
-    result: Option<Result<Res, error::Error<Exn>>>,
-    waker: Option<Waker>,
-}
+impl<Res, Exn> Drop for QueryFuture<Res, Exn> {
+    fn drop(&mut self) {
+        // The message keeps its own reference on the state, released when
+        // the query is answered, timed out or canceled with its channel.
+        let mut state = self.state.lock().unwrap();
 
-pub struct QueryFuture<Res, Exn> {
-    state: Arc<Mutex<QueryState<Res, Exn>>>,
+        // the answer is still given to the callback, see `on_result`
+        if state.callback.is_some() {
+            return;
+        }
+        state.abandoned = true;
+        state.waker = None;
+        state.result = None;
+    }
 }
 
 impl<Res, Exn> Future for QueryFuture<Res, Exn> {
     type Output = Result<Res, error::Error<Exn>>;
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Some(loopback) = &mut self.loopback {
+            loopback.poll_dispatch(cx);
+        }
+
         let mut state = self.state.lock().unwrap();
         match state.result.take() {
             Some(r) => Poll::Ready(r),
@@ -384,32 +3133,126 @@ type MsgPayload<Res, Exn> = Mutex<QueryState<Res, Exn>>;
 
 impl<Res, Exn> QueryFuture<Res, Exn>
 where
-    Res: DeserializeOwned,
-    Exn: DeserializeOwned,
+    Res: DeserializeOwned + 'static,
+    Exn: DeserializeOwned + 'static,
 {
-    pub fn new(ic: &mut Channel, input: &[u8], cmd: i32, async_: bool) -> Self {
-        let msg = unsafe { sys::ic_msg_new(std::mem::size_of::<*const c_void>() as i32) };
+    pub fn new<I>(ic: &mut Channel, input: &I, cmd: i32, async_: bool) -> Self
+    where
+        I: Serialize,
+    {
+        Self::new_with_hdr(ic, None, input, cmd, async_)
+    }
 
-        // Serialize input
-        let mut data = Vec::new();
-        data.resize(12, 0);
-        data.extend_from_slice(input);
-        let mut data = data.into_boxed_slice();
+    /// Send a query with a header, as `ic_query` does when `msg->hdr` is set.
+    pub fn new_with_hdr<I>(
+        ic: &mut Channel,
+        hdr: Option<&QueryHeader>,
+        input: &I,
+        cmd: i32,
+        async_: bool,
+    ) -> Self
+    where
+        I: Serialize,
+    {
+        if let Some(register) = ic.loopback.clone() {
+            return Self::loopback(register, ic.deadline, hdr, input, cmd);
+        }
 
-        unsafe {
-            (*msg).dlen = data.len() as u32;
-            (*msg).data = data.as_mut_ptr() as *mut c_void;
+        let raw_ic = match ic.live_raw() {
+            Some(raw_ic) => raw_ic,
+            None => {
+                return Self::failed(
+                    cmd,
+                    sys::ic_status_t_IC_MSG_CANCELED,
+                    error::Error::Canceled,
+                )
+            }
+        };
+        if ic.is_full() {
+            log::warn!(cmd; "send queue above its hard watermarks, query rejected");
+            return Self::failed(cmd, sys::ic_status_t_IC_MSG_RETRY, error::Error::Retry);
+        }
+        let register = unsafe { InnerClient::from_raw(raw_ic) }.register.as_ref();
+        let middlewares = register
+            .map(|reg| reg.call_middlewares.clone())
+            .filter(|middlewares| !middlewares.is_empty());
+
+        // Serialize input after the 12 bytes reserved for the ic header, and
+        // the query header if any, directly in the buffer of the message.
+        let mut data = MsgBuffer::new(PACK_BUFFER_SIZE);
+        if let Some(hdr) = hdr {
+            data.extend_from_slice(&hdr.pack());
+        }
+        let args_pos = data.len();
+        if let Err(e) = to_buffer(input, &mut data) {
+            return Self::failed(cmd, sys::ic_status_t_IC_MSG_INVALID, pack_error(cmd, e));
+        }
+        if let Some(max) = unsafe { InnerClient::from_raw(raw_ic) }
+            .payload_limits
+            .outgoing
+        {
+            let size = data.len() - args_pos;
+
+            if size > max {
+                log::warn!(cmd; "query payload of {} bytes exceeds the limit of {}", size, max);
+                return Self::failed(
+                    cmd,
+                    sys::ic_status_t_IC_MSG_INVALID,
+                    error::Error::PayloadTooLarge(size),
+                );
+            }
+        }
+        let args: Option<Rc<[u8]>> = middlewares.as_ref().map(|_| data[args_pos..].into());
+        if let Some(compressed) = compress_payload(raw_ic, &data[args_pos..]) {
+            data.truncate(args_pos);
+            data.extend_from_slice(&compressed);
+        }
+        let metrics =
+            register.map(|reg| QueryMetrics::new(reg.metrics.clone(), cmd, data.len() - args_pos));
 
+        let msg = unsafe { sys::ic_msg_new(std::mem::size_of::<*const c_void>() as i32) };
+        unsafe {
+            data.give_to(msg);
             (*msg).cb2 = Some(Self::msg_cb);
             (*msg).set_async(async_);
             (*msg).cmd = cmd;
         }
-        std::mem::forget(data);
+
+        // the query times out at the deadline of its header, or else at the
+        // one of the channel
+        let deadline = hdr.and_then(|hdr| hdr.deadline).or(ic.deadline);
+        if let Some(deadline) = deadline {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+
+            // a timeout of 0 means no timeout at all
+            unsafe {
+                (*msg).timeout = (timeout.as_millis() as u32).max(1);
+            }
+        }
+
+        let call_hdr = middlewares
+            .as_ref()
+            .map(|_| hdr.cloned().unwrap_or_default());
+        let hdr = hdr.map(RawHeader::new);
+        if let Some(hdr) = &hdr {
+            unsafe {
+                (*msg).hdr = hdr.as_ptr();
+            }
+        }
 
         // Create state that will be shared between the future, and the query callback.
         let state = QueryState {
             result: None,
             waker: None,
+            callback: None,
+            abandoned: false,
+            wiped: false,
+            msg: MsgPtr(msg),
+            status_tx: None,
+            _hdr: hdr,
+            metrics,
+            #[cfg(feature = "tracing")]
+            span: trace::QuerySpan::new(unsafe { InnerClient::from_raw(raw_ic) }.peer_addr, cmd),
         };
         let state = Arc::new(Mutex::new(state));
 
@@ -425,16 +3268,151 @@ where
             }
         }
 
-        unsafe {
-            sys::__ic_query(ic.to_raw(), msg);
+        {
+            let query: Arc<dyn CancelQuery> = state.clone();
+
+            unsafe { InnerClient::from_raw(raw_ic) }.track_query(cmd, Arc::downgrade(&query));
+        }
+        match (middlewares, args, call_hdr) {
+            (Some(middlewares), Some(args), Some(call_hdr)) => {
+                let call = Call {
+                    ic: Rc::new(ic.dup()),
+                    hdr: Rc::new(call_hdr),
+                    cmd,
+                    data: args,
+                };
+                Self::send_through(middlewares, raw_ic, call, msg, &state);
+            }
+            _ => send_query(raw_ic, msg, &state),
         }
 
         // and return a future with the shared state
-        Self { state }
+        Self {
+            state,
+            loopback: None,
+        }
+    }
+
+    /// Query failing without being sent.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables, unused_mut))]
+    fn failed(cmd: i32, status: sys::ic_status_t, error: error::Error<Exn>) -> Self {
+        let mut state = QueryState {
+            result: Some(Err(error)),
+            waker: None,
+            callback: None,
+            abandoned: false,
+            wiped: false,
+            msg: MsgPtr(std::ptr::null_mut()),
+            status_tx: None,
+            _hdr: None,
+            metrics: None,
+            #[cfg(feature = "tracing")]
+            span: trace::QuerySpan::new(None, cmd),
+        };
+        #[cfg(feature = "tracing")]
+        state.span.finish(status);
+
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            loopback: None,
+        }
+    }
+
+    /// Query of a loopback channel, handled when the future is polled.
+    fn loopback<I>(
+        register: Rc<RpcRegister>,
+        deadline: Option<Instant>,
+        hdr: Option<&QueryHeader>,
+        input: &I,
+        cmd: i32,
+    ) -> Self
+    where
+        I: Serialize,
+    {
+        let mut data = Vec::new();
+        if let Err(e) = to_buffer(input, &mut data) {
+            return Self::failed(cmd, sys::ic_status_t_IC_MSG_INVALID, pack_error(cmd, e));
+        }
+
+        let slot = LOOPBACK_SLOTS.with(|slots| {
+            slots.set(slots.get() + 1);
+            LOOPBACK_SLOT | slots.get()
+        });
+        let state = QueryState {
+            result: None,
+            waker: None,
+            callback: None,
+            abandoned: false,
+            wiped: false,
+            msg: MsgPtr(std::ptr::null_mut()),
+            status_tx: None,
+            _hdr: None,
+            metrics: None,
+            #[cfg(feature = "tracing")]
+            span: trace::QuerySpan::new(None, cmd),
+        };
+        let state = Arc::new(Mutex::new(state));
+
+        // the implementation may reply as soon as it is called, if the
+        // arguments cannot be unpacked
+        {
+            let state = state.clone();
+            let answer: LoopbackAnswer =
+                Box::new(move |status, answer| Self::resolve(&state, status, answer));
+
+            LOOPBACK_ANSWERS.with(|answers| answers.borrow_mut().insert(slot, answer));
+        }
+        let dispatch = loopback_dispatch(register, deadline, hdr, cmd, &data, slot);
+
+        Self {
+            state,
+            loopback: Some(LoopbackQuery { slot, dispatch }),
+        }
+    }
+
+    /// Send the query once it went through the middlewares.
+    fn send_through(
+        middlewares: Rc<[Middleware]>,
+        raw_ic: *mut sys::ichannel_t,
+        call: Call,
+        msg: *mut sys::ic_msg_t,
+        state: &Arc<MsgPayload<Res, Exn>>,
+    ) {
+        let (status_tx, status_rx) = oneshot::channel();
+        state.lock().unwrap().status_tx = Some(status_tx);
+
+        let sent = Rc::new(Cell::new(false));
+        let terminal: Next = {
+            let pending = PendingQuery {
+                raw_ic,
+                msg,
+                state: state.clone(),
+            };
+            let sent = sent.clone();
+
+            Box::new(move || {
+                sent.set(true);
+                pending.send();
+                status_rx
+                    .map(|status| status.unwrap_or(sys::ic_status_t_IC_MSG_CANCELED))
+                    .boxed_local()
+            })
+        };
+        let chain = middleware::run(middlewares, terminal, call);
+        let state = state.clone();
+
+        el_future::spawn(async move {
+            let status = chain.await;
+
+            // the query was canceled by a middleware
+            if !sent.get() {
+                state.fail(status);
+            }
+        });
     }
 
     extern "C" fn msg_cb(
-        _ic: *mut sys::ichannel_t,
+        raw_ic: *mut sys::ichannel_t,
         msg: *mut sys::ic_msg_t,
         status: sys::ic_status_t,
         res: *const c_uchar,
@@ -442,36 +3420,63 @@ where
         exn: *const c_uchar,
         elen: u32,
     ) {
-        let res = match status {
-            sys::ic_status_t_IC_MSG_OK => {
-                let bytes = unsafe { std::slice::from_raw_parts(res, rlen as usize) };
-                match from_bytes::<Res>(bytes) {
-                    Ok(v) => Ok(v),
-                    Err(e) => Err(error::Error::Generic(format!(
-                        "error when unpacking rpc response: {}",
-                        e
-                    ))),
-                }
-            }
-            sys::ic_status_t_IC_MSG_EXN => {
-                let iop_exn = unsafe { std::slice::from_raw_parts(exn, elen as usize) };
-                match from_bytes::<Exn>(iop_exn) {
-                    Ok(v) => Err(error::Error::Exn(v)),
-                    Err(e) => Err(error::Error::Generic(format!(
-                        "error when unpacking rpc exception: {}",
-                        e
-                    ))),
-                }
+        let answer: &[u8] = unsafe {
+            match status {
+                // void answers may have no payload at all
+                sys::ic_status_t_IC_MSG_OK if rlen == 0 => &[],
+                sys::ic_status_t_IC_MSG_OK => std::slice::from_raw_parts(res, rlen as usize),
+                sys::ic_status_t_IC_MSG_EXN if elen == 0 => &[],
+                sys::ic_status_t_IC_MSG_EXN => std::slice::from_raw_parts(exn, elen as usize),
+                _ => &[],
             }
-            _ => Err(error::Error::from(status)),
         };
+        if !raw_ic.is_null() {
+            call_wire_hook(
+                raw_ic,
+                Direction::Received,
+                unsafe { (*msg).cmd },
+                Some(status),
+                unsafe { (*msg).slot } as u64,
+                std::ptr::null(),
+                answer,
+            );
+        }
 
         let state = unsafe {
             let payload = (*msg).priv_.as_ptr() as *const *const MsgPayload<Res, Exn>;
             Arc::from_raw(std::ptr::read(payload))
         };
 
+        Self::resolve(&state, status, answer);
+    }
+
+    /// Resolve the query with its answer.
+    fn resolve(state: &MsgPayload<Res, Exn>, status: sys::ic_status_t, answer: &[u8]) {
         let mut state = state.lock().unwrap();
+
+        // the message is deleted once answered
+        state.msg = MsgPtr(std::ptr::null_mut());
+        if let Some(metrics) = state.metrics.take() {
+            metrics.finish(status, answer.len());
+        }
+        #[cfg(feature = "tracing")]
+        state.span.finish(status);
+        if let Some(status_tx) = state.status_tx.take() {
+            let _ = status_tx.send(status);
+        }
+
+        // nobody waits for the answer anymore
+        if state.abandoned {
+            return;
+        }
+
+        let res = unpack_answer(status, answer);
+
+        if let Some(callback) = state.callback.take() {
+            drop(state);
+            callback(res);
+            return;
+        }
         state.result = Some(res);
         if let Some(waker) = state.waker.take() {
             waker.wake();
@@ -479,6 +3484,464 @@ where
     }
 }
 
+/// Unpack the answer of a query given its status, as its result or its
+/// error.
+pub(crate) fn unpack_answer<Res, Exn>(
+    status: sys::ic_status_t,
+    answer: &[u8],
+) -> Result<Res, error::Error<Exn>>
+where
+    Res: DeserializeOwned,
+    Exn: DeserializeOwned,
+{
+    match compress::decompress(answer, None) {
+        Err(e) => Err(error::Error::Generic(format!(
+            "error when unpacking rpc answer: {}",
+            e
+        ))),
+        Ok(answer) => match status {
+            sys::ic_status_t_IC_MSG_OK => match from_bytes::<Res>(&answer) {
+                Ok(v) => Ok(v),
+                Err(e) => Err(error::Error::Generic(format!(
+                    "error when unpacking rpc response: {}",
+                    e
+                ))),
+            },
+            sys::ic_status_t_IC_MSG_EXN => match from_bytes::<Exn>(&answer) {
+                Ok(v) => Err(error::Error::Exn(v)),
+                Err(e) => Err(error::Error::Generic(format!(
+                    "error when unpacking rpc exception: {}",
+                    e
+                ))),
+            },
+            _ => Err(error::Error::from(status)),
+        },
+    }
+}
+
+fn send_query<Res, Exn>(
+    raw_ic: *mut sys::ichannel_t,
+    msg: *mut sys::ic_msg_t,
+    state: &Arc<MsgPayload<Res, Exn>>,
+) {
+    unsafe {
+        sys::__ic_query(raw_ic, msg);
+    }
+
+    // the message is already deleted if the query failed without reaching
+    // the peer, otherwise its slot is given once it is queued
+    if state.lock().unwrap().msg.0.is_null() {
+        return;
+    }
+    unsafe {
+        let data = std::slice::from_raw_parts((*msg).data as *const u8, (*msg).dlen as usize);
+
+        call_wire_hook(
+            raw_ic,
+            Direction::Sent,
+            (*msg).cmd,
+            None,
+            (*msg).slot as u64,
+            (*msg).hdr,
+            &data[12..],
+        );
+    }
+}
+
+/// Query not sent yet, waiting for its middlewares.
+struct PendingQuery<Res, Exn> {
+    raw_ic: *mut sys::ichannel_t,
+    msg: *mut sys::ic_msg_t,
+    state: Arc<MsgPayload<Res, Exn>>,
+}
+
+impl<Res, Exn> PendingQuery<Res, Exn> {
+    fn send(mut self) {
+        // the channel is gone, the message is deleted when dropped
+        if self.state.lock().unwrap().wiped {
+            return;
+        }
+        let msg = mem::replace(&mut self.msg, std::ptr::null_mut());
+
+        send_query(self.raw_ic, msg, &self.state);
+    }
+}
+
+impl<Res, Exn> Drop for PendingQuery<Res, Exn> {
+    fn drop(&mut self) {
+        if self.msg.is_null() {
+            return;
+        }
+
+        // the query is not sent, its message is deleted here
+        self.state.lock().unwrap().msg = MsgPtr(std::ptr::null_mut());
+        unsafe {
+            let payload = (*self.msg).priv_.as_ptr() as *const *const MsgPayload<Res, Exn>;
+            drop(Arc::from_raw(std::ptr::read(payload)));
+
+            // the buffer was allocated with `malloc` by `MsgBuffer`, so it
+            // is freed the way lib-common would have
+            libc::free((*self.msg).data);
+            (*self.msg).data = std::ptr::null_mut();
+            (*self.msg).dlen = 0;
+
+            sys::ic_msg_delete(&mut self.msg);
+        }
+    }
+}
+
+// }}}
+// {{{ Async queries
+
+/// Send an async query, which is not answered.
+///
+/// The query is posted without a slot nor a callback, so nothing tracks it
+/// once it is queued on the channel. It only fails if the channel is gone or
+/// full, or if its arguments cannot be packed or exceed the payload limit of
+/// the channel.
+pub fn send_async<I: Serialize, E>(
+    ic: &mut Channel,
+    input: &I,
+    cmd: i32,
+) -> Result<(), error::Error<E>> {
+    if let Some(register) = ic.loopback.clone() {
+        return loopback_send(register, input, cmd);
+    }
+
+    let raw_ic = match ic.live_raw() {
+        Some(raw_ic) => raw_ic,
+        None => return Err(error::Error::Canceled),
+    };
+    if ic.is_full() {
+        log::warn!(cmd; "send queue above its hard watermarks, query dropped");
+        return Err(error::Error::Retry);
+    }
+
+    let mut data = MsgBuffer::new(PACK_BUFFER_SIZE);
+    if let Err(e) = to_buffer(input, &mut data) {
+        return Err(pack_error(cmd, e));
+    }
+    if let Some(max) = unsafe { InnerClient::from_raw(raw_ic) }
+        .payload_limits
+        .outgoing
+    {
+        let size = data.len() - 12;
+
+        if size > max {
+            log::warn!(cmd; "query payload of {} bytes exceeds the limit of {}", size, max);
+            return Err(error::Error::PayloadTooLarge(size));
+        }
+    }
+    if let Some(compressed) = compress_payload(raw_ic, &data[12..]) {
+        data.truncate(12);
+        data.extend_from_slice(&compressed);
+    }
+
+    let msg = unsafe { sys::ic_msg_new(0) };
+
+    unsafe {
+        data.give_to(msg);
+        (*msg).set_async(true);
+        (*msg).cmd = cmd;
+    }
+
+    // async queries have no slot
+    let args =
+        unsafe { std::slice::from_raw_parts((*msg).data as *const u8, (*msg).dlen as usize) };
+    call_wire_hook(
+        raw_ic,
+        Direction::Sent,
+        cmd,
+        None,
+        0,
+        std::ptr::null(),
+        &args[12..],
+    );
+
+    unsafe {
+        sys::__ic_query(raw_ic, msg);
+    }
+    Ok(())
+}
+
+// }}}
+// {{{ Loopback
+
+// Mark of the slots of the queries of loopback channels. lib-common marks
+// the slots of the HTTP and gateway queries with one of the two upper bits,
+// but never with both.
+const LOOPBACK_SLOT: u64 = 3 << 62;
+
+type LoopbackAnswer = Box<dyn FnOnce(sys::ic_status_t, &[u8])>;
+
+thread_local! {
+    static LOOPBACK_SLOTS: Cell<u64> = const { Cell::new(0) };
+
+    // queries of loopback channels waiting for their reply, by slot
+    static LOOPBACK_ANSWERS: RefCell<BTreeMap<u64, LoopbackAnswer>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Handling of a query of a loopback channel.
+struct LoopbackQuery {
+    slot: u64,
+    dispatch: Option<LocalBoxFuture<'static, sys::ic_status_t>>,
+}
+
+impl LoopbackQuery {
+    fn poll_dispatch(&mut self, cx: &mut Context) {
+        if let Some(dispatch) = &mut self.dispatch {
+            if dispatch.as_mut().poll(cx).is_ready() {
+                self.dispatch = None;
+            }
+        }
+    }
+}
+
+impl Drop for LoopbackQuery {
+    fn drop(&mut self) {
+        LOOPBACK_ANSWERS.with(|answers| answers.borrow_mut().remove(&self.slot));
+    }
+}
+
+/// Call the implementation of a query of a loopback channel, or answer it
+/// as unimplemented.
+fn loopback_dispatch(
+    register: Rc<RpcRegister>,
+    deadline: Option<Instant>,
+    hdr: Option<&QueryHeader>,
+    cmd: i32,
+    data: &[u8],
+    slot: u64,
+) -> Option<LocalBoxFuture<'static, sys::ic_status_t>> {
+    let mut register = register;
+    if let Some(hdr) = hdr {
+        while let Some(routed) = register.routed(hdr).map(|routed| routed.register.clone()) {
+            register = routed;
+        }
+    }
+
+    // the implementation is cloned, so that it can replace itself
+    let cb = register.impls.borrow().get(&cmd).cloned();
+    let cb = match cb.or_else(|| register.fallback.clone()) {
+        Some(cb) => cb,
+        None => {
+            send_reply(&[], cmd, slot, sys::ic_status_t_IC_MSG_UNIMPLEMENTED);
+            return None;
+        }
+    };
+    let hdr = hdr.cloned().unwrap_or_default();
+    let mut ic = Channel::loopback(&RpcDispatcher { register });
+
+    ic.set_deadline(hdr.deadline.or(deadline));
+    Some((cb)(ic, hdr, cmd, data, slot))
+}
+
+/// Give its reply to a query of a loopback channel, unless it was dropped.
+fn loopback_reply(slot: u64, status: sys::ic_status_t, res: &[u8]) {
+    let answer = LOOPBACK_ANSWERS.with(|answers| answers.borrow_mut().remove(&slot));
+
+    if let Some(answer) = answer {
+        (answer)(status, res);
+    }
+}
+
+/// Slot of a query handled as a query of a loopback channel, with the
+/// receiver of its reply.
+fn loopback_slot() -> (u64, oneshot::Receiver<(sys::ic_status_t, Vec<u8>)>) {
+    let slot = LOOPBACK_SLOTS.with(|slots| {
+        slots.set(slots.get() + 1);
+        LOOPBACK_SLOT | slots.get()
+    });
+    let (tx, rx) = oneshot::channel();
+    let answer: LoopbackAnswer = Box::new(move |status, answer| {
+        let _ = tx.send((status, answer.to_vec()));
+    });
+
+    LOOPBACK_ANSWERS.with(|answers| answers.borrow_mut().insert(slot, answer));
+    (slot, rx)
+}
+
+/// Run the handling of the query of `slot`, resolving with its reply.
+fn loopback_answer(
+    slot: u64,
+    mut rx: oneshot::Receiver<(sys::ic_status_t, Vec<u8>)>,
+    dispatch: Option<LocalBoxFuture<'static, sys::ic_status_t>>,
+) -> impl Future<Output = (sys::ic_status_t, Vec<u8>)> {
+    let mut query = LoopbackQuery { slot, dispatch };
+
+    future::poll_fn(move |cx| {
+        query.poll_dispatch(cx);
+        rx.poll_unpin(cx)
+            .map(|res| res.unwrap_or((sys::ic_status_t_IC_MSG_CANCELED, Vec::new())))
+    })
+}
+
+/// Handle packed arguments with the implementations of `register`, as a
+/// query of a loopback channel, resolving with the status and the answer.
+pub(crate) fn loopback_query(
+    register: Rc<RpcRegister>,
+    hdr: Option<&QueryHeader>,
+    cmd: i32,
+    data: &[u8],
+) -> impl Future<Output = (sys::ic_status_t, Vec<u8>)> {
+    let (slot, rx) = loopback_slot();
+    let dispatch = loopback_dispatch(register, None, hdr, cmd, data, slot);
+
+    loopback_answer(slot, rx, dispatch)
+}
+
+/// Send an async query on a loopback channel, handled by the event loop.
+fn loopback_send<I: Serialize, E>(
+    register: Rc<RpcRegister>,
+    input: &I,
+    cmd: i32,
+) -> Result<(), error::Error<E>> {
+    let mut data = Vec::new();
+    if let Err(e) = to_buffer(input, &mut data) {
+        return Err(pack_error(cmd, e));
+    }
+
+    // async queries have no slot, so their reply is dropped
+    if let Some(dispatch) = loopback_dispatch(register, None, None, cmd, &data, LOOPBACK_SLOT) {
+        el_future::spawn(dispatch.map(|_| ()));
+    }
+    Ok(())
+}
+
+// }}}
+// {{{ Writable
+
+/// Channels waiting for their send queue to go below its soft watermarks.
+///
+/// lib-common does not notify when the queue is flushed, which it does once
+/// the socket is writable, so they are checked before every iteration of
+/// the event loop following one.
+struct WritableWaiters {
+    _hook: el::Before,
+    waiters: Vec<(Channel, Waker)>,
+}
+
+thread_local! {
+    static WRITABLE_WAITERS: RefCell<Option<WritableWaiters>> = const { RefCell::new(None) };
+}
+
+/// Wake `waker` once the send queue of `ic` goes below its soft watermarks.
+fn wait_writable(ic: Channel, waker: &Waker) {
+    WRITABLE_WAITERS.with(|waiters| {
+        let mut waiters = waiters.borrow_mut();
+        let waiters = waiters.get_or_insert_with(|| {
+            let mut hook = el::Before::new(wake_writable);
+
+            // the waiters keep the event loop running with their channels
+            hook.unref();
+            WritableWaiters {
+                _hook: hook,
+                waiters: Vec::new(),
+            }
+        });
+
+        waiters.waiters.push((ic, waker.clone()));
+    });
+}
+
+fn wake_writable() {
+    let writable = WRITABLE_WAITERS.with(|waiters| match &mut *waiters.borrow_mut() {
+        Some(waiters) => {
+            let (writable, waiting) = mem::take(&mut waiters.waiters)
+                .into_iter()
+                .partition(|(ic, _)| ic.is_writable());
+
+            waiters.waiters = waiting;
+            writable
+        }
+        None => Vec::new(),
+    });
+
+    // woken once the waiters are released, as they may wait again
+    for (_, waker) in writable {
+        waker.wake();
+    }
+}
+
+// }}}
+// {{{ State Future
+
+/// Future resolving once a channel is connected or disconnected, built with
+/// `connected` and `disconnected`.
+///
+/// It must not outlive the channel.
+pub struct StateFuture {
+    raw: *const sys::ichannel_t,
+    alive: Option<Rc<Cell<bool>>>,
+    connected: bool,
+}
+
+impl StateFuture {
+    fn new(raw: *const sys::ichannel_t, alive: Option<Rc<Cell<bool>>>, connected: bool) -> Self {
+        Self {
+            raw,
+            alive,
+            connected,
+        }
+    }
+}
+
+impl Future for StateFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let alive = match &self.alive {
+            Some(alive) => alive.get(),
+            // loopback channels are always connected
+            None => {
+                return if self.connected {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                };
+            }
+        };
+        // dropped channels are closed for good
+        if !alive {
+            return if self.connected {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            };
+        }
+
+        let ic = unsafe { InnerClient::from_raw(self.raw as *mut _) };
+        if ic.state().is_connected() == self.connected {
+            return Poll::Ready(());
+        }
+        if ic.closed && self.connected {
+            return Poll::Pending;
+        }
+        if !ic.state_wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            ic.state_wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+// }}}
+// {{{ Connection Events
+
+/// Stream of the changes of the state of the connection of a channel, built
+/// with `events`.
+pub struct ConnectionEvents {
+    receiver: mpsc::UnboundedReceiver<ConnectionEvent>,
+}
+
+impl Stream for ConnectionEvents {
+    type Item = ConnectionEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
 // }}}
 // {{{ Connect Future
 
@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+// {{{ Config
+
+/// Periodic pings of the peer of a channel, disconnecting it when it stops
+/// answering, to detect half-open connections.
+///
+/// The keepalive messages of lib-common only mark such channels inactive,
+/// and do not go through the RPC registers, so they cannot tell a stuck
+/// peer from a busy one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Delay between two pings, which is also their timeout.
+    pub interval: Duration,
+    /// Number of consecutive pings timing out after which the channel is
+    /// disconnected.
+    pub max_misses: u32,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            max_misses: 3,
+        }
+    }
+}
+
+/// Command of the pings, answered by every register with their payload.
+pub(crate) const PING_CMD: i32 = 0x7fff_ff01;
+
+// }}}
@@ -1,10 +1,38 @@
+pub mod acl;
+pub mod addr;
+pub mod auth;
+pub mod batch;
+pub mod blocking;
+pub mod capture;
+pub mod compress;
+pub mod concurrency;
+pub mod connect;
 pub mod error;
+pub mod extensions;
+pub mod handle;
+pub mod handshake;
+pub mod hdr;
+pub mod http;
 pub mod ic;
 pub mod ic_sync;
-pub mod msg_sync;
+pub mod keepalive;
+pub mod metrics;
+pub mod middleware;
+mod msgbuf;
+pub mod pool;
+pub mod ratelimit;
+pub mod record;
+pub mod reflect;
+pub mod retry;
+pub mod socket;
+pub mod testing;
+pub mod tls;
+#[cfg(feature = "tracing")]
+mod trace;
 pub mod types;
 pub mod types_sync;
 
+pub use libcommon_ic_derive::service;
 use libcommon_module::Module;
 use libcommon_sys as sys;
 
@@ -0,0 +1,164 @@
+use libcommon_sys as sys;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// {{{ Latency histogram
+
+/// Upper bounds of the buckets of the latency histograms, the last bucket
+/// holding the slower queries.
+pub const LATENCY_BUCKETS: [Duration; 8] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LatencyHistogram {
+    counts: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: Duration,
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, latency: Duration) {
+        let pos = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| latency <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        self.counts[pos] += 1;
+        self.sum += latency;
+    }
+
+    /// Number of queries of each bucket, with its upper bound, `None` for
+    /// the last one.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        LATENCY_BUCKETS
+            .iter()
+            .map(|bound| Some(*bound))
+            .chain(std::iter::once(None))
+            .zip(self.counts.iter().copied())
+    }
+
+    /// Number of queries recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Sum of the latencies of the queries recorded.
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+}
+
+// }}}
+// {{{ Metrics
+
+/// Metrics of a command.
+///
+/// Errors are the queries not answered with `IC_MSG_OK`, exceptions
+/// included. Bytes are the sizes of the packed arguments and answers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CmdMetrics {
+    pub calls: u64,
+    pub errors: u64,
+    pub timeouts: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub latency: LatencyHistogram,
+}
+
+impl CmdMetrics {
+    fn record(&mut self, status: sys::ic_status_t, latency: Duration) {
+        self.calls += 1;
+        if status != sys::ic_status_t_IC_MSG_OK {
+            self.errors += 1;
+        }
+        if status == sys::ic_status_t_IC_MSG_TIMEDOUT {
+            self.timeouts += 1;
+        }
+        self.latency.record(latency);
+    }
+}
+
+/// Metrics of a register, keyed by command.
+#[derive(Clone, Debug, Default)]
+pub struct MetricsSnapshot {
+    /// Queries received and handled by the RPC implementations.
+    pub handled: HashMap<i32, CmdMetrics>,
+    /// Queries sent on the channels using the register.
+    pub sent: HashMap<i32, CmdMetrics>,
+}
+
+/// Metrics collected by a register, shared with its pending queries.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    handled: Mutex<HashMap<i32, CmdMetrics>>,
+    sent: Mutex<HashMap<i32, CmdMetrics>>,
+}
+
+impl Metrics {
+    /// Record a handled query, once its implementation answered.
+    pub(crate) fn record_handled(
+        &self,
+        cmd: i32,
+        status: sys::ic_status_t,
+        latency: Duration,
+        bytes_in: usize,
+    ) {
+        let mut handled = self.handled.lock().unwrap();
+        let metrics = handled.entry(cmd).or_default();
+
+        metrics.record(status, latency);
+        metrics.bytes_in += bytes_in as u64;
+    }
+
+    /// Record the answer of a handled query.
+    pub(crate) fn record_reply(&self, cmd: i32, bytes_out: usize) {
+        let mut handled = self.handled.lock().unwrap();
+
+        handled.entry(cmd).or_default().bytes_out += bytes_out as u64;
+    }
+
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            handled: self.handled.lock().unwrap().clone(),
+            sent: self.sent.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Sent query, recorded once answered.
+pub(crate) struct QueryMetrics {
+    metrics: Arc<Metrics>,
+    cmd: i32,
+    start: Instant,
+    bytes_out: usize,
+}
+
+impl QueryMetrics {
+    pub(crate) fn new(metrics: Arc<Metrics>, cmd: i32, bytes_out: usize) -> Self {
+        Self {
+            metrics,
+            cmd,
+            start: Instant::now(),
+            bytes_out,
+        }
+    }
+
+    pub(crate) fn finish(self, status: sys::ic_status_t, bytes_in: usize) {
+        let mut sent = self.metrics.sent.lock().unwrap();
+        let metrics = sent.entry(self.cmd).or_default();
+
+        metrics.record(status, self.start.elapsed());
+        metrics.bytes_in += bytes_in as u64;
+        metrics.bytes_out += self.bytes_out as u64;
+    }
+}
+
+// }}}
@@ -0,0 +1,68 @@
+use crate::hdr::QueryHeader;
+use crate::ic::Channel;
+use futures::future::LocalBoxFuture;
+use libcommon_sys as sys;
+use std::rc::Rc;
+
+// {{{ Middlewares
+
+/// Rest of the chain of middlewares, ending with the RPC implementation, or
+/// with the sending of the query.
+///
+/// It resolves with the status of the answer.
+pub type Next = Box<dyn FnOnce() -> LocalBoxFuture<'static, sys::ic_status_t>>;
+
+/// Middleware wrapping the RPC implementations, or the queries.
+///
+/// It is given the rest of the chain, the channel, the header, the command
+/// and the packed arguments of the query, and resolves with the status of the
+/// answer. A middleware not calling `next` rejects the query with the status
+/// it returns.
+pub(crate) type Middleware = Rc<
+    dyn Fn(Next, &Channel, &QueryHeader, i32, &[u8]) -> LocalBoxFuture<'static, sys::ic_status_t>,
+>;
+
+/// Query going through the middlewares, owned so that the middlewares can
+/// call `next` after awaiting.
+#[derive(Clone)]
+pub(crate) struct Call {
+    // channel of the query, with its deadline
+    pub(crate) ic: Rc<Channel>,
+    pub(crate) hdr: Rc<QueryHeader>,
+    pub(crate) cmd: i32,
+    pub(crate) data: Rc<[u8]>,
+}
+
+/// Run `terminal` through the middlewares, the last one wrapped being the
+/// first one called.
+pub(crate) fn run(
+    middlewares: Rc<[Middleware]>,
+    terminal: Next,
+    call: Call,
+) -> LocalBoxFuture<'static, sys::ic_status_t> {
+    let len = middlewares.len();
+
+    run_from(middlewares, len, terminal, call)
+}
+
+fn run_from(
+    middlewares: Rc<[Middleware]>,
+    pos: usize,
+    terminal: Next,
+    call: Call,
+) -> LocalBoxFuture<'static, sys::ic_status_t> {
+    if pos == 0 {
+        return terminal();
+    }
+
+    let middleware = middlewares[pos - 1].clone();
+    let next: Next = {
+        let call = call.clone();
+
+        Box::new(move || run_from(middlewares, pos - 1, terminal, call))
+    };
+
+    (middleware)(next, &call.ic, &call.hdr, call.cmd, &call.data)
+}
+
+// }}}
@@ -0,0 +1,154 @@
+use libcommon_sys as sys;
+use serde_iop::Output;
+use std::alloc::{handle_alloc_error, Layout};
+use std::mem;
+use std::ops::Deref;
+use std::os::raw::c_void;
+use std::ptr::NonNull;
+
+// {{{ Buffer
+
+/// Size of the ic header, at the start of the data of every message.
+const HEADER_SIZE: usize = 12;
+
+/// Buffer of a message, starting with the 12 bytes reserved for the ic
+/// header, after which the payload is packed.
+///
+/// lib-common frees the data of its messages with `free`, so the buffer is
+/// allocated with `malloc`, whatever the global allocator of the program.
+///
+/// Neither the buffers nor the messages are pooled: lib-common frees both
+/// once the message is answered or dropped, so they are never given back to
+/// Rust to be reused.
+pub(crate) struct MsgBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    cap: usize,
+}
+
+impl MsgBuffer {
+    /// Allocate a buffer with room for a payload of `capacity` bytes.
+    pub(crate) fn new(capacity: usize) -> Self {
+        let mut buf = Self {
+            ptr: NonNull::dangling(),
+            len: 0,
+            cap: 0,
+        };
+
+        buf.grow(HEADER_SIZE + capacity);
+        buf.resize(HEADER_SIZE, 0);
+        buf
+    }
+
+    /// Allocate a buffer holding `payload`.
+    pub(crate) fn from_payload(payload: &[u8]) -> Self {
+        let mut buf = Self::new(payload.len());
+
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    /// Payload packed after the header.
+    pub(crate) fn payload(&self) -> &[u8] {
+        &self[HEADER_SIZE..]
+    }
+
+    /// Shorten the buffer to `len` bytes, keeping its capacity.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    /// Give the buffer to a message, which frees it.
+    ///
+    /// # Safety
+    ///
+    /// `msg` must be a valid message without data.
+    pub(crate) unsafe fn give_to(self, msg: *mut sys::ic_msg_t) {
+        let buf = mem::ManuallyDrop::new(self);
+
+        (*msg).dlen = buf.len as u32;
+        (*msg).data = buf.ptr.as_ptr() as *mut c_void;
+    }
+
+    /// Reallocate the buffer to hold at least `cap` bytes.
+    fn grow(&mut self, cap: usize) {
+        if cap <= self.cap {
+            return;
+        }
+        let cap = cap.max(2 * self.cap);
+        let ptr = unsafe {
+            if self.cap == 0 {
+                libc::malloc(cap)
+            } else {
+                libc::realloc(self.ptr.as_ptr() as *mut c_void, cap)
+            }
+        };
+
+        match NonNull::new(ptr as *mut u8) {
+            Some(ptr) => self.ptr = ptr,
+            None => handle_alloc_error(Layout::array::<u8>(cap).unwrap()),
+        }
+        self.cap = cap;
+    }
+}
+
+impl Deref for MsgBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for MsgBuffer {
+    fn drop(&mut self) {
+        if self.cap > 0 {
+            unsafe { libc::free(self.ptr.as_ptr() as *mut c_void) }
+        }
+    }
+}
+
+// }}}
+// {{{ Output
+
+impl Output for MsgBuffer {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn push(&mut self, value: u8) {
+        self.extend_from_slice(&[value])
+    }
+
+    fn extend_from_slice(&mut self, values: &[u8]) {
+        self.grow(self.len + values.len());
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                values.as_ptr(),
+                self.ptr.as_ptr().add(self.len),
+                values.len(),
+            );
+        }
+        self.len += values.len();
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.grow(self.len + additional)
+    }
+
+    fn resize(&mut self, len: usize, value: u8) {
+        if len > self.len {
+            self.grow(len);
+            unsafe {
+                std::ptr::write_bytes(self.ptr.as_ptr().add(self.len), value, len - self.len);
+            }
+        }
+        self.len = len;
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+// }}}
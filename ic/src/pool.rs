@@ -0,0 +1,155 @@
+use crate::addr::AddrError;
+use crate::error;
+use crate::ic::{Channel, Client, QueryFuture, RpcDispatcher};
+use crate::tls::TlsConfig;
+use crate::types::Rpc;
+use futures::future;
+use futures::stream::{self, StreamExt};
+use std::cell::{Cell, RefCell};
+
+// {{{ Balancing
+
+/// Selection of the backend of a query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Balancing {
+    /// Each healthy backend in turn.
+    RoundRobin,
+    /// The healthy backend with the fewest queries waiting for an answer.
+    LeastPending,
+}
+
+// }}}
+// {{{ Pool
+
+struct Backend {
+    addr: String,
+    client: Client,
+}
+
+/// Channels to several servers exposing the same RPCs, the queries being
+/// spread over the healthy ones.
+///
+/// The channels reconnect by themselves when their server disconnects, and
+/// are skipped until then, or while their server does not answer the
+/// keepalive messages of lib-common.
+pub struct ClientPool {
+    register: Option<RpcDispatcher>,
+    balancing: Balancing,
+    backends: RefCell<Vec<Backend>>,
+    next: Cell<usize>,
+}
+
+impl ClientPool {
+    pub fn new(register: Option<&RpcDispatcher>, balancing: Balancing) -> Self {
+        Self {
+            register: register.cloned(),
+            balancing,
+            backends: RefCell::new(Vec::new()),
+            next: Cell::new(0),
+        }
+    }
+
+    /// Add a server to the pool, encrypting its channel if `tls` is given.
+    pub fn add(&mut self, addr: &str, tls: Option<&TlsConfig>) -> Result<(), AddrError> {
+        let mut client = Client::new(self.register.as_ref());
+
+        // the connection is followed through the events of the channel
+        client.connect_once(addr, tls)?;
+        self.backends.get_mut().push(Backend {
+            addr: addr.to_owned(),
+            client,
+        });
+        Ok(())
+    }
+
+    /// Addresses of the servers whose channel is healthy.
+    pub fn healthy(&self) -> Vec<String> {
+        self.backends
+            .borrow()
+            .iter()
+            .filter(|backend| backend.client.is_healthy())
+            .map(|backend| backend.addr.clone())
+            .collect()
+    }
+
+    /// Wait for a channel of the pool to be healthy.
+    ///
+    /// The channels are checked again on each of their connection events,
+    /// and it never resolves once they are all closed.
+    pub async fn ready(&self) {
+        // followed before checking the channels, not to miss an event
+        let events = self
+            .backends
+            .borrow_mut()
+            .iter_mut()
+            .map(|backend| backend.client.get_channel().events())
+            .collect::<Vec<_>>();
+        let mut events = stream::select_all(events);
+
+        while self.healthy().is_empty() {
+            if events.next().await.is_none() {
+                future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Pick the channel of the next query, skipping the backends in
+    /// `excluded`.
+    fn pick(&self, excluded: &[usize]) -> Option<(usize, Channel)> {
+        let mut backends = self.backends.borrow_mut();
+        let len = backends.len();
+        let mut candidates = (0..len)
+            .map(|i| (self.next.get() + i) % len)
+            .filter(|pos| !excluded.contains(pos) && backends[*pos].client.is_healthy())
+            .collect::<Vec<_>>()
+            .into_iter();
+
+        let pos = match self.balancing {
+            Balancing::RoundRobin => candidates.next(),
+            Balancing::LeastPending => candidates.min_by_key(|pos| {
+                backends[*pos]
+                    .client
+                    .get_channel()
+                    .queue_state()
+                    .pending_queries
+            }),
+        }?;
+
+        self.next.set(pos + 1);
+        Some((pos, backends[pos].client.get_channel()))
+    }
+
+    /// Send a query to a healthy backend.
+    ///
+    /// Queries failing with `Error::Retry`, as when their backend
+    /// disconnects, are sent again to the other backends. They fail with
+    /// `Error::Retry` if no backend is healthy.
+    pub async fn call<R: Rpc>(
+        &self,
+        iface_tag: u16,
+        arg: R::Input,
+    ) -> Result<R::Output, error::Error<R::Exception>> {
+        let mut excluded = Vec::new();
+
+        loop {
+            let (pos, mut channel) = match self.pick(&excluded) {
+                Some(picked) => picked,
+                None => return Err(error::Error::Retry),
+            };
+
+            let cmd = R::get_cmd(iface_tag);
+            match QueryFuture::new(&mut channel, &arg, cmd, R::ASYNC).await {
+                Err(error::Error::Retry) => {
+                    let backends = self.backends.borrow();
+
+                    log::info!(cmd; "query to {} failed, trying another server",
+                               backends[pos].addr);
+                    excluded.push(pos);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+// }}}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+// {{{ Config
+
+/// Token bucket limiting the rate of queries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Queries accepted per second, once the burst is consumed.
+    pub rate: u32,
+    /// Queries accepted at once, after an idle period.
+    pub burst: u32,
+}
+
+/// Rate limits of the queries received on each channel of a server,
+/// answered with `IC_MSG_RETRY` when exceeded.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    /// Limit of all the queries of a channel.
+    pub channel: Option<RateLimit>,
+    /// Limits of the queries of a channel to given commands.
+    pub cmds: HashMap<i32, RateLimit>,
+}
+
+// }}}
+// {{{ Limiter
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.limit.rate as f64).min(self.limit.burst as f64);
+        self.last = now;
+    }
+}
+
+/// Buckets of the queries received on a channel.
+pub(crate) struct RateLimiter {
+    limits: RateLimits,
+    channel: Option<TokenBucket>,
+    cmds: HashMap<i32, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limits: RateLimits) -> Self {
+        let now = Instant::now();
+
+        Self {
+            channel: limits.channel.map(|limit| TokenBucket::new(limit, now)),
+            cmds: HashMap::new(),
+            limits,
+        }
+    }
+
+    /// Take a token for a query to `cmd`, returning false if the channel or
+    /// the command exceeds its limit.
+    pub(crate) fn try_acquire(&mut self, cmd: i32) -> bool {
+        let now = Instant::now();
+        let limits = &self.limits;
+        let cmd_bucket = match limits.cmds.get(&cmd) {
+            Some(limit) => Some(
+                self.cmds
+                    .entry(cmd)
+                    .or_insert_with(|| TokenBucket::new(*limit, now)),
+            ),
+            None => None,
+        };
+        let mut buckets = self
+            .channel
+            .iter_mut()
+            .chain(cmd_bucket)
+            .collect::<Vec<_>>();
+
+        for bucket in buckets.iter_mut() {
+            bucket.refill(now);
+            if bucket.tokens < 1.0 {
+                return false;
+            }
+        }
+        for bucket in buckets {
+            bucket.tokens -= 1.0;
+        }
+        true
+    }
+}
+
+// }}}
@@ -0,0 +1,280 @@
+use crate::compress;
+use crate::ic::{loopback_query, Direction, RpcDispatcher};
+use libcommon_sys as sys;
+use std::cell::{Cell, RefCell};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// {{{ Record
+
+/// Message sent or received on a channel, as saved by a `Recorder`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Time of the message, since the Unix epoch.
+    pub timestamp: Duration,
+    pub direction: Direction,
+    /// Command of the query, or of the query answered.
+    pub cmd: i32,
+    /// Slot of the query, 0 for async queries.
+    pub slot: u64,
+    /// Status of the answer, `None` for queries.
+    pub status: Option<sys::ic_status_t>,
+    /// Packed arguments or answer, as sent on the wire, so possibly
+    /// compressed.
+    pub payload: Vec<u8>,
+}
+
+impl Record {
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let direction: u8 = match self.direction {
+            Direction::Sent => 0,
+            Direction::Received => 1,
+        };
+        let status = self.status.map_or(-1, |status| status as i32);
+
+        out.write_all(&(self.timestamp.as_micros() as u64).to_le_bytes())?;
+        out.write_all(&[direction])?;
+        out.write_all(&self.cmd.to_le_bytes())?;
+        out.write_all(&self.slot.to_le_bytes())?;
+        out.write_all(&status.to_le_bytes())?;
+        out.write_all(&(self.payload.len() as u32).to_le_bytes())?;
+        out.write_all(&self.payload)
+    }
+
+    /// Read the next record, `None` at the end of `input`.
+    fn read_from<R: Read>(input: &mut R) -> io::Result<Option<Self>> {
+        let mut buf = [0; 8 + 1 + 4 + 8 + 4 + 4];
+        match input.read_exact(&mut buf[..1]) {
+            Ok(()) => input.read_exact(&mut buf[1..])?,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let timestamp = Duration::from_micros(le_u64(&buf[0..]));
+        let cmd = le_i32(&buf[9..]);
+        let slot = le_u64(&buf[13..]);
+        let status = le_i32(&buf[21..]);
+        let mut payload = vec![0; le_i32(&buf[25..]) as u32 as usize];
+        input.read_exact(&mut payload)?;
+
+        let direction = match buf[8] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid direction",
+                ))
+            }
+        };
+        Ok(Some(Self {
+            timestamp,
+            direction,
+            cmd,
+            slot,
+            status: if status < 0 {
+                None
+            } else {
+                Some(status as sys::ic_status_t)
+            },
+            payload,
+        }))
+    }
+}
+
+fn le_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn le_i32(buf: &[u8]) -> i32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[..4]);
+    i32::from_le_bytes(bytes)
+}
+
+/// Read the records saved by a `Recorder`.
+pub fn read_records<R: Read>(input: R) -> io::Result<Vec<Record>> {
+    let mut input = BufReader::new(input);
+    let mut records = Vec::new();
+
+    while let Some(record) = Record::read_from(&mut input)? {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+// }}}
+// {{{ Recorder
+
+struct RecorderOutput {
+    out: RefCell<Box<dyn Write>>,
+    failed: Cell<bool>,
+}
+
+/// Saves the messages of channels, to reproduce their queries with a
+/// `Replay`.
+///
+/// It is given to channels with `Client::set_recorder` and
+/// `Server::set_recorder`, the recorders of a server being shared by its
+/// channels. The messages are written in a compact binary format, read back
+/// by `read_records`. The headers of the queries are not saved.
+#[derive(Clone)]
+pub struct Recorder {
+    output: Rc<RecorderOutput>,
+}
+
+impl Recorder {
+    /// Save the messages in a new file at `path`, truncating it if it
+    /// exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn new<W: Write + 'static>(out: W) -> Self {
+        Self {
+            output: Rc::new(RecorderOutput {
+                out: RefCell::new(Box::new(out)),
+                failed: Cell::new(false),
+            }),
+        }
+    }
+
+    /// Write the messages buffered so far.
+    pub fn flush(&self) -> io::Result<()> {
+        self.output.out.borrow_mut().flush()
+    }
+
+    pub(crate) fn record(
+        &self,
+        direction: Direction,
+        cmd: i32,
+        slot: u64,
+        status: Option<sys::ic_status_t>,
+        payload: &[u8],
+    ) {
+        if self.output.failed.get() {
+            return;
+        }
+
+        let record = Record {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            direction,
+            cmd,
+            slot,
+            status,
+            payload: payload.to_vec(),
+        };
+        if let Err(e) = record.write_to(&mut *self.output.out.borrow_mut()) {
+            // the next records would be unreadable anyway
+            log::error!(cmd, slot; "cannot record message, recording stopped: {}", e);
+            self.output.failed.set(true);
+        }
+    }
+}
+
+// }}}
+// {{{ Replay
+
+/// Query of a recording handled again, with its new and recorded answers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Replayed {
+    pub cmd: i32,
+    pub slot: u64,
+    pub status: sys::ic_status_t,
+    pub answer: Vec<u8>,
+    /// Status and decompressed answer sent when the query was recorded, if
+    /// any.
+    pub recorded: Option<(sys::ic_status_t, Vec<u8>)>,
+}
+
+/// Queries received in a recording, given again to the implementations of
+/// an `RpcRegister`.
+///
+/// ```ignore
+/// let replay = Replay::load("incident.rec")?;
+/// for replayed in replay.run(&reg).await {
+///     assert_eq!(Some((replayed.status, replayed.answer)), replayed.recorded);
+/// }
+/// ```
+pub struct Replay {
+    records: Vec<Record>,
+}
+
+impl Replay {
+    pub fn new(records: Vec<Record>) -> Self {
+        Self { records }
+    }
+
+    /// Load the recording saved by a `Recorder` at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(read_records(File::open(path)?)?))
+    }
+
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// Handle the received queries with the implementations of `register`,
+    /// one after the other in the order of the recording, without waiting
+    /// between them.
+    ///
+    /// The queries are handled as on a loopback channel: the middlewares of
+    /// the register are not applied, and the queries sent by the
+    /// implementations are looped back to it. Async queries are handled as
+    /// well, without a recorded answer.
+    pub async fn run(&self, register: &RpcDispatcher) -> Vec<Replayed> {
+        let mut res = Vec::new();
+
+        for (pos, record) in self.records.iter().enumerate() {
+            if record.direction != Direction::Received || record.status.is_some() {
+                continue;
+            }
+
+            let (status, answer) = match compress::decompress(&record.payload, None) {
+                Ok(args) => {
+                    loopback_query(register.register.clone(), None, record.cmd, &args).await
+                }
+                Err(e) => {
+                    log::warn!(
+                        cmd = record.cmd, slot = record.slot;
+                        "invalid query payload: {}", e
+                    );
+                    (sys::ic_status_t_IC_MSG_INVALID, Vec::new())
+                }
+            };
+            res.push(Replayed {
+                cmd: record.cmd,
+                slot: record.slot,
+                status,
+                answer,
+                recorded: self.recorded_answer(pos, record),
+            });
+        }
+        res
+    }
+
+    /// Answer sent after the query at `pos`, as recorded.
+    fn recorded_answer(&self, pos: usize, query: &Record) -> Option<(sys::ic_status_t, Vec<u8>)> {
+        if query.slot == 0 {
+            return None;
+        }
+        let answer = self.records[pos + 1..].iter().find(|record| {
+            record.direction == Direction::Sent
+                && record.slot == query.slot
+                && record.cmd == query.cmd
+                && record.status.is_some()
+        })?;
+        let payload = compress::decompress(&answer.payload, None).ok()?;
+
+        Some((answer.status?, payload.into_owned()))
+    }
+}
+
+// }}}
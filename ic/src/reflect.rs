@@ -0,0 +1,45 @@
+use crate::types::Rpc;
+use serde_iop::{Deserialize, Serialize};
+
+// {{{ Description
+
+/// Command handled by a register, as listed by `RpcRegister::rpcs`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcDesc {
+    pub iface_tag: u16,
+    pub rpc_tag: u16,
+    /// Name given by the description of the RPC, if any.
+    pub name: Option<String>,
+    /// Whether the queries are forwarded to another channel, with
+    /// `RpcRegister::proxy`.
+    pub proxied: bool,
+}
+
+impl RpcDesc {
+    pub fn cmd(&self) -> i32 {
+        ((self.iface_tag as i32) << 16) | (self.rpc_tag as i32)
+    }
+}
+
+// }}}
+// {{{ Reflection RPC
+
+/// RPC listing the commands handled by a peer, implemented with
+/// `RpcRegister::expose_reflection` in the interface of the peer's choice.
+pub struct ListRpcs {}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ListRpcsRes {
+    pub rpcs: Vec<RpcDesc>,
+}
+
+impl Rpc for ListRpcs {
+    type Input = ();
+    type Output = ListRpcsRes;
+    type Exception = ();
+    const TAG: u16 = 1;
+    const ASYNC: bool = false;
+    const NAME: Option<&'static str> = Some("ic.Reflection.listRpcs");
+}
+
+// }}}
@@ -0,0 +1,95 @@
+use crate::error;
+use crate::ic::{Channel, QueryFuture};
+use crate::types::Rpc;
+use futures::future::{self, Either};
+use libcommon_el::el_future;
+use libcommon_sys as sys;
+use std::time::Duration;
+
+// {{{ Retry policy
+
+/// Retries of the queries failing with given statuses, for the RPCs which
+/// can be called several times without side effects.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of attempts after the first one.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each of the next ones.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Statuses of the failures which are retried.
+    pub retry_on: Vec<sys::ic_status_t>,
+    /// Delay after which an attempt not answered yet is sent again, the
+    /// first answer of both being kept.
+    pub hedge_after: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            retry_on: vec![
+                sys::ic_status_t_IC_MSG_RETRY,
+                sys::ic_status_t_IC_MSG_TIMEDOUT,
+            ],
+            hedge_after: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Send a query, retrying it following the policy.
+    ///
+    /// `Rpc::call_with_retry` is a shortcut for it.
+    pub async fn call<R: Rpc>(
+        &self,
+        ic: &mut Channel,
+        iface_tag: u16,
+        arg: R::Input,
+    ) -> Result<R::Output, error::Error<R::Exception>> {
+        let cmd = R::get_cmd(iface_tag);
+        let mut backoff = self.backoff;
+        let mut retries = 0;
+
+        loop {
+            match self.attempt::<R>(ic, cmd, &arg).await {
+                Err(e) if retries < self.max_retries && self.retry_on.contains(&e.status()) => {
+                    log::info!(cmd; "{}, retrying in {:?}", e, backoff);
+                    el_future::Timer::new(backoff.as_millis() as i64, 0)
+                        .await
+                        .await;
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    retries += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    async fn attempt<R: Rpc>(
+        &self,
+        ic: &mut Channel,
+        cmd: i32,
+        arg: &R::Input,
+    ) -> Result<R::Output, error::Error<R::Exception>> {
+        let query = QueryFuture::new(ic, arg, cmd, R::ASYNC);
+        let hedge_after = match self.hedge_after {
+            Some(delay) => delay,
+            None => return query.await,
+        };
+
+        let timer = el_future::Timer::new(hedge_after.as_millis() as i64, 0).await;
+        match future::select(query, timer).await {
+            Either::Left((res, _)) => res,
+            Either::Right(((), query)) => {
+                let hedged = QueryFuture::new(ic, arg, cmd, R::ASYNC);
+
+                future::select(query, hedged).await.factor_first().0
+            }
+        }
+    }
+}
+
+// }}}
@@ -0,0 +1,151 @@
+use std::io;
+use std::mem;
+use std::os::raw::c_void;
+use std::time::Duration;
+
+// {{{ Options
+
+/// Keepalive probes sent by the kernel on idle connections, closing them
+/// when the peer is gone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// Idle time before the first probe.
+    pub idle: Duration,
+    /// Delay between two probes.
+    pub interval: Duration,
+    /// Number of probes not acknowledged after which the connection is
+    /// closed.
+    pub count: u32,
+}
+
+impl Default for TcpKeepalive {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            count: 6,
+        }
+    }
+}
+
+/// Options of the sockets of channels, set once connected.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientOptions {
+    /// Send the messages at once, disabling Nagle's algorithm.
+    pub nodelay: bool,
+    pub tcp_keepalive: Option<TcpKeepalive>,
+}
+
+/// Options of the listening socket of a server.
+///
+/// It is created by lib-common, so the options are set once listening.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerOptions {
+    /// Let another socket listen on the address while connections of the
+    /// server are in `TIME_WAIT`.
+    pub reuse_addr: bool,
+    /// Maximum number of connections waiting to be accepted, if not the
+    /// default one of lib-common.
+    pub backlog: Option<i32>,
+    /// Only accept connections received on this network interface.
+    pub bind_interface: Option<String>,
+    /// Options of the channels accepted from now on.
+    pub clients: ClientOptions,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            reuse_addr: true,
+            backlog: None,
+            bind_interface: None,
+            clients: ClientOptions::default(),
+        }
+    }
+}
+
+// }}}
+// {{{ Socket options
+
+fn setsockopt<T>(fd: i32, level: i32, name: i32, value: &T) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value as *const T as *const c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl ClientOptions {
+    pub(crate) fn apply(&self, fd: i32) -> io::Result<()> {
+        setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_NODELAY,
+            &(self.nodelay as i32),
+        )?;
+        setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_KEEPALIVE,
+            &(self.tcp_keepalive.is_some() as i32),
+        )?;
+        if let Some(keepalive) = &self.tcp_keepalive {
+            let idle = keepalive.idle.as_secs().max(1) as i32;
+            let interval = keepalive.interval.as_secs().max(1) as i32;
+
+            setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, &idle)?;
+            setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, &interval)?;
+            setsockopt(
+                fd,
+                libc::IPPROTO_TCP,
+                libc::TCP_KEEPCNT,
+                &(keepalive.count as i32),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl ServerOptions {
+    pub(crate) fn apply(&self, fd: i32) -> io::Result<()> {
+        setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            &(self.reuse_addr as i32),
+        )?;
+        if let Some(interface) = &self.bind_interface {
+            let res = unsafe {
+                libc::setsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    libc::SO_BINDTODEVICE,
+                    interface.as_ptr() as *const c_void,
+                    interface.len() as libc::socklen_t,
+                )
+            };
+
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        // listening again only changes the backlog
+        if let Some(backlog) = self.backlog {
+            if unsafe { libc::listen(fd, backlog) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+// }}}
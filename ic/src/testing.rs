@@ -0,0 +1,150 @@
+//! Helpers to test RPC implementations without a client and a server.
+//!
+//! A `MockChannel` gives loopback channels to the implementations under
+//! test, recording the queries they send and answering them with scripted
+//! responses:
+//!
+//! ```ignore
+//! let mock = MockChannel::new();
+//! mock.respond::<GetNbTotalSteps, _>(CUSTOM, |arg| Ok(GetNbTotalStepsRes { nb_total_steps: 20 }));
+//!
+//! let res = block_on(rpc_get_completion_rate(mock.channel(), arg));
+//! assert_eq!(mock.calls_to::<GetNbTotalSteps>(CUSTOM).len(), 1);
+//! ```
+use crate::error;
+use crate::hdr::QueryHeader;
+use crate::ic::{send_reply, Channel, RpcDispatcher, RpcImpl, RpcRegister};
+use crate::types::Rpc;
+use futures::future::{self, FutureExt};
+use libcommon_sys as sys;
+use serde_iop::{from_bytes, DeserializeOwned};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// {{{ Calls
+
+/// Query sent on a mock channel.
+#[derive(Clone)]
+pub struct MockCall {
+    pub cmd: i32,
+    pub hdr: QueryHeader,
+    /// Packed arguments of the query.
+    pub args: Vec<u8>,
+}
+
+impl MockCall {
+    /// Unpack the arguments of the query.
+    pub fn args<T: DeserializeOwned>(&self) -> Result<T, serde_iop::Error> {
+        from_bytes(&self.args)
+    }
+}
+
+// }}}
+// {{{ Mock channel
+
+#[derive(Default)]
+struct MockState {
+    calls: RefCell<Vec<MockCall>>,
+    responses: RefCell<HashMap<i32, RpcImpl>>,
+}
+
+/// Channels answering their queries with scripted responses.
+///
+/// Queries of commands without a response are answered as unimplemented.
+/// Like the loopback channels, the queries are answered when their future
+/// is polled, without lib-common nor the event loop.
+pub struct MockChannel {
+    state: Rc<MockState>,
+    register: RpcDispatcher,
+}
+
+impl MockChannel {
+    pub fn new() -> Self {
+        let state = Rc::new(MockState::default());
+        let mut register = RpcRegister::new();
+
+        let fallback: RpcImpl = {
+            let state = state.clone();
+
+            Rc::new(move |ic, hdr, cmd, data, slot| {
+                state.calls.borrow_mut().push(MockCall {
+                    cmd,
+                    hdr: hdr.clone(),
+                    args: data.to_vec(),
+                });
+
+                // the response is cloned, so that it can script new ones
+                let response = state.responses.borrow().get(&cmd).cloned();
+                match response {
+                    Some(response) => (response)(ic, hdr, cmd, data, slot),
+                    None => {
+                        let status = sys::ic_status_t_IC_MSG_UNIMPLEMENTED;
+
+                        future::ready(send_reply(&[], cmd, slot, status)).boxed_local()
+                    }
+                }
+            })
+        };
+        register.fallback = Some(fallback);
+
+        Self {
+            state,
+            register: register.build(),
+        }
+    }
+
+    /// Channel to give to the implementations under test.
+    pub fn channel(&self) -> Channel {
+        Channel::loopback(&self.register)
+    }
+
+    /// Answer the queries of the RPC `R` with `fun`, replacing its previous
+    /// response.
+    pub fn respond<R, F>(&self, iface_tag: u16, fun: F)
+    where
+        R: Rpc,
+        F: Fn(R::Input) -> Result<R::Output, error::Error<R::Exception>> + 'static,
+    {
+        let response = RpcRegister::make_impl(move |_ic, _hdr, input| future::ready(fun(input)));
+
+        self.state
+            .responses
+            .borrow_mut()
+            .insert(R::get_cmd(iface_tag), response);
+    }
+
+    /// Queries sent on the channels, in order.
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.state.calls.borrow().clone()
+    }
+
+    /// Arguments of the queries of the RPC `R` sent on the channels, in
+    /// order.
+    ///
+    /// Panics if the arguments of a query cannot be unpacked.
+    pub fn calls_to<R: Rpc>(&self, iface_tag: u16) -> Vec<R::Input> {
+        let cmd = R::get_cmd(iface_tag);
+
+        self.state
+            .calls
+            .borrow()
+            .iter()
+            .filter(|call| call.cmd == cmd)
+            .map(|call| call.args().unwrap())
+            .collect()
+    }
+
+    /// Forget the queries sent so far.
+    pub fn clear_calls(&self) {
+        self.state.calls.borrow_mut().clear();
+    }
+}
+
+impl Default for MockChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// }}}
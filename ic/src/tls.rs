@@ -0,0 +1,247 @@
+use crate::addr::AddrError;
+use libcommon_sys as sys;
+use std::cell::RefCell;
+use std::error;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::rc::Rc;
+
+// {{{ Error
+
+#[derive(Debug)]
+pub struct TlsError(String);
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tls error: {}", self.0)
+    }
+}
+
+impl error::Error for TlsError {}
+
+// }}}
+// {{{ Config
+
+/// Verification of the certificate of the peer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerifyMode {
+    /// Do not check the certificate of the peer.
+    None,
+    /// Check the certificate of the peer if it sends one. Servers always
+    /// send one.
+    Peer,
+    /// Check the certificate of the peer, and fail if it does not send one.
+    RequirePeer,
+}
+
+struct SslCtx(*mut sys::SSL_CTX);
+
+impl Drop for SslCtx {
+    fn drop(&mut self) {
+        unsafe {
+            sys::SSL_CTX_free(self.0);
+        }
+    }
+}
+
+thread_local! {
+    // Context given to lib-common, kept alive as long as it is used.
+    static CURRENT_CTX: RefCell<Option<Rc<SslCtx>>> = const { RefCell::new(None) };
+}
+
+/// TLS configuration of servers and clients.
+///
+/// The certificate, its private key and the CA certificates are loaded when
+/// the configuration is built.
+///
+/// lib-common uses a single SSL context for all its channels, so every TLS
+/// channel of a thread uses the same configuration: once one is given to a
+/// `Server` or a `Client`, giving them another one fails with
+/// `AddrError::Tls`. Clones of a configuration are the same configuration.
+#[derive(Clone)]
+pub struct TlsConfig {
+    ctx: Rc<SslCtx>,
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsConfig").finish()
+    }
+}
+
+fn path_to_cstring(path: &Path) -> Result<CString, TlsError> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| TlsError(format!("invalid path `{}`", path.display())))
+}
+
+impl TlsConfig {
+    /// Load a certificate chain and its private key, both in PEM format.
+    ///
+    /// The certificate of the peer is not verified until a CA is given.
+    pub fn new<P: AsRef<Path>, K: AsRef<Path>>(cert: P, key: K) -> Result<Self, TlsError> {
+        let cert = cert.as_ref();
+        let key = key.as_ref();
+        let cert_path = path_to_cstring(cert)?;
+        let key_path = path_to_cstring(key)?;
+
+        let ctx = unsafe { sys::SSL_CTX_new(sys::TLS_method()) };
+        if ctx.is_null() {
+            return Err(TlsError("cannot create SSL context".to_owned()));
+        }
+        let ctx = SslCtx(ctx);
+
+        unsafe {
+            if sys::SSL_CTX_use_certificate_chain_file(ctx.0, cert_path.as_ptr()) != 1 {
+                return Err(TlsError(format!(
+                    "cannot load certificate `{}`",
+                    cert.display()
+                )));
+            }
+            if sys::SSL_CTX_use_PrivateKey_file(
+                ctx.0,
+                key_path.as_ptr(),
+                sys::SSL_FILETYPE_PEM as i32,
+            ) != 1
+            {
+                return Err(TlsError(format!(
+                    "cannot load private key `{}`",
+                    key.display()
+                )));
+            }
+            if sys::SSL_CTX_check_private_key(ctx.0) != 1 {
+                return Err(TlsError(format!(
+                    "private key `{}` does not match certificate `{}`",
+                    key.display(),
+                    cert.display()
+                )));
+            }
+            sys::SSL_CTX_set_verify(ctx.0, sys::SSL_VERIFY_NONE as i32, None);
+        }
+
+        Ok(Self { ctx: Rc::new(ctx) })
+    }
+
+    /// Load the CA certificates used to verify the peer, in PEM format, and
+    /// verify the certificate of the peer.
+    pub fn ca<P: AsRef<Path>>(self, ca: P) -> Result<Self, TlsError> {
+        let ca = ca.as_ref();
+        let ca_path = path_to_cstring(ca)?;
+
+        unsafe {
+            if sys::SSL_CTX_load_verify_locations(self.ctx.0, ca_path.as_ptr(), std::ptr::null())
+                != 1
+            {
+                return Err(TlsError(format!(
+                    "cannot load CA certificates `{}`",
+                    ca.display()
+                )));
+            }
+        }
+        Ok(self.verify(VerifyMode::Peer))
+    }
+
+    /// Set the verification of the certificate of the peer.
+    pub fn verify(self, mode: VerifyMode) -> Self {
+        let mode = match mode {
+            VerifyMode::None => sys::SSL_VERIFY_NONE,
+            VerifyMode::Peer => sys::SSL_VERIFY_PEER,
+            VerifyMode::RequirePeer => sys::SSL_VERIFY_PEER | sys::SSL_VERIFY_FAIL_IF_NO_PEER_CERT,
+        };
+
+        unsafe {
+            sys::SSL_CTX_set_verify(self.ctx.0, mode as i32, None);
+        }
+        self
+    }
+
+    /// Give the SSL context to lib-common, for the channels requiring TLS.
+    ///
+    /// It fails if another configuration was given before on the thread,
+    /// as it would apply to its channels too.
+    pub(crate) fn install(&self) -> Result<(), AddrError> {
+        CURRENT_CTX.with(|current| {
+            let mut current = current.borrow_mut();
+
+            match &*current {
+                Some(ctx) if Rc::ptr_eq(ctx, &self.ctx) => Ok(()),
+                Some(_) => Err(AddrError::Tls(
+                    "another TLS configuration is used by the channels of the thread".to_owned(),
+                )),
+                None => {
+                    unsafe {
+                        sys::ic_set_ssl_ctx(self.ctx.0);
+                    }
+                    *current = Some(self.ctx.clone());
+                    Ok(())
+                }
+            }
+        })
+    }
+}
+
+// }}}
+// {{{ Peer identity
+
+/// Identity of the peer of a TLS channel, given by its certificate.
+///
+/// Servers requiring the certificates of their clients, with
+/// `VerifyMode::RequirePeer` and a CA, can check it in their authenticator
+/// instead of the login of the queries.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// Subject of the certificate, as `/O=org/CN=name`.
+    pub subject: String,
+    /// Common name of the subject, if any.
+    pub common_name: Option<String>,
+}
+
+/// Identity of the peer of `ssl`, if it sent a certificate which was
+/// verified.
+pub(crate) fn peer_identity(ssl: *mut sys::SSL) -> Option<PeerIdentity> {
+    if ssl.is_null() {
+        return None;
+    }
+
+    unsafe {
+        let cert = sys::SSL_get1_peer_certificate(ssl);
+        if cert.is_null() {
+            return None;
+        }
+        // the certificates not verified against a CA cannot be trusted
+        if sys::SSL_get_verify_result(ssl) != sys::X509_V_OK as _ {
+            sys::X509_free(cert);
+            return None;
+        }
+
+        let name = sys::X509_get_subject_name(cert);
+        let mut buf = [0; 256];
+        let subject = if name.is_null() {
+            String::new()
+        } else {
+            sys::X509_NAME_oneline(name, buf.as_mut_ptr(), buf.len() as i32);
+            CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned()
+        };
+        let common_name = if name.is_null() {
+            None
+        } else {
+            let len = sys::X509_NAME_get_text_by_NID(
+                name,
+                sys::NID_commonName as i32,
+                buf.as_mut_ptr(),
+                buf.len() as i32,
+            );
+
+            (len >= 0).then(|| CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned())
+        };
+
+        sys::X509_free(cert);
+        Some(PeerIdentity {
+            subject,
+            common_name,
+        })
+    }
+}
+
+// }}}
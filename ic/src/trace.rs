@@ -0,0 +1,83 @@
+use futures::future::{FutureExt, LocalBoxFuture};
+use libcommon_sys as sys;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tracing::{field, Instrument, Span};
+
+// {{{ Dispatch
+
+/// Run the implementation of a received query in a span, recording the
+/// status of its answer and its latency.
+pub(crate) fn dispatch(
+    fut: LocalBoxFuture<'static, sys::ic_status_t>,
+    peer: Option<SocketAddr>,
+    cmd: i32,
+    slot: u64,
+) -> LocalBoxFuture<'static, sys::ic_status_t> {
+    let span = tracing::info_span!(
+        "ic.dispatch",
+        cmd,
+        iface = cmd >> 16,
+        rpc = cmd & 0xffff,
+        slot,
+        peer = field::Empty,
+        status = field::Empty,
+        latency_us = field::Empty,
+    );
+    if let Some(peer) = peer {
+        span.record("peer", field::display(peer));
+    }
+    let start = Instant::now();
+
+    fut.instrument(span.clone())
+        .map(move |status| {
+            record_answer(&span, status, start);
+            status
+        })
+        .boxed_local()
+}
+
+// }}}
+// {{{ Query
+
+/// Span of a sent query, closed once it is answered.
+pub(crate) struct QuerySpan {
+    span: Option<Span>,
+    start: Instant,
+}
+
+impl QuerySpan {
+    pub(crate) fn new(peer: Option<SocketAddr>, cmd: i32) -> Self {
+        let span = tracing::info_span!(
+            "ic.query",
+            cmd,
+            iface = cmd >> 16,
+            rpc = cmd & 0xffff,
+            peer = field::Empty,
+            status = field::Empty,
+            latency_us = field::Empty,
+        );
+        if let Some(peer) = peer {
+            span.record("peer", field::display(peer));
+        }
+
+        Self {
+            span: Some(span),
+            start: Instant::now(),
+        }
+    }
+
+    /// Record the status of the answer, only the first one counting.
+    pub(crate) fn finish(&mut self, status: sys::ic_status_t) {
+        if let Some(span) = self.span.take() {
+            record_answer(&span, status, self.start);
+        }
+    }
+}
+
+// }}}
+
+fn record_answer(span: &Span, status: sys::ic_status_t, start: Instant) {
+    span.record("status", status);
+    span.record("latency_us", start.elapsed().as_micros() as u64);
+}
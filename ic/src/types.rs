@@ -1,16 +1,21 @@
 use crate::error;
-use crate::ic::{Channel, QueryFuture, RpcRegister};
-use futures::future::Future;
-use serde_iop::to_bytes;
+use crate::hdr::QueryHeader;
+use crate::ic::{send_async, Channel, QueryFuture, RpcRegister, StateRegister};
+use crate::retry::RetryPolicy;
+use futures::future::{Future, FutureExt, LocalBoxFuture};
 use serde_iop::{DeserializeOwned, Serialize};
+use std::rc::Rc;
 
 pub trait Rpc {
     type Input: Serialize + DeserializeOwned;
-    type Output: Serialize + DeserializeOwned;
-    type Exception: Serialize + DeserializeOwned;
+    type Output: Serialize + DeserializeOwned + 'static;
+    type Exception: Serialize + DeserializeOwned + 'static;
 
     const TAG: u16;
     const ASYNC: bool;
+    /// Name of the RPC, as `package.Interface.rpc`, listed by
+    /// `RpcRegister::rpcs` once implemented.
+    const NAME: Option<&'static str> = None;
 
     fn get_cmd(iface_tag: u16) -> i32 {
         ((iface_tag as i32) << 16) | (Self::TAG as i32)
@@ -24,6 +29,32 @@ pub trait Rpc {
         Self::Exception: 'static,
     {
         reg.register(Self::get_cmd(iface_tag), fun);
+        reg.set_name(Self::get_cmd(iface_tag), Self::NAME);
+    }
+
+    /// Same as `implement`, giving the header of the queries to `fun`.
+    fn implement_with_hdr<F, Fut>(reg: &mut RpcRegister, iface_tag: u16, fun: F)
+    where
+        F: Fn(Channel, QueryHeader, Self::Input) -> Fut + 'static,
+        Fut: Future<Output = Result<Self::Output, error::Error<Self::Exception>>> + 'static,
+        Self::Output: 'static,
+        Self::Exception: 'static,
+    {
+        reg.register_with_hdr(Self::get_cmd(iface_tag), fun);
+        reg.set_name(Self::get_cmd(iface_tag), Self::NAME);
+    }
+
+    /// Same as `implement`, giving the state of the register to `fun`.
+    fn implement_with_state<S, F, Fut>(reg: &mut StateRegister<'_, S>, iface_tag: u16, fun: F)
+    where
+        S: 'static,
+        F: Fn(Rc<S>, Channel, Self::Input) -> Fut + 'static,
+        Fut: Future<Output = Result<Self::Output, error::Error<Self::Exception>>> + 'static,
+        Self::Output: 'static,
+        Self::Exception: 'static,
+    {
+        reg.register(Self::get_cmd(iface_tag), fun);
+        reg.set_name(Self::get_cmd(iface_tag), Self::NAME);
     }
 
     fn call(
@@ -31,8 +62,56 @@ pub trait Rpc {
         iface_tag: u16,
         arg: Self::Input,
     ) -> QueryFuture<Self::Output, Self::Exception> {
-        let input = to_bytes(&arg).unwrap();
+        QueryFuture::new(ic, &arg, Self::get_cmd(iface_tag), Self::ASYNC)
+    }
 
-        QueryFuture::new(ic, &input, Self::get_cmd(iface_tag), Self::ASYNC)
+    /// Same as `call`, attaching a header to the query, for example to
+    /// authenticate with a login and a password.
+    fn call_with_hdr(
+        ic: &mut Channel,
+        iface_tag: u16,
+        hdr: &QueryHeader,
+        arg: Self::Input,
+    ) -> QueryFuture<Self::Output, Self::Exception> {
+        QueryFuture::new_with_hdr(ic, Some(hdr), &arg, Self::get_cmd(iface_tag), Self::ASYNC)
+    }
+
+    /// Same as `call`, retrying the query following `policy`, for RPCs
+    /// without side effects.
+    fn call_with_retry<'a>(
+        ic: &'a mut Channel,
+        iface_tag: u16,
+        arg: Self::Input,
+        policy: &'a RetryPolicy,
+    ) -> LocalBoxFuture<'a, Result<Self::Output, error::Error<Self::Exception>>>
+    where
+        Self: Sized + 'a,
+    {
+        policy.call::<Self>(ic, iface_tag, arg).boxed_local()
+    }
+
+    /// Send a query to an async RPC, without waiting for it to be handled,
+    /// for example to notify the peer of an event.
+    ///
+    /// It fails with `Error::Invalid` for RPCs which are not async.
+    fn send(
+        ic: &mut Channel,
+        iface_tag: u16,
+        arg: Self::Input,
+    ) -> Result<(), error::Error<Self::Exception>> {
+        if !Self::ASYNC {
+            return Err(error::Error::Invalid);
+        }
+        send_async(ic, &arg, Self::get_cmd(iface_tag))
     }
 }
+
+/// RPC implementations of an interface, provided by the methods of a type.
+///
+/// It is implemented by the `#[service]` attribute on an impl block, whose
+/// methods marked with `#[rpc(...)]` implement the given RPCs.
+pub trait Service: 'static {
+    /// Implement the RPCs of the service on `reg`, for the interface
+    /// `iface_tag`, the implementations sharing the service.
+    fn implement(self: Rc<Self>, reg: &mut RpcRegister, iface_tag: u16);
+}
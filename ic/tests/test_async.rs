@@ -1,10 +1,13 @@
 use ic::error;
-use ic::ic::{Client, RpcRegister, Server};
+use ic::hdr::QueryHeader;
+use ic::ic::{Client, RpcDispatcher, RpcRegister, Server};
 use ic::types::Rpc;
 use libcommon_el as el;
 use libcommon_ic as ic;
 use serde_iop::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 // {{{ Hello RPC definition
 
@@ -54,6 +57,37 @@ impl Rpc for GetUser {
 
     const TAG: u16 = 2;
     const ASYNC: bool = false;
+    const NAME: Option<&'static str> = Some("tst.User.getUser");
+}
+
+// Notify RPC on server, not answered
+
+#[derive(Serialize, Deserialize)]
+pub struct NotifyArg {
+    event: String,
+}
+pub struct Notify {}
+
+impl Rpc for Notify {
+    type Input = NotifyArg;
+    type Output = ();
+    type Exception = ();
+
+    const TAG: u16 = 3;
+    const ASYNC: bool = true;
+}
+
+// Ack RPC on server, answered without data
+
+pub struct Ack {}
+
+impl Rpc for Ack {
+    type Input = NotifyArg;
+    type Output = ();
+    type Exception = ();
+
+    const TAG: u16 = 4;
+    const ASYNC: bool = false;
 }
 
 pub mod iop_module {
@@ -62,14 +96,22 @@ pub mod iop_module {
 
 // }}}
 
-#[test]
-fn test_server_client() {
-    use iop_module::IFACE;
+// {{{ Hello server
 
-    let _m = ic::use_module();
+/// Implement SayHello, which gets the users from the client.
+fn hello_server_reg() -> RpcRegister {
+    use iop_module::IFACE;
 
     let mut server_reg = RpcRegister::new();
-    SayHello::implement(&mut server_reg, IFACE, |mut ic, arg| async move {
+    SayHello::implement_with_hdr(&mut server_reg, IFACE, |mut ic, hdr, arg| async move {
+        // only the query for Gyro is sent with a header
+        if arg.user_id == 1 {
+            assert_eq!(hdr.login.as_deref(), Some("gyro"));
+            assert_eq!(hdr.password.as_deref(), Some("zeppeli"));
+        } else {
+            assert_eq!(hdr, QueryHeader::default());
+        }
+
         let user = GetUser::call(
             &mut ic,
             IFACE,
@@ -86,6 +128,12 @@ fn test_server_client() {
 
         Ok(SayHelloRes { result })
     });
+    server_reg
+}
+
+/// Implement GetUser, user 3 failing with a generic error.
+fn hello_client_reg() -> RpcRegister {
+    use iop_module::IFACE;
 
     let mut client_reg = RpcRegister::new();
     GetUser::implement(&mut client_reg, IFACE, |_ic, arg| async move {
@@ -100,18 +148,57 @@ fn test_server_client() {
                 middlename: None,
                 lastname: "Zeppeli".to_owned(),
             }),
+            3 => Err(error::Error::Generic("database unavailable".to_owned())),
             _ => Err(error::Error::Exn(GetUserExn {
                 error: format!("unknown user with id {}", arg.user_id),
             })),
         }
     });
+    client_reg
+}
+
+/// Start a hello server, and connect a client with the given register to
+/// it.
+async fn hello_connect(client_reg: &RpcDispatcher) -> (Server, Client) {
+    let server = Server::new("127.0.0.1:0", Some(hello_server_reg().build()), None).unwrap();
+
+    let mut client = Client::new(Some(client_reg));
+    let connected = client
+        .connect_once(&server.local_addr().to_string(), None)
+        .unwrap()
+        .await;
+    assert!(connected);
+
+    (server, client)
+}
+
+// }}}
+
+#[test]
+fn test_server_client() {
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
 
     el::exec_test_async(async {
-        let _server = Server::new("127.0.0.1", Some(server_reg));
+        let mut server =
+            Server::new("127.0.0.1:0", Some(hello_server_reg().build()), None).unwrap();
 
-        let client_reg = Rc::new(client_reg);
+        let nb_peers = Rc::new(Cell::new(0));
+        {
+            let nb_peers = nb_peers.clone();
+            server.on_client_connected(move |_id, _ic, addr| {
+                assert!(addr.is_some());
+                nb_peers.set(nb_peers.get() + 1);
+            });
+        }
+
+        let client_reg = hello_client_reg().build();
         let mut client = Client::new(Some(&client_reg));
-        let connected = client.connect_once("127.0.0.1").await;
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
         assert!(connected);
 
         let mut channel = client.get_channel();
@@ -120,8 +207,13 @@ fn test_server_client() {
             .await
             .unwrap();
         assert!(res.result == "Hi, Joseph `JoJo` Joestar.");
+        assert_eq!(nb_peers.get(), 1);
 
-        let res = SayHello::call(&mut channel, IFACE, SayHelloArg { user_id: 1 })
+        let hdr = QueryHeader {
+            deadline: Some(Instant::now() + Duration::from_secs(10)),
+            ..QueryHeader::with_login("gyro", "zeppeli")
+        };
+        let res = SayHello::call_with_hdr(&mut channel, IFACE, &hdr, SayHelloArg { user_id: 1 })
             .await
             .unwrap();
         assert!(res.result == "Hi, Gyro Zeppeli.");
@@ -135,3 +227,2750 @@ fn test_server_client() {
         };
     });
 }
+
+#[test]
+fn test_dropped_query() {
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    el::exec_test_async(async {
+        let client_reg = hello_client_reg().build();
+        let (_server, mut client) = hello_connect(&client_reg).await;
+        let mut channel = client.get_channel();
+
+        // the answers of dropped queries are ignored
+        drop(SayHello::call(
+            &mut channel,
+            IFACE,
+            SayHelloArg { user_id: 2 },
+        ));
+        let res = SayHello::call(&mut channel, IFACE, SayHelloArg { user_id: 0 })
+            .await
+            .unwrap();
+        assert!(res.result == "Hi, Joseph `JoJo` Joestar.");
+    });
+}
+
+#[test]
+fn test_cancel() {
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    el::exec_test_async(async {
+        let client_reg = hello_client_reg().build();
+        let (_server, mut client) = hello_connect(&client_reg).await;
+        let mut channel = client.get_channel();
+
+        // canceled queries resolve at once
+        let query = SayHello::call(&mut channel, IFACE, SayHelloArg { user_id: 0 });
+        query.handle().cancel();
+        assert!(matches!(query.await, Err(error::Error::Canceled)));
+
+        // the channel is still usable
+        let res = SayHello::call(&mut channel, IFACE, SayHelloArg { user_id: 0 })
+            .await
+            .unwrap();
+        assert!(res.result == "Hi, Joseph `JoJo` Joestar.");
+    });
+}
+
+#[test]
+fn test_server_error() {
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    el::exec_test_async(async {
+        let client_reg = hello_client_reg().build();
+        let (_server, mut client) = hello_connect(&client_reg).await;
+        let mut channel = client.get_channel();
+
+        // generic errors are replied as server errors
+        let res = SayHello::call(&mut channel, IFACE, SayHelloArg { user_id: 3 }).await;
+        assert!(matches!(res, Err(error::Error::ServerError)));
+    });
+}
+
+#[test]
+fn test_server_peers() {
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    el::exec_test_async(async {
+        let client_reg = hello_client_reg().build();
+        let (mut server, _client) = hello_connect(&client_reg).await;
+
+        // the server can query its peers
+        let peers = server.peers();
+        assert_eq!(peers.len(), 1);
+        assert!(server.peer_addr(peers[0]).is_some());
+
+        let mut peer = server.peer_channel(peers[0]).unwrap();
+        let user = GetUser::call(&mut peer, IFACE, GetUserArg { user_id: 1 })
+            .await
+            .unwrap();
+        assert!(user.firstname == "Gyro");
+
+        let res = server
+            .broadcast::<GetUser>(IFACE, GetUserArg { user_id: 0 })
+            .await;
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].0, peers[0]);
+        assert!(res[0].1.as_ref().unwrap().firstname == "Joseph");
+    });
+}
+
+#[test]
+fn test_replace_rpc() {
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    el::exec_test_async(async {
+        let client_reg = hello_client_reg().build();
+        let (mut server, _client) = hello_connect(&client_reg).await;
+        let mut peer = server.peer_channel(server.peers()[0]).unwrap();
+
+        // implementations can be replaced and removed at runtime
+        let cmd = GetUser::get_cmd(IFACE);
+        assert!(client_reg.replace(cmd, |_ic, _arg: GetUserArg| async move {
+            Err::<GetUserRes, _>(error::Error::Exn(GetUserExn {
+                error: "replaced".to_owned(),
+            }))
+        }));
+        let res = GetUser::call(&mut peer, IFACE, GetUserArg { user_id: 0 }).await;
+        assert!(matches!(res, Err(error::Error::Exn(e)) if e.error == "replaced"));
+
+        assert!(client_reg.unregister(cmd));
+        let res = GetUser::call(&mut peer, IFACE, GetUserArg { user_id: 0 }).await;
+        assert!(matches!(res, Err(error::Error::Unimplemented)));
+    });
+}
+
+#[test]
+fn test_addr() {
+    use ic::addr::{AddrError, SockAddr};
+
+    assert!(SockAddr::parse("127.0.0.1:1234").is_ok());
+    assert!(SockAddr::parse("[::1]:1234").is_ok());
+    assert_eq!(
+        SockAddr::parse("127.0.0.1").err(),
+        Some(AddrError::Parse("127.0.0.1".to_owned()))
+    );
+    assert_eq!(
+        SockAddr::parse("127.0.0.1:0").err(),
+        Some(AddrError::Parse("127.0.0.1:0".to_owned()))
+    );
+    assert!(SockAddr::parse_listen("127.0.0.1:0").is_ok());
+}
+
+#[test]
+fn test_resolve() {
+    use futures::executor::block_on;
+    use ic::addr::{AddrError, SockAddr};
+    use std::net::ToSocketAddrs;
+
+    let _m = ic::use_module();
+
+    assert!(block_on(SockAddr::resolve("[::1]:1234")).is_ok());
+    assert!(block_on(SockAddr::resolve("localhost:1234")).is_ok());
+    assert_eq!(
+        block_on(SockAddr::resolve("localhost")).err(),
+        Some(AddrError::Parse("localhost".to_owned()))
+    );
+    assert_eq!(
+        block_on(SockAddr::resolve("localhost:0")).err(),
+        Some(AddrError::Parse("localhost:0".to_owned()))
+    );
+    assert_eq!(
+        block_on(SockAddr::resolve("unknown.invalid:1234")).err(),
+        Some(AddrError::Resolve("unknown.invalid:1234".to_owned()))
+    );
+
+    // the server listens on the first address of localhost, which is the
+    // one the client connects to
+    let mut addr = "localhost:1234".to_socket_addrs().unwrap().next().unwrap();
+    addr.set_port(0);
+
+    el::exec_test_async(async move {
+        let server = Server::new(&addr.to_string(), None, None).unwrap();
+        let hostname = format!("localhost:{}", server.local_addr().port());
+
+        let mut client = Client::new(None);
+        assert_eq!(client.connect(&hostname, None).await, Ok(true));
+    });
+}
+
+#[test]
+fn test_server_client_ipv6() {
+    let _m = ic::use_module();
+
+    el::exec_test_async(async {
+        let server = Server::new("[::1]:0", None, None).unwrap();
+
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+    });
+}
+
+#[test]
+fn test_middlewares() {
+    use futures::future::{self, FutureExt};
+    use iop_module::IFACE;
+    use libcommon_sys as sys;
+
+    let _m = ic::use_module();
+
+    // the server rejects the queries without login, and counts the others
+    let nb_queries = Rc::new(Cell::new(0));
+    let mut server_reg = RpcRegister::new();
+    GetUser::implement(&mut server_reg, IFACE, |_ic, _arg| async move {
+        Ok(GetUserRes {
+            firstname: "Joseph".to_owned(),
+            middlename: None,
+            lastname: "Joestar".to_owned(),
+        })
+    });
+    {
+        let nb_queries = nb_queries.clone();
+        server_reg.wrap(move |next, _ic, hdr, _cmd, _data| {
+            if hdr.login.is_none() {
+                return future::ready(sys::ic_status_t_IC_MSG_INVALID).boxed_local();
+            }
+            nb_queries.set(nb_queries.get() + 1);
+            next()
+        });
+    }
+
+    // the client cancels the queries of kind "skip", and records the status
+    // of the others
+    let statuses = Rc::new(RefCell::new(Vec::new()));
+    let mut client_reg = RpcRegister::new();
+    {
+        let statuses = statuses.clone();
+        client_reg.wrap_calls(move |next, _ic, hdr, _cmd, _data| {
+            if hdr.kind.as_deref() == Some("skip") {
+                return future::ready(sys::ic_status_t_IC_MSG_ABORT).boxed_local();
+            }
+            let statuses = statuses.clone();
+            next()
+                .inspect(move |status| statuses.borrow_mut().push(*status))
+                .boxed_local()
+        });
+    }
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(server_reg.build()), None).unwrap();
+
+        let client_reg = client_reg.build();
+        let mut client = Client::new(Some(&client_reg));
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        let arg = || GetUserArg { user_id: 0 };
+
+        let res = GetUser::call(&mut channel, IFACE, arg()).await;
+        assert!(matches!(res, Err(error::Error::Invalid)));
+
+        let hdr = QueryHeader::with_login("jojo", "joestar");
+        let res = GetUser::call_with_hdr(&mut channel, IFACE, &hdr, arg()).await;
+        assert!(res.unwrap().firstname == "Joseph");
+
+        let hdr = QueryHeader {
+            kind: Some("skip".to_owned()),
+            ..hdr
+        };
+        let res = GetUser::call_with_hdr(&mut channel, IFACE, &hdr, arg()).await;
+        assert!(matches!(res, Err(error::Error::Abort)));
+
+        assert_eq!(nb_queries.get(), 1);
+        assert_eq!(
+            *statuses.borrow(),
+            vec![sys::ic_status_t_IC_MSG_INVALID, sys::ic_status_t_IC_MSG_OK]
+        );
+    });
+}
+
+#[test]
+fn test_wire_hooks() {
+    use ic::ic::Direction;
+    use iop_module::IFACE;
+    use libcommon_sys as sys;
+
+    let _m = ic::use_module();
+
+    let mut server_reg = RpcRegister::new();
+    GetUser::implement(&mut server_reg, IFACE, |_ic, _arg| async move {
+        Ok(GetUserRes {
+            firstname: "Jonathan".to_owned(),
+            middlename: None,
+            lastname: "Joestar".to_owned(),
+        })
+    });
+
+    el::exec_test_async(async move {
+        let server_msgs = Rc::new(RefCell::new(Vec::new()));
+        let mut server = Server::new("127.0.0.1:0", Some(server_reg.build()), None).unwrap();
+        {
+            let server_msgs = server_msgs.clone();
+            server.on_wire(move |dir, cmd, slot, _data| {
+                server_msgs.borrow_mut().push((dir, cmd, slot));
+            });
+        }
+
+        let client_msgs = Rc::new(RefCell::new(Vec::new()));
+        let mut client = Client::new(None);
+        {
+            let client_msgs = client_msgs.clone();
+            client.on_wire(move |dir, cmd, _slot, data| {
+                client_msgs.borrow_mut().push((dir, cmd, data.len()));
+            });
+        }
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 0 }).await;
+        assert!(res.unwrap().firstname == "Jonathan");
+
+        let cmd = GetUser::get_cmd(IFACE);
+        let ok = sys::ic_status_t_IC_MSG_OK as i32;
+
+        let client_msgs = client_msgs.borrow();
+        assert_eq!(client_msgs.len(), 2);
+        assert_eq!(client_msgs[0].0, Direction::Sent);
+        assert_eq!(client_msgs[0].1, cmd);
+        assert_eq!(client_msgs[1].0, Direction::Received);
+        assert_eq!(client_msgs[1].1, ok);
+        assert!(client_msgs[1].2 > 0);
+
+        let server_msgs = server_msgs.borrow();
+        assert_eq!(server_msgs.len(), 2);
+        assert_eq!(server_msgs[0].0, Direction::Received);
+        assert_eq!(server_msgs[0].1, cmd);
+        assert_eq!(server_msgs[1].0, Direction::Sent);
+        assert_eq!(server_msgs[1].1, ok);
+        assert_eq!(server_msgs[0].2, server_msgs[1].2);
+    });
+}
+
+#[test]
+fn test_metrics() {
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let mut server_reg = RpcRegister::new();
+    GetUser::implement(&mut server_reg, IFACE, |_ic, arg| async move {
+        if arg.user_id == 0 {
+            Ok(GetUserRes {
+                firstname: "Jotaro".to_owned(),
+                middlename: None,
+                lastname: "Kujo".to_owned(),
+            })
+        } else {
+            Err(error::Error::Exn(GetUserExn {
+                error: "unknown user".to_owned(),
+            }))
+        }
+    });
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(server_reg.build()), None).unwrap();
+
+        let client_reg = RpcRegister::new().build();
+        let mut client = Client::new(Some(&client_reg));
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 0 }).await;
+        assert!(res.is_ok());
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 3 }).await;
+        assert!(matches!(res, Err(error::Error::Exn(_))));
+
+        let cmd = GetUser::get_cmd(IFACE);
+
+        let handled = &server.rpc_register().unwrap().metrics().handled[&cmd];
+        assert_eq!(handled.calls, 2);
+        assert_eq!(handled.errors, 1);
+        assert_eq!(handled.timeouts, 0);
+        assert!(handled.bytes_in > 0);
+        assert!(handled.bytes_out > 0);
+        assert_eq!(handled.latency.count(), 2);
+
+        let snapshot = client_reg.metrics();
+        assert!(snapshot.handled.is_empty());
+        let sent = &snapshot.sent[&cmd];
+        assert_eq!(sent.calls, 2);
+        assert_eq!(sent.errors, 1);
+        assert_eq!(sent.bytes_in, handled.bytes_out);
+        assert_eq!(sent.bytes_out, handled.bytes_in);
+        assert_eq!(sent.latency.buckets().map(|(_, n)| n).sum::<u64>(), 2);
+    });
+}
+
+#[test]
+fn test_send_async() {
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let mut server_reg = RpcRegister::new();
+    {
+        let events = events.clone();
+        Notify::implement(&mut server_reg, IFACE, move |_ic, arg| {
+            events.borrow_mut().push(arg.event);
+            async { Ok(()) }
+        });
+    }
+    GetUser::implement(&mut server_reg, IFACE, |_ic, _arg| async move {
+        Ok(GetUserRes {
+            firstname: "Dio".to_owned(),
+            middlename: None,
+            lastname: "Brando".to_owned(),
+        })
+    });
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(server_reg.build()), None).unwrap();
+
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        Notify::send(
+            &mut channel,
+            IFACE,
+            NotifyArg {
+                event: "za warudo".to_owned(),
+            },
+        )
+        .unwrap();
+
+        // queries are handled in order, so the notification is received
+        // once this one is answered
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 0 }).await;
+        assert!(res.unwrap().firstname == "Dio");
+        assert_eq!(*events.borrow(), vec!["za warudo".to_owned()]);
+    });
+}
+
+#[test]
+fn test_backpressure() {
+    use futures::future::join_all;
+    use ic::ic::Watermarks;
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let mut server_reg = RpcRegister::new();
+    GetUser::implement(&mut server_reg, IFACE, |_ic, _arg| async move {
+        Ok(GetUserRes {
+            firstname: "Giorno".to_owned(),
+            middlename: None,
+            lastname: "Giovanna".to_owned(),
+        })
+    });
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(server_reg.build()), None).unwrap();
+
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        assert!(channel.is_writable());
+        assert_eq!(channel.queue_state().pending_queries, 0);
+
+        channel.set_watermarks(Watermarks {
+            msgs: 0,
+            bytes: 0,
+            ..Watermarks::default()
+        });
+        assert!(!channel.is_writable());
+
+        // the queries are rejected above the hard watermarks
+        channel.set_watermarks(Watermarks {
+            hard_msgs: Some(0),
+            ..Watermarks::default()
+        });
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 0 }).await;
+        assert!(matches!(res, Err(error::Error::Retry)));
+
+        // send queries by bursts, waiting for the queue to be flushed
+        channel.set_watermarks(Watermarks {
+            msgs: 4,
+            bytes: 4096,
+            ..Watermarks::default()
+        });
+        let mut queries = Vec::new();
+        for _ in 0..32 {
+            channel.writable().await;
+            queries.push(GetUser::call(
+                &mut channel,
+                IFACE,
+                GetUserArg { user_id: 0 },
+            ));
+        }
+        for res in join_all(queries).await {
+            assert!(res.unwrap().firstname == "Giorno");
+        }
+        assert_eq!(channel.queue_state().pending_queries, 0);
+    });
+}
+
+#[test]
+fn test_payload_limits() {
+    use ic::ic::PayloadLimits;
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let make_reg = || {
+        let mut reg = RpcRegister::new();
+        GetUser::implement(&mut reg, IFACE, |_ic, _arg| async move {
+            Ok(GetUserRes {
+                firstname: "Bruno".to_owned(),
+                middlename: None,
+                lastname: "Bucciarati".to_owned(),
+            })
+        });
+        reg
+    };
+
+    el::exec_test_async(async move {
+        // the first server rejects the queries, the second one their replies
+        let mut server = Server::new("127.0.0.1:0", Some(make_reg().build()), None).unwrap();
+        server.set_payload_limits(PayloadLimits {
+            incoming: Some(2),
+            outgoing: None,
+        });
+        let mut server2 = Server::new("127.0.0.1:0", Some(make_reg().build()), None).unwrap();
+        server2.set_payload_limits(PayloadLimits {
+            incoming: None,
+            outgoing: Some(4),
+        });
+
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+        let mut client2 = Client::new(None);
+        let connected = client2
+            .connect_once(&server2.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let arg = || GetUserArg { user_id: 1000 };
+
+        let mut channel = client.get_channel();
+        let res = GetUser::call(&mut channel, IFACE, arg()).await;
+        assert!(matches!(res, Err(error::Error::Invalid)));
+
+        client.set_payload_limits(PayloadLimits {
+            incoming: None,
+            outgoing: Some(2),
+        });
+        let res = GetUser::call(&mut channel, IFACE, arg()).await;
+        assert!(matches!(res, Err(error::Error::PayloadTooLarge(_))));
+
+        let mut channel2 = client2.get_channel();
+        let res = GetUser::call(&mut channel2, IFACE, arg()).await;
+        assert!(matches!(res, Err(error::Error::ServerError)));
+    });
+}
+
+#[test]
+fn test_compression() {
+    use ic::compress::Compression;
+    use ic::ic::Direction;
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let mut server_reg = RpcRegister::new();
+    GetUser::implement(&mut server_reg, IFACE, |_ic, arg| async move {
+        Ok(GetUserRes {
+            firstname: "ora".repeat(arg.user_id as usize),
+            middlename: None,
+            lastname: "Kujo".to_owned(),
+        })
+    });
+
+    el::exec_test_async(async move {
+        let compression = Compression {
+            threshold: 64,
+            ..Compression::default()
+        };
+        let mut server = Server::new("127.0.0.1:0", Some(server_reg.build()), None).unwrap();
+        server.set_compression(Some(compression));
+
+        // sizes of the answers received
+        let sizes = Rc::new(RefCell::new(Vec::new()));
+        let mut client = Client::new(None);
+        client.set_compression(Some(compression));
+        {
+            let sizes = sizes.clone();
+            client.on_wire(move |dir, _cmd, _slot, data| {
+                if dir == Direction::Received {
+                    sizes.borrow_mut().push(data.len());
+                }
+            });
+        }
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 2 }).await;
+        assert_eq!(res.unwrap().firstname, "oraora");
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 1000 }).await;
+        assert_eq!(res.unwrap().firstname.len(), 3000);
+
+        // only the last answer is above the threshold, and compressed
+        let sizes = sizes.borrow();
+        assert_eq!(sizes.len(), 2);
+        assert!(sizes[0] < 64);
+        assert!(sizes[1] < 3000);
+    });
+}
+
+#[test]
+fn test_client_pool() {
+    use ic::pool::{Balancing, ClientPool};
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    fn register(name: &'static str) -> RpcRegister {
+        let mut reg = RpcRegister::new();
+        GetUser::implement(&mut reg, IFACE, move |_ic, _arg| async move {
+            Ok(GetUserRes {
+                firstname: name.to_owned(),
+                middlename: None,
+                lastname: "Joestar".to_owned(),
+            })
+        });
+        reg
+    }
+
+    el::exec_test_async(async move {
+        let server1 = Server::new("127.0.0.1:0", Some(register("Jonathan").build()), None).unwrap();
+        let server2 = Server::new("127.0.0.1:0", Some(register("Joseph").build()), None).unwrap();
+
+        let mut pool = ClientPool::new(None, Balancing::RoundRobin);
+        pool.add(&server1.local_addr().to_string(), None).unwrap();
+        pool.add(&server2.local_addr().to_string(), None).unwrap();
+        while pool.healthy().len() < 2 {
+            el::el_future::Timer::new(1, 0).await.await;
+        }
+
+        let mut names = Vec::new();
+        for _ in 0..4 {
+            let res = pool.call::<GetUser>(IFACE, GetUserArg { user_id: 0 }).await;
+            names.push(res.unwrap().firstname);
+        }
+        assert_eq!(names, ["Jonathan", "Joseph", "Jonathan", "Joseph"]);
+
+        // the queries are only sent to the remaining server
+        drop(server1);
+        while pool.healthy().len() > 1 {
+            el::el_future::Timer::new(1, 0).await.await;
+        }
+        assert_eq!(pool.healthy(), [server2.local_addr().to_string()]);
+        for _ in 0..2 {
+            let res = pool.call::<GetUser>(IFACE, GetUserArg { user_id: 0 }).await;
+            assert_eq!(res.unwrap().firstname, "Joseph");
+        }
+    });
+}
+
+#[test]
+fn test_proxy() {
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let mut backend_reg = RpcRegister::new();
+    GetUser::implement(&mut backend_reg, IFACE, |_ic, arg| async move {
+        Ok(GetUserRes {
+            firstname: format!("user {}", arg.user_id),
+            middlename: None,
+            lastname: "backend".to_owned(),
+        })
+    });
+
+    el::exec_test_async(async move {
+        let backend = Server::new("127.0.0.1:0", Some(backend_reg.build()), None).unwrap();
+
+        let mut backend_client = Client::new(None);
+        let connected = backend_client
+            .connect_once(&backend.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        // the gateway forwards every RPC of the interface to the backend
+        let mut gateway_reg = RpcRegister::new();
+        let cmds = (IFACE as i32) << 16..((IFACE as i32) + 1) << 16;
+        assert!(gateway_reg.proxy(cmds, &backend_client.get_channel()));
+        assert!(!gateway_reg.proxy(vec![GetUser::get_cmd(IFACE)], &backend_client.get_channel()));
+        let gateway = Server::new("127.0.0.1:0", Some(gateway_reg.build()), None).unwrap();
+
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&gateway.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 4 }).await;
+        assert_eq!(res.unwrap().firstname, "user 4");
+
+        // unimplemented RPCs are answered by the backend
+        let res = SayHello::call(&mut channel, IFACE, SayHelloArg { user_id: 4 }).await;
+        assert!(matches!(res, Err(error::Error::Unimplemented)));
+    });
+}
+
+#[test]
+fn test_http() {
+    use ic::http::{HttpClient, HttpServer};
+    use iop_module::IFACE;
+
+    struct DeleteUser {}
+
+    impl Rpc for DeleteUser {
+        type Input = GetUserArg;
+        type Output = ();
+        type Exception = ();
+
+        const TAG: u16 = 5;
+        const ASYNC: bool = false;
+        const NAME: Option<&'static str> = Some("tst.User.deleteUser");
+    }
+
+    let _m = ic::use_module();
+
+    let mut reg = RpcRegister::new();
+    GetUser::implement(&mut reg, IFACE, |_ic, arg| async move {
+        match arg.user_id {
+            0 => Ok(GetUserRes {
+                firstname: "Jotaro".to_owned(),
+                middlename: None,
+                lastname: "Kujo".to_owned(),
+            }),
+            _ => Err(error::Error::Exn(GetUserExn {
+                error: format!("unknown user with id {}", arg.user_id),
+            })),
+        }
+    });
+
+    el::exec_test_async(async move {
+        let server = HttpServer::new("127.0.0.1:0", "/iop", &reg.build()).unwrap();
+        let url = format!("http://{}/iop", server.local_addr());
+        let client = HttpClient::new(&url).unwrap();
+
+        let res = client.call::<GetUser>(GetUserArg { user_id: 0 }).await;
+        assert_eq!(res.unwrap().lastname, "Kujo");
+
+        let res = client.call::<GetUser>(GetUserArg { user_id: 1 }).await;
+        assert!(matches!(res, Err(error::Error::Exn(e)) if e.error == "unknown user with id 1"));
+
+        // RPCs not implemented, or without name
+        let res = client.call::<DeleteUser>(GetUserArg { user_id: 0 }).await;
+        assert!(matches!(res, Err(error::Error::Unimplemented)));
+        let res = client.call::<SayHello>(SayHelloArg { user_id: 0 }).await;
+        assert!(matches!(res, Err(error::Error::Unimplemented)));
+
+        // the queries are only posted
+        let answer = raw_http(
+            server.local_addr(),
+            b"GET /iop/tst.User/getUser HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert!(answer.starts_with("HTTP/1.1 405 "));
+
+        // nothing listens anymore once the server is dropped
+        drop(server);
+        let res = client.call::<GetUser>(GetUserArg { user_id: 0 }).await;
+        assert!(matches!(res, Err(error::Error::Generic(_))));
+    });
+}
+
+/// Send `request` on a connection of its own and read the answer until the
+/// server closes it, from a thread so that the server keeps running.
+async fn raw_http(addr: std::net::SocketAddr, request: &'static [u8]) -> String {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let peer = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut answer = String::new();
+
+        stream.write_all(request).unwrap();
+        stream.read_to_string(&mut answer).unwrap();
+        answer
+    });
+    while !peer.is_finished() {
+        el::el_future::Timer::new(1, 0).await.await;
+    }
+    peer.join().unwrap()
+}
+
+#[test]
+fn test_http_limits() {
+    use ic::http::{HttpClient, HttpLimits, HttpServer};
+    use iop_module::IFACE;
+    use std::io::Read;
+    use std::net::TcpStream;
+
+    let _m = ic::use_module();
+
+    let mut reg = RpcRegister::new();
+    GetUser::implement(&mut reg, IFACE, |_ic, _arg| async move {
+        Ok(GetUserRes {
+            firstname: "Jolyne".to_owned(),
+            middlename: None,
+            lastname: "Cujoh".to_owned(),
+        })
+    });
+
+    el::exec_test_async(async move {
+        let mut server = HttpServer::new("127.0.0.1:0", "/iop", &reg.build()).unwrap();
+        let addr = server.local_addr();
+        server.set_limits(HttpLimits {
+            max_connections: 1,
+            timeout: Duration::from_millis(100),
+            idle_timeout: Duration::from_millis(200),
+        });
+
+        // connections over the cap are refused
+        let idle = TcpStream::connect(addr).unwrap();
+        el::el_future::Timer::new(20, 0).await.await;
+        let answer = raw_http(addr, b"").await;
+        assert!(answer.starts_with("HTTP/1.1 503 "));
+
+        // and idle connections are closed
+        let start = Instant::now();
+        let peer = std::thread::spawn(move || {
+            let mut buf = Vec::new();
+
+            (&idle).read_to_end(&mut buf).unwrap();
+            buf
+        });
+        while !peer.is_finished() {
+            el::el_future::Timer::new(1, 0).await.await;
+        }
+        assert!(peer.join().unwrap().is_empty());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // as are the connections of requests not sent in time
+        let answer = raw_http(addr, b"POST /iop/tst.User/getUser HTTP/1.1\r\n").await;
+        assert!(answer.starts_with("HTTP/1.1 408 "));
+
+        // the server still answers once they are closed
+        let client = HttpClient::new(&format!("http://{}/iop", addr)).unwrap();
+        let res = client.call::<GetUser>(GetUserArg { user_id: 0 }).await;
+        assert_eq!(res.unwrap().lastname, "Cujoh");
+    });
+}
+
+#[test]
+fn test_http_admission() {
+    use futures::future;
+    use ic::acl::Acl;
+    use ic::http::{HttpClient, HttpLimits};
+    use ic::ic::{Channel, PayloadLimits};
+    use ic::ratelimit::{RateLimit, RateLimits};
+    use iop_module::IFACE;
+    use libcommon_sys as sys;
+
+    let _m = ic::use_module();
+
+    // the middleware counts the queries reaching the implementations
+    let nb_queries = Rc::new(Cell::new(0));
+    let mut reg = RpcRegister::new();
+    GetUser::implement(&mut reg, IFACE, |_ic, _arg| {
+        future::ready(Ok(GetUserRes {
+            firstname: "Bruno".to_owned(),
+            middlename: None,
+            lastname: "Bucciarati".to_owned(),
+        }))
+    });
+    {
+        let nb_queries = nb_queries.clone();
+        reg.wrap(move |next, _ic, _hdr, _cmd, _data| {
+            nb_queries.set(nb_queries.get() + 1);
+            next()
+        });
+    }
+
+    let mut acl = Acl::new();
+    acl.allow::<GetUser>(Some("admin"), IFACE);
+    let mut limits = RateLimits::default();
+    limits
+        .cmds
+        .insert(GetUser::get_cmd(IFACE), RateLimit { rate: 1, burst: 5 });
+
+    el::exec_test_async(async move {
+        let mut server = Server::new("127.0.0.1:0", Some(reg.build()), None).unwrap();
+        server.set_authenticator(|_ic: &Channel, hdr: &QueryHeader, _cmd| {
+            match (hdr.login.as_deref(), hdr.password.as_deref()) {
+                (Some("bruno"), Some("zipper")) => Ok(()),
+                _ => Err(sys::ic_status_t_IC_MSG_ABORT),
+            }
+        });
+        server.set_acl(Some(acl));
+        server.set_rate_limits(Some(limits));
+        server.set_payload_limits(PayloadLimits {
+            incoming: Some(64),
+            outgoing: None,
+        });
+        let addr = server
+            .listen_http("127.0.0.1:0", "/iop", HttpLimits::default())
+            .unwrap();
+        let client = HttpClient::new(&format!("http://{}/iop", addr)).unwrap();
+
+        // the queries are authenticated, then checked by the ACL
+        let res = client.call::<GetUser>(GetUserArg { user_id: 0 }).await;
+        assert!(matches!(res, Err(error::Error::Abort)));
+        let hdr = QueryHeader::with_login("bruno", "zipper");
+        let res = client
+            .call_with_hdr::<GetUser>(&hdr, GetUserArg { user_id: 0 })
+            .await;
+        assert!(matches!(res, Err(error::Error::Abort)));
+        assert_eq!(nb_queries.get(), 0);
+
+        let hdr = QueryHeader {
+            group: Some("admin".to_owned()),
+            ..hdr
+        };
+        let res = client
+            .call_with_hdr::<GetUser>(&hdr, GetUserArg { user_id: 0 })
+            .await;
+        assert_eq!(res.unwrap().lastname, "Bucciarati");
+        assert_eq!(nb_queries.get(), 1);
+
+        // and share the rate limits of one channel
+        for _ in 0..2 {
+            let res = client
+                .call_with_hdr::<GetUser>(&hdr, GetUserArg { user_id: 0 })
+                .await;
+            assert!(res.is_ok());
+        }
+        let res = client
+            .call_with_hdr::<GetUser>(&hdr, GetUserArg { user_id: 0 })
+            .await;
+        assert!(matches!(res, Err(error::Error::Retry)));
+        assert_eq!(nb_queries.get(), 3);
+
+        // the bodies above the payload limits are not read
+        let answer = raw_http(
+            addr,
+            b"POST /iop/tst.User/getUser HTTP/1.1\r\nContent-Length: 1024\r\n\r\n",
+        )
+        .await;
+        assert!(answer.starts_with("HTTP/1.1 413 "));
+
+        // the queries are rejected once the server shuts down
+        assert!(server.shutdown(Duration::from_millis(100)).await);
+        let hdr = QueryHeader {
+            login: Some("bruno".to_owned()),
+            ..QueryHeader::default()
+        };
+        let res = client
+            .call_with_hdr::<GetUser>(&hdr, GetUserArg { user_id: 0 })
+            .await;
+        assert!(matches!(res, Err(error::Error::Retry)));
+    });
+}
+
+#[test]
+fn test_reply_token() {
+    use ic::ic::ReplyToken;
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let tokens: Rc<RefCell<Vec<ReplyToken<GetUserRes, GetUserExn>>>> = Default::default();
+    let mut server_reg = RpcRegister::new();
+    {
+        let tokens = tokens.clone();
+        GetUser::implement(&mut server_reg, IFACE, move |mut ic, arg| {
+            let token = ic.take_reply_token().unwrap();
+            assert!(ic.take_reply_token::<GetUserRes, GetUserExn>().is_none());
+
+            // the token of the first user is dropped without replying
+            if arg.user_id > 0 {
+                tokens.borrow_mut().push(token);
+            }
+            async { Err(error::Error::Generic("ignored".to_owned())) }
+        });
+    }
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(server_reg.build()), None).unwrap();
+
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+        assert!(client.get_channel().take_reply_token::<(), ()>().is_none());
+
+        {
+            let tokens = tokens.clone();
+            el::el_future::spawn(async move {
+                while tokens.borrow().is_empty() {
+                    el::el_future::Timer::new(1, 0).await.await;
+                }
+                let token = tokens.borrow_mut().pop().unwrap();
+
+                token.reply(Ok(GetUserRes {
+                    firstname: "Jotaro".to_owned(),
+                    middlename: None,
+                    lastname: "Kujo".to_owned(),
+                }));
+            });
+        }
+
+        let mut channel = client.get_channel();
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 3 }).await;
+        assert_eq!(res.unwrap().firstname, "Jotaro");
+
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 0 }).await;
+        assert!(matches!(res, Err(error::Error::ServerError)));
+    });
+}
+
+#[test]
+fn test_loopback() {
+    use futures::executor::block_on;
+    use ic::ic::Channel;
+    use iop_module::IFACE;
+
+    let mut reg = RpcRegister::new();
+    GetUser::implement(&mut reg, IFACE, |_ic, arg| async move {
+        if arg.user_id == 0 {
+            return Err(error::Error::Exn(GetUserExn {
+                error: "unknown user".to_owned(),
+            }));
+        }
+        Ok(GetUserRes {
+            firstname: "Giorno".to_owned(),
+            middlename: None,
+            lastname: "Giovanna".to_owned(),
+        })
+    });
+    // queries sent by the implementations are looped back too
+    SayHello::implement(&mut reg, IFACE, |mut ic, arg| async move {
+        let user = GetUser::call(
+            &mut ic,
+            IFACE,
+            GetUserArg {
+                user_id: arg.user_id,
+            },
+        )
+        .await?;
+
+        Ok(SayHelloRes {
+            result: format!("Hello {} {}", user.firstname, user.lastname),
+        })
+    });
+    let reg = reg.build();
+
+    // neither lib-common nor the event loop are needed
+    let mut channel = Channel::loopback(&reg);
+    assert!(channel.is_loopback());
+
+    let res = block_on(SayHello::call(
+        &mut channel,
+        IFACE,
+        SayHelloArg { user_id: 1 },
+    ));
+    assert_eq!(res.unwrap().result, "Hello Giorno Giovanna");
+
+    let res = block_on(GetUser::call(
+        &mut channel,
+        IFACE,
+        GetUserArg { user_id: 0 },
+    ));
+    match res {
+        Err(error::Error::Exn(exn)) => assert_eq!(exn.error, "unknown user"),
+        _ => panic!("expected an exception"),
+    }
+
+    let res = block_on(Notify::call(
+        &mut channel,
+        IFACE,
+        NotifyArg {
+            event: "muda".to_owned(),
+        },
+    ));
+    assert!(matches!(res, Err(error::Error::Unimplemented)));
+}
+
+#[test]
+fn test_service() {
+    use futures::executor::block_on;
+    use ic::ic::Channel;
+    use ic::types::Service;
+    use iop_module::IFACE;
+
+    #[derive(Default)]
+    struct UserService {
+        calls: Cell<u32>,
+    }
+
+    #[ic::service]
+    impl UserService {
+        #[rpc(GetUser)]
+        async fn get_user(
+            &self,
+            _ic: Channel,
+            arg: GetUserArg,
+        ) -> Result<GetUserRes, error::Error<GetUserExn>> {
+            self.calls.set(self.calls.get() + 1);
+            Ok(GetUserRes {
+                firstname: format!("user {}", arg.user_id),
+                middlename: None,
+                lastname: "service".to_owned(),
+            })
+        }
+
+        #[rpc(SayHello)]
+        async fn say_hello(
+            &self,
+            mut ic: Channel,
+            hdr: QueryHeader,
+            arg: SayHelloArg,
+        ) -> Result<SayHelloRes, error::Error<GetUserExn>> {
+            let user = GetUser::call(
+                &mut ic,
+                IFACE,
+                GetUserArg {
+                    user_id: arg.user_id,
+                },
+            )
+            .await?;
+
+            Ok(SayHelloRes {
+                result: format!("Hello {} from {:?}", user.firstname, hdr.login),
+            })
+        }
+    }
+
+    let service = Rc::new(UserService::default());
+    let mut reg = RpcRegister::new();
+    service.clone().implement(&mut reg, IFACE);
+    let reg = reg.build();
+
+    let mut channel = Channel::loopback(&reg);
+    let hdr = QueryHeader {
+        login: Some("dio".to_owned()),
+        ..QueryHeader::default()
+    };
+    let res = block_on(SayHello::call_with_hdr(
+        &mut channel,
+        IFACE,
+        &hdr,
+        SayHelloArg { user_id: 7 },
+    ));
+    assert_eq!(res.unwrap().result, "Hello user 7 from Some(\"dio\")");
+    assert_eq!(service.calls.get(), 1);
+}
+
+#[test]
+fn test_reflection() {
+    use futures::executor::block_on;
+    use ic::ic::Channel;
+    use ic::reflect::{ListRpcs, RpcDesc};
+    use iop_module::IFACE;
+
+    let mut reg = RpcRegister::new();
+    GetUser::implement(&mut reg, IFACE, |_ic, _arg| async move {
+        Err(error::Error::Unimplemented)
+    });
+    SayHello::implement(&mut reg, IFACE, |_ic, _arg| async move {
+        Err(error::Error::Unimplemented)
+    });
+    reg.expose_reflection(42);
+    assert!(reg.unregister(SayHello::get_cmd(IFACE)));
+    let reg = reg.build();
+
+    let expected = vec![
+        RpcDesc {
+            iface_tag: IFACE,
+            rpc_tag: 2,
+            name: Some("tst.User.getUser".to_owned()),
+            proxied: false,
+        },
+        RpcDesc {
+            iface_tag: 42,
+            rpc_tag: 1,
+            name: Some("ic.Reflection.listRpcs".to_owned()),
+            proxied: false,
+        },
+    ];
+    assert_eq!(reg.rpcs(), expected);
+    assert_eq!(expected[1].cmd(), ListRpcs::get_cmd(42));
+
+    // the peers of the register get the same list
+    let mut channel = Channel::loopback(&reg);
+    let res = block_on(ListRpcs::call(&mut channel, 42, ()));
+    assert_eq!(res.unwrap().rpcs, expected);
+}
+
+#[test]
+fn test_ping() {
+    use futures::future::{self, FutureExt};
+    use ic::keepalive::Keepalive;
+    use libcommon_sys as sys;
+    use std::net::TcpListener;
+
+    let _m = ic::use_module();
+
+    // pings do not go through the middlewares
+    let mut reg = RpcRegister::new();
+    reg.wrap(|_next, _ic, _hdr, _cmd, _data| {
+        future::ready(sys::ic_status_t_IC_MSG_INVALID).boxed_local()
+    });
+
+    el::exec_test_async(async move {
+        for reg in [Some(reg.build()), None] {
+            let server = Server::new("127.0.0.1:0", reg, None).unwrap();
+            let mut client = Client::new(None);
+            let connected = client
+                .connect_once(&server.local_addr().to_string(), None)
+                .unwrap()
+                .await;
+            assert!(connected);
+
+            let rtt = client.get_channel().ping(Duration::from_secs(1)).await;
+            assert!(rtt.unwrap() < Duration::from_secs(1));
+        }
+
+        // a peer accepting the connection without ever answering is
+        // disconnected, and the client reconnects
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let mut client = Client::new(None);
+        client.set_keepalive(Some(Keepalive {
+            interval: Duration::from_millis(20),
+            max_misses: 2,
+        }));
+        let connected = client
+            .connect_once(&listener.local_addr().unwrap().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut accepted = Vec::new();
+        while accepted.len() < 2 {
+            assert!(Instant::now() < deadline, "the client did not reconnect");
+            if let Ok((stream, _)) = listener.accept() {
+                accepted.push(stream);
+            }
+            el::el_future::Timer::new(5, 0).await.await;
+        }
+    });
+}
+
+#[test]
+fn test_connection_state() {
+    use futures::executor::block_on;
+    use ic::ic::{Channel, ConnectionState};
+
+    let _m = ic::use_module();
+
+    let loopback = Channel::loopback(&RpcRegister::new().build());
+    assert_eq!(loopback.state(), ConnectionState::Connected);
+    block_on(loopback.connected());
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", None, None).unwrap();
+        let mut client = Client::new(None);
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+        assert!(!client.get_channel().is_connected());
+
+        let connect = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap();
+        client.connected().await;
+        assert_eq!(client.state(), ConnectionState::Connected);
+        assert!(connect.await);
+
+        let channel = client.get_channel();
+        assert!(channel.is_connected());
+        channel.connected().await;
+
+        drop(server);
+        channel.disconnected().await;
+        assert_eq!(channel.state(), ConnectionState::Disconnected);
+        assert!(!client.is_connected());
+    });
+}
+
+#[test]
+fn test_extensions() {
+    use futures::future;
+    use ic::ic::Channel;
+    use iop_module::IFACE;
+
+    struct Session {
+        login: String,
+        queries: u32,
+    }
+
+    let _m = ic::use_module();
+
+    // the middleware opens a session on the first query of a connection
+    let mut reg = RpcRegister::new();
+    reg.wrap(|next, ic, hdr, _cmd, _data| {
+        let mut extensions = ic.extensions_mut();
+        let session = extensions.get_or_insert_with(|| Session {
+            login: hdr.login.clone().unwrap_or_default(),
+            queries: 0,
+        });
+
+        session.queries += 1;
+        drop(extensions);
+        next()
+    });
+    GetUser::implement(&mut reg, IFACE, |ic, _arg| {
+        let extensions = ic.extensions();
+        let session = extensions.get::<Session>().unwrap();
+
+        future::ready(Ok(GetUserRes {
+            firstname: session.login.clone(),
+            middlename: None,
+            lastname: format!("query {}", session.queries),
+        }))
+    });
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(reg.build()), None).unwrap();
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let hdr = QueryHeader {
+            login: Some("jotaro".to_owned()),
+            ..QueryHeader::default()
+        };
+        let mut channel = client.get_channel();
+        for i in 1..=2 {
+            let res = GetUser::call_with_hdr(&mut channel, IFACE, &hdr, GetUserArg { user_id: 0 })
+                .await
+                .unwrap();
+            assert_eq!(res.firstname, "jotaro");
+            assert_eq!(res.lastname, format!("query {}", i));
+        }
+        assert!(channel.extensions().is_empty());
+    });
+
+    let loopback = Channel::loopback(&RpcRegister::new().build());
+    assert_eq!(loopback.extensions_mut().insert(3u32), None);
+    assert_eq!(loopback.extensions_mut().insert(4u32), Some(3));
+    assert_eq!(loopback.extensions().get::<u32>(), Some(&4));
+    assert_eq!(loopback.extensions_mut().remove::<u32>(), Some(4));
+    assert!(!loopback.extensions().contains::<u32>());
+}
+
+#[test]
+fn test_shutdown() {
+    use futures::future;
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    // the answer is delayed by `user_id` milliseconds, and never sent for 0
+    let register = || {
+        let mut reg = RpcRegister::new();
+
+        GetUser::implement(&mut reg, IFACE, |_ic, arg| async move {
+            if arg.user_id == 0 {
+                future::pending::<()>().await;
+            }
+            el::el_future::Timer::new(arg.user_id.into(), 0).await.await;
+            Ok(GetUserRes {
+                firstname: "Bruno".to_owned(),
+                middlename: None,
+                lastname: "Bucciarati".to_owned(),
+            })
+        });
+        reg
+    };
+
+    el::exec_test_async(async move {
+        let mut server = Server::new("127.0.0.1:0", Some(register().build()), None).unwrap();
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        // the query being handled is answered, the one received during the
+        // shutdown is rejected
+        let mut channel = client.get_channel();
+        let query = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 50 });
+        el::el_future::Timer::new(10, 0).await.await;
+
+        let start = Instant::now();
+        let (drained, res, rejected) =
+            futures::join!(server.shutdown(Duration::from_secs(1)), query, async {
+                el::el_future::Timer::new(5, 0).await.await;
+                GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 1 }).await
+            });
+        assert!(drained);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(res.unwrap().lastname, "Bucciarati");
+        assert!(matches!(rejected, Err(error::Error::Retry)));
+
+        // the queries not answered after the grace period are abandoned
+        let mut server = Server::new("127.0.0.1:0", Some(register().build()), None).unwrap();
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let _query = GetUser::call(&mut client.get_channel(), IFACE, GetUserArg { user_id: 0 });
+        el::el_future::Timer::new(10, 0).await.await;
+        assert!(!server.shutdown(Duration::from_millis(20)).await);
+    });
+}
+
+#[test]
+fn test_connection_events() {
+    use futures::executor::block_on;
+    use futures::StreamExt;
+    use ic::ic::{Channel, ConnectionEvent};
+
+    let _m = ic::use_module();
+
+    let loopback = Channel::loopback(&RpcRegister::new().build());
+    assert_eq!(block_on(loopback.events().next()), None);
+
+    el::exec_test_async(async move {
+        let mut server = Server::new("127.0.0.1:0", None, None).unwrap();
+        let accepted = Rc::new(RefCell::new(None));
+        let accepted2 = accepted.clone();
+        server.on_client_connected(move |_, channel, _| {
+            *accepted2.borrow_mut() = Some(channel.events());
+        });
+
+        let mut client = Client::new(None);
+        let mut events = client.events();
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+        assert_eq!(events.next().await, Some(ConnectionEvent::Connected));
+
+        let mut server_events = loop {
+            if let Some(events) = accepted.borrow_mut().take() {
+                break events;
+            }
+            el::el_future::Timer::new(1, 0).await.await;
+        };
+
+        // the channel accepted by the server is closed once the client is
+        // disconnected, ending its stream
+        client.disconnect();
+        assert_eq!(events.next().await, Some(ConnectionEvent::Disconnected));
+        assert_eq!(
+            server_events.next().await,
+            Some(ConnectionEvent::Disconnected)
+        );
+        assert_eq!(server_events.next().await, None);
+
+        drop(client);
+        while events.next().await.is_some() {}
+    });
+}
+
+#[test]
+fn test_connect_with() {
+    use ic::connect::{ConnectError, ConnectOptions};
+    use std::net::TcpListener;
+
+    let _m = ic::use_module();
+
+    let options = ConnectOptions {
+        deadline: Duration::from_millis(200),
+        retry_interval: Duration::from_millis(20),
+    };
+
+    el::exec_test_async(async move {
+        let mut client = Client::new(None);
+        let res = client.connect_with("127.0.0.1", None, options).await;
+        assert_eq!(res, Err(ConnectError::InvalidAddr("127.0.0.1".to_owned())));
+
+        // nothing listens on the port until the server is started
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let start = Instant::now();
+        let res = client.connect_with(&addr, None, options).await;
+        assert_eq!(res, Err(ConnectError::Refused));
+        assert!(start.elapsed() >= options.deadline);
+
+        let server = Rc::new(RefCell::new(None));
+        let server2 = server.clone();
+        let addr2 = addr.clone();
+        el::el_future::spawn(async move {
+            el::el_future::Timer::new(50, 0).await.await;
+            *server2.borrow_mut() = Some(Server::new(&addr2, None, None).unwrap());
+        });
+
+        let mut client = Client::new(None);
+        client.connect_with(&addr, None, options).await.unwrap();
+        assert!(client.is_connected());
+        assert!(server.borrow().is_some());
+    });
+}
+
+#[test]
+fn test_wiped_channel() {
+    use futures::future::{self, FutureExt};
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let mut server_reg = RpcRegister::new();
+    GetUser::implement(&mut server_reg, IFACE, |_ic, _arg| future::pending());
+
+    // the queries of the client are delayed before being sent
+    let mut client_reg = RpcRegister::new();
+    client_reg.wrap_calls(|next, _ic, _hdr, _cmd, _data| {
+        async move {
+            el::el_future::Timer::new(50, 0).await.await;
+            next().await
+        }
+        .boxed_local()
+    });
+    let client_reg = client_reg.build();
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(server_reg.build()), None).unwrap();
+
+        for reg in [None, Some(&client_reg)] {
+            let mut client = Client::new(reg);
+            let connected = client
+                .connect_once(&server.local_addr().to_string(), None)
+                .unwrap()
+                .await;
+            assert!(connected);
+
+            // the queries waiting for their answer or their middlewares are
+            // canceled with the client
+            let query = GetUser::call(&mut client.get_channel(), IFACE, GetUserArg { user_id: 0 });
+            el::el_future::Timer::new(10, 0).await.await;
+            drop(client);
+            assert!(matches!(query.await, Err(error::Error::Canceled)));
+        }
+        el::el_future::Timer::new(50, 0).await.await;
+    });
+}
+
+#[test]
+fn test_connection_count() {
+    let _m = ic::use_module();
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", None, None).unwrap();
+        let addr = server.local_addr().to_string();
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let mut client = Client::new(None);
+
+            assert!(client.connect_once(&addr, None).unwrap().await);
+            clients.push(client);
+        }
+        while server.connection_count() < 3 {
+            el::el_future::Timer::new(1, 0).await.await;
+        }
+
+        // the channels of the disconnected clients are dropped by the server
+        clients.truncate(1);
+        while server.connection_count() > 1 {
+            el::el_future::Timer::new(1, 0).await.await;
+        }
+        assert_eq!(server.peers().len(), 1);
+
+        drop(clients);
+        while server.connection_count() > 0 {
+            el::el_future::Timer::new(1, 0).await.await;
+        }
+        el::el_future::Timer::new(10, 0).await.await;
+    });
+}
+
+#[test]
+fn test_socket_options() {
+    use ic::socket::{ClientOptions, ServerOptions, TcpKeepalive};
+
+    let _m = ic::use_module();
+
+    let options = ClientOptions {
+        nodelay: true,
+        tcp_keepalive: Some(TcpKeepalive::default()),
+    };
+
+    el::exec_test_async(async move {
+        let mut server = Server::new("127.0.0.1:0", None, None).unwrap();
+        server
+            .set_socket_options(ServerOptions {
+                backlog: Some(16),
+                clients: options.clone(),
+                ..ServerOptions::default()
+            })
+            .unwrap();
+
+        let mut client = Client::new(None);
+        client.set_socket_options(options.clone()).unwrap();
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        // the options can be changed once connected
+        client.set_socket_options(ClientOptions::default()).unwrap();
+        let rtt = client.get_channel().ping(Duration::from_secs(1)).await;
+        assert!(rtt.is_ok());
+    });
+}
+
+#[test]
+fn test_authenticator() {
+    use futures::future;
+    use ic::ic::Channel;
+    use iop_module::IFACE;
+    use libcommon_sys as sys;
+
+    let _m = ic::use_module();
+
+    let mut reg = RpcRegister::new();
+    GetUser::implement(&mut reg, IFACE, |_ic, _arg| {
+        future::ready(Ok(GetUserRes {
+            firstname: "Narancia".to_owned(),
+            middlename: None,
+            lastname: "Ghirga".to_owned(),
+        }))
+    });
+
+    el::exec_test_async(async move {
+        let mut server = Server::new("127.0.0.1:0", Some(reg.build()), None).unwrap();
+        server.set_authenticator(|_ic: &Channel, hdr: &QueryHeader, _cmd| {
+            match (hdr.login.as_deref(), hdr.password.as_deref()) {
+                (Some("bruno"), Some("zipper")) => Ok(()),
+                _ => Err(sys::ic_status_t_IC_MSG_ABORT),
+            }
+        });
+
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 0 }).await;
+        assert!(matches!(res, Err(error::Error::Abort)));
+
+        let hdr = QueryHeader::with_login("bruno", "sticky");
+        let res =
+            GetUser::call_with_hdr(&mut channel, IFACE, &hdr, GetUserArg { user_id: 0 }).await;
+        assert!(matches!(res, Err(error::Error::Abort)));
+
+        let hdr = QueryHeader::with_login("bruno", "zipper");
+        let res =
+            GetUser::call_with_hdr(&mut channel, IFACE, &hdr, GetUserArg { user_id: 0 }).await;
+        assert_eq!(res.unwrap().lastname, "Ghirga");
+
+        // pings are not authenticated
+        assert!(channel.ping(Duration::from_secs(1)).await.is_ok());
+    });
+}
+
+#[test]
+fn test_acl() {
+    use futures::future;
+    use ic::acl::Acl;
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let mut acl = Acl::new();
+    acl.allow::<SayHello>(None, IFACE)
+        .allow::<GetUser>(Some("admin"), IFACE)
+        .allow_iface(Some("root"), IFACE);
+    assert!(acl.is_allowed(None, SayHello::get_cmd(IFACE)));
+    assert!(!acl.is_allowed(None, GetUser::get_cmd(IFACE)));
+    assert!(acl.is_allowed(Some("admin"), GetUser::get_cmd(IFACE)));
+    assert!(!acl.is_allowed(Some("admin"), Notify::get_cmd(IFACE)));
+    assert!(acl.is_allowed(Some("root"), Notify::get_cmd(IFACE)));
+    assert!(!acl.is_allowed(Some("root"), GetUser::get_cmd(IFACE + 1)));
+
+    let mut reg = RpcRegister::new();
+    SayHello::implement(&mut reg, IFACE, |_ic, _arg| {
+        future::ready(Ok(SayHelloRes {
+            result: "Hi".to_owned(),
+        }))
+    });
+    GetUser::implement(&mut reg, IFACE, |_ic, _arg| {
+        future::ready(Ok(GetUserRes {
+            firstname: "Guido".to_owned(),
+            middlename: None,
+            lastname: "Mista".to_owned(),
+        }))
+    });
+
+    el::exec_test_async(async move {
+        let mut server = Server::new("127.0.0.1:0", Some(reg.build()), None).unwrap();
+        server.set_acl(Some(acl));
+
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        let res = SayHello::call(&mut channel, IFACE, SayHelloArg { user_id: 0 }).await;
+        assert_eq!(res.unwrap().result, "Hi");
+
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 0 }).await;
+        assert!(matches!(res, Err(error::Error::Abort)));
+
+        let hdr = QueryHeader {
+            group: Some("admin".to_owned()),
+            ..QueryHeader::default()
+        };
+        let res =
+            GetUser::call_with_hdr(&mut channel, IFACE, &hdr, GetUserArg { user_id: 0 }).await;
+        assert_eq!(res.unwrap().lastname, "Mista");
+    });
+}
+
+#[test]
+fn test_rate_limits() {
+    use futures::future::{self, join_all};
+    use ic::ratelimit::{RateLimit, RateLimits};
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let mut reg = RpcRegister::new();
+    SayHello::implement(&mut reg, IFACE, |_ic, _arg| {
+        future::ready(Ok(SayHelloRes {
+            result: "Hi".to_owned(),
+        }))
+    });
+    GetUser::implement(&mut reg, IFACE, |_ic, _arg| {
+        future::ready(Ok(GetUserRes {
+            firstname: "Leone".to_owned(),
+            middlename: None,
+            lastname: "Abbacchio".to_owned(),
+        }))
+    });
+
+    let mut limits = RateLimits {
+        channel: Some(RateLimit { rate: 1, burst: 8 }),
+        ..RateLimits::default()
+    };
+    limits
+        .cmds
+        .insert(GetUser::get_cmd(IFACE), RateLimit { rate: 1, burst: 2 });
+
+    el::exec_test_async(async move {
+        let mut server = Server::new("127.0.0.1:0", Some(reg.build()), None).unwrap();
+        server.set_rate_limits(Some(limits));
+
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        // the burst of the command is consumed first, then the one of the
+        // channel
+        let mut channel = client.get_channel();
+        let queries = (0..4).map(|_| GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 0 }));
+        let res = join_all(queries.collect::<Vec<_>>()).await;
+        assert_eq!(res.iter().filter(|res| res.is_ok()).count(), 2);
+        assert!(matches!(res[3], Err(error::Error::Retry)));
+
+        let queries =
+            (0..8).map(|_| SayHello::call(&mut channel, IFACE, SayHelloArg { user_id: 0 }));
+        let res = join_all(queries.collect::<Vec<_>>()).await;
+        assert_eq!(res.iter().filter(|res| res.is_ok()).count(), 6);
+    });
+}
+
+#[test]
+fn test_retry_policy() {
+    use futures::future;
+    use ic::retry::RetryPolicy;
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    // the first queries fail with `Error::Retry`, or are never answered for
+    // the user 1
+    let calls = Rc::new(Cell::new(0));
+    let mut reg = RpcRegister::new();
+    {
+        let calls = calls.clone();
+
+        GetUser::implement(&mut reg, IFACE, move |_ic, arg| {
+            let calls = calls.clone();
+
+            async move {
+                calls.set(calls.get() + 1);
+                match (arg.user_id, calls.get()) {
+                    (0, 1..=2) => Err(error::Error::Retry),
+                    (1, 1) => future::pending().await,
+                    _ => Ok(GetUserRes {
+                        firstname: "Pannacotta".to_owned(),
+                        middlename: None,
+                        lastname: "Fugo".to_owned(),
+                    }),
+                }
+            }
+        });
+    }
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(reg.build()), None).unwrap();
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        let mut policy = RetryPolicy {
+            max_retries: 1,
+            backoff: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        let res = GetUser::call_with_retry(&mut channel, IFACE, GetUserArg { user_id: 0 }, &policy);
+        assert!(matches!(res.await, Err(error::Error::Retry)));
+        assert_eq!(calls.get(), 2);
+
+        calls.set(0);
+        policy.max_retries = 3;
+        let res = GetUser::call_with_retry(&mut channel, IFACE, GetUserArg { user_id: 0 }, &policy);
+        assert_eq!(res.await.unwrap().lastname, "Fugo");
+        assert_eq!(calls.get(), 3);
+
+        // the query not answered is sent again
+        calls.set(0);
+        policy.hedge_after = Some(Duration::from_millis(20));
+        let res = policy
+            .call::<GetUser>(&mut channel, IFACE, GetUserArg { user_id: 1 })
+            .await;
+        assert_eq!(res.unwrap().lastname, "Fugo");
+        assert_eq!(calls.get(), 2);
+    });
+}
+
+#[test]
+fn test_any_error() {
+    use error::{AnyError, Error, ErrorKind};
+    use libcommon_sys as sys;
+
+    // errors of RPCs with different exceptions are propagated with `?`
+    fn query(exn: bool) -> Result<(), AnyError> {
+        if exn {
+            Err(Error::Exn(GetUserExn {
+                error: "Zipper Man".to_owned(),
+            }))?;
+        }
+        Err(Error::<()>::PayloadTooLarge(42))?;
+        Ok(())
+    }
+
+    let err = query(true).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Exn);
+    assert_eq!(err.status(), sys::ic_status_t_IC_MSG_EXN);
+    assert_eq!(err.exn::<GetUserExn>().unwrap().error, "Zipper Man");
+    assert!(err.exn::<()>().is_none());
+    let err = err.downcast::<()>().unwrap_err();
+    match err.downcast::<GetUserExn>() {
+        Ok(Error::Exn(exn)) => assert_eq!(exn.error, "Zipper Man"),
+        _ => panic!("the exception should have been kept"),
+    }
+
+    // other errors can be downcast to any type
+    let err = query(false).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PayloadTooLarge);
+    assert!(err.exn::<GetUserExn>().is_none());
+    assert_eq!(err.to_string(), "query error: payload too large");
+    assert!(matches!(
+        err.downcast::<GetUserExn>(),
+        Ok(Error::PayloadTooLarge(42))
+    ));
+
+    let err = AnyError::from(sys::ic_status_t_IC_MSG_RETRY);
+    assert_eq!(err.kind(), ErrorKind::Retry);
+    let boxed: Box<dyn std::error::Error + Send + Sync> = err.into();
+    assert_eq!(boxed.to_string(), "query error: retry");
+}
+
+#[test]
+fn test_reply_statuses() {
+    use iop_module::IFACE;
+    use libcommon_sys as sys;
+
+    let _m = ic::use_module();
+
+    // each user gives a different error
+    let mut reg = RpcRegister::new();
+    GetUser::implement(&mut reg, IFACE, |_ic, arg| async move {
+        Err(match arg.user_id {
+            0 => error::Error::Exn(GetUserExn {
+                error: "Sticky Fingers".to_owned(),
+            }),
+            1 => error::Error::Retry,
+            2 => error::Error::Invalid,
+            3 => error::Error::Unimplemented,
+            4 => error::Error::Abort,
+            5 => error::Error::Generic("Moody Blues".to_owned()),
+            6 => error::Error::PayloadTooLarge(1 << 20),
+            _ => error::Error::Forbidden,
+        })
+    });
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(reg.build()), None).unwrap();
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        let expected = [
+            sys::ic_status_t_IC_MSG_EXN,
+            sys::ic_status_t_IC_MSG_RETRY,
+            sys::ic_status_t_IC_MSG_INVALID,
+            sys::ic_status_t_IC_MSG_UNIMPLEMENTED,
+            sys::ic_status_t_IC_MSG_ABORT,
+            sys::ic_status_t_IC_MSG_SERVER_ERROR,
+            sys::ic_status_t_IC_MSG_INVALID,
+            sys::ic_status_t_IC_MSG_ABORT,
+        ];
+        for (user_id, status) in expected.iter().enumerate() {
+            let arg = GetUserArg {
+                user_id: user_id as u32,
+            };
+            let err = match GetUser::call(&mut channel, IFACE, arg).await {
+                Err(err) => err,
+                Ok(_) => panic!("the query should have failed"),
+            };
+
+            assert_eq!(err.status(), *status);
+            if let error::Error::Exn(exn) = err {
+                assert_eq!(exn.error, "Sticky Fingers");
+            }
+        }
+    });
+
+    // unknown statuses of peers are not trusted
+    let err = error::Error::<()>::from(42);
+    assert!(matches!(err, error::Error::Generic(msg) if msg == "unexpected answer status 42"));
+}
+
+#[test]
+fn test_batch() {
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    // the handler records the number of queries handled at once
+    let running = Rc::new(Cell::new(0));
+    let max_running = Rc::new(Cell::new(0));
+    let mut reg = RpcRegister::new();
+    {
+        let running = running.clone();
+        let max_running = max_running.clone();
+
+        GetUser::implement(&mut reg, IFACE, move |_ic, arg| {
+            let running = running.clone();
+            let max_running = max_running.clone();
+
+            async move {
+                running.set(running.get() + 1);
+                max_running.set(max_running.get().max(running.get()));
+                el::el_future::Timer::new(5, 0).await.await;
+                running.set(running.get() - 1);
+
+                match arg.user_id {
+                    3 => Err(error::Error::Exn(GetUserExn {
+                        error: "Kiss".to_owned(),
+                    })),
+                    id => Ok(GetUserRes {
+                        firstname: format!("Stone Ocean {}", id),
+                        middlename: None,
+                        lastname: "Kujo".to_owned(),
+                    }),
+                }
+            }
+        });
+    }
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(reg.build()), None).unwrap();
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let channel = client.get_channel();
+        let mut batch = channel.batch::<GetUser>(IFACE);
+        batch.set_limit(2);
+        for user_id in 0..6 {
+            batch.push(GetUserArg { user_id });
+        }
+        assert_eq!(batch.len(), 6);
+
+        // the results are in the order of the queries
+        let res = batch.run().await;
+        assert_eq!(res.len(), 6);
+        for (id, res) in res.iter().enumerate() {
+            match res {
+                Ok(user) => assert_eq!(user.firstname, format!("Stone Ocean {}", id)),
+                Err(error::Error::Exn(exn)) => {
+                    assert_eq!(id, 3);
+                    assert_eq!(exn.error, "Kiss");
+                }
+                Err(e) => panic!("unexpected error {}", e),
+            }
+        }
+        assert_eq!(max_running.get(), 2);
+
+        max_running.set(0);
+        let args = (0..6).map(|user_id| GetUserArg { user_id });
+        let res = ic::batch::call_all::<GetUser, _>(&channel, IFACE, args, 4).await;
+        assert_eq!(res.iter().filter(|res| res.is_ok()).count(), 5);
+        assert_eq!(max_running.get(), 4);
+    });
+}
+
+#[test]
+fn test_record_replay() {
+    use ic::ic::Direction;
+    use ic::record::{read_records, Recorder, Replay};
+    use iop_module::IFACE;
+    use libcommon_sys as sys;
+
+    let _m = ic::use_module();
+
+    // the user 1 is unknown until fixed
+    fn register(fixed: bool) -> RpcRegister {
+        let mut reg = RpcRegister::new();
+
+        GetUser::implement(&mut reg, IFACE, move |_ic, arg| async move {
+            if arg.user_id == 1 && !fixed {
+                return Err(error::Error::Exn(GetUserExn {
+                    error: "Made in Heaven".to_owned(),
+                }));
+            }
+            Ok(GetUserRes {
+                firstname: "Enrico".to_owned(),
+                middlename: None,
+                lastname: "Pucci".to_owned(),
+            })
+        });
+        reg
+    }
+
+    let path = std::env::temp_dir().join(format!("ic-record-{}.rec", std::process::id()));
+    let recorder = Recorder::create(&path).unwrap();
+
+    el::exec_test_async(async move {
+        let mut server = Server::new("127.0.0.1:0", Some(register(false).build()), None).unwrap();
+        server.set_recorder(Some(recorder.clone()));
+
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        for user_id in 0..2 {
+            let _ = GetUser::call(&mut channel, IFACE, GetUserArg { user_id }).await;
+        }
+        recorder.flush().unwrap();
+    });
+
+    let cmd = GetUser::get_cmd(IFACE);
+    let records = read_records(std::fs::File::open(&path).unwrap()).unwrap();
+    assert_eq!(records.len(), 4);
+    assert_eq!(records[0].direction, Direction::Received);
+    assert_eq!(records[0].cmd, cmd);
+    assert_eq!(records[0].status, None);
+    assert_eq!(records[1].direction, Direction::Sent);
+    assert_eq!(records[1].cmd, cmd);
+    assert_eq!(records[1].slot, records[0].slot);
+    assert_eq!(records[1].status, Some(sys::ic_status_t_IC_MSG_OK));
+    assert_eq!(records[3].status, Some(sys::ic_status_t_IC_MSG_EXN));
+    assert!(records[0].timestamp <= records[3].timestamp);
+
+    // the queries are handled again by the fixed implementation
+    let replay = Replay::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let replayed = futures::executor::block_on(replay.run(&register(true).build()));
+    assert_eq!(replayed.len(), 2);
+    assert!(replayed.iter().all(|replayed| replayed.cmd == cmd));
+    assert_eq!(
+        Some((replayed[0].status, replayed[0].answer.clone())),
+        replayed[0].recorded
+    );
+    assert_eq!(replayed[1].status, sys::ic_status_t_IC_MSG_OK);
+    assert_eq!(
+        replayed[1].recorded.as_ref().map(|recorded| recorded.0),
+        Some(sys::ic_status_t_IC_MSG_EXN)
+    );
+}
+
+#[test]
+fn test_capture() {
+    use ic::capture::{self, CaptureConfig};
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let mut reg = RpcRegister::new();
+    GetUser::implement(&mut reg, IFACE, |_ic, _arg| async move {
+        Ok(GetUserRes {
+            firstname: "Funny".to_owned(),
+            middlename: None,
+            lastname: "Valentine".to_owned(),
+        })
+    });
+    capture::register_rpc::<GetUser>(IFACE);
+
+    let path = std::env::temp_dir().join(format!("ic-capture-{}.log", std::process::id()));
+    capture::start(CaptureConfig {
+        max_size: 2048,
+        max_files: 8,
+        ..CaptureConfig::new(&path)
+    })
+    .unwrap();
+    assert!(capture::is_active());
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(reg.build()), None).unwrap();
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        let hdr = QueryHeader::with_login("d4c", "love-train");
+        for user_id in 0..4 {
+            let res = GetUser::call_with_hdr(&mut channel, IFACE, &hdr, GetUserArg { user_id });
+            assert_eq!(res.await.unwrap().lastname, "Valentine");
+        }
+    });
+    capture::stop();
+    assert!(!capture::is_active());
+
+    // the frames of the queries above are rotated
+    let mut frames = String::new();
+    for n in (1..=8).rev() {
+        let mut rotated = path.clone().into_os_string();
+
+        rotated.push(format!(".{}", n));
+        if let Ok(content) = std::fs::read_to_string(&rotated) {
+            frames += &content;
+            std::fs::remove_file(&rotated).unwrap();
+        }
+    }
+    assert!(!frames.is_empty());
+    frames += &std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let cmd = format!("{:#010x} tst.User.getUser", GetUser::get_cmd(IFACE));
+    assert!(frames.contains(&format!("sent query {}", cmd)));
+    assert!(frames.contains(&format!("received query {}", cmd)));
+    assert!(frames.contains(&format!("sent answer {}", cmd)));
+    assert!(frames.contains("(user_id): 3"));
+    assert!(frames.contains("(lastname): \"Valentine\""));
+    assert!(frames.contains("d4c"));
+    assert!(!frames.contains("love-train"));
+}
+
+#[test]
+fn test_raw_handler() {
+    use iop_module::IFACE;
+    use libcommon_sys as sys;
+
+    let _m = ic::use_module();
+
+    let cmd = GetUser::get_cmd(IFACE);
+    let mut server_reg = RpcRegister::new();
+    server_reg.register_raw(cmd, |ic, cmd, data, slot| {
+        assert_eq!(slot.cmd(), cmd);
+        assert!(!slot.is_async());
+        let arg: GetUserArg = serde_iop::from_bytes(data).unwrap();
+
+        // answered later, as a gateway would once its backend answers
+        async move {
+            el::el_future::Timer::new(1, 0).await.await;
+            if arg.user_id == 0 {
+                let exn = GetUserExn {
+                    error: "no stand user".to_owned(),
+                };
+                return ic.reply_raw(
+                    slot,
+                    sys::ic_status_t_IC_MSG_EXN,
+                    &serde_iop::to_bytes(&exn).unwrap(),
+                );
+            }
+            let res = GetUserRes {
+                firstname: "Josuke".to_owned(),
+                middlename: None,
+                lastname: "Higashikata".to_owned(),
+            };
+            ic.reply_raw(
+                slot,
+                sys::ic_status_t_IC_MSG_OK,
+                &serde_iop::to_bytes(&res).unwrap(),
+            )
+        }
+    });
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(server_reg.build()), None).unwrap();
+
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 4 }).await;
+        assert_eq!(res.unwrap().lastname, "Higashikata");
+
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 0 }).await;
+        match res {
+            Err(error::Error::Exn(exn)) => assert_eq!(exn.error, "no stand user"),
+            _ => panic!("expected an exception"),
+        }
+    });
+}
+
+#[test]
+fn test_dropped_channel() {
+    use futures::executor::block_on;
+    use ic::ic::{Channel, ConnectionState};
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let mut client = Client::new(None);
+    let mut channel = client.get_channel();
+    channel.extensions_mut().insert(String::from("Speedwagon"));
+    assert!(!channel.is_dropped());
+
+    // the channel is closed, and its queries canceled, once its client is
+    // dropped
+    drop(client);
+    assert!(channel.is_dropped());
+    assert!(channel.to_raw().is_null());
+    assert_eq!(channel.state(), ConnectionState::Closed);
+    assert!(channel.extensions().get::<String>().is_none());
+    block_on(channel.disconnected());
+
+    let res = block_on(GetUser::call(
+        &mut channel,
+        IFACE,
+        GetUserArg { user_id: 1 },
+    ));
+    assert!(matches!(res, Err(error::Error::Canceled)));
+    let res = Notify::send(
+        &mut channel,
+        IFACE,
+        NotifyArg {
+            event: "dropped".to_owned(),
+        },
+    );
+    assert!(matches!(res, Err(error::Error::Canceled)));
+    // only async RPCs can be sent without waiting for an answer
+    let res = GetUser::send(&mut channel, IFACE, GetUserArg { user_id: 1 });
+    assert!(matches!(res, Err(error::Error::Invalid)));
+    let res = block_on(channel.ping(Duration::from_secs(1)));
+    assert!(matches!(res, Err(error::Error::Canceled)));
+
+    // as are the channels of the peers of a server
+    let peers: Rc<RefCell<Vec<Channel>>> = Default::default();
+    el::exec_test_async(async move {
+        let mut server = Server::new("127.0.0.1:0", None, None).unwrap();
+        {
+            let peers = peers.clone();
+            server.on_client_connected(move |_id, ic, _addr| peers.borrow_mut().push(ic));
+        }
+
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+        while peers.borrow().is_empty() {
+            el::el_future::Timer::new(1, 0).await.await;
+        }
+
+        let mut peer = peers.borrow_mut().pop().unwrap();
+        assert!(peer.is_connected());
+        drop(server);
+        assert!(peer.is_dropped());
+        let res = GetUser::call(&mut peer, IFACE, GetUserArg { user_id: 2 }).await;
+        assert!(matches!(res, Err(error::Error::Canceled)));
+    });
+}
+
+#[test]
+fn test_channel_handle() {
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let mut server_reg = RpcRegister::new();
+    GetUser::implement(&mut server_reg, IFACE, |_ic, arg| async move {
+        Ok(GetUserRes {
+            firstname: "Giorno".to_owned(),
+            middlename: None,
+            lastname: format!("Giovanna {}", arg.user_id),
+        })
+    });
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(server_reg.build()), None).unwrap();
+
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        // the queries of worker threads are sent by the event loop
+        let handle = client.get_channel().handle();
+        let workers: Vec<_> = (0..4)
+            .map(|id| {
+                let handle = handle.clone();
+
+                std::thread::spawn(move || {
+                    let query = handle.call::<GetUser>(IFACE, GetUserArg { user_id: id });
+
+                    futures::executor::block_on(query).unwrap().lastname
+                })
+            })
+            .collect();
+        while !workers.iter().all(|worker| worker.is_finished()) {
+            el::el_future::Timer::new(1, 0).await.await;
+        }
+        for (id, worker) in workers.into_iter().enumerate() {
+            assert_eq!(worker.join().unwrap(), format!("Giovanna {}", id));
+        }
+
+        // and canceled once the channel is dropped
+        drop(client);
+        let worker = std::thread::spawn(move || {
+            let query = handle.call::<GetUser>(IFACE, GetUserArg { user_id: 5 });
+
+            futures::executor::block_on(query)
+        });
+        while !worker.is_finished() {
+            el::el_future::Timer::new(1, 0).await.await;
+        }
+        assert!(matches!(
+            worker.join().unwrap(),
+            Err(error::Error::Canceled)
+        ));
+    });
+}
+
+#[test]
+fn test_sync_client() {
+    use futures::future;
+    use ic::blocking::SyncClient;
+    use ic::connect::ConnectOptions;
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let mut server_reg = RpcRegister::new();
+    GetUser::implement(&mut server_reg, IFACE, |_ic, arg| async move {
+        // the query of the last user is never answered
+        if arg.user_id == 0 {
+            future::pending::<()>().await;
+        }
+        Ok(GetUserRes {
+            firstname: "Bruno".to_owned(),
+            middlename: None,
+            lastname: "Bucciarati".to_owned(),
+        })
+    });
+    let server = Server::new("127.0.0.1:0", Some(server_reg.build()), None).unwrap();
+
+    let mut client = SyncClient::new(None);
+    client
+        .connect(
+            &server.local_addr().to_string(),
+            None,
+            ConnectOptions::default(),
+        )
+        .unwrap();
+
+    let res = client.call::<GetUser>(IFACE, GetUserArg { user_id: 1 }, Duration::from_secs(5));
+    assert_eq!(res.unwrap().lastname, "Bucciarati");
+
+    let res = client.call::<GetUser>(IFACE, GetUserArg { user_id: 0 }, Duration::from_millis(50));
+    assert!(matches!(res, Err(error::Error::TimedOut)));
+}
+
+#[test]
+fn test_concurrency_limits() {
+    use futures::future::join_all;
+    use ic::concurrency::ConcurrencyLimits;
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    // queries being handled, and the most seen at once
+    let running = Rc::new((Cell::new(0), Cell::new(0)));
+
+    let mut reg = RpcRegister::new();
+    {
+        let running = running.clone();
+
+        GetUser::implement(&mut reg, IFACE, move |_ic, _arg| {
+            let running = running.clone();
+
+            async move {
+                running.0.set(running.0.get() + 1);
+                running.1.set(running.1.get().max(running.0.get()));
+                el::el_future::Timer::new(5, 0).await.await;
+                running.0.set(running.0.get() - 1);
+                Ok(GetUserRes {
+                    firstname: "Narancia".to_owned(),
+                    middlename: None,
+                    lastname: "Ghirga".to_owned(),
+                })
+            }
+        });
+    }
+
+    let mut limits = ConcurrencyLimits {
+        global: Some(8),
+        max_queued: 2,
+        ..ConcurrencyLimits::default()
+    };
+    limits.cmds.insert(GetUser::get_cmd(IFACE), 2);
+
+    el::exec_test_async(async move {
+        let mut server = Server::new("127.0.0.1:0", Some(reg.build()), None).unwrap();
+        server.set_concurrency_limits(Some(limits));
+
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        // two queries are handled, two wait for them, the others are
+        // rejected
+        let mut channel = client.get_channel();
+        let queries = (0..6).map(|_| GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 0 }));
+        let res = join_all(queries.collect::<Vec<_>>()).await;
+        assert_eq!(res.iter().filter(|res| res.is_ok()).count(), 4);
+        assert!(matches!(res[5], Err(error::Error::Retry)));
+        assert_eq!(running.1.get(), 2);
+
+        // the slots are given back once answered
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 0 }).await;
+        assert_eq!(res.unwrap().lastname, "Ghirga");
+    });
+}
+
+#[test]
+fn test_pending_stats() {
+    use futures::future;
+    use ic::ic::PendingStats;
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let mut reg = RpcRegister::new();
+    GetUser::implement(&mut reg, IFACE, |_ic, arg| async move {
+        // the queries of user 0 are never answered
+        if arg.user_id == 0 {
+            future::pending::<()>().await;
+        }
+        Ok(GetUserRes {
+            firstname: "Guido".to_owned(),
+            middlename: None,
+            lastname: "Mista".to_owned(),
+        })
+    });
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(reg.build()), None).unwrap();
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        assert_eq!(channel.pending_stats(), PendingStats::default());
+
+        let _stuck = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 0 });
+        el::el_future::Timer::new(10, 0).await.await;
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 4 }).await;
+        assert_eq!(res.unwrap().lastname, "Mista");
+
+        // only the query not answered is pending
+        let stats = channel.pending_stats();
+        assert_eq!(stats.count, 1);
+        assert_eq!(stats.cmds.get(&GetUser::get_cmd(IFACE)), Some(&1));
+        assert!(stats.oldest.unwrap() >= Duration::from_millis(10));
+
+        drop(client);
+        assert_eq!(channel.pending_stats(), PendingStats::default());
+    });
+}
+
+#[test]
+fn test_void_answer() {
+    use futures::future;
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let mut reg = RpcRegister::new();
+    {
+        let events = events.clone();
+
+        Ack::implement(&mut reg, IFACE, move |_ic, arg| {
+            events.borrow_mut().push(arg.event);
+            future::ready(Ok(()))
+        });
+    }
+    // a newer version of the RPC, answering with data
+    reg.register(Ack::get_cmd(IFACE) + 1, |_ic, _arg: NotifyArg| async move {
+        Ok::<_, error::Error<()>>(SayHelloRes {
+            result: "Arrivederci".to_owned(),
+        })
+    });
+
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(reg.build()), None).unwrap();
+        let mut client = Client::new(None);
+        let connected = client
+            .connect_once(&server.local_addr().to_string(), None)
+            .unwrap()
+            .await;
+        assert!(connected);
+
+        let mut channel = client.get_channel();
+        let event = "sticky fingers".to_owned();
+        let res = Ack::call(&mut channel, IFACE, NotifyArg { event }).await;
+        assert!(res.is_ok());
+        assert_eq!(*events.borrow(), vec!["sticky fingers".to_owned()]);
+
+        // the data of the answer is ignored
+        let res = ic::ic::QueryFuture::<(), ()>::new(
+            &mut channel,
+            &NotifyArg {
+                event: "zipper".to_owned(),
+            },
+            Ack::get_cmd(IFACE) + 1,
+            false,
+        )
+        .await;
+        assert!(res.is_ok());
+    });
+}
+
+#[test]
+fn test_handshake() {
+    use ic::handshake::Capabilities;
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    let mut reg = RpcRegister::new();
+    GetUser::implement(&mut reg, IFACE, |ic, _arg| async move {
+        // the middle name is only known by the clients of version 2
+        let caps = ic.peer_capabilities();
+        let v2 = caps.and_then(|caps| caps.iface_version(IFACE)) >= Some(2);
+
+        Ok(GetUserRes {
+            firstname: "Trish".to_owned(),
+            middlename: v2.then(|| "Una".to_owned()),
+            lastname: "Una".to_owned(),
+        })
+    });
+
+    el::exec_test_async(async move {
+        let mut server = Server::new("127.0.0.1:0", Some(reg.build()), None).unwrap();
+        let server_caps = Capabilities::new()
+            .with_iface(IFACE, 2)
+            .with_feature("spicy-lady");
+        server.set_capabilities(Some(server_caps.clone()));
+        let addr = server.local_addr().to_string();
+
+        let mut client = Client::new(None);
+        client.set_capabilities(Some(Capabilities::new().with_iface(IFACE, 2)));
+        assert!(client.connect_once(&addr, None).unwrap().await);
+
+        let mut channel = client.get_channel();
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 1 }).await;
+        assert_eq!(res.unwrap().middlename.as_deref(), Some("Una"));
+        // the handshake was answered before the query
+        let caps = channel.peer_capabilities().unwrap();
+        assert_eq!(*caps, server_caps);
+        assert!(caps.has_feature("spicy-lady"));
+
+        // older clients advertise nothing
+        let mut old_client = Client::new(None);
+        assert!(old_client.connect_once(&addr, None).unwrap().await);
+
+        let mut channel = old_client.get_channel();
+        let res = GetUser::call(&mut channel, IFACE, GetUserArg { user_id: 1 }).await;
+        assert_eq!(res.unwrap().middlename, None);
+        assert!(channel.peer_capabilities().is_none());
+    });
+}
+
+#[test]
+fn test_routing() {
+    use futures::future;
+    use ic::ic::Channel;
+    use iop_module::IFACE;
+
+    let _m = ic::use_module();
+
+    fn instance(lastname: &'static str) -> RpcRegister {
+        let mut reg = RpcRegister::new();
+        GetUser::implement(&mut reg, IFACE, move |_ic, _arg| {
+            future::ready(Ok(GetUserRes {
+                firstname: "Leone".to_owned(),
+                middlename: None,
+                lastname: lastname.to_owned(),
+            }))
+        });
+        reg
+    }
+
+    let mut reg = instance("Abbacchio");
+    reg.route("passione", &instance("Bucciarati").build());
+    let mut by_group = instance("Narancia");
+    by_group.route_on(|hdr| hdr.group.as_deref());
+    by_group.route("ghirga", &instance("Ghirga").build());
+    reg.route("squad", &by_group.build());
+
+    let reg = reg.build();
+    el::exec_test_async(async move {
+        let server = Server::new("127.0.0.1:0", Some(reg.clone()), None).unwrap();
+        let mut client = Client::new(None);
+        assert!(
+            client
+                .connect_once(&server.local_addr().to_string(), None)
+                .unwrap()
+                .await
+        );
+
+        for mut channel in [client.get_channel(), Channel::loopback(&reg)] {
+            let mut lastname = |host: Option<&str>, group: Option<&str>| {
+                let hdr = QueryHeader {
+                    host: host.map(str::to_owned),
+                    group: group.map(str::to_owned),
+                    ..QueryHeader::default()
+                };
+                let query =
+                    GetUser::call_with_hdr(&mut channel, IFACE, &hdr, GetUserArg { user_id: 1 });
+
+                async move { query.await.unwrap().lastname }
+            };
+
+            assert_eq!(lastname(Some("passione"), None).await, "Bucciarati");
+            // the queries of unknown hosts are handled by the router
+            assert_eq!(lastname(Some("gelato"), None).await, "Abbacchio");
+            assert_eq!(lastname(None, None).await, "Abbacchio");
+            // the routers can be nested
+            assert_eq!(lastname(Some("squad"), Some("ghirga")).await, "Ghirga");
+            assert_eq!(lastname(Some("squad"), None).await, "Narancia");
+        }
+    });
+}
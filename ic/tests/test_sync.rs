@@ -1,3 +1,4 @@
+use ic::error;
 use ic::ic_sync::{Client, RpcRegister, Server};
 use ic::types_sync::Rpc;
 use libcommon_el as el;
@@ -16,11 +17,16 @@ struct HelloArg {
 struct HelloRes {
     result: u32,
 }
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct HelloExn {
+    reason: String,
+}
 struct Hello {}
 
 impl Rpc for Hello {
     type Input = HelloArg;
     type Output = HelloRes;
+    type Exception = HelloExn;
 
     const ASYNC: bool = false;
     const TAG: u16 = 2;
@@ -41,36 +47,60 @@ fn test_server_client() {
     let mut reg = RpcRegister::new();
 
     Hello::implement(&mut reg, IFACE, |arg| {
+        if arg.value > 200 {
+            return Err(error::Error::Exn(HelloExn {
+                reason: "too big".to_owned(),
+            }));
+        }
         Ok(HelloRes {
             result: arg.value as u32 + 23,
         })
     });
 
     thread_local! {
-        static RESULT: RefCell<u32> = RefCell::new(0);
+        static RESULT: RefCell<u32> = const { RefCell::new(0) };
+        static EXN: RefCell<Option<HelloExn>> = const { RefCell::new(None) };
     }
 
     let blocker = RefCell::new(el::el::Blocker::new());
 
-    let _server = Server::new("127.0.0.1", Some(reg));
+    let server = Server::new("127.0.0.1:0", Some(reg.build())).unwrap();
 
     let mut client = Client::new(None);
-    client.ic.connect("127.0.0.1", |ic, connected| {
-        if !connected {
-            return;
-        }
-
-        blocker.borrow_mut().unregister();
-
-        Hello::call(ic, IFACE, HelloArg { value: 30 }, |ic, res| {
-            RESULT.with(|result| {
-                *result.borrow_mut() = res.unwrap().result;
+    client
+        .ic
+        .connect(&server.local_addr().to_string(), |ic, connected| {
+            if !connected {
+                return;
+            }
+
+            blocker.borrow_mut().unregister();
+
+            Hello::call(ic, IFACE, HelloArg { value: 30 }, |ic, res| {
+                RESULT.with(|result| {
+                    *result.borrow_mut() = res.unwrap().result;
+                });
+
+                // the exceptions are given to the callback unpacked
+                Hello::call(ic, IFACE, HelloArg { value: 255 }, |ic, res| {
+                    if let Err(error::Error::Exn(exn)) = res {
+                        EXN.with(|e| *e.borrow_mut() = Some(exn));
+                    }
+                    ic.disconnect();
+                });
             });
-            ic.disconnect();
-        });
-    });
+        })
+        .unwrap();
 
     el::el::el_loop();
 
     RESULT.with(|result| assert!(*result.borrow() == 53));
+    EXN.with(|exn| {
+        assert_eq!(
+            *exn.borrow(),
+            Some(HelloExn {
+                reason: "too big".to_owned()
+            })
+        )
+    });
 }
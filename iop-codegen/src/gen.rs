@@ -0,0 +1,548 @@
+use crate::parser::{Enum, Field, Interface, Item, Literal, Module, Package, Repeat, Spec, Struct};
+use crate::Error;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// {{{ Names
+
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn",
+    "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in", "let", "loop",
+    "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return", "static",
+    "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use", "virtual",
+    "where", "while", "yield",
+];
+
+/// `completedSteps` to `completed_steps`, acronyms being kept together.
+fn snake_case(name: &str) -> String {
+    let chars = name.chars().collect::<Vec<_>>();
+    let mut res = String::with_capacity(name.len() + 4);
+
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|c| c.is_ascii_lowercase());
+
+            if prev.is_ascii_lowercase()
+                || prev.is_ascii_digit()
+                || (prev.is_ascii_uppercase() && next_lower)
+            {
+                res.push('_');
+            }
+        }
+        res.push(c.to_ascii_lowercase());
+    }
+    res
+}
+
+/// Name of a field or of a module, escaped if it is a keyword.
+fn field_name(name: &str) -> String {
+    let name = snake_case(name);
+
+    match name.as_str() {
+        // these keywords cannot be raw identifiers
+        "crate" | "self" | "super" => format!("{}_", name),
+        name if KEYWORDS.contains(&name) => format!("r#{}", name),
+        _ => name,
+    }
+}
+
+/// `customId` to `CustomId`, `get_user` to `GetUser`.
+fn camel_case(name: &str) -> String {
+    let mut res = String::with_capacity(name.len());
+
+    for part in name.split('_').filter(|part| !part.is_empty()) {
+        let mut chars = part.chars();
+
+        if let Some(c) = chars.next() {
+            res.push(c.to_ascii_uppercase());
+            res.extend(chars);
+        }
+    }
+    res
+}
+
+fn const_name(name: &str) -> String {
+    snake_case(name).to_ascii_uppercase()
+}
+
+// }}}
+// {{{ Types
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Enum,
+    Struct,
+    Union,
+    Typedef,
+}
+
+fn builtin_type(ty: &str) -> Option<&'static str> {
+    Some(match ty {
+        "byte" => "i8",
+        "ubyte" => "u8",
+        "short" => "i16",
+        "ushort" => "u16",
+        "int" => "i32",
+        "uint" => "u32",
+        "long" => "i64",
+        "ulong" => "u64",
+        "double" => "f64",
+        "bool" => "bool",
+        "string" | "xml" => "String",
+        "bytes" => "Vec<u8>",
+        "void" => "()",
+        _ => return None,
+    })
+}
+
+struct Gen<'a> {
+    pkg: &'a Package,
+    types: HashMap<&'a str, Kind>,
+    out: String,
+}
+
+impl<'a> Gen<'a> {
+    fn rust_type(&self, ty: &str, repeat: Repeat, line: usize) -> Result<String, Error> {
+        let base = match builtin_type(ty) {
+            Some(base) => base.to_owned(),
+            None if self.types.contains_key(ty) => ty.to_owned(),
+            None => return Err(Error::syntax(line, format!("unknown type `{}`", ty))),
+        };
+
+        Ok(match repeat {
+            Repeat::Mandatory => base,
+            Repeat::Optional => format!("Option<{}>", base),
+            Repeat::Repeated => format!("Vec<{}>", base),
+            Repeat::Reference => format!("Box<{}>", base),
+        })
+    }
+
+    /// Type of the values of a field, following typedefs.
+    fn resolve<'b>(&'b self, mut ty: &'b str) -> &'b str {
+        while self.types.get(ty) == Some(&Kind::Typedef) {
+            let typedef = self.pkg.items.iter().find_map(|item| match item {
+                Item::Typedef(def) if def.name == ty && def.repeat == Repeat::Mandatory => {
+                    Some(def)
+                }
+                _ => None,
+            });
+
+            match typedef {
+                Some(def) => ty = &def.ty,
+                None => break,
+            }
+        }
+        ty
+    }
+
+    /// Rust expression of the default value of a field, `None` if it is
+    /// the default value of its type.
+    fn default_value(&self, field: &Field) -> Result<Option<String>, Error> {
+        let value = match &field.default {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let ty = self.resolve(&field.ty);
+        let invalid = || {
+            Error::syntax(
+                field.line,
+                format!("invalid default value for field `{}`", field.name),
+            )
+        };
+
+        let expr = match (ty, value) {
+            ("double", Literal::Int(v)) => format!("{}.0", v),
+            ("double", Literal::Float(v)) => v.clone(),
+            ("bool", Literal::Ident(v)) if v == "true" || v == "false" => v.clone(),
+            ("string" | "xml", Literal::Str(v)) if v.is_empty() => return Ok(None),
+            ("string" | "xml", Literal::Str(v)) => format!("{:?}.to_owned()", v),
+            (ty, Literal::Int(v)) if builtin_type(ty).is_some_and(is_integer) => v.to_string(),
+            (ty, Literal::Ident(v)) if self.types.get(ty) == Some(&Kind::Enum) => {
+                let values = self.enum_values(ty);
+
+                if !values.iter().any(|(name, _)| name == v) {
+                    return Err(invalid());
+                }
+                if values.first().map(|(name, _)| name) == Some(v) {
+                    return Ok(None);
+                }
+                format!("{}::{}", ty, v)
+            }
+            _ => return Err(invalid()),
+        };
+
+        if ["0", "0.0", "false"].contains(&expr.trim_start_matches('-')) {
+            Ok(None)
+        } else {
+            Ok(Some(expr))
+        }
+    }
+
+    fn enum_values(&self, name: &str) -> &[(String, i64)] {
+        self.pkg
+            .items
+            .iter()
+            .find_map(|item| match item {
+                Item::Enum(def) if def.name == name => Some(def.values.as_slice()),
+                _ => None,
+            })
+            .unwrap_or(&[])
+    }
+
+    /// `#[iop(...)]` items of a field, its tag being given if it does not
+    /// follow the previous one.
+    fn iop_attrs(&self, field: &Field, prev_tag: u16) -> Result<Vec<String>, Error> {
+        let mut res = Vec::new();
+
+        if field.tag != prev_tag + 1 {
+            res.push(format!("tag = {}", field.tag));
+        }
+        if self.resolve(&field.ty) == "bytes" {
+            if field.repeat == Repeat::Repeated {
+                return Err(Error::syntax(
+                    field.line,
+                    "arrays of bytes are not supported",
+                ));
+            }
+            res.push("bytes".to_owned());
+        }
+
+        for (name, value) in &field.attrs {
+            let key = match name.as_str() {
+                "nonEmpty" | "nonZero" => {
+                    res.push(snake_case(name));
+                    continue;
+                }
+                "min" | "max" | "minLength" | "maxLength" | "minOccurs" | "maxOccurs"
+                | "pattern" => snake_case(name),
+                // other attributes do not change the Rust types
+                _ => continue,
+            };
+
+            let value = match value {
+                Some(Literal::Int(v)) if *v < 0 => format!("\"{}\"", v),
+                Some(Literal::Int(v)) => v.to_string(),
+                Some(Literal::Float(v)) if v.starts_with('-') => format!("\"{}\"", v),
+                Some(Literal::Float(v)) => v.clone(),
+                Some(Literal::Str(v)) => format!("{:?}", v),
+                _ => {
+                    return Err(Error::syntax(
+                        field.line,
+                        format!("invalid value of attribute `{}`", name),
+                    ))
+                }
+            };
+            res.push(format!("{} = {}", key, value));
+        }
+        Ok(res)
+    }
+}
+
+fn is_integer(ty: &str) -> bool {
+    ty.starts_with('i') || ty.starts_with('u')
+}
+
+// }}}
+// {{{ Generation
+
+macro_rules! out {
+    ($gen:expr, $indent:expr, $($arg:tt)*) => {{
+        $gen.out.push_str(&"    ".repeat($indent));
+        writeln!($gen.out, $($arg)*).unwrap();
+    }};
+}
+
+pub(crate) fn generate(pkg: &Package) -> Result<String, Error> {
+    let mut gen = Gen {
+        pkg,
+        types: HashMap::new(),
+        out: String::new(),
+    };
+
+    for item in &pkg.items {
+        let (name, kind, line) = match item {
+            Item::Enum(def) => (&def.name, Kind::Enum, def.line),
+            Item::Struct(def) => (&def.name, Kind::Struct, def.line),
+            Item::Union(def) => (&def.name, Kind::Union, def.line),
+            Item::Typedef(def) => (&def.name, Kind::Typedef, def.line),
+            _ => continue,
+        };
+        if gen.types.insert(name, kind).is_some() {
+            return Err(Error::syntax(line, format!("duplicated type `{}`", name)));
+        }
+    }
+
+    out!(
+        gen,
+        0,
+        "// Generated by iop-codegen from package `{}`.",
+        pkg.name
+    );
+    out!(gen, 0, "#[allow(unused_imports)]");
+    out!(gen, 0, "use serde_iop::{{Deserialize, Serialize}};");
+    if gen.types.values().any(|kind| *kind == Kind::Enum) {
+        out!(
+            gen,
+            0,
+            "use serde_repr::{{Deserialize_repr, Serialize_repr}};"
+        );
+    }
+
+    for item in &pkg.items {
+        match item {
+            Item::Enum(def) => {
+                out!(gen, 0, "");
+                gen.enum_def(def)?;
+            }
+            Item::Struct(def) => {
+                out!(gen, 0, "");
+                gen.struct_def(0, &def.name, &def.fields)?;
+            }
+            Item::Union(def) => {
+                out!(gen, 0, "");
+                gen.union_def(def)?;
+            }
+            Item::Typedef(def) => {
+                let ty = gen.rust_type(&def.ty, def.repeat, def.line)?;
+
+                out!(gen, 0, "");
+                out!(gen, 0, "pub type {} = {};", def.name, ty);
+            }
+            _ => (),
+        }
+    }
+
+    let ifaces = pkg.items.iter().filter_map(|item| match item {
+        Item::Interface(iface) => Some(iface),
+        _ => None,
+    });
+    let mut first = true;
+    for iface in ifaces {
+        if first {
+            out!(gen, 0, "");
+            out!(gen, 0, "pub mod rpcs {{");
+            first = false;
+        } else {
+            out!(gen, 0, "");
+        }
+        gen.interface(iface)?;
+    }
+    if !first {
+        out!(gen, 0, "}}");
+    }
+
+    let modules = pkg.items.iter().filter_map(|item| match item {
+        Item::Module(module) => Some(module),
+        _ => None,
+    });
+    let mut first = true;
+    for module in modules {
+        if first {
+            out!(gen, 0, "");
+            out!(gen, 0, "pub mod modules {{");
+            first = false;
+        }
+        gen.module(module);
+    }
+    if !first {
+        out!(gen, 0, "}}");
+    }
+
+    Ok(gen.out)
+}
+
+impl<'a> Gen<'a> {
+    fn enum_def(&mut self, def: &Enum) -> Result<(), Error> {
+        if def.values.is_empty() {
+            return Err(Error::syntax(
+                def.line,
+                format!("enum `{}` has no values", def.name),
+            ));
+        }
+
+        out!(self, 0, "#[allow(non_camel_case_types)]");
+        out!(
+            self,
+            0,
+            "#[derive(PartialEq, Eq, Clone, Default, Serialize_repr, Deserialize_repr)]"
+        );
+        out!(self, 0, "#[repr(i32)]");
+        out!(self, 0, "pub enum {} {{", def.name);
+        for (i, (name, value)) in def.values.iter().enumerate() {
+            if i == 0 {
+                out!(self, 1, "#[default]");
+            }
+            out!(self, 1, "{} = {},", name, value);
+        }
+        out!(self, 0, "}}");
+        Ok(())
+    }
+
+    fn struct_def(&mut self, indent: usize, name: &str, fields: &[Field]) -> Result<(), Error> {
+        let mut fields = fields.iter().collect::<Vec<_>>();
+        let mut members = Vec::with_capacity(fields.len());
+        let mut prev_tag = 0;
+
+        // fields are packed in the order of their tags
+        fields.sort_by_key(|field| field.tag);
+        for field in fields {
+            members.push((
+                field_name(&field.name),
+                self.rust_type(&field.ty, field.repeat, field.line)?,
+                self.iop_attrs(field, prev_tag)?,
+                self.default_value(field)?,
+            ));
+            prev_tag = field.tag;
+        }
+        let has_attrs = members.iter().any(|(_, _, attrs, _)| !attrs.is_empty());
+        let has_defaults = members.iter().any(|(_, _, _, default)| default.is_some());
+
+        if has_attrs {
+            out!(self, indent, "#[serde_iop::iop]");
+        }
+        if has_defaults {
+            out!(self, indent, "#[derive(Clone, Serialize, Deserialize)]");
+        } else {
+            out!(
+                self,
+                indent,
+                "#[derive(Clone, Default, Serialize, Deserialize)]"
+            );
+        }
+        if members.is_empty() {
+            out!(self, indent, "pub struct {} {{}}", name);
+        } else {
+            out!(self, indent, "pub struct {} {{", name);
+            for (member, ty, attrs, _) in &members {
+                if !attrs.is_empty() {
+                    out!(self, indent + 1, "#[iop({})]", attrs.join(", "));
+                }
+                out!(self, indent + 1, "pub {}: {},", member, ty);
+            }
+            out!(self, indent, "}}");
+        }
+
+        if has_defaults {
+            out!(self, indent, "impl Default for {} {{", name);
+            out!(self, indent + 1, "fn default() -> Self {{");
+            out!(self, indent + 2, "Self {{");
+            for (member, _, _, default) in &members {
+                match default {
+                    Some(expr) => out!(self, indent + 3, "{}: {},", member, expr),
+                    None => out!(self, indent + 3, "{}: Default::default(),", member),
+                }
+            }
+            out!(self, indent + 2, "}}");
+            out!(self, indent + 1, "}}");
+            out!(self, indent, "}}");
+        }
+        Ok(())
+    }
+
+    fn union_def(&mut self, def: &Struct) -> Result<(), Error> {
+        let mut variants = Vec::with_capacity(def.fields.len());
+        let mut prev_tag = 0;
+
+        for field in &def.fields {
+            if field.repeat == Repeat::Optional {
+                return Err(Error::syntax(field.line, "union fields cannot be optional"));
+            }
+            variants.push((
+                camel_case(&field.name),
+                self.rust_type(&field.ty, field.repeat, field.line)?,
+                self.iop_attrs(field, prev_tag)?,
+            ));
+            prev_tag = field.tag;
+        }
+        let first = match variants.first() {
+            Some((variant, _, _)) => variant.clone(),
+            None => {
+                let msg = format!("union `{}` has no fields", def.name);
+
+                return Err(Error::syntax(def.line, msg));
+            }
+        };
+
+        if variants.iter().any(|(_, _, attrs)| !attrs.is_empty()) {
+            out!(self, 0, "#[serde_iop::iop]");
+        }
+        out!(self, 0, "#[derive(Clone, Serialize, Deserialize)]");
+        out!(self, 0, "pub enum {} {{", def.name);
+        for (variant, ty, attrs) in &variants {
+            if !attrs.is_empty() {
+                out!(self, 1, "#[iop({})]", attrs.join(", "));
+            }
+            out!(self, 1, "{}({}),", variant, ty);
+        }
+        out!(self, 0, "}}");
+        out!(self, 0, "impl Default for {} {{", def.name);
+        out!(self, 1, "fn default() -> Self {{");
+        out!(self, 2, "{}::{}(Default::default())", def.name, first);
+        out!(self, 1, "}}");
+        out!(self, 0, "}}");
+        Ok(())
+    }
+
+    /// Type of the arguments, answer or exception of a RPC.
+    fn spec(&mut self, name: &str, spec: &Spec, line: usize) -> Result<(), Error> {
+        match spec {
+            Spec::Void | Spec::Null => out!(self, 2, "pub type {} = ();", name),
+            Spec::Type(ty) => {
+                let ty = self.rust_type(ty, Repeat::Mandatory, line)?;
+
+                out!(self, 2, "pub type {} = {};", name, ty);
+            }
+            Spec::Fields(fields) => self.struct_def(2, name, fields)?,
+        }
+        Ok(())
+    }
+
+    fn interface(&mut self, iface: &Interface) -> Result<(), Error> {
+        out!(self, 1, "pub mod {} {{", field_name(&iface.name));
+        out!(self, 2, "#[allow(unused_imports)]");
+        out!(self, 2, "use super::super::*;");
+
+        for rpc in &iface.rpcs {
+            let name = camel_case(&rpc.name);
+
+            out!(self, 0, "");
+            self.spec(&format!("{}Args", name), &rpc.args, rpc.line)?;
+            self.spec(&format!("{}Res", name), &rpc.res, rpc.line)?;
+            self.spec(&format!("{}Exn", name), &rpc.exn, rpc.line)?;
+            out!(self, 2, "pub struct {} {{}}", name);
+            out!(self, 2, "impl ::libcommon_ic::types::Rpc for {} {{", name);
+            out!(self, 3, "type Input = {}Args;", name);
+            out!(self, 3, "type Output = {}Res;", name);
+            out!(self, 3, "type Exception = {}Exn;", name);
+            out!(self, 3, "const TAG: u16 = {};", rpc.tag);
+            out!(
+                self,
+                3,
+                "const ASYNC: bool = {};",
+                matches!(rpc.res, Spec::Null)
+            );
+            out!(
+                self,
+                3,
+                "const NAME: Option<&'static str> = Some(\"{}.{}.{}\");",
+                self.pkg.name,
+                iface.name,
+                rpc.name
+            );
+            out!(self, 2, "}}");
+        }
+        out!(self, 1, "}}");
+        Ok(())
+    }
+
+    fn module(&mut self, module: &Module) {
+        out!(self, 1, "pub mod {} {{", field_name(&module.name));
+        for (tag, _, name) in &module.ifaces {
+            out!(self, 2, "pub const {}: u16 = {};", const_name(name), tag);
+        }
+        out!(self, 1, "}}");
+    }
+}
+
+// }}}
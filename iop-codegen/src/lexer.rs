@@ -0,0 +1,115 @@
+use crate::Error;
+
+/// Token of an IOP file.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Token {
+    Ident(String),
+    Int(i64),
+    /// Floats are kept as written, to be emitted as is.
+    Float(String),
+    Str(String),
+    Punct(char),
+}
+
+/// Split an IOP file in tokens, with their line numbers.
+pub(crate) fn tokenize(src: &str) -> Result<Vec<(Token, usize)>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = src.chars().peekable();
+    let mut line = 1;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => (),
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                let start = line;
+                let mut prev = chars.next();
+
+                loop {
+                    match chars.next() {
+                        Some('/') if prev == Some('*') => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            prev = Some(c);
+                        }
+                        None => return Err(Error::syntax(start, "unterminated comment")),
+                    }
+                }
+            }
+            '"' => {
+                let mut s = String::new();
+
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some(c) => s.push(c),
+                            None => return Err(Error::syntax(line, "unterminated string")),
+                        },
+                        Some('\n') | None => {
+                            return Err(Error::syntax(line, "unterminated string"))
+                        }
+                        Some(c) => s.push(c),
+                    }
+                }
+                tokens.push((Token::Str(s), line));
+            }
+            c if c.is_ascii_digit() => {
+                let mut s = c.to_string();
+
+                while let Some(&c) = chars.peek() {
+                    if !c.is_ascii_alphanumeric() && c != '.' && c != '_' {
+                        break;
+                    }
+                    s.push(c);
+                    chars.next();
+                }
+                tokens.push((parse_number(&s, line)?, line));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut s = c.to_string();
+
+                while let Some(&c) = chars.peek() {
+                    if !c.is_ascii_alphanumeric() && c != '_' {
+                        break;
+                    }
+                    s.push(c);
+                    chars.next();
+                }
+                tokens.push((Token::Ident(s), line));
+            }
+            '{' | '}' | '(' | ')' | '[' | ']' | ';' | ',' | ':' | '=' | '?' | '&' | '@' | '.'
+            | '-' => tokens.push((Token::Punct(c), line)),
+            c => return Err(Error::syntax(line, format!("unexpected character `{}`", c))),
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(s: &str, line: usize) -> Result<Token, Error> {
+    let digits = s.replace('_', "");
+    let res = if let Some(hex) = digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).map(Token::Int).ok()
+    } else if digits.contains(&['.', 'e', 'E'][..]) {
+        digits
+            .parse::<f64>()
+            .ok()
+            .map(|_| Token::Float(digits.clone()))
+    } else {
+        digits.parse().map(Token::Int).ok()
+    };
+
+    res.ok_or_else(|| Error::syntax(line, format!("invalid number `{}`", s)))
+}
@@ -0,0 +1,304 @@
+//! Compiler of IOP files into Rust modules.
+//!
+//! It is meant to be called from build scripts, so that the Rust types of a
+//! package always follow its `.iop` file:
+//!
+//! ```ignore
+//! fn main() {
+//!     let out_dir = std::env::var("OUT_DIR").unwrap();
+//!
+//!     println!("cargo:rerun-if-changed=iop/course.iop");
+//!     iop_codegen::compile("iop/course.iop", &out_dir).unwrap();
+//! }
+//! ```
+//!
+//! and the generated module included with
+//! `include!(concat!(env!("OUT_DIR"), "/course.rs"))`.
+//!
+//! The generated module has the same layout as the one of iopc: the types
+//! of the package, a `rpcs::<iface>` module per interface with the `Rpc`
+//! descriptions of its RPCs, and a `modules::<module>` module per module
+//! with the tags of its interfaces. It uses `serde_iop`, `serde_repr` if
+//! the package has enums, and `libcommon_ic` if it has interfaces.
+//!
+//! Classes and imports of other packages are not supported.
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+mod gen;
+mod lexer;
+mod parser;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Syntax { line: usize, msg: String },
+}
+
+impl Error {
+    pub(crate) fn syntax<S: Into<String>>(line: usize, msg: S) -> Self {
+        Error::Syntax {
+            line,
+            msg: msg.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => e.fmt(fmt),
+            Error::Syntax { line, msg } => write!(fmt, "line {}: {}", line, msg),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Generate the Rust module of an IOP file.
+pub fn generate(source: &str) -> Result<String, Error> {
+    let tokens = lexer::tokenize(source)?;
+    let package = parser::parse(tokens)?;
+
+    gen::generate(&package)
+}
+
+/// Compile an IOP file into `<package>.rs` in `out_dir`, returning the path
+/// of the generated file.
+pub fn compile<P: AsRef<Path>, Q: AsRef<Path>>(path: P, out_dir: Q) -> Result<PathBuf, Error> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path)?;
+    let tokens = lexer::tokenize(&source)?;
+    let package = parser::parse(tokens)?;
+    let out = out_dir.as_ref().join(format!("{}.rs", package.name));
+
+    fs::write(&out, gen::generate(&package)?)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syntax_error(source: &str) -> (usize, String) {
+        match generate(source) {
+            Err(Error::Syntax { line, msg }) => (line, msg),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("generation should have failed"),
+        }
+    }
+
+    #[test]
+    fn test_types() {
+        let out = generate(
+            "package test;
+
+            /* comment */
+            enum Kind {
+                A,
+                B = 4,
+                C,
+            };
+
+            union Value {
+                int i;
+              4: string s;
+                Kind kind;
+            };
+
+            // comment
+            struct Item {
+              2: ulong id;
+                @minLength(1) @maxLength(64)
+                string name;
+                bytes? data;
+                Value[] values;
+                Kind kind = C;
+                double ratio = 1.5;
+                int min = -1;
+                bool type = false;
+            };
+
+            typedef Item[] Items;",
+        )
+        .unwrap();
+
+        assert!(out.contains(
+            "#[repr(i32)]
+pub enum Kind {
+    #[default]
+    A = 0,
+    B = 4,
+    C = 5,
+}"
+        ));
+        assert!(out.contains(
+            "#[serde_iop::iop]
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Value {
+    I(i32),
+    #[iop(tag = 4)]
+    S(String),
+    Kind(Kind),
+}
+impl Default for Value {
+    fn default() -> Self {
+        Value::I(Default::default())
+    }
+}"
+        ));
+        assert!(out.contains(
+            "#[serde_iop::iop]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Item {
+    #[iop(tag = 2)]
+    pub id: u64,
+    #[iop(min_length = 1, max_length = 64)]
+    pub name: String,
+    #[iop(bytes)]
+    pub data: Option<Vec<u8>>,
+    pub values: Vec<Value>,
+    pub kind: Kind,
+    pub ratio: f64,
+    pub min: i32,
+    pub r#type: bool,
+}
+impl Default for Item {
+    fn default() -> Self {
+        Self {
+            id: Default::default(),
+            name: Default::default(),
+            data: Default::default(),
+            values: Default::default(),
+            kind: Kind::C,
+            ratio: 1.5,
+            min: -1,
+            r#type: Default::default(),
+        }
+    }
+}"
+        ));
+        assert!(out.contains("pub type Items = Vec<Item>;"));
+        assert!(!out.contains("pub mod rpcs"));
+    }
+
+    #[test]
+    fn test_interfaces() {
+        let out = generate(
+            "package test;
+
+            struct Error {
+                string msg;
+            };
+
+            interface UserAdmin {
+                getUser
+                    in (ulong id)
+                    out (string name)
+                    throw Error;
+             5: notify
+                    in (@min(-5) int delta)
+                    out null;
+                ping;
+            };
+
+            module Admin {
+             2: UserAdmin userAdmin;
+            };",
+        )
+        .unwrap();
+
+        assert!(out.contains(
+            "pub mod rpcs {
+    pub mod user_admin {
+        #[allow(unused_imports)]
+        use super::super::*;
+
+        #[derive(Clone, Default, Serialize, Deserialize)]
+        pub struct GetUserArgs {
+            pub id: u64,
+        }
+        #[derive(Clone, Default, Serialize, Deserialize)]
+        pub struct GetUserRes {
+            pub name: String,
+        }
+        pub type GetUserExn = Error;
+        pub struct GetUser {}
+        impl ::libcommon_ic::types::Rpc for GetUser {
+            type Input = GetUserArgs;
+            type Output = GetUserRes;
+            type Exception = GetUserExn;
+            const TAG: u16 = 1;
+            const ASYNC: bool = false;
+            const NAME: Option<&'static str> = Some(\"test.UserAdmin.getUser\");
+        }
+"
+        ));
+        assert!(out.contains(
+            "        #[serde_iop::iop]
+        #[derive(Clone, Default, Serialize, Deserialize)]
+        pub struct NotifyArgs {
+            #[iop(min = \"-5\")]
+            pub delta: i32,
+        }
+        pub type NotifyRes = ();
+        pub type NotifyExn = ();
+        pub struct Notify {}
+        impl ::libcommon_ic::types::Rpc for Notify {
+            type Input = NotifyArgs;
+            type Output = NotifyRes;
+            type Exception = NotifyExn;
+            const TAG: u16 = 5;
+            const ASYNC: bool = true;
+            const NAME: Option<&'static str> = Some(\"test.UserAdmin.notify\");
+        }"
+        ));
+        assert!(out.contains("pub type PingArgs = ();"));
+        assert!(out.contains("const TAG: u16 = 6;"));
+        assert!(out.contains(
+            "pub mod modules {
+    pub mod admin {
+        pub const USER_ADMIN: u16 = 2;
+    }
+}"
+        ));
+        assert!(!out.contains("serde_repr"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            syntax_error("package test;\nstruct A {\n  int a;\n  1: int b;\n};"),
+            (4, "duplicated tag 1".to_owned())
+        );
+        assert_eq!(
+            syntax_error("package test;\n\nstruct A {\n  Unknown a;\n};"),
+            (4, "unknown type `Unknown`".to_owned())
+        );
+        assert_eq!(
+            syntax_error("package test;\nenum E {\n  A,\n};\nstruct S {\n  E e = B;\n};"),
+            (6, "invalid default value for field `e`".to_owned())
+        );
+        assert_eq!(
+            syntax_error("package test;\ninterface I {\n  f out null throw (int a);\n};"),
+            (3, "asynchronous RPCs cannot throw exceptions".to_owned())
+        );
+        assert_eq!(
+            syntax_error("package test;\n/* unterminated"),
+            (2, "unterminated comment".to_owned())
+        );
+        assert_eq!(
+            syntax_error("package test;\nclass A : 1 {\n};"),
+            (2, "classes are not supported".to_owned())
+        );
+    }
+}
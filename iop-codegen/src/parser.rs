@@ -0,0 +1,524 @@
+use crate::lexer::Token;
+use crate::Error;
+
+// {{{ AST
+
+pub(crate) struct Package {
+    pub name: String,
+    pub items: Vec<Item>,
+}
+
+pub(crate) enum Item {
+    Enum(Enum),
+    Struct(Struct),
+    Union(Struct),
+    Typedef(Typedef),
+    Interface(Interface),
+    Module(Module),
+}
+
+pub(crate) struct Enum {
+    pub name: String,
+    pub values: Vec<(String, i64)>,
+    pub line: usize,
+}
+
+pub(crate) struct Struct {
+    pub name: String,
+    pub fields: Vec<Field>,
+    pub line: usize,
+}
+
+pub(crate) struct Typedef {
+    pub name: String,
+    pub ty: String,
+    pub repeat: Repeat,
+    pub line: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Repeat {
+    Mandatory,
+    Optional,
+    Repeated,
+    Reference,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Literal {
+    Int(i64),
+    Float(String),
+    Str(String),
+    Ident(String),
+}
+
+pub(crate) struct Field {
+    pub name: String,
+    pub tag: u16,
+    pub ty: String,
+    pub repeat: Repeat,
+    pub default: Option<Literal>,
+    /// `@attr(value)` attributes, the value being missing for flags.
+    pub attrs: Vec<(String, Option<Literal>)>,
+    pub line: usize,
+}
+
+/// Arguments, answer or exception of a RPC.
+pub(crate) enum Spec {
+    Void,
+    /// `out null`, for asynchronous RPCs.
+    Null,
+    Fields(Vec<Field>),
+    Type(String),
+}
+
+pub(crate) struct Rpc {
+    pub name: String,
+    pub tag: u16,
+    pub args: Spec,
+    pub res: Spec,
+    pub exn: Spec,
+    pub line: usize,
+}
+
+pub(crate) struct Interface {
+    pub name: String,
+    pub rpcs: Vec<Rpc>,
+}
+
+pub(crate) struct Module {
+    pub name: String,
+    /// Tag, interface and name of the interfaces of the module.
+    pub ifaces: Vec<(u16, String, String)>,
+}
+
+// }}}
+// {{{ Parser
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+pub(crate) fn parse(tokens: Vec<(Token, usize)>) -> Result<Package, Error> {
+    let mut parser = Parser { tokens, pos: 0 };
+
+    parser.skip_attrs()?;
+    parser.expect_keyword("package")?;
+    let name = parser.ident()?;
+    parser.expect(';')?;
+
+    let mut items = Vec::new();
+    while parser.peek().is_some() {
+        items.push(parser.item()?);
+    }
+    Ok(Package { name, items })
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        match self.tokens.get(self.pos) {
+            Some((_, line)) => *line,
+            None => self.tokens.last().map_or(1, |(_, line)| *line),
+        }
+    }
+
+    fn error<T, S: Into<String>>(&self, msg: S) -> Result<T, Error> {
+        Err(Error::syntax(self.line(), msg))
+    }
+
+    fn next(&mut self) -> Result<Token, Error> {
+        match self.tokens.get(self.pos) {
+            Some((token, _)) => {
+                self.pos += 1;
+                Ok(token.clone())
+            }
+            None => self.error("unexpected end of file"),
+        }
+    }
+
+    fn is_punct(&self, c: char) -> bool {
+        self.peek() == Some(&Token::Punct(c))
+    }
+
+    fn is_keyword(&self, kw: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(id)) if id == kw)
+    }
+
+    /// Consume the punctuation if it is the next token.
+    fn eat(&mut self, c: char) -> bool {
+        let res = self.is_punct(c);
+
+        if res {
+            self.pos += 1;
+        }
+        res
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), Error> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            self.error(format!("expected `{}`", c))
+        }
+    }
+
+    fn expect_keyword(&mut self, kw: &str) -> Result<(), Error> {
+        if self.is_keyword(kw) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            self.error(format!("expected `{}`", kw))
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            Some(Token::Ident(id)) => {
+                let id = id.clone();
+
+                self.pos += 1;
+                Ok(id)
+            }
+            _ => self.error("expected an identifier"),
+        }
+    }
+
+    fn tag(&mut self) -> Result<Option<u16>, Error> {
+        match (self.peek(), self.tokens.get(self.pos + 1)) {
+            (Some(Token::Int(tag)), Some((Token::Punct(':'), _))) => {
+                if *tag < 1 || *tag > 0x7fff {
+                    return self.error(format!("invalid tag {}", tag));
+                }
+                let tag = *tag as u16;
+
+                self.pos += 2;
+                Ok(Some(tag))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn literal(&mut self) -> Result<Literal, Error> {
+        let neg = self.eat('-');
+
+        match (self.next()?, neg) {
+            (Token::Int(v), neg) => Ok(Literal::Int(if neg { -v } else { v })),
+            (Token::Float(v), true) => Ok(Literal::Float(format!("-{}", v))),
+            (Token::Float(v), false) => Ok(Literal::Float(v)),
+            (Token::Str(v), false) => Ok(Literal::Str(v)),
+            (Token::Ident(v), false) => Ok(Literal::Ident(v)),
+            _ => {
+                self.pos -= 1;
+                self.error("expected a literal")
+            }
+        }
+    }
+
+    /// Parse the `@attr` and `@attr(...)` attributes preceding an item.
+    fn attrs(&mut self) -> Result<Vec<(String, Option<Literal>)>, Error> {
+        let mut attrs = Vec::new();
+
+        while self.eat('@') {
+            let name = self.ident()?;
+
+            if !self.eat('(') {
+                attrs.push((name, None));
+                continue;
+            }
+            if self.eat(')') {
+                attrs.push((name, None));
+                continue;
+            }
+            let value = self.literal()?;
+            if self.eat(')') {
+                attrs.push((name, Some(value)));
+                continue;
+            }
+
+            // attributes with several arguments have no Rust counterpart
+            let mut depth = 1;
+            while depth > 0 {
+                match self.next()? {
+                    Token::Punct('(') => depth += 1,
+                    Token::Punct(')') => depth -= 1,
+                    _ => (),
+                }
+            }
+        }
+        Ok(attrs)
+    }
+
+    fn skip_attrs(&mut self) -> Result<(), Error> {
+        self.attrs().map(|_| ())
+    }
+
+    /// Closing brace of a block, followed by an optional semicolon.
+    fn end_block(&mut self) -> Result<bool, Error> {
+        if self.eat('}') {
+            self.eat(';');
+            Ok(true)
+        } else if self.peek().is_none() {
+            self.error("expected `}`")
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn item(&mut self) -> Result<Item, Error> {
+        self.skip_attrs()?;
+
+        let line = self.line();
+        let kw = self.ident()?;
+        match kw.as_str() {
+            "enum" => self.enum_def().map(Item::Enum),
+            "struct" => self.struct_def().map(Item::Struct),
+            "union" => self.struct_def().map(Item::Union),
+            "typedef" => {
+                let (ty, repeat) = self.ty()?;
+                let name = self.ident()?;
+
+                self.expect(';')?;
+                Ok(Item::Typedef(Typedef {
+                    name,
+                    ty,
+                    repeat,
+                    line,
+                }))
+            }
+            "interface" => self.interface().map(Item::Interface),
+            "module" => self.module().map(Item::Module),
+            "class" | "abstract" | "local" => Err(Error::syntax(line, "classes are not supported")),
+            "import" => Err(Error::syntax(line, "imports are not supported")),
+            _ => Err(Error::syntax(line, format!("unexpected `{}`", kw))),
+        }
+    }
+
+    fn enum_def(&mut self) -> Result<Enum, Error> {
+        let line = self.line();
+        let name = self.ident()?;
+        let mut values: Vec<(String, i64)> = Vec::new();
+
+        self.expect('{')?;
+        while !self.end_block()? {
+            self.skip_attrs()?;
+
+            let value_name = self.ident()?;
+            let value = if self.eat('=') {
+                match self.literal()? {
+                    Literal::Int(v) => v,
+                    _ => return self.error("expected an integer"),
+                }
+            } else {
+                values.last().map_or(0, |(_, v)| v + 1)
+            };
+            if values.iter().any(|(n, _)| *n == value_name) {
+                return self.error(format!("duplicated value `{}`", value_name));
+            }
+            if value < i32::MIN as i64 || value > i32::MAX as i64 {
+                return self.error(format!("value {} does not fit in an int", value));
+            }
+            values.push((value_name, value));
+            if !self.eat(',') && !self.is_punct('}') {
+                return self.error("expected `,`");
+            }
+        }
+        Ok(Enum { name, values, line })
+    }
+
+    fn ty(&mut self) -> Result<(String, Repeat), Error> {
+        let ty = self.ident()?;
+
+        if self.is_punct('.') {
+            return self.error("types of other packages are not supported");
+        }
+        let repeat = if self.eat('?') {
+            Repeat::Optional
+        } else if self.eat('&') {
+            Repeat::Reference
+        } else if self.eat('[') {
+            self.expect(']')?;
+            Repeat::Repeated
+        } else {
+            Repeat::Mandatory
+        };
+        Ok((ty, repeat))
+    }
+
+    /// Parse a field, with its tag following `prev_tag` if not explicit.
+    fn field(&mut self, prev_tag: u16) -> Result<Field, Error> {
+        let attrs = self.attrs()?;
+        let tag = match self.tag()? {
+            Some(tag) => tag,
+            None if prev_tag < 0x7fff => prev_tag + 1,
+            None => return self.error("tag overflow"),
+        };
+        let line = self.line();
+        let (ty, repeat) = self.ty()?;
+        let name = self.ident()?;
+        let default = if self.eat('=') {
+            if repeat != Repeat::Mandatory {
+                return self.error("only mandatory fields can have default values");
+            }
+            Some(self.literal()?)
+        } else {
+            None
+        };
+
+        Ok(Field {
+            name,
+            tag,
+            ty,
+            repeat,
+            default,
+            attrs,
+            line,
+        })
+    }
+
+    fn check_field(fields: &[Field], field: &Field) -> Result<(), Error> {
+        for other in fields {
+            if other.name == field.name {
+                return Err(Error::syntax(
+                    field.line,
+                    format!("duplicated field `{}`", field.name),
+                ));
+            }
+            if other.tag == field.tag {
+                return Err(Error::syntax(
+                    field.line,
+                    format!("duplicated tag {}", field.tag),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn struct_def(&mut self) -> Result<Struct, Error> {
+        let line = self.line();
+        let name = self.ident()?;
+        let mut fields: Vec<Field> = Vec::new();
+
+        self.expect('{')?;
+        while !self.end_block()? {
+            let field = self.field(fields.last().map_or(0, |f| f.tag))?;
+
+            Self::check_field(&fields, &field)?;
+            fields.push(field);
+            self.expect(';')?;
+        }
+        Ok(Struct { name, fields, line })
+    }
+
+    fn spec(&mut self, allow_null: bool) -> Result<Spec, Error> {
+        if self.eat('(') {
+            let mut fields: Vec<Field> = Vec::new();
+
+            while !self.eat(')') {
+                if !fields.is_empty() {
+                    self.expect(',')?;
+                }
+                let field = self.field(fields.last().map_or(0, |f| f.tag))?;
+
+                Self::check_field(&fields, &field)?;
+                fields.push(field);
+            }
+            return Ok(Spec::Fields(fields));
+        }
+
+        match self.ident()?.as_str() {
+            "void" => Ok(Spec::Void),
+            "null" if allow_null => Ok(Spec::Null),
+            "null" => self.error("only answers can be null"),
+            ty => {
+                let ty = ty.to_owned();
+
+                if self.is_punct('.') {
+                    return self.error("types of other packages are not supported");
+                }
+                Ok(Spec::Type(ty))
+            }
+        }
+    }
+
+    fn interface(&mut self) -> Result<Interface, Error> {
+        let name = self.ident()?;
+        let mut rpcs: Vec<Rpc> = Vec::new();
+
+        self.expect('{')?;
+        while !self.end_block()? {
+            self.skip_attrs()?;
+
+            let tag = match self.tag()? {
+                Some(tag) => tag,
+                None => rpcs.last().map_or(1, |rpc| rpc.tag + 1),
+            };
+            let line = self.line();
+            let rpc_name = self.ident()?;
+            let mut rpc = Rpc {
+                name: rpc_name,
+                tag,
+                args: Spec::Void,
+                res: Spec::Void,
+                exn: Spec::Void,
+                line,
+            };
+
+            if rpcs.iter().any(|other| other.name == rpc.name) {
+                return Err(Error::syntax(
+                    line,
+                    format!("duplicated RPC `{}`", rpc.name),
+                ));
+            }
+            if rpcs.iter().any(|other| other.tag == rpc.tag) {
+                return Err(Error::syntax(line, format!("duplicated tag {}", rpc.tag)));
+            }
+            while !self.eat(';') {
+                match self.ident()?.as_str() {
+                    "in" => rpc.args = self.spec(false)?,
+                    "out" => rpc.res = self.spec(true)?,
+                    "throw" => rpc.exn = self.spec(false)?,
+                    kw => return self.error(format!("unexpected `{}`", kw)),
+                }
+            }
+            if matches!(rpc.res, Spec::Null) && !matches!(rpc.exn, Spec::Void) {
+                return Err(Error::syntax(
+                    line,
+                    "asynchronous RPCs cannot throw exceptions",
+                ));
+            }
+            rpcs.push(rpc);
+        }
+        Ok(Interface { name, rpcs })
+    }
+
+    fn module(&mut self) -> Result<Module, Error> {
+        let name = self.ident()?;
+        let mut ifaces: Vec<(u16, String, String)> = Vec::new();
+
+        self.expect('{')?;
+        while !self.end_block()? {
+            self.skip_attrs()?;
+
+            let tag = match self.tag()? {
+                Some(tag) => tag,
+                None => ifaces.last().map_or(1, |(tag, _, _)| tag + 1),
+            };
+            let iface = self.ident()?;
+            let iface_name = self.ident()?;
+
+            if ifaces.iter().any(|(other, _, _)| *other == tag) {
+                return self.error(format!("duplicated tag {}", tag));
+            }
+            ifaces.push((tag, iface, iface_name));
+            self.expect(';')?;
+        }
+        Ok(Module { name, ifaces })
+    }
+}
+
+// }}}
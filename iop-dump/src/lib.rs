@@ -0,0 +1,327 @@
+//! Dump of the wire structure of packed IOP buffers.
+//!
+//! Without a schema, the contents of blocks are guessed: strings are printed
+//! as strings, and blocks which can be parsed as a sequence of elements are
+//! printed as structs. With a schema, built with `serde_iop::desc::describe`,
+//! the fields are named and printed according to their types:
+//!
+//! ```ignore
+//! fn main() -> std::process::ExitCode {
+//!     let schema = serde_iop::desc::describe::<MyStruct>().unwrap();
+//!
+//!     iop_dump::run(Some(&schema))
+//! }
+//! ```
+use std::fmt::Write;
+use std::io::Read;
+use std::process::ExitCode;
+
+use serde_iop::debug::{parse, Element, Value};
+use serde_iop::desc::{FieldDesc, Schema, TypeDesc};
+use serde_iop::Error;
+
+const USAGE: &str = "usage: iop-dump [--hex] [FILE]
+
+Print the wire structure of a packed IOP buffer, read from FILE or from the
+standard input.
+
+    --hex   the input is in hexadecimal, whitespaces are ignored";
+
+/// Number of bytes printed for bytes and unknown blocks.
+const MAX_BYTES: usize = 32;
+
+/// Dump a packed buffer, one line per element.
+pub fn dump(data: &[u8], schema: Option<&Schema>) -> Result<String, Error> {
+    let elements = parse(data)?;
+    let mut dumper = Dumper {
+        schema,
+        out: String::new(),
+    };
+
+    let fields = match schema {
+        Some(schema) => match schema.root {
+            TypeDesc::Struct(name) => schema.structs.get(name),
+            // unions are packed as their variant at the top level
+            TypeDesc::Union(name) => schema.unions.get(name),
+            _ => None,
+        },
+        None => None,
+    };
+    dumper.elements(&elements, fields.map(Vec::as_slice), 0);
+    Ok(dumper.out)
+}
+
+/// Parse the arguments, and dump the input.
+pub fn run(schema: Option<&Schema>) -> ExitCode {
+    let mut hex = false;
+    let mut path = None;
+
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--hex" => hex = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ if path.is_none() && (arg == "-" || !arg.starts_with('-')) => path = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::from(2);
+            }
+        }
+    }
+
+    let res = read_input(path.as_deref(), hex)
+        .and_then(|data| dump(&data, schema).map_err(|e| e.to_string()));
+    match res {
+        Ok(out) => {
+            print!("{}", out);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("iop-dump: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn read_input(path: Option<&str>, hex: bool) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+
+    let res = match path {
+        Some("-") | None => std::io::stdin().read_to_end(&mut data),
+        Some(path) => std::fs::File::open(path).and_then(|mut f| f.read_to_end(&mut data)),
+    };
+    if let Err(e) = res {
+        return Err(format!("cannot read input: {}", e));
+    }
+
+    if hex {
+        parse_hex(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+fn parse_hex(data: &[u8]) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = data
+        .iter()
+        .copied()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect();
+
+    if !digits.len().is_multiple_of(2) {
+        return Err("odd number of hexadecimal digits".to_owned());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("invalid hexadecimal `{}`", String::from_utf8_lossy(pair)))
+        })
+        .collect()
+}
+
+fn format_bytes(bytes: &[u8]) -> String {
+    let mut res = String::from("[");
+
+    for (i, b) in bytes.iter().take(MAX_BYTES).enumerate() {
+        if i > 0 {
+            res.push(' ');
+        }
+        let _ = write!(res, "{:02x}", b);
+    }
+    if bytes.len() > MAX_BYTES {
+        res.push_str(" ...");
+    }
+    res.push(']');
+    res
+}
+
+fn format_int(v: i64, ty: Option<&TypeDesc>) -> String {
+    match ty {
+        Some(TypeDesc::Bool) => (v != 0).to_string(),
+        Some(TypeDesc::U64) => (v as u64).to_string(),
+        Some(TypeDesc::F32) => f32::from_bits(v as u32).to_string(),
+        Some(TypeDesc::F64) => f64::from_bits(v as u64).to_string(),
+        Some(TypeDesc::Char) => match std::char::from_u32(v as u32) {
+            Some(c) => format!("{:?}", c),
+            None => v.to_string(),
+        },
+        _ => v.to_string(),
+    }
+}
+
+struct Dumper<'s> {
+    schema: Option<&'s Schema>,
+    out: String,
+}
+
+impl<'s> Dumper<'s> {
+    fn elements(&mut self, elements: &[Element], fields: Option<&'s [FieldDesc]>, depth: usize) {
+        for element in elements {
+            let field = fields.and_then(|fields| fields.iter().find(|f| f.tag == element.tag));
+
+            self.element(element, field.map(|f| f.name), field.map(|f| &f.ty), depth);
+        }
+    }
+
+    fn element(
+        &mut self,
+        element: &Element,
+        name: Option<&str>,
+        ty: Option<&'s TypeDesc>,
+        depth: usize,
+    ) {
+        let ty = match ty {
+            Some(TypeDesc::Option(ty)) => Some(&**ty),
+            ty => ty,
+        };
+
+        let _ = write!(
+            self.out,
+            "{:06x}  {:indent$}{:<6} tag {}",
+            element.offset,
+            "",
+            format!("{:?}", element.wire),
+            element.tag,
+            indent = 2 * depth,
+        );
+        if let Some(name) = name {
+            let _ = write!(self.out, " ({})", name);
+        }
+
+        match &element.value {
+            Value::Int(v) => {
+                let _ = writeln!(self.out, ": {}", format_int(*v, ty));
+            }
+            Value::Repeat(items) => {
+                let ty = match ty {
+                    Some(TypeDesc::Array(ty)) => Some(&**ty),
+                    _ => None,
+                };
+
+                let _ = writeln!(self.out, ": array of {}", items.len());
+                for item in items {
+                    self.element(item, None, ty, depth + 1);
+                }
+            }
+            Value::Block(payload) => self.block(element, payload, ty, depth),
+        }
+    }
+
+    fn block(&mut self, element: &Element, payload: &[u8], ty: Option<&'s TypeDesc>, depth: usize) {
+        // strings and bytes are packed with a trailing 0
+        let content = match payload.split_last() {
+            Some((0, content)) => content,
+            _ => payload,
+        };
+        let schema = self.schema;
+        let fields = match ty {
+            Some(TypeDesc::String) => {
+                let _ = writeln!(self.out, ": {:?}", String::from_utf8_lossy(content));
+                return;
+            }
+            Some(TypeDesc::Bytes) => {
+                let _ = writeln!(self.out, ": {}", format_bytes(content));
+                return;
+            }
+            Some(TypeDesc::Struct(name)) => schema.and_then(|s| s.structs.get(name)),
+            Some(TypeDesc::Union(name)) => schema.and_then(|s| s.unions.get(name)),
+            _ => None,
+        };
+
+        if ty.is_none() {
+            if let Some(s) = element.as_str() {
+                let _ = writeln!(self.out, ": {:?}", s);
+                return;
+            }
+        }
+        match element.children() {
+            Some(children) => {
+                let _ = writeln!(self.out, ": {} bytes", payload.len());
+                self.elements(&children, fields.map(Vec::as_slice), depth + 1);
+            }
+            None => {
+                let _ = writeln!(self.out, ": {}", format_bytes(payload));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use serde_iop::desc::describe;
+    use serde_iop::to_bytes;
+
+    #[derive(Serialize, Deserialize)]
+    struct Inner {
+        f: f64,
+        data: Vec<u8>,
+    }
+    #[derive(Serialize, Deserialize)]
+    struct Test {
+        a: u32,
+        name: String,
+        inner: Option<Inner>,
+        tab: Vec<bool>,
+    }
+
+    #[test]
+    fn test_dump() {
+        let test = Test {
+            a: 3,
+            name: "abc".to_owned(),
+            inner: Some(Inner {
+                f: 1.5,
+                data: vec![1],
+            }),
+            tab: vec![true, false],
+        };
+        let bytes = to_bytes(&test).unwrap();
+
+        assert_eq!(
+            dump(&bytes, None).unwrap(),
+            "\
+000000  INT1   tag 1: 3
+000002  BLK1   tag 2: \"abc\"
+000008  BLK4   tag 3: 16 bytes
+00000d    QUAD   tag 1: 4609434218613702656
+000016    REPEAT tag 2: array of 1
+00001b      INT1   tag 0: 1
+00001d  REPEAT tag 4: array of 2
+000022    INT1   tag 0: 1
+000024    INT1   tag 0: 0
+"
+        );
+
+        let schema = describe::<Test>().unwrap();
+        assert_eq!(
+            dump(&bytes, Some(&schema)).unwrap(),
+            "\
+000000  INT1   tag 1 (a): 3
+000002  BLK1   tag 2 (name): \"abc\"
+000008  BLK4   tag 3 (inner): 16 bytes
+00000d    QUAD   tag 1 (f): 1.5
+000016    REPEAT tag 2 (data): array of 1
+00001b      INT1   tag 0: 1
+00001d  REPEAT tag 4 (tab): array of 2
+000022    INT1   tag 0: true
+000024    INT1   tag 0: false
+"
+        );
+
+        assert!(dump(&bytes[..4], None).is_err());
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex(b"01 ff\n0A").unwrap(), [0x01, 0xFF, 0x0A]);
+        assert!(parse_hex(b"0").is_err());
+        assert!(parse_hex(b"0g").is_err());
+    }
+}
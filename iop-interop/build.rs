@@ -0,0 +1,37 @@
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    let var = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let manifest_dir = Path::new(&var);
+    let root_dir = manifest_dir.join("../sys/lib-common");
+    let src_dir = root_dir.join("src");
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let iop_file = manifest_dir.join("iop/tstiop.iop");
+
+    println!("cargo:rerun-if-changed=iop/tstiop.iop");
+    println!("cargo:rerun-if-changed=src/helpers.c");
+
+    // Generate the C descriptors of the test package
+    let status = Command::new(src_dir.join("iopc/iopc"))
+        .arg("-l")
+        .arg("c")
+        .arg("-o")
+        .arg(&out_dir)
+        .arg(&iop_file)
+        .status()
+        .expect("cannot run iopc");
+    assert!(status.success(), "iopc failed on {}", iop_file.display());
+
+    cc::Build::new()
+        .file(Path::new(&out_dir).join("tstiop.iop.c"))
+        .file("src/helpers.c")
+        .include(&root_dir)
+        .include(src_dir.join("compat"))
+        .include(&out_dir)
+        .define("_GNU_SOURCE", "1")
+        .flag("-std=gnu11")
+        .warnings(false)
+        .compile("tstiop");
+}
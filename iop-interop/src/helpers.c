@@ -0,0 +1,27 @@
+/* Helpers exposing the C packer to the interop tests. */
+
+#include "lib-common/iop.h"
+
+/* Unpack a value with the C library, then pack it again. */
+int iop_interop_repack(const iop_struct_t *st, const void *data, int len,
+                       void **out, int *out_len)
+{
+    t_scope;
+    void *value = NULL;
+    lstr_t res;
+
+    if (iop_bunpack_ptr(t_pool(), st, &value, ps_init(data, len), false) < 0)
+    {
+        return -1;
+    }
+    res = t_iop_bpack_struct(st, value);
+
+    *out = p_dup((const char *)res.s, res.len);
+    *out_len = res.len;
+    return 0;
+}
+
+void iop_interop_free(void *data)
+{
+    p_delete(&data);
+}
@@ -0,0 +1,61 @@
+//! Differential testing of serde-iop against the C IOP packer.
+//!
+//! The `tstiop` package is compiled with iopc, and its descriptors are
+//! linked with lib-common, so that values packed by serde-iop can be
+//! unpacked and packed again by the C library.
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+
+/// Opaque IOP struct descriptor.
+#[repr(C)]
+pub struct IopStruct {
+    _private: [u8; 0],
+}
+
+extern "C" {
+    #[link_name = "tstiop__inner__s"]
+    pub static TSTIOP_INNER: IopStruct;
+    #[link_name = "tstiop__basic__s"]
+    pub static TSTIOP_BASIC: IopStruct;
+
+    fn iop_interop_repack(
+        st: *const IopStruct,
+        data: *const c_void,
+        len: c_int,
+        out: *mut *mut c_void,
+        out_len: *mut c_int,
+    ) -> c_int;
+    fn iop_interop_free(data: *mut c_void);
+}
+
+// Make sure lib-common is linked
+extern crate libcommon_sys;
+
+/// Unpack `data` with the C library as a value of type `st`, and pack it
+/// again.
+///
+/// Returns `None` if the C library failed to unpack the data.
+pub fn c_repack(st: &IopStruct, data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = ptr::null_mut();
+    let mut out_len = 0;
+
+    let res = unsafe {
+        iop_interop_repack(
+            st,
+            data.as_ptr() as *const c_void,
+            data.len() as c_int,
+            &mut out,
+            &mut out_len,
+        )
+    };
+    if res < 0 {
+        return None;
+    }
+
+    let packed = unsafe {
+        let packed = std::slice::from_raw_parts(out as *const u8, out_len as usize).to_vec();
+        iop_interop_free(out);
+        packed
+    };
+    Some(packed)
+}
@@ -0,0 +1,168 @@
+use libcommon_iop_interop::{c_repack, IopStruct, TSTIOP_BASIC, TSTIOP_INNER};
+use serde::{Deserialize, Serialize};
+use serde_iop::{from_bytes, to_bytes, DeserializeOwned};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::fmt::Debug;
+
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug, Clone)]
+#[repr(i32)]
+enum MyEnum {
+    A = 0,
+    B = 1,
+    C = 16,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+enum MyUnion {
+    Ua(i32),
+    Ub(i8),
+    Us(String),
+}
+
+#[serde_iop::iop]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+struct Inner {
+    a: Option<i32>,
+    #[iop(tag = 3)]
+    b: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+struct Basic {
+    i: i32,
+    u: u32,
+    i8: i8,
+    u8: u8,
+    i16: i16,
+    u16: u16,
+    l: i64,
+    ul: u64,
+    d: f64,
+    b: bool,
+    s: String,
+    e: MyEnum,
+    un: MyUnion,
+    inner: Inner,
+    opt_inner: Option<Inner>,
+    tab: Vec<i32>,
+    strs: Vec<String>,
+}
+
+/// Check that the C packer produces the same bytes as serde-iop for
+/// `value`, and that serde-iop can read them back.
+fn check<T>(st: &IopStruct, value: &T)
+where
+    T: Serialize + DeserializeOwned + PartialEq + Debug,
+{
+    let bytes = to_bytes(value).unwrap();
+    let c_bytes = c_repack(st, &bytes).expect("C unpacking failed");
+
+    assert_eq!(bytes, c_bytes, "packing of {:?} differs", value);
+    assert_eq!(&from_bytes::<T>(&c_bytes).unwrap(), value);
+}
+
+fn basic() -> Basic {
+    Basic {
+        i: -42,
+        u: 5,
+        i8: -120,
+        u8: 230,
+        i16: -540,
+        u16: 2000,
+        l: i64::MIN,
+        ul: u64::MAX,
+        d: 2.5,
+        b: true,
+        s: "baré© \" foo .".to_owned(),
+        e: MyEnum::C,
+        un: MyUnion::Us("union".to_owned()),
+        inner: Inner {
+            a: Some(1),
+            b: "b".to_owned(),
+        },
+        opt_inner: None,
+        tab: vec![1, -1, i32::MAX, i32::MIN],
+        strs: vec!["a".to_owned(), "".to_owned()],
+    }
+}
+
+#[test]
+fn test_inner() {
+    check(
+        unsafe { &TSTIOP_INNER },
+        &Inner {
+            a: None,
+            b: "".to_owned(),
+        },
+    );
+    check(
+        unsafe { &TSTIOP_INNER },
+        &Inner {
+            a: Some(-1),
+            b: "foo".repeat(100),
+        },
+    );
+}
+
+#[test]
+fn test_basic() {
+    let st = unsafe { &TSTIOP_BASIC };
+
+    check(st, &basic());
+    check(
+        st,
+        &Basic {
+            un: MyUnion::Ua(0),
+            opt_inner: Some(basic().inner),
+            ..basic()
+        },
+    );
+    check(
+        st,
+        &Basic {
+            i: 0,
+            u: u32::MAX,
+            l: 1 << 40,
+            ul: 0,
+            un: MyUnion::Ub(-1),
+            s: "x".repeat(70000),
+            ..basic()
+        },
+    );
+}
+
+#[test]
+fn test_special_doubles() {
+    let st = unsafe { &TSTIOP_BASIC };
+
+    for d in [
+        f64::NAN,
+        f64::from_bits(0x7ff0_0000_dead_beef),
+        f64::INFINITY,
+        f64::NEG_INFINITY,
+        -0.0,
+        f64::MIN_POSITIVE / 2.0,
+    ]
+    .iter()
+    {
+        // NaN is not equal to itself, so the bits are compared
+        let bytes = to_bytes(&Basic { d: *d, ..basic() }).unwrap();
+        let c_bytes = c_repack(st, &bytes).expect("C unpacking failed");
+
+        assert_eq!(bytes, c_bytes, "packing of {} differs", d);
+        assert_eq!(
+            from_bytes::<Basic>(&c_bytes).unwrap().d.to_bits(),
+            d.to_bits()
+        );
+    }
+}
+
+#[test]
+fn test_invalid_input() {
+    // the C library rejects what serde-iop rejects
+    let bytes = to_bytes(&basic()).unwrap();
+    let truncated = &bytes[..(bytes.len() - 1)];
+
+    assert!(c_repack(unsafe { &TSTIOP_BASIC }, truncated).is_none());
+    assert!(from_bytes::<Basic>(truncated).is_err());
+}
@@ -0,0 +1,393 @@
+//! Attribute macro describing IOP attributes of the fields of a type.
+//!
+//! serde does not provide a way to give custom attributes to a serializer,
+//! so this macro gathers the `#[iop(...)]` attributes of the fields of a
+//! type in an attribute table, appended to the serde name of the type. The
+//! names of the fields are left untouched, so the type serializes as before
+//! in the other formats. The macro must thus be placed before the serde
+//! derives:
+//!
+//! ```ignore
+//! #[serde_iop::iop]
+//! #[derive(Serialize, Deserialize)]
+//! struct User {
+//!     #[iop(non_empty, max_length = 64)]
+//!     name: String,
+//!     #[iop(min = 0, max = 150)]
+//!     age: i32,
+//! }
+//! ```
+//!
+//! Negative bounds must be given as strings, eg `#[iop(min = "-1")]`.
+//!
+//! The tag of a field or of a union variant is given with `#[iop(tag = 3)]`.
+//! Fields and variants without explicit tags follow the previous one, the
+//! first one having tag 1. Tags can have gaps, for example when deprecated
+//! fields are removed.
+//!
+//! `#[iop(inline)]` packs the fields of a struct field directly in the
+//! enclosing struct, their tags following the previous field. This replaces
+//! `#[serde(flatten)]`, which relies on self-describing formats. Inlined
+//! fields cannot be optional.
+//!
+//! A field or variant can be renamed without changing its tag. Its former
+//! names are given with `#[iop(alias = "old_name")]`, which can be repeated
+//! during a migration: they are still accepted when transcoding from other
+//! formats. `#[serde(alias)]` cannot be used, as serde lists the aliases
+//! with the fields, which would shift their tags.
+//!
+//! Byte buffers (`Vec<u8>`) are packed as arrays by serde; `#[iop(bytes)]`
+//! packs them as a single blob, as done for the IOP `bytes` type.
+//!
+//! The attributes are found by the serde names of the fields, so
+//! `#[serde(rename_all)]` cannot be used on types with IOP attributes.
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Error, Ident, Lit, LitStr, Meta, NestedMeta,
+    Result,
+};
+
+#[proc_macro_attribute]
+pub fn iop(_args: TokenStream, input: TokenStream) -> TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+
+    match expand(&mut input) {
+        Ok(()) => quote!(#input).into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(input: &mut DeriveInput) -> Result<()> {
+    let table = match &mut input.data {
+        Data::Struct(data) => expand_items(
+            data.fields
+                .iter_mut()
+                .map(|f| (&mut f.attrs, f.ident.as_ref().map(ident_to_name))),
+            true,
+        ),
+        Data::Enum(data) => expand_items(
+            data.variants
+                .iter_mut()
+                .map(|v| (&mut v.attrs, Some(ident_to_name(&v.ident)))),
+            false,
+        ),
+        Data::Union(_) => Err(Error::new(
+            Span::call_site(),
+            "iop attributes are not supported on unions",
+        )),
+    }?;
+    if table.is_empty() {
+        return Ok(());
+    }
+    reject_serde_rename_all(&input.attrs)?;
+
+    // the table follows the name of the type, each field being given as
+    // `name;attr=value;...`
+    let mut name = match take_serde_rename(&mut input.attrs)? {
+        Some(name) => name,
+        None => ident_to_name(&input.ident),
+    };
+    for record in table {
+        name.push('\0');
+        name.push_str(&record);
+    }
+    let name = LitStr::new(&name, Span::call_site());
+    input
+        .attrs
+        .push(syn::parse_quote!(#[serde(rename = #name)]));
+    Ok(())
+}
+
+fn ident_to_name(ident: &Ident) -> String {
+    ident.to_string().trim_start_matches("r#").to_owned()
+}
+
+/// Encode the IOP attributes of the fields or variants of a type, returning
+/// the records of the attribute table of the type.
+///
+/// If any of them has an explicit tag, the tags of all of them are encoded,
+/// the ones without explicit tags following the previous one. Struct fields
+/// are packed in their declaration order, so their tags must be increasing.
+/// The implicit tags of the fields following an inlined struct are left to
+/// the serializer, as they depend on the number of fields of that struct.
+fn expand_items<'a, I>(items: I, is_struct: bool) -> Result<Vec<String>>
+where
+    I: Iterator<Item = (&'a mut Vec<Attribute>, Option<String>)>,
+{
+    let mut parsed = Vec::new();
+    let mut table = Vec::new();
+
+    for (attrs, name) in items {
+        let mut iop_attrs = IopAttrs::default();
+
+        for attr in take_attrs(attrs, "iop") {
+            parse_iop_attr(&attr, &mut iop_attrs)?;
+        }
+        reject_serde_alias(attrs)?;
+        // the pattern can contain any character, so it must be the last one
+        iop_attrs.encoded.sort_by_key(|v| v.starts_with("pattern="));
+        parsed.push((attrs, name, iop_attrs));
+    }
+
+    let has_tags = parsed.iter().any(|(_, _, a)| a.tag.is_some());
+    let mut prev_tags = Vec::new();
+    // the tags of the fields following an inlined struct depend on its
+    // number of fields
+    let mut after_inline = false;
+    for (attrs, name, iop_attrs) in parsed {
+        let mut encoded = iop_attrs.encoded;
+
+        if iop_attrs.bytes {
+            attrs.push(syn::parse_quote!(#[serde(with = "serde_iop::serde_bytes")]));
+        }
+        if !iop_attrs.aliases.is_empty() {
+            encoded.insert(0, format!("alias={}", iop_attrs.aliases.join(",")));
+        }
+        if iop_attrs.inline && !is_struct {
+            return Err(Error::new(
+                Span::call_site(),
+                "inline is only supported on struct fields",
+            ));
+        }
+
+        let prev_tag: u16 = prev_tags.last().copied().unwrap_or(0);
+        let tag = match iop_attrs.tag {
+            Some(v) => Some(v),
+            None if !has_tags || iop_attrs.inline || after_inline => None,
+            None => match prev_tag.checked_add(1) {
+                Some(tag) => Some((tag, Span::call_site())),
+                None => return Err(Error::new(Span::call_site(), "tag overflow")),
+            },
+        };
+        if let Some((tag, span)) = tag {
+            if is_struct && tag <= prev_tag {
+                return Err(Error::new(span, "field tags must be increasing"));
+            }
+            if prev_tags.contains(&tag) {
+                return Err(Error::new(span, format!("duplicated tag {}", tag)));
+            }
+            encoded.insert(0, format!("tag={}", tag));
+            prev_tags.push(tag);
+        }
+        after_inline = iop_attrs.inline || (after_inline && tag.is_none());
+        if encoded.is_empty() {
+            continue;
+        }
+
+        let name = match serde_rename(attrs)? {
+            Some(name) => name,
+            None => name.ok_or_else(|| {
+                Error::new(
+                    Span::call_site(),
+                    "iop attributes are only supported on named fields",
+                )
+            })?,
+        };
+
+        let mut record = name;
+        for attr in encoded {
+            record.push(';');
+            record.push_str(&attr);
+        }
+        table.push(record);
+    }
+    Ok(table)
+}
+
+/// Remove and return the attributes with the given name.
+fn take_attrs(attrs: &mut Vec<Attribute>, name: &str) -> Vec<Attribute> {
+    let (taken, kept) = attrs.drain(..).partition(|a| a.path.is_ident(name));
+
+    *attrs = kept;
+    taken
+}
+
+/// Content of the `#[iop(...)]` attributes of a field or variant.
+#[derive(Default)]
+struct IopAttrs {
+    tag: Option<(u16, Span)>,
+    /// Pack the field as a blob rather than as an array of bytes.
+    bytes: bool,
+    /// Pack the fields of the struct in the enclosing struct.
+    inline: bool,
+    /// Former names of the field or variant.
+    aliases: Vec<String>,
+    /// Attributes to encode in the attribute table, in their `attr=value`
+    /// forms.
+    encoded: Vec<String>,
+}
+
+/// Parse an `#[iop(...)]` attribute.
+fn parse_iop_attr(attr: &Attribute, res: &mut IopAttrs) -> Result<()> {
+    let list = match attr.parse_meta()? {
+        Meta::List(list) => list,
+        meta => return Err(Error::new_spanned(meta, "expected #[iop(...)]")),
+    };
+
+    for nested in list.nested {
+        let meta = match nested {
+            NestedMeta::Meta(meta) => meta,
+            NestedMeta::Lit(lit) => return Err(Error::new_spanned(lit, "unexpected literal")),
+        };
+
+        let encoded = match &meta {
+            Meta::Path(path) if path.is_ident("non_empty") => "nonEmpty".to_owned(),
+            Meta::Path(path) if path.is_ident("non_zero") => "nonZero".to_owned(),
+            Meta::Path(path) if path.is_ident("bytes") => {
+                res.bytes = true;
+                continue;
+            }
+            Meta::Path(path) if path.is_ident("inline") => {
+                res.inline = true;
+                "inline".to_owned()
+            }
+            Meta::NameValue(nv) if nv.path.is_ident("tag") => {
+                let tag = match &nv.lit {
+                    Lit::Int(v) if v.base10_parse::<u16>()? > 0 => v.base10_parse()?,
+                    lit => return Err(Error::new_spanned(lit, "expected a positive integer")),
+                };
+                res.tag = Some((tag, nv.path.get_ident().unwrap().span()));
+                continue;
+            }
+            Meta::NameValue(nv) if nv.path.is_ident("alias") => {
+                let alias = match &nv.lit {
+                    Lit::Str(v) if is_valid_alias(&v.value()) => v.value(),
+                    lit => return Err(Error::new_spanned(lit, "expected a field name")),
+                };
+                res.aliases.push(alias);
+                continue;
+            }
+            Meta::NameValue(nv) => {
+                let key = match nv.path.get_ident().map(|i| i.to_string()).as_deref() {
+                    Some("min") => "min",
+                    Some("max") => "max",
+                    Some("min_length") => "minLength",
+                    Some("max_length") => "maxLength",
+                    Some("min_occurs") => "minOccurs",
+                    Some("max_occurs") => "maxOccurs",
+                    Some("pattern") => "pattern",
+                    _ => return Err(Error::new_spanned(&nv.path, "unknown iop attribute")),
+                };
+                let value = lit_to_string(&nv.lit)?;
+                if value.contains('\0') {
+                    return Err(Error::new_spanned(&nv.lit, "unexpected NUL character"));
+                }
+                format!("{}={}", key, value)
+            }
+            _ => return Err(Error::new_spanned(meta, "unknown iop attribute")),
+        };
+        res.encoded.push(encoded);
+    }
+    Ok(())
+}
+
+/// Aliases are encoded in the attribute table, separated by commas.
+fn is_valid_alias(alias: &str) -> bool {
+    !alias.is_empty() && !alias.contains(&[';', ',', '\0'][..])
+}
+
+fn lit_to_string(lit: &Lit) -> Result<String> {
+    match lit {
+        Lit::Int(v) => Ok(v.base10_digits().to_owned()),
+        Lit::Float(v) => Ok(v.base10_digits().to_owned()),
+        Lit::Str(v) => Ok(v.value()),
+        _ => Err(Error::new_spanned(lit, "expected a number or a string")),
+    }
+}
+
+/// serde lists the aliases of fields with their names, so they would be
+/// given tags, shifting the tags of the following fields.
+fn reject_serde_alias(attrs: &[Attribute]) -> Result<()> {
+    for attr in attrs.iter().filter(|a| a.path.is_ident("serde")) {
+        if let Meta::List(list) = attr.parse_meta()? {
+            for nested in list.nested {
+                match &nested {
+                    NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("alias") => {
+                        return Err(Error::new_spanned(
+                            nv,
+                            "serde aliases change the tags of the following fields, \
+                             use #[iop(alias = \"...\")]",
+                        ));
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The attributes are found by the serde names of the fields, which
+/// `rename_all` would change.
+fn reject_serde_rename_all(attrs: &[Attribute]) -> Result<()> {
+    for attr in attrs.iter().filter(|a| a.path.is_ident("serde")) {
+        if let Meta::List(list) = attr.parse_meta()? {
+            for nested in list.nested {
+                if let NestedMeta::Meta(meta) = &nested {
+                    if meta.path().is_ident("rename_all") {
+                        return Err(Error::new_spanned(
+                            meta,
+                            "rename_all is not supported on types with iop attributes, \
+                             rename the fields with #[serde(rename = \"...\")]",
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Value of the `rename = "..."` item of the serde attributes.
+fn serde_rename(attrs: &[Attribute]) -> Result<Option<String>> {
+    let mut name = None;
+
+    for attr in attrs.iter().filter(|a| a.path.is_ident("serde")) {
+        if let Meta::List(list) = attr.parse_meta()? {
+            for nested in list.nested {
+                match &nested {
+                    NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("rename") => {
+                        match &nv.lit {
+                            Lit::Str(s) => name = Some(s.value()),
+                            lit => return Err(Error::new_spanned(lit, "expected a string")),
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+    Ok(name)
+}
+
+/// Remove the `rename = "..."` item of the serde attributes, returning its
+/// value.
+fn take_serde_rename(attrs: &mut [Attribute]) -> Result<Option<String>> {
+    let mut name = None;
+
+    for attr in attrs.iter_mut().filter(|a| a.path.is_ident("serde")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            _ => continue,
+        };
+
+        let mut kept = Vec::new();
+        for nested in list.nested {
+            match &nested {
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("rename") => {
+                    match &nv.lit {
+                        Lit::Str(s) => name = Some(s.value()),
+                        lit => return Err(Error::new_spanned(lit, "expected a string")),
+                    }
+                }
+                _ => kept.push(nested),
+            }
+        }
+        *attr = syn::parse_quote!(#[serde(#(#kept),*)]);
+    }
+    Ok(name)
+}
@@ -0,0 +1,131 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use serde::{Deserialize, Serialize};
+use serde_iop::{from_bytes, to_bytes, DeserializeOwned};
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Small {
+    id: u64,
+    name: String,
+    email: Option<String>,
+    is_admin: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Blob {
+    name: String,
+    data: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Bytes {
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Arrays {
+    ints: Vec<i32>,
+    longs: Vec<u64>,
+    structs: Vec<Small>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+enum Node {
+    Leaf(i32),
+    Nested(Box<Deep>),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Deep {
+    depth: u32,
+    node: Node,
+}
+
+fn small() -> Small {
+    Small {
+        id: 42,
+        name: "John Doe".to_owned(),
+        email: Some("john.doe@example.com".to_owned()),
+        is_admin: false,
+    }
+}
+
+fn blob() -> Blob {
+    Blob {
+        name: "blob".to_owned(),
+        data: "a".repeat(1 << 20),
+    }
+}
+
+fn bytes(len: usize) -> Bytes {
+    Bytes {
+        data: (0..len).map(|v| v as u8).collect(),
+    }
+}
+
+fn arrays() -> Arrays {
+    Arrays {
+        ints: (-5000..5000).collect(),
+        longs: (0..10_000).map(|v| v << 33).collect(),
+        structs: (0..1000).map(|_| small()).collect(),
+    }
+}
+
+fn deep(depth: u32) -> Deep {
+    let mut v = Deep {
+        depth: 0,
+        node: Node::Leaf(0),
+    };
+    for i in 1..=depth {
+        v = Deep {
+            depth: i,
+            node: Node::Nested(Box::new(v)),
+        };
+    }
+    v
+}
+
+fn bench_value<T>(c: &mut Criterion, name: &str, value: &T)
+where
+    T: Serialize + DeserializeOwned,
+{
+    let bytes = to_bytes(value).unwrap();
+    let mut group = c.benchmark_group(name);
+
+    group.throughput(Throughput::Bytes(bytes.len() as u64));
+    group.bench_function("to_bytes", |b| b.iter(|| to_bytes(black_box(value))));
+    group.bench_function("from_bytes", |b| {
+        b.iter(|| from_bytes::<T>(black_box(&bytes)))
+    });
+    group.finish();
+}
+
+fn bench_small(c: &mut Criterion) {
+    bench_value(c, "small", &small());
+}
+
+fn bench_blob(c: &mut Criterion) {
+    bench_value(c, "blob", &blob());
+}
+
+fn bench_bytes(c: &mut Criterion) {
+    bench_value(c, "bytes_16MiB", &bytes(16 << 20));
+}
+
+fn bench_arrays(c: &mut Criterion) {
+    bench_value(c, "arrays", &arrays());
+}
+
+fn bench_deep(c: &mut Criterion) {
+    bench_value(c, "deep", &deep(100));
+}
+
+criterion_group!(
+    benches,
+    bench_small,
+    bench_blob,
+    bench_bytes,
+    bench_arrays,
+    bench_deep
+);
+criterion_main!(benches);
@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use serde_iop_fuzz::{check_from_bytes, Basic};
+
+fuzz_target!(|data: &[u8]| {
+    check_from_bytes::<Basic>(data);
+});
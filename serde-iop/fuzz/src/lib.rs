@@ -0,0 +1,84 @@
+//! Types unpacked by the fuzz targets.
+//!
+//! Run a target with `cargo fuzz run <target>` from the `serde-iop`
+//! directory.
+use serde::{Deserialize, Serialize};
+use serde_iop::{from_bytes, to_bytes, DeserializeOwned};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::fmt::Debug;
+
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+#[repr(i32)]
+pub enum Enum {
+    A = 0,
+    B = 1,
+    C = 16,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub enum Union {
+    I(i32),
+    L(u64),
+    S(String),
+    St(Box<Inner>),
+    Tab(Vec<i16>),
+}
+
+#[serde_iop::iop]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Inner {
+    pub a: Option<i8>,
+    #[iop(tag = 3, max_length = 16)]
+    pub b: String,
+    pub c: Option<Union>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Basic {
+    pub i8: i8,
+    pub u8: u8,
+    pub i16: i16,
+    pub u16: u16,
+    pub i32: i32,
+    pub u32: u32,
+    pub i64: i64,
+    pub u64: u64,
+    pub f32: f32,
+    pub f64: f64,
+    pub b: bool,
+    pub c: char,
+    pub e: Enum,
+    pub s: String,
+    pub opt: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct WithUnion {
+    pub u: Union,
+    pub opt: Option<Union>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Nested {
+    pub inner: Inner,
+    pub opt_inner: Option<Inner>,
+    pub inners: Vec<Inner>,
+    pub unions: Vec<Union>,
+    pub strs: Vec<String>,
+    pub tab: Vec<u32>,
+}
+
+/// Unpack `data`, and if it is valid, check that the value can be packed
+/// and unpacked again.
+pub fn check_from_bytes<T>(data: &[u8])
+where
+    T: Serialize + DeserializeOwned + Debug,
+{
+    if let Ok(value) = from_bytes::<T>(data) {
+        let bytes = to_bytes(&value).expect("cannot pack an unpacked value");
+
+        // compare the debug outputs, as floats can be NaN
+        let unpacked = from_bytes::<T>(&bytes).expect("cannot unpack a packed value");
+        assert_eq!(format!("{:?}", unpacked), format!("{:?}", value));
+    }
+}
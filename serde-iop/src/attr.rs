@@ -0,0 +1,230 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::constraints::{Bound, Constraints};
+use crate::error::{Error, Result};
+
+/// Attributes of a field or of a union variant.
+///
+/// The `iop` attribute macro encodes them as `name;attr=value;...` in the
+/// attribute table of the type, see `TypeAttrs`. The `pattern` attribute,
+/// whose value can contain any character, is always the last one.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FieldAttrs {
+    pub name: &'static str,
+    pub tag: Option<u16>,
+    /// The fields of the struct are packed in the enclosing struct.
+    pub inline: bool,
+    pub constraints: Constraints,
+    /// Former names of the field, separated by commas.
+    aliases: &'static str,
+}
+
+impl FieldAttrs {
+    pub fn parse(encoded: &'static str) -> Result<Self> {
+        let mut parts = encoded.splitn(2, ';');
+        let name = parts.next().unwrap_or("");
+        let mut attrs = Self {
+            name,
+            tag: None,
+            inline: false,
+            constraints: Constraints::default(),
+            aliases: "",
+        };
+        let mut rest = match parts.next() {
+            Some(rest) => rest,
+            None => return Ok(attrs),
+        };
+
+        while !rest.is_empty() {
+            if let Some(pattern) = rest.strip_prefix("pattern=") {
+                attrs.constraints.pattern = Some(pattern);
+                break;
+            }
+
+            let (attr, next) = match rest.find(';') {
+                Some(pos) => (&rest[..pos], &rest[(pos + 1)..]),
+                None => (rest, ""),
+            };
+            parse_attr(&mut attrs, attr)?;
+            rest = next;
+        }
+        Ok(attrs)
+    }
+
+    /// Former names of the field, still accepted by name-based formats.
+    pub fn aliases(&self) -> impl Iterator<Item = &'static str> {
+        self.aliases.split(',').filter(|v| !v.is_empty())
+    }
+}
+
+/// Attributes of the fields or variants of a type.
+///
+/// serde only gives the names of the types and of their fields to the
+/// serializers, so the `iop` attribute macro appends the attributes of the
+/// fields to the serde name of the type, as `Name\0field;attr...\0...`. The
+/// field names are left untouched for the other formats.
+///
+/// The table of a type is parsed once per thread, and cached by address.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TypeAttrs {
+    /// Name of the type, without its attribute table.
+    pub name: &'static str,
+    fields: &'static [FieldAttrs],
+}
+
+thread_local! {
+    static TABLES: RefCell<HashMap<(usize, usize), TypeAttrs>> = RefCell::new(HashMap::new());
+}
+
+impl TypeAttrs {
+    /// Attributes of the type with the given serde name.
+    pub fn get(encoded: &'static str) -> Result<Self> {
+        let pos = match encoded.find('\0') {
+            Some(pos) => pos,
+            None => {
+                return Ok(Self {
+                    name: encoded,
+                    fields: &[],
+                })
+            }
+        };
+        let key = (encoded.as_ptr() as usize, encoded.len());
+
+        if let Some(attrs) = TABLES.with(|t| t.borrow().get(&key).copied()) {
+            return Ok(attrs);
+        }
+        let fields = encoded[(pos + 1)..]
+            .split('\0')
+            .map(FieldAttrs::parse)
+            .collect::<Result<Vec<_>>>()?;
+        let attrs = Self {
+            name: &encoded[..pos],
+            // leaked once per type and thread
+            fields: Box::leak(fields.into_boxed_slice()),
+        };
+
+        TABLES.with(|t| t.borrow_mut().insert(key, attrs));
+        Ok(attrs)
+    }
+
+    /// Attributes of a field or variant, by serde name.
+    pub fn field(&self, name: &'static str) -> FieldAttrs {
+        self.fields
+            .iter()
+            .find(|f| f.name == name)
+            .copied()
+            .unwrap_or(FieldAttrs {
+                name,
+                ..Default::default()
+            })
+    }
+}
+
+fn invalid(name: &str, attr: &str) -> Error {
+    Error::Custom(format!("invalid attribute `{}` on field `{}`", attr, name))
+}
+
+fn parse_attr(attrs: &mut FieldAttrs, attr: &'static str) -> Result<()> {
+    let name = attrs.name;
+    let c = &mut attrs.constraints;
+    let (key, value) = match attr.find('=') {
+        Some(pos) => (&attr[..pos], Some(&attr[(pos + 1)..])),
+        None => (attr, None),
+    };
+    let parse_usize = |v: &str| v.parse().map_err(|_| invalid(name, attr));
+    let parse_bound = |v: &str| parse_bound(v).ok_or_else(|| invalid(name, attr));
+
+    match (key, value) {
+        ("tag", Some(v)) => attrs.tag = Some(v.parse().map_err(|_| invalid(name, attr))?),
+        ("inline", None) => attrs.inline = true,
+        ("alias", Some(v)) => attrs.aliases = v,
+        ("nonEmpty", None) => c.non_empty = true,
+        ("nonZero", None) => c.non_zero = true,
+        ("min", Some(v)) => c.min = Some(parse_bound(v)?),
+        ("max", Some(v)) => c.max = Some(parse_bound(v)?),
+        ("minLength", Some(v)) => c.min_length = Some(parse_usize(v)?),
+        ("maxLength", Some(v)) => c.max_length = Some(parse_usize(v)?),
+        ("minOccurs", Some(v)) => c.min_occurs = Some(parse_usize(v)?),
+        ("maxOccurs", Some(v)) => c.max_occurs = Some(parse_usize(v)?),
+        _ => return Err(invalid(name, attr)),
+    }
+    Ok(())
+}
+
+fn parse_bound(v: &str) -> Option<Bound> {
+    match v.parse() {
+        Ok(i) => Some(Bound::Int(i)),
+        Err(_) => v.parse().ok().map(Bound::Float),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            FieldAttrs::parse("name").unwrap(),
+            FieldAttrs {
+                name: "name",
+                tag: None,
+                inline: false,
+                constraints: Constraints::default(),
+                aliases: "",
+            }
+        );
+
+        let attrs =
+            FieldAttrs::parse("a;nonEmpty;min=-3;max=2.5;maxLength=4;pattern=a;b=c").unwrap();
+        assert_eq!(attrs.name, "a");
+        assert_eq!(
+            attrs.constraints,
+            Constraints {
+                non_empty: true,
+                min: Some(Bound::Int(-3)),
+                max: Some(Bound::Float(2.5)),
+                max_length: Some(4),
+                pattern: Some("a;b=c"),
+                ..Default::default()
+            }
+        );
+
+        let attrs = FieldAttrs::parse("a;tag=12;nonZero").unwrap();
+        assert_eq!(attrs.tag, Some(12));
+        assert!(attrs.constraints.non_zero);
+        assert!(!attrs.inline);
+
+        let attrs = FieldAttrs::parse("a;inline").unwrap();
+        assert!(attrs.inline);
+        assert_eq!(attrs.aliases().count(), 0);
+
+        let attrs = FieldAttrs::parse("a;tag=2;alias=b,c").unwrap();
+        assert_eq!(attrs.aliases().collect::<Vec<_>>(), ["b", "c"]);
+
+        assert!(FieldAttrs::parse("a;min").is_err());
+        assert!(FieldAttrs::parse("a;maxLength=-1").is_err());
+        assert!(FieldAttrs::parse("a;foo=1").is_err());
+    }
+
+    #[test]
+    fn test_type_attrs() {
+        let attrs = TypeAttrs::get("User").unwrap();
+        assert_eq!(attrs.name, "User");
+        assert_eq!(attrs.field("a"), FieldAttrs::parse("a").unwrap());
+
+        let encoded = "User\0a;tag=2\0b;pattern=x;y";
+        let attrs = TypeAttrs::get(encoded).unwrap();
+        assert_eq!(attrs.name, "User");
+        assert_eq!(attrs.field("a").tag, Some(2));
+        assert_eq!(attrs.field("b").constraints.pattern, Some("x;y"));
+        assert_eq!(attrs.field("c"), FieldAttrs::parse("c").unwrap());
+
+        // parsed once
+        let cached = TypeAttrs::get(encoded).unwrap();
+        assert!(std::ptr::eq(attrs.fields, cached.fields));
+
+        assert!(TypeAttrs::get("User\0a;foo=1").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! Polymorphic IOP classes.
+//!
+//! A class is packed as a struct whose first field, with tag 0, is the id of
+//! its concrete class. Fields typed as a class can thus hold any of its
+//! children, which are only known when unpacking them.
+//!
+//! In Rust, a class hierarchy is a trait implemented by the concrete classes,
+//! and class fields are typed `Box<dyn Trait>`. The `class_registry!` macro
+//! gives the concrete classes which can be unpacked in these fields:
+//!
+//! ```ignore
+//! trait Animal: serde_iop::class::ClassObject {
+//!     fn name(&self) -> &str;
+//! }
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Dog {
+//!     name: String,
+//! }
+//! impl serde_iop::class::Class for Dog {
+//!     const CLASS_ID: u16 = 1;
+//! }
+//! impl Animal for Dog { ... }
+//!
+//! serde_iop::class_registry!(Animal { Dog, Cat });
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Farm {
+//!     animals: Vec<Box<dyn Animal>>,
+//! }
+//! ```
+//!
+//! The fields of the parent classes are packed before the fields of the
+//! child, they can be given with an `#[iop(inline)]` field.
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::ser::{self, Serializer};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::{from_bytes, to_buffer};
+
+/// Name of the newtype struct wrapping the packed content of a class, which
+/// the IOP serializer and deserializer pack as a raw struct.
+pub(crate) const CLASS_NAME: &str = "$serde_iop::Class";
+
+/// A concrete IOP class.
+pub trait Class: Serialize {
+    const CLASS_ID: u16;
+}
+
+/// Object-safe packing of a class, implemented for all classes.
+///
+/// Class hierarchy traits must have it as a supertrait.
+pub trait ClassObject {
+    fn class_id(&self) -> u16;
+
+    /// Pack the class id and the fields of the class.
+    fn pack_class(&self) -> Result<Vec<u8>>;
+}
+
+// the class id has tag 0, which the `iop` attribute macro does not accept
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "ClassId\0_class_id;tag=0")]
+struct ClassId {
+    #[serde(rename = "_class_id")]
+    id: u16,
+}
+
+impl<C: Class> ClassObject for C {
+    fn class_id(&self) -> u16 {
+        C::CLASS_ID
+    }
+
+    fn pack_class(&self) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+
+        to_buffer(&ClassId { id: C::CLASS_ID }, &mut output)?;
+        to_buffer(self, &mut output)?;
+        Ok(output)
+    }
+}
+
+type UnpackFn<T> = Box<dyn Fn(&[u8]) -> Result<Box<T>> + Send + Sync>;
+
+/// Unpacking functions of the concrete classes of a hierarchy, by class id.
+pub struct Registry<T: ?Sized> {
+    classes: HashMap<u16, UnpackFn<T>>,
+}
+
+impl<T: ?Sized> Default for Registry<T> {
+    fn default() -> Self {
+        Self {
+            classes: HashMap::new(),
+        }
+    }
+}
+
+impl<T: ?Sized> Registry<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a concrete class, `into` converting it into the boxed trait
+    /// object.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a class with the same id is already registered.
+    pub fn register<C>(&mut self, into: fn(C) -> Box<T>)
+    where
+        C: Class + de::DeserializeOwned + 'static,
+        T: 'static,
+    {
+        let unpack = move |data: &[u8]| from_bytes::<C>(data).map(into);
+
+        if self.classes.insert(C::CLASS_ID, Box::new(unpack)).is_some() {
+            panic!("class id {} registered twice", C::CLASS_ID);
+        }
+    }
+
+    /// Unpack a class from its packed content.
+    pub fn unpack(&self, data: &[u8]) -> Result<Box<T>> {
+        let id = from_bytes::<ClassId>(data)?.id;
+
+        match self.classes.get(&id) {
+            Some(unpack) => unpack(data),
+            None => Err(Error::UnknownClass(id)),
+        }
+    }
+}
+
+/// Serialize a class object, for the `Serialize` implementations of boxed
+/// class traits.
+pub fn serialize<T, S>(value: &T, serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    T: ?Sized + ClassObject,
+    S: Serializer,
+{
+    let data = value.pack_class().map_err(ser::Error::custom)?;
+
+    serializer.serialize_newtype_struct(CLASS_NAME, serde_bytes::Bytes::new(&data))
+}
+
+/// Deserialize a class object, for the `Deserialize` implementations of
+/// boxed class traits.
+pub fn deserialize<'de, T, D>(
+    deserializer: D,
+    registry: &Registry<T>,
+) -> std::result::Result<Box<T>, D::Error>
+where
+    T: ?Sized,
+    D: Deserializer<'de>,
+{
+    struct ClassVisitor<'a, T: ?Sized>(&'a Registry<T>);
+
+    impl<'de, 'a, T: ?Sized> Visitor<'de> for ClassVisitor<'a, T> {
+        type Value = Box<T>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a packed IOP class")
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> std::result::Result<Box<T>, E> {
+            self.0.unpack(v).map_err(E::custom)
+        }
+    }
+
+    deserializer.deserialize_newtype_struct(CLASS_NAME, ClassVisitor(registry))
+}
+
+/// Implement `Serialize` and `Deserialize` for a boxed class trait, the given
+/// concrete classes being the ones that can be unpacked.
+#[macro_export]
+macro_rules! class_registry {
+    ($trait:path { $($class:ty),* $(,)? }) => {
+        impl $crate::Serialize for Box<dyn $trait> {
+            fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+            where
+                S: $crate::__serde::Serializer,
+            {
+                $crate::class::serialize(&**self, serializer)
+            }
+        }
+
+        impl<'de> $crate::Deserialize<'de> for Box<dyn $trait> {
+            fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+            where
+                D: $crate::__serde::Deserializer<'de>,
+            {
+                static REGISTRY: ::std::sync::OnceLock<$crate::class::Registry<dyn $trait>> =
+                    ::std::sync::OnceLock::new();
+
+                let registry = REGISTRY.get_or_init(|| {
+                    let mut registry = $crate::class::Registry::new();
+                    $(registry.register(|c: $class| Box::new(c) as Box<dyn $trait>);)*
+                    registry
+                });
+                $crate::class::deserialize(deserializer, registry)
+            }
+        }
+    };
+}
@@ -0,0 +1,185 @@
+//! Wire compatibility between versions of IOP types.
+//!
+//! Interfaces evolve: fields are added and removed, integers are widened.
+//! `check` tells whether values packed from one version of a type can be
+//! unpacked into another one, and `compare` checks both directions:
+//!
+//! ```ignore
+//! let compat = serde_iop::compat::compare::<v1::Query, v2::Query>()?;
+//! assert!(compat.is_compatible(), "{:?}", compat);
+//! ```
+//!
+//! Fields and variants are matched by tag, so they can be renamed. Mandatory
+//! fields absent from the packed values are reported even if they have a
+//! default value, as `describe` cannot know about it.
+use std::collections::HashSet;
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+
+use crate::desc::{describe, FieldDesc, Schema, TypeDesc};
+use crate::error::Result;
+
+/// A change preventing packed values from being unpacked.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Incompatibility {
+    /// Path of the field or variant, empty for the type itself.
+    pub path: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            self.reason.fmt(fmt)
+        } else {
+            write!(fmt, "{}: {}", self.path, self.reason)
+        }
+    }
+}
+
+/// Compatibility between an old and a new version of a type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Compatibility {
+    /// Changes preventing the new version from unpacking old values.
+    pub backward: Vec<Incompatibility>,
+    /// Changes preventing the old version from unpacking new values.
+    pub forward: Vec<Incompatibility>,
+}
+
+impl Compatibility {
+    pub fn is_compatible(&self) -> bool {
+        self.backward.is_empty() && self.forward.is_empty()
+    }
+}
+
+/// Check that values packed from `W` can be unpacked as `R`.
+pub fn check<W, R>() -> Result<Vec<Incompatibility>>
+where
+    W: DeserializeOwned,
+    R: DeserializeOwned,
+{
+    Ok(check_schemas(&describe::<W>()?, &describe::<R>()?))
+}
+
+/// Check both directions between an old and a new version of a type.
+pub fn compare<Old, New>() -> Result<Compatibility>
+where
+    Old: DeserializeOwned,
+    New: DeserializeOwned,
+{
+    let old = describe::<Old>()?;
+    let new = describe::<New>()?;
+
+    Ok(Compatibility {
+        backward: check_schemas(&old, &new),
+        forward: check_schemas(&new, &old),
+    })
+}
+
+/// Check that values packed with the `writer` schema can be unpacked with
+/// the `reader` schema.
+pub fn check_schemas(writer: &Schema, reader: &Schema) -> Vec<Incompatibility> {
+    let mut checker = Checker {
+        writer,
+        reader,
+        visited: HashSet::new(),
+        res: Vec::new(),
+    };
+
+    checker.types(String::new(), &writer.root, &reader.root);
+    checker.res
+}
+
+fn join(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+struct Checker<'a> {
+    writer: &'a Schema,
+    reader: &'a Schema,
+    // pairs of structs or unions already checked, the flag being set for
+    // unions
+    visited: HashSet<(bool, &'static str, &'static str)>,
+    res: Vec<Incompatibility>,
+}
+
+impl<'a> Checker<'a> {
+    fn push(&mut self, path: String, reason: String) {
+        self.res.push(Incompatibility { path, reason });
+    }
+
+    fn types(&mut self, path: String, w: &TypeDesc, r: &TypeDesc) {
+        match (w, r) {
+            (TypeDesc::Option(w), TypeDesc::Option(r)) => self.types(path, w, r),
+            (w, TypeDesc::Option(r)) => self.types(path, w, r),
+            (TypeDesc::Option(_), _) => self.push(
+                path,
+                "optional value unpacked in a mandatory field".to_owned(),
+            ),
+            (TypeDesc::Array(w), TypeDesc::Array(r)) => self.types(path + "[]", w, r),
+            (TypeDesc::Struct(w), TypeDesc::Struct(r)) => self.structs(path, w, r),
+            (TypeDesc::Union(w), TypeDesc::Union(r)) => self.unions(path, w, r),
+            (TypeDesc::String, TypeDesc::Bytes) => (),
+            (w, r) if w == r => (),
+            (w, r) => {
+                let widened = match (w.int_range(), r.int_range()) {
+                    (Some((w_min, w_max)), Some((r_min, r_max))) => {
+                        r_min <= w_min && w_max <= r_max
+                    }
+                    _ => false,
+                };
+
+                if !widened {
+                    self.push(path, format!("`{:?}` cannot be unpacked as `{:?}`", w, r));
+                }
+            }
+        }
+    }
+
+    fn structs(&mut self, path: String, w: &'static str, r: &'static str) {
+        if !self.visited.insert((false, w, r)) {
+            return;
+        }
+        let w_fields = self.writer.structs.get(w).map_or(&[][..], Vec::as_slice);
+        let r_fields = self.reader.structs.get(r).map_or(&[][..], Vec::as_slice);
+
+        // fields unknown to the reader are skipped
+        for r_field in r_fields {
+            let path = join(&path, r_field.name);
+
+            match w_fields.iter().find(|f| f.tag == r_field.tag) {
+                Some(w_field) => self.types(path, &w_field.ty, &r_field.ty),
+                None if can_be_absent(r_field) => (),
+                None => self.push(path, "mandatory field is not packed".to_owned()),
+            }
+        }
+    }
+
+    fn unions(&mut self, path: String, w: &'static str, r: &'static str) {
+        if !self.visited.insert((true, w, r)) {
+            return;
+        }
+        let w_variants = self.writer.unions.get(w).map_or(&[][..], Vec::as_slice);
+        let r_variants = self.reader.unions.get(r).map_or(&[][..], Vec::as_slice);
+
+        for w_variant in w_variants {
+            let path = join(&path, w_variant.name);
+
+            match r_variants.iter().find(|v| v.tag == w_variant.tag) {
+                Some(r_variant) => self.types(path, &w_variant.ty, &r_variant.ty),
+                None => self.push(path, format!("unknown variant tag {}", w_variant.tag)),
+            }
+        }
+    }
+}
+
+/// Whether a field can be unpacked when it is not packed.
+fn can_be_absent(field: &FieldDesc) -> bool {
+    // empty arrays are not packed by all implementations
+    field.is_optional() || field.is_repeated() || field.ty == TypeDesc::Unit
+}
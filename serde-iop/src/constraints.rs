@@ -0,0 +1,345 @@
+use crate::error::{Error, Result};
+use std::fmt;
+
+/// Bound of a `min` or `max` constraint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bound {
+    Int(i128),
+    Float(f64),
+}
+
+impl Bound {
+    fn as_f64(self) -> f64 {
+        match self {
+            Bound::Int(v) => v as f64,
+            Bound::Float(v) => v,
+        }
+    }
+}
+
+impl fmt::Display for Bound {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bound::Int(v) => v.fmt(fmt),
+            Bound::Float(v) => v.fmt(fmt),
+        }
+    }
+}
+
+/// Constraints on the value of a field, as described by the IOP attributes
+/// `@min`, `@max`, `@minLength`, `@maxLength`, `@minOccurs`, `@maxOccurs`,
+/// `@nonEmpty`, `@nonZero` and `@pattern`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Constraints {
+    pub min: Option<Bound>,
+    pub max: Option<Bound>,
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+    pub min_occurs: Option<usize>,
+    pub max_occurs: Option<usize>,
+    pub non_empty: bool,
+    pub non_zero: bool,
+    pub pattern: Option<&'static str>,
+}
+
+fn violation(field: &str, constraint: String) -> Error {
+    Error::ConstraintViolation {
+        field: field.to_owned(),
+        constraint,
+    }
+}
+
+impl Constraints {
+    /// Constraints applying to the elements of a repeated field.
+    ///
+    /// The number of elements is checked with `check_len`, the other
+    /// constraints apply to every element.
+    pub fn elements(&self) -> Self {
+        Self {
+            min_occurs: None,
+            max_occurs: None,
+            non_empty: false,
+            ..*self
+        }
+    }
+
+    pub fn check_int(&self, field: &str, v: i128) -> Result<()> {
+        if self.non_zero && v == 0 {
+            return Err(violation(field, "@nonZero".to_owned()));
+        }
+        match self.min {
+            Some(Bound::Int(min)) if v < min => {
+                return Err(violation(field, format!("@min({})", min)))
+            }
+            Some(Bound::Float(min)) if (v as f64) < min => {
+                return Err(violation(field, format!("@min({})", min)))
+            }
+            _ => (),
+        }
+        match self.max {
+            Some(Bound::Int(max)) if v > max => {
+                return Err(violation(field, format!("@max({})", max)))
+            }
+            Some(Bound::Float(max)) if (v as f64) > max => {
+                return Err(violation(field, format!("@max({})", max)))
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    pub fn check_float(&self, field: &str, v: f64) -> Result<()> {
+        if self.non_zero && v == 0.0 {
+            return Err(violation(field, "@nonZero".to_owned()));
+        }
+        // NaN is not in any range
+        if let Some(min) = self.min {
+            if v.is_nan() || v < min.as_f64() {
+                return Err(violation(field, format!("@min({})", min)));
+            }
+        }
+        if let Some(max) = self.max {
+            if v.is_nan() || v > max.as_f64() {
+                return Err(violation(field, format!("@max({})", max)));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_length(&self, field: &str, len: usize) -> Result<()> {
+        if self.non_empty && len == 0 {
+            return Err(violation(field, "@nonEmpty".to_owned()));
+        }
+        if let Some(min) = self.min_length {
+            if len < min {
+                return Err(violation(field, format!("@minLength({})", min)));
+            }
+        }
+        if let Some(max) = self.max_length {
+            if len > max {
+                return Err(violation(field, format!("@maxLength({})", max)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_str(&self, field: &str, v: &str) -> Result<()> {
+        self.check_length(field, v.chars().count())?;
+        if let Some(pattern) = self.pattern {
+            if !pattern_match(pattern, v) {
+                return Err(violation(field, format!("@pattern({:?})", pattern)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_bytes(&self, field: &str, v: &[u8]) -> Result<()> {
+        self.check_length(field, v.len())
+    }
+
+    pub fn check_len(&self, field: &str, len: usize) -> Result<()> {
+        if self.non_empty && len == 0 {
+            return Err(violation(field, "@nonEmpty".to_owned()));
+        }
+        if let Some(min) = self.min_occurs {
+            if len < min {
+                return Err(violation(field, format!("@minOccurs({})", min)));
+            }
+        }
+        if let Some(max) = self.max_occurs {
+            if len > max {
+                return Err(violation(field, format!("@maxOccurs({})", max)));
+            }
+        }
+        Ok(())
+    }
+}
+
+// {{{ Pattern
+
+/// A single element of a pattern, matching one character.
+enum Atom {
+    Any,
+    Char(char),
+    Set {
+        negated: bool,
+        items: Vec<(char, char)>,
+    },
+}
+
+impl Atom {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Atom::Any => true,
+            Atom::Char(v) => *v == c,
+            Atom::Set { negated, items } => {
+                items.iter().any(|&(from, to)| from <= c && c <= to) != *negated
+            }
+        }
+    }
+}
+
+/// Number of times an atom can be repeated.
+#[derive(Clone, Copy)]
+enum Repeat {
+    One,
+    Optional,
+    Star,
+    Plus,
+}
+
+fn parse_pattern(pattern: &str) -> Vec<(Atom, Repeat)> {
+    let mut chars = pattern.chars().peekable();
+    let mut res = Vec::new();
+
+    while let Some(c) = chars.next() {
+        let atom = match c {
+            '.' => Atom::Any,
+            '\\' => Atom::Char(chars.next().unwrap_or('\\')),
+            '[' => {
+                let negated = chars.peek() == Some(&'^');
+                if negated {
+                    chars.next();
+                }
+
+                let mut items = Vec::new();
+                while let Some(c) = chars.next() {
+                    let from = match c {
+                        ']' => break,
+                        '\\' => chars.next().unwrap_or('\\'),
+                        c => c,
+                    };
+                    let mut to = from;
+                    if chars.peek() == Some(&'-') {
+                        chars.next();
+                        match chars.next() {
+                            Some(']') => {
+                                items.push((from, from));
+                                items.push(('-', '-'));
+                                break;
+                            }
+                            Some('\\') => to = chars.next().unwrap_or('\\'),
+                            Some(c) => to = c,
+                            None => items.push(('-', '-')),
+                        }
+                    }
+                    items.push((from, to));
+                }
+                Atom::Set { negated, items }
+            }
+            c => Atom::Char(c),
+        };
+        let repeat = match chars.peek() {
+            Some('?') => Repeat::Optional,
+            Some('*') => Repeat::Star,
+            Some('+') => Repeat::Plus,
+            _ => Repeat::One,
+        };
+        if !matches!(repeat, Repeat::One) {
+            chars.next();
+        }
+        res.push((atom, repeat));
+    }
+    res
+}
+
+fn match_atoms(atoms: &[(Atom, Repeat)], s: &[char]) -> bool {
+    let (atom, repeat) = match atoms.first() {
+        Some(v) => v,
+        None => return s.is_empty(),
+    };
+    let rest = &atoms[1..];
+
+    let (min, max) = match repeat {
+        Repeat::One => (1, 1),
+        Repeat::Optional => (0, 1),
+        Repeat::Star => (0, s.len()),
+        Repeat::Plus => (1, s.len()),
+    };
+    let nb_matching = s.iter().take(max).take_while(|&&c| atom.matches(c)).count();
+
+    (min..=nb_matching)
+        .rev()
+        .any(|n| match_atoms(rest, &s[n..]))
+}
+
+/// Check that the whole string matches the pattern.
+///
+/// Patterns support `.`, character sets such as `[a-z_]` or `[^0-9]`, the
+/// `?`, `*` and `+` repetitions, and `\` to escape special characters.
+pub fn pattern_match(pattern: &str, s: &str) -> bool {
+    let atoms = parse_pattern(pattern);
+    let chars: Vec<char> = s.chars().collect();
+
+    match_atoms(&atoms, &chars)
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_match() {
+        assert!(pattern_match("", ""));
+        assert!(!pattern_match("", "a"));
+        assert!(pattern_match("abc", "abc"));
+        assert!(!pattern_match("abc", "abcd"));
+        assert!(!pattern_match("abc", "ab"));
+        assert!(pattern_match("a.c", "a+c"));
+        assert!(pattern_match("a\\.c", "a.c"));
+        assert!(!pattern_match("a\\.c", "abc"));
+
+        assert!(pattern_match("[a-z]*", ""));
+        assert!(pattern_match("[a-z]*", "foo"));
+        assert!(!pattern_match("[a-z]*", "fOo"));
+        assert!(pattern_match("[a-zA-Z_][a-zA-Z0-9_]*", "foo_Bar2"));
+        assert!(!pattern_match("[a-zA-Z_][a-zA-Z0-9_]*", "2foo"));
+        assert!(pattern_match("[^0-9]+", "abc"));
+        assert!(!pattern_match("[^0-9]+", ""));
+        assert!(!pattern_match("[^0-9]+", "a1c"));
+        assert!(pattern_match("[a-]+", "a-a"));
+
+        assert!(pattern_match("ab?c", "ac"));
+        assert!(pattern_match("ab?c", "abc"));
+        assert!(!pattern_match("ab?c", "abbc"));
+        assert!(pattern_match("a.*b", "aXXbYYb"));
+        assert!(pattern_match("é+", "éé"));
+    }
+
+    #[test]
+    fn test_check() {
+        let c = Constraints {
+            min: Some(Bound::Int(-2)),
+            max: Some(Bound::Int(10)),
+            non_zero: true,
+            ..Default::default()
+        };
+        assert!(c.check_int("a", -2).is_ok());
+        assert!(c.check_int("a", 10).is_ok());
+        assert_eq!(
+            c.check_int("a", -3),
+            Err(Error::ConstraintViolation {
+                field: "a".to_owned(),
+                constraint: "@min(-2)".to_owned(),
+            })
+        );
+        assert!(c.check_int("a", 11).is_err());
+        assert!(c.check_int("a", 0).is_err());
+        assert!(c.check_float("a", 0.5).is_ok());
+        assert!(c.check_float("a", 10.5).is_err());
+
+        let c = Constraints {
+            non_empty: true,
+            max_length: Some(3),
+            ..Default::default()
+        };
+        assert!(c.check_str("a", "éé").is_ok());
+        assert!(c.check_str("a", "").is_err());
+        assert!(c.check_str("a", "abcd").is_err());
+        assert!(c.check_bytes("a", "éé".as_bytes()).is_err());
+        assert!(c.check_len("a", 0).is_err());
+        assert!(c.elements().check_len("a", 0).is_ok());
+    }
+}
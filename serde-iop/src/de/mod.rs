@@ -1,11 +1,17 @@
+use std::borrow::Cow;
+
 use serde::de::{
-    self, DeserializeSeed, EnumAccess, IntoDeserializer, SeqAccess, VariantAccess, Visitor,
+    self, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess, VariantAccess,
+    Visitor,
 };
 use serde::{forward_to_deserialize_any, Deserialize};
 
-mod read;
+pub(crate) mod read;
 use read::BinReader;
 
+use crate::attr::{FieldAttrs, TypeAttrs};
+use crate::class::CLASS_NAME;
+use crate::debug;
 use crate::error::{Error, Result};
 use crate::wire::Wire;
 
@@ -14,6 +20,13 @@ use crate::wire::Wire;
 pub struct Deserializer<'de> {
     reader: BinReader<'de>,
     current_tag: Option<u16>,
+    // attributes of the field being unpacked
+    current_field: Option<FieldAttrs>,
+    strict: bool,
+    lossy_utf8: bool,
+    // the value unpacked now is an optional value or a union member, which
+    // cannot be an option itself
+    value_required: bool,
 }
 
 impl<'de> Deserializer<'de> {
@@ -21,6 +34,40 @@ impl<'de> Deserializer<'de> {
         Self {
             reader: BinReader::new(input),
             current_tag: None,
+            current_field: None,
+            strict: false,
+            lossy_utf8: false,
+            value_required: false,
+        }
+    }
+
+    /// Reject structs missing mandatory fields.
+    ///
+    /// By default, a mandatory field absent from the input is filled with its
+    /// default value if the type provides one (`#[serde(default)]`), as peers
+    /// using an older version of a struct do not pack the fields added since.
+    /// In strict mode, the absence of the field is an error.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Replace invalid UTF-8 sequences in strings.
+    ///
+    /// By default, a string which is not valid UTF-8 is rejected with
+    /// `Error::InvalidUtf8`. In lossy mode, the invalid sequences are replaced
+    /// with `U+FFFD`, and the string is unpacked in a new allocation.
+    pub fn lossy_utf8(mut self, lossy_utf8: bool) -> Self {
+        self.lossy_utf8 = lossy_utf8;
+        self
+    }
+
+    /// Check that the whole input has been consumed.
+    pub fn end(&self) -> Result<()> {
+        if self.reader.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::TrailingCharacters)
         }
     }
 }
@@ -31,16 +78,31 @@ where
 {
     let mut deserializer = Deserializer::from_bytes(input);
     let t = T::deserialize(&mut deserializer)?;
-    if deserializer.reader.is_empty() {
-        Ok(t)
-    } else {
-        Err(Error::TrailingCharacters)
-    }
+    deserializer.end()?;
+    Ok(t)
+}
+
+/// Same as `from_bytes`, but without default values for missing fields.
+pub fn from_bytes_strict<'a, T>(input: &'a [u8]) -> Result<T>
+where
+    T: Deserialize<'a>,
+{
+    let mut deserializer = Deserializer::from_bytes(input).strict(true);
+    let t = T::deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(t)
 }
 
 impl<'de> Deserializer<'de> {
     pub fn get_wire(&mut self) -> Result<Wire> {
         let tag = self.current_tag.ok_or(Error::MissingTag)?;
+        if self.reader.get_optional_tag(tag)?.is_none() {
+            let field = match &self.current_field {
+                Some(f) => f.name.to_owned(),
+                None => tag.to_string(),
+            };
+            return Err(Error::MissingField { field });
+        }
         self.reader.get_tag(tag)
     }
 
@@ -51,13 +113,18 @@ impl<'de> Deserializer<'de> {
 }
 
 macro_rules! deserialize_int_method {
-    ($method:ident) => {
+    ($method:ident, $ty:ty, $visit:ident) => {
         fn $method<V>(self, visitor: V) -> Result<V::Value>
         where
             V: Visitor<'de>,
         {
             let wire = self.get_wire()?;
-            self.reader.visit_integer(wire, visitor)
+            let v = self.reader.read_u64(wire)? as $ty;
+
+            if let Some(f) = &self.current_field {
+                f.constraints.check_int(f.name, v as i128)?;
+            }
+            visitor.$visit(v)
         }
     };
 }
@@ -65,11 +132,54 @@ macro_rules! deserialize_int_method {
 impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     type Error = Error;
 
-    fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value>
+    /// Unpack a value without knowing its type.
+    ///
+    /// The packed data do not describe themselves, so the type is guessed
+    /// from the wire types: integers and floats are given as i64, structs
+    /// and unions as maps from tags to values, and blocks which are neither
+    /// strings nor structs as bytes.
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        Err(Error::Unimplemented("any"))
+        if self.current_tag.is_none() {
+            return visitor.visit_map(AnyStructDeserializer { de: self });
+        }
+
+        let wire = self.get_wire()?;
+        match wire {
+            Wire::INT1 | Wire::INT2 | Wire::INT4 | Wire::QUAD => {
+                visitor.visit_i64(self.reader.read_u64(wire)? as i64)
+            }
+            Wire::BLK1 | Wire::BLK2 | Wire::BLK4 => {
+                let payload = self.reader.read_block(wire)?;
+
+                if let Some(s) = debug::payload_as_str(payload) {
+                    return visitor.visit_borrowed_str(s);
+                }
+                if debug::parse(payload).is_ok() {
+                    let mut de = Deserializer {
+                        reader: BinReader::new(payload),
+                        current_tag: None,
+                        current_field: None,
+                        strict: self.strict,
+                        lossy_utf8: self.lossy_utf8,
+                        value_required: false,
+                    };
+                    return visitor.visit_map(AnyStructDeserializer { de: &mut de });
+                }
+                match payload.split_last() {
+                    Some((0, bytes)) => visitor.visit_borrowed_bytes(bytes),
+                    _ => visitor.visit_borrowed_bytes(payload),
+                }
+            }
+            Wire::REPEAT => {
+                let len = self.reader.read_repeated_len(wire)?;
+
+                self.current_field = None;
+                visitor.visit_seq(SeqDeserializer::new(self, len))
+            }
+        }
     }
 
     fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
@@ -82,22 +192,26 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         visitor.visit_bool(if v == 0 { false } else { true })
     }
 
-    deserialize_int_method!(deserialize_i8);
-    deserialize_int_method!(deserialize_i16);
-    deserialize_int_method!(deserialize_i32);
-    deserialize_int_method!(deserialize_i64);
-    deserialize_int_method!(deserialize_u8);
-    deserialize_int_method!(deserialize_u16);
-    deserialize_int_method!(deserialize_u32);
-    deserialize_int_method!(deserialize_u64);
+    deserialize_int_method!(deserialize_i8, i64, visit_i64);
+    deserialize_int_method!(deserialize_i16, i64, visit_i64);
+    deserialize_int_method!(deserialize_i32, i64, visit_i64);
+    deserialize_int_method!(deserialize_i64, i64, visit_i64);
+    deserialize_int_method!(deserialize_u8, i64, visit_i64);
+    deserialize_int_method!(deserialize_u16, i64, visit_i64);
+    deserialize_int_method!(deserialize_u32, i64, visit_i64);
+    deserialize_int_method!(deserialize_u64, u64, visit_u64);
 
     fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
         let wire = self.get_wire()?;
+        let v = self.reader.read_f32(wire)?;
 
-        visitor.visit_f32(self.reader.read_f32(wire)?)
+        if let Some(f) = &self.current_field {
+            f.constraints.check_float(f.name, v as f64)?;
+        }
+        visitor.visit_f32(v)
     }
 
     fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value>
@@ -105,8 +219,12 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         V: Visitor<'de>,
     {
         let wire = self.get_wire()?;
+        let v = self.reader.read_f64(wire)?;
 
-        visitor.visit_f64(self.reader.read_f64(wire)?)
+        if let Some(f) = &self.current_field {
+            f.constraints.check_float(f.name, v)?;
+        }
+        visitor.visit_f64(v)
     }
 
     fn deserialize_char<V>(self, visitor: V) -> Result<V::Value>
@@ -132,14 +250,34 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     where
         V: Visitor<'de>,
     {
-        self.deserialize_bytes(visitor)
+        let wire = self.get_wire()?;
+        let v = self.reader.read_bytes(wire)?;
+
+        let s = match std::str::from_utf8(v) {
+            Ok(s) => Cow::Borrowed(s),
+            Err(_) if self.lossy_utf8 => String::from_utf8_lossy(v),
+            Err(_) => {
+                let field = self.current_field.map(|f| f.name).unwrap_or_default();
+
+                return Err(Error::InvalidUtf8 {
+                    field: field.to_owned(),
+                });
+            }
+        };
+        if let Some(f) = &self.current_field {
+            f.constraints.check_str(f.name, &s)?;
+        }
+        match s {
+            Cow::Borrowed(s) => visitor.visit_borrowed_str(s),
+            Cow::Owned(s) => visitor.visit_string(s),
+        }
     }
 
     fn deserialize_string<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        self.deserialize_bytes(visitor)
+        self.deserialize_str(visitor)
     }
 
     fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
@@ -147,8 +285,12 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         V: Visitor<'de>,
     {
         let wire = self.get_wire()?;
+        let v = self.reader.read_bytes(wire)?;
 
-        visitor.visit_borrowed_bytes(self.reader.read_bytes(wire)?)
+        if let Some(f) = &self.current_field {
+            f.constraints.check_bytes(f.name, v)?;
+        }
+        visitor.visit_borrowed_bytes(v)
     }
 
     fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value>
@@ -162,10 +304,19 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     where
         V: Visitor<'de>,
     {
+        // see `Serializer::check_option`
+        if self.value_required || self.current_tag == Some(0) {
+            return Err(Error::NestedOption {
+                field: self.current_field.map_or("", |f| f.name).to_owned(),
+            });
+        }
         let wire = self.get_optional_wire()?;
 
         match wire {
-            Some(_w) => visitor.visit_some(self),
+            Some(_w) => {
+                self.value_required = true;
+                visitor.visit_some(self)
+            }
             None => visitor.visit_none(),
         }
     }
@@ -174,6 +325,14 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     where
         V: Visitor<'de>,
     {
+        if self.current_tag.is_none() {
+            // void arguments or answers of RPCs, whose payload is ignored
+            self.reader.skip_remaining()?;
+        } else if self.get_optional_wire()?.is_some() {
+            // optional values and union members are packed as an empty block
+            let wire = self.get_wire()?;
+            self.reader.skip_data(wire)?;
+        }
         visitor.visit_unit()
     }
 
@@ -184,10 +343,21 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         Err(Error::Unimplemented("unit struct"))
     }
 
-    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+    fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
+        if name == CLASS_NAME {
+            // give the packed content of the class to the class registry
+            let data = match self.current_tag {
+                Some(_) => {
+                    let wire = self.get_wire()?;
+                    self.reader.read_block(wire)?
+                }
+                None => self.reader.read_remaining(),
+            };
+            return visitor.visit_borrowed_bytes(data);
+        }
         visitor.visit_newtype_struct(self)
     }
 
@@ -195,9 +365,19 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     where
         V: Visitor<'de>,
     {
-        let wire = self.get_wire()?;
-
-        let len = self.reader.read_repeated_len(wire)?;
+        // empty arrays are not packed by all implementations
+        let len = match self.get_optional_wire()? {
+            Some(_) => {
+                let wire = self.get_wire()?;
+                self.reader.read_repeated_len(wire)?
+            }
+            None => 0,
+        };
+        if let Some(f) = &mut self.current_field {
+            f.constraints.check_len(f.name, len)?;
+            f.constraints = f.constraints.elements();
+            f.inline = false;
+        }
         visitor.visit_seq(SeqDeserializer::new(&mut self, len))
     }
 
@@ -228,45 +408,74 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     }
 
     fn deserialize_struct<V>(
-        mut self,
-        _name: &'static str,
+        self,
+        name: &'static str,
         fields: &'static [&'static str],
         visitor: V,
     ) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
+        let attrs = TypeAttrs::get(name)?;
+
+        if self.current_field.is_some_and(|f| f.inline) {
+            // the fields are packed in the enclosing struct, following its
+            // tags
+            let mut de = StructDeserializer {
+                current_tag: self.current_tag.ok_or(Error::MissingTag)?,
+                de: self,
+                attrs,
+                fields,
+            };
+            let v = visitor.visit_seq(&mut de)?;
+
+            de.de.current_tag = Some(de.current_tag);
+            return Ok(v);
+        }
         match self.current_tag {
             Some(_) => {
                 let wire = self.get_wire()?;
 
                 let len = self.reader.read_len(wire)?;
-                visitor.visit_seq(StructDeserializer::new(&mut self, fields.len(), Some(len)))
+                let rest = self.reader.enter_block(len)?;
+                let v = visitor.visit_seq(StructDeserializer::new(self, attrs, fields))?;
+                // skip fields not declared in the struct
+                self.reader.skip_remaining()?;
+                self.reader.leave_block(rest)?;
+                Ok(v)
+            }
+            None => {
+                let v = visitor.visit_seq(StructDeserializer::new(self, attrs, fields))?;
+                self.reader.skip_remaining()?;
+                Ok(v)
             }
-            None => visitor.visit_seq(StructDeserializer::new(&mut self, fields.len(), None)),
         }
     }
 
     fn deserialize_enum<V>(
         self,
-        _name: &'static str,
-        _variants: &'static [&'static str],
+        name: &'static str,
+        variants: &'static [&'static str],
         visitor: V,
     ) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
+        let attrs = TypeAttrs::get(name)?;
+
         // This is actually for variants, ie unions
-        let mut deserializer = match self.current_tag {
+        match self.current_tag {
             Some(_) => {
                 let wire = self.get_wire()?;
 
                 let len = self.reader.read_len(wire)?;
-                UnionDeserializer::new(self, Some(len))
+                let rest = self.reader.enter_block(len)?;
+                let v = visitor.visit_enum(&mut UnionDeserializer::new(self, attrs, variants))?;
+                self.reader.leave_block(rest)?;
+                Ok(v)
             }
-            None => UnionDeserializer::new(self, None),
-        };
-        visitor.visit_enum(&mut deserializer)
+            None => visitor.visit_enum(&mut UnionDeserializer::new(self, attrs, variants)),
+        }
     }
 
     fn deserialize_identifier<V>(self, _visitor: V) -> Result<V::Value>
@@ -276,11 +485,18 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         Err(Error::Unimplemented("identifier"))
     }
 
-    fn deserialize_ignored_any<V>(self, _visitor: V) -> Result<V::Value>
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        Err(Error::Unimplemented("ignored any"))
+        if self.current_tag.is_none() {
+            // a whole value, such as a payload whose type is not known
+            self.reader.skip_remaining()?;
+        } else if self.get_optional_wire()?.is_some() {
+            let wire = self.get_wire()?;
+            self.reader.skip_data(wire)?;
+        }
+        visitor.visit_unit()
     }
 }
 
@@ -313,28 +529,64 @@ impl<'de, 'a> SeqAccess<'de> for SeqDeserializer<'a, 'de> {
         }
         self.remaining_elements -= 1;
         self.de.current_tag.replace(0);
+        self.de.value_required = false;
         seed.deserialize(&mut *self.de).map(Some)
     }
 }
 
+/* }}} */
+/* {{{ Any struct */
+
+/// Fields of a struct of unknown type, by tag.
+struct AnyStructDeserializer<'a, 'de: 'a> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'de, 'a> MapAccess<'de> for AnyStructDeserializer<'a, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        if self.de.reader.is_empty() {
+            return Ok(None);
+        }
+        let tag = self.de.reader.get_next_tag_value()?;
+
+        self.de.current_tag.replace(tag);
+        self.de.current_field = None;
+        seed.deserialize(tag.into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
 /* }}} */
 /* {{{ Struct */
 
 struct StructDeserializer<'a, 'de: 'a> {
     de: &'a mut Deserializer<'de>,
-    nb_fields: usize,
-    struct_len: Option<usize>,
+    attrs: TypeAttrs,
+    fields: &'static [&'static str],
     current_tag: u16,
 }
 
 impl<'a, 'de> StructDeserializer<'a, 'de> {
-    fn new(de: &'a mut Deserializer<'de>, nb_fields: usize, struct_len: Option<usize>) -> Self {
-        let current_read_len = de.reader.get_total_read_len();
-
+    fn new(
+        de: &'a mut Deserializer<'de>,
+        attrs: TypeAttrs,
+        fields: &'static [&'static str],
+    ) -> Self {
         StructDeserializer {
             de,
-            nb_fields,
-            struct_len: struct_len.map(|v| v + current_read_len),
+            attrs,
+            fields,
             current_tag: 1,
         }
     }
@@ -347,17 +599,35 @@ impl<'de, 'a> SeqAccess<'de> for StructDeserializer<'a, 'de> {
     where
         T: DeserializeSeed<'de>,
     {
-        let stop = match self.struct_len {
-            Some(max_len) => self.de.reader.get_total_read_len() >= max_len,
-            None => self.de.reader.is_empty(),
+        let (field, fields) = match self.fields.split_first() {
+            Some(v) => v,
+            None => return Ok(None),
         };
-        if stop && self.nb_fields == 0 {
-            return Ok(None);
+        let attrs = self.attrs.field(field);
+        let tag = attrs.tag.unwrap_or(self.current_tag);
+
+        self.fields = fields;
+        self.de.value_required = false;
+        if attrs.inline {
+            self.de.current_tag.replace(tag);
+            self.de.current_field = Some(attrs);
+            let v = seed.deserialize(&mut *self.de)?;
+            // the inlined struct gives back the tag following its fields
+            self.current_tag = self.de.current_tag.ok_or(Error::MissingTag)?;
+            return Ok(Some(v));
+        }
+        self.current_tag = tag.saturating_add(1);
+
+        let present = self.de.reader.get_optional_tag(tag)?.is_some();
+        self.de.current_tag.replace(tag);
+        self.de.current_field = Some(attrs);
+        match seed.deserialize(&mut *self.de) {
+            Ok(v) => Ok(Some(v)),
+            // Let the visitor fill the missing field with its default value,
+            // or fail if the field has none.
+            Err(Error::MissingField { .. }) if !present && !self.de.strict => Ok(None),
+            Err(e) => Err(e),
         }
-        self.de.current_tag.replace(self.current_tag);
-        self.current_tag += 1;
-        self.nb_fields -= 1;
-        seed.deserialize(&mut *self.de).map(Some)
     }
 }
 
@@ -366,18 +636,39 @@ impl<'de, 'a> SeqAccess<'de> for StructDeserializer<'a, 'de> {
 
 struct UnionDeserializer<'a, 'de: 'a> {
     de: &'a mut Deserializer<'de>,
-    _union_len: Option<usize>,
+    attrs: TypeAttrs,
+    variants: &'static [&'static str],
 }
 
 impl<'a, 'de> UnionDeserializer<'a, 'de> {
-    fn new(de: &'a mut Deserializer<'de>, union_len: Option<usize>) -> Self {
-        let current_read_len = de.reader.get_total_read_len();
-
+    fn new(
+        de: &'a mut Deserializer<'de>,
+        attrs: TypeAttrs,
+        variants: &'static [&'static str],
+    ) -> Self {
         UnionDeserializer {
             de,
-            _union_len: union_len.map(|v| v + current_read_len),
+            attrs,
+            variants,
         }
     }
+
+    /// Read the tag of the union, and find the index of the matching variant.
+    fn read_variant(&mut self) -> Result<u32> {
+        let tag = self.de.reader.get_next_tag_value()?;
+
+        for (index, variant) in self.variants.iter().enumerate() {
+            let attrs = self.attrs.field(variant);
+
+            if attrs.tag.unwrap_or(index as u16 + 1) == tag {
+                self.de.current_tag.replace(tag);
+                self.de.current_field = Some(attrs);
+                self.de.value_required = true;
+                return Ok(index as u32);
+            }
+        }
+        Err(Error::InvalidEncoding)
+    }
 }
 
 impl<'de, 'a> de::Deserializer<'de> for &'a mut UnionDeserializer<'a, 'de> {
@@ -393,10 +684,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut UnionDeserializer<'a, 'de> {
     where
         V: Visitor<'de>,
     {
-        let tag = self.de.reader.get_next_tag_value()?;
-        // TODO: map tag to index
-        self.de.current_tag.replace(tag);
-        visitor.visit_u16(tag)
+        visitor.visit_u32(self.read_variant()?)
     }
 }
 
@@ -408,10 +696,8 @@ impl<'de, 'a> EnumAccess<'de> for &'a mut UnionDeserializer<'a, 'de> {
     where
         V: DeserializeSeed<'de>,
     {
-        let tag = self.de.reader.get_next_tag_value()?;
-        // TODO: map tag to index
-        self.de.current_tag.replace(tag);
-        let v = seed.deserialize(tag.into_deserializer())?;
+        let index = self.read_variant()?;
+        let v = seed.deserialize(index.into_deserializer())?;
         Ok((v, self))
     }
 }
@@ -420,7 +706,7 @@ impl<'de, 'a> VariantAccess<'de> for &'a mut UnionDeserializer<'a, 'de> {
     type Error = Error;
 
     fn unit_variant(self) -> Result<()> {
-        Err(Error::Unimplemented("unit variant"))
+        <()>::deserialize(&mut *self.de)
     }
 
     fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
@@ -1,6 +1,5 @@
 use crate::error::{Error, Result};
 use crate::wire::Wire;
-use serde::de::Visitor;
 use std::mem::size_of;
 
 #[derive(Clone, Copy)]
@@ -11,7 +10,6 @@ struct Header {
 
 pub struct BinReader<'de> {
     slice: &'de [u8],
-    total_read_len: usize,
     current_hdr: Option<Header>,
 }
 
@@ -30,17 +28,46 @@ impl<'de> BinReader<'de> {
     pub fn new(slice: &'de [u8]) -> Self {
         Self {
             slice,
-            total_read_len: 0,
             current_hdr: None,
         }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.slice.is_empty()
+        self.slice.is_empty() && self.current_hdr.is_none()
     }
 
-    pub fn get_total_read_len(&self) -> usize {
-        self.total_read_len
+    /// Restrict reading to the next `len` bytes, ie the payload of a block.
+    ///
+    /// The rest of the input is returned, and must be given back to
+    /// `leave_block` once the block has been unpacked.
+    pub fn enter_block(&mut self, len: usize) -> Result<&'de [u8]> {
+        if self.slice.len() < len {
+            return Err(Error::InputTooShort);
+        }
+        let (block, rest) = self.slice.split_at(len);
+
+        self.slice = block;
+        Ok(rest)
+    }
+
+    /// Skip all the remaining fields of the current block.
+    pub fn skip_remaining(&mut self) -> Result<()> {
+        if let Some(hdr) = self.current_hdr.take() {
+            self.skip_data(hdr.wire)?;
+        }
+        while !self.slice.is_empty() {
+            let hdr = self.read_hdr()?;
+            self.skip_data(hdr.wire)?;
+        }
+        Ok(())
+    }
+
+    pub fn leave_block(&mut self, rest: &'de [u8]) -> Result<()> {
+        if !self.is_empty() {
+            return Err(Error::TrailingCharacters);
+        }
+        self.slice = rest;
+        Ok(())
     }
 
     fn read_hdr(&mut self) -> Result<Header> {
@@ -63,6 +90,18 @@ impl<'de> BinReader<'de> {
         Ok(Header { wire, tag })
     }
 
+    /// Read the header of the next element, returning its wire type and tag.
+    pub fn read_header(&mut self) -> Result<(Wire, u16)> {
+        let hdr = self.read_hdr()?;
+
+        Ok((hdr.wire, hdr.tag))
+    }
+
+    /// Number of bytes left to read.
+    pub fn remaining(&self) -> usize {
+        self.slice.len()
+    }
+
     pub fn get_next_tag_value(&mut self) -> Result<u16> {
         if let Some(hdr) = self.current_hdr {
             Ok(hdr.tag)
@@ -105,6 +144,8 @@ impl<'de> BinReader<'de> {
     pub fn get_tag(&mut self, target_tag: u16) -> Result<Wire> {
         let hdr = self.skip_upto_tag(target_tag)?;
         if hdr.tag > target_tag {
+            // keep the header for the field it belongs to
+            self.current_hdr.replace(hdr);
             Err(Error::InvalidEncoding)
         } else {
             Ok(hdr.wire)
@@ -130,7 +171,7 @@ impl<'de> BinReader<'de> {
                 self.get_slice(len)?;
             }
             Wire::REPEAT => {
-                let len = self.read_len(wire)?;
+                let len = self.read_repeated_len(wire)?;
                 for _ in 0..len {
                     let new_hdr = self.read_hdr()?;
                     if new_hdr.tag != 0 {
@@ -150,19 +191,6 @@ impl<'de> BinReader<'de> {
     read_integer_method!(read_i32, i32);
     read_integer_method!(read_i64, i64);
 
-    pub fn visit_integer<V>(&mut self, wire: Wire, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'de>,
-    {
-        match wire {
-            Wire::INT1 => visitor.visit_i8(self.read_i8()?),
-            Wire::INT2 => visitor.visit_i16(self.read_i16()?),
-            Wire::INT4 => visitor.visit_i32(self.read_i32()?),
-            Wire::QUAD => visitor.visit_i64(self.read_i64()?),
-            _ => Err(Error::InvalidEncoding),
-        }
-    }
-
     pub fn read_u64(&mut self, wire: Wire) -> Result<u64> {
         Ok(match wire {
             Wire::INT1 => self.read_i8()? as u64,
@@ -197,7 +225,7 @@ impl<'de> BinReader<'de> {
         }
     }
 
-    pub fn read_len(&mut self, wire: Wire) -> Result<usize> {
+    fn read_raw_len(&mut self, wire: Wire) -> Result<usize> {
         Ok(match wire {
             Wire::BLK1 => self.read_i8()? as u8 as usize,
             Wire::BLK2 => self.read_i16()? as u16 as usize,
@@ -207,13 +235,39 @@ impl<'de> BinReader<'de> {
         })
     }
 
-    pub fn read_repeated_len(&mut self, wire: Wire) -> Result<usize> {
+    /// Read the length of a block.
+    ///
+    /// The length is checked against the remaining input, so that it can be
+    /// safely used to size buffers.
+    pub fn read_len(&mut self, wire: Wire) -> Result<usize> {
+        let len = self.read_raw_len(wire)?;
+
+        if len > self.slice.len() {
+            return Err(Error::InputTooShort);
+        }
+        Ok(len)
+    }
+
+    fn read_raw_repeated_len(&mut self, wire: Wire) -> Result<usize> {
         match wire {
-            Wire::REPEAT => Ok(self.read_i32()? as usize),
+            Wire::REPEAT => Ok(self.read_i32()? as u32 as usize),
             _ => Err(Error::InvalidEncoding),
         }
     }
 
+    /// Read the number of elements of an array.
+    ///
+    /// As every element takes at least one byte, the number is checked
+    /// against the remaining input.
+    pub fn read_repeated_len(&mut self, wire: Wire) -> Result<usize> {
+        let len = self.read_raw_repeated_len(wire)?;
+
+        if len > self.slice.len() {
+            return Err(Error::InputTooShort);
+        }
+        Ok(len)
+    }
+
     pub fn read_bytes(&mut self, wire: Wire) -> Result<&'de [u8]> {
         let len = self.read_len(wire)?;
 
@@ -231,6 +285,18 @@ impl<'de> BinReader<'de> {
         Ok(slice)
     }
 
+    /// Read the payload of a block.
+    pub fn read_block(&mut self, wire: Wire) -> Result<&'de [u8]> {
+        let len = self.read_len(wire)?;
+
+        self.get_slice(len)
+    }
+
+    /// Read all the remaining input.
+    pub fn read_remaining(&mut self) -> &'de [u8] {
+        std::mem::take(&mut self.slice)
+    }
+
     fn get_slice(&mut self, len: usize) -> Result<&'de [u8]> {
         if self.slice.len() < len {
             Err(Error::InputTooShort)
@@ -238,7 +304,6 @@ impl<'de> BinReader<'de> {
             let slice = &self.slice[..len];
 
             self.slice = &self.slice[len..];
-            self.total_read_len += len;
 
             Ok(slice)
         }
@@ -282,6 +347,30 @@ mod tests {
         test(&[0x1F, 0xFF, 0xFF], std::u16::MAX, Ok(Wire::BLK1));
     }
 
+    #[test]
+    fn test_read_len_overflow() {
+        fn test(slice: &[u8], expected_res: Result<usize>) {
+            let mut reader = BinReader::new(slice);
+            let wire = reader.get_tag(1).unwrap();
+            let res = match wire {
+                Wire::REPEAT => reader.read_repeated_len(wire),
+                _ => reader.read_len(wire),
+            };
+            assert_eq!(res, expected_res);
+        }
+
+        test(&[0x01, 0x02, 0x00, 0x00], Ok(2)); // BLK1 | 1, 2
+        test(&[0x01, 0x03, 0x00, 0x00], Err(Error::InputTooShort)); // BLK1 | 1, 3
+        test(&[0x21, 0x00, 0x01], Err(Error::InputTooShort)); // BLK2 | 1, 256
+        test(&[0x41, 0xFF, 0xFF, 0xFF, 0xFF], Err(Error::InputTooShort)); // BLK4 | 1, U32_MAX
+        test(&[0xE1, 0x01, 0x00, 0x00, 0x00, 0x80, 0x00], Ok(1)); // REPEAT | 1, 1
+        test(
+            &[0xE1, 0x02, 0x00, 0x00, 0x00, 0x80],
+            Err(Error::InputTooShort),
+        ); // REPEAT | 1, 2
+        test(&[0xE1, 0xFF, 0xFF, 0xFF, 0xFF], Err(Error::InputTooShort)); // REPEAT | 1, -1
+    }
+
     // symmetric of test_push_byte in ser mod
     #[test]
     fn test_read_i8() {
@@ -414,7 +503,7 @@ mod tests {
         fn test(slice: &[u8], tag: u16, expected_res: Result<usize>) {
             let mut reader = BinReader::new(slice);
             let wire = reader.get_tag(tag).unwrap();
-            let res = reader.read_len(wire);
+            let res = reader.read_raw_len(wire);
             match &expected_res {
                 Ok(w) => assert_eq!(res.unwrap(), *w),
                 Err(e) => assert_eq!(res.unwrap_err(), *e),
@@ -453,7 +542,7 @@ mod tests {
         fn test(slice: &[u8], tag: u16, expected_res: Result<usize>) {
             let mut reader = BinReader::new(slice);
             let wire = reader.get_tag(tag).unwrap();
-            let res = reader.read_repeated_len(wire);
+            let res = reader.read_raw_repeated_len(wire);
             match &expected_res {
                 Ok(w) => assert_eq!(res.unwrap(), *w),
                 Err(e) => assert_eq!(res.unwrap_err(), *e),
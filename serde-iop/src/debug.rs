@@ -0,0 +1,308 @@
+//! Inspection of packed buffers.
+//!
+//! The IOP binary format is not self-describing: the wire types tell how to
+//! read the elements, but not what they contain. A block can be a string, a
+//! struct or a union, `Element::children` and `Element::as_str` help guessing
+//! which one it is.
+use std::fmt::Write;
+
+use crate::de::read::BinReader;
+use crate::error::{Error, Result};
+use crate::wire::Wire;
+
+/// Element of a packed buffer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Element<'a> {
+    /// Offset of the element in the whole buffer.
+    pub offset: usize,
+    pub wire: Wire,
+    pub tag: u16,
+    /// Packed element, header included.
+    pub raw: &'a [u8],
+    pub value: Value<'a>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value<'a> {
+    /// Integer, or bits of a float.
+    Int(i64),
+    /// Payload of a block.
+    Block(&'a [u8]),
+    /// Elements of an array.
+    Repeat(Vec<Element<'a>>),
+}
+
+/// Parse the elements of a packed buffer.
+///
+/// Arrays are parsed with their elements, but the payloads of blocks are not.
+pub fn parse(data: &[u8]) -> Result<Vec<Element<'_>>> {
+    parse_at(data, 0)
+}
+
+fn parse_at(data: &[u8], base: usize) -> Result<Vec<Element<'_>>> {
+    let mut reader = BinReader::new(data);
+    let mut elements = Vec::new();
+
+    while reader.remaining() > 0 {
+        elements.push(read_element(&mut reader, data, base)?);
+    }
+    Ok(elements)
+}
+
+fn read_element<'a>(
+    reader: &mut BinReader<'a>,
+    data: &'a [u8],
+    base: usize,
+) -> Result<Element<'a>> {
+    let start = data.len() - reader.remaining();
+    let (wire, tag) = reader.read_header()?;
+
+    let value = match wire {
+        Wire::INT1 | Wire::INT2 | Wire::INT4 | Wire::QUAD => {
+            Value::Int(reader.read_u64(wire)? as i64)
+        }
+        Wire::BLK1 | Wire::BLK2 | Wire::BLK4 => Value::Block(reader.read_block(wire)?),
+        Wire::REPEAT => {
+            let len = reader.read_repeated_len(wire)?;
+            let mut elements = Vec::with_capacity(len);
+
+            for _ in 0..len {
+                let element = read_element(reader, data, base)?;
+
+                if element.tag != 0 {
+                    return Err(Error::InvalidEncoding);
+                }
+                elements.push(element);
+            }
+            Value::Repeat(elements)
+        }
+    };
+
+    let end = data.len() - reader.remaining();
+    Ok(Element {
+        offset: base + start,
+        wire,
+        tag,
+        raw: &data[start..end],
+        value,
+    })
+}
+
+impl<'a> Element<'a> {
+    /// Parse the payload of a block as the fields of a struct or a union.
+    ///
+    /// Returns None if this is not a block, or if its payload is not a valid
+    /// sequence of elements.
+    pub fn children(&self) -> Option<Vec<Element<'a>>> {
+        match self.value {
+            Value::Block(payload) => {
+                let header_len = self.raw.len() - payload.len();
+
+                parse_at(payload, self.offset + header_len).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Get the payload of a block as a string.
+    ///
+    /// Returns None if this is not a block, or if its payload does not look
+    /// like a packed string: printable UTF-8 followed by a trailing 0.
+    pub fn as_str(&self) -> Option<&'a str> {
+        match self.value {
+            Value::Block(payload) => payload_as_str(payload),
+            _ => None,
+        }
+    }
+}
+
+/// Get the payload of a block as a string, if it looks like one.
+pub(crate) fn payload_as_str(payload: &[u8]) -> Option<&str> {
+    let s = match payload.split_last() {
+        Some((0, s)) => std::str::from_utf8(s).ok()?,
+        _ => return None,
+    };
+
+    if s.chars().any(|c| c.is_control() && !c.is_whitespace()) {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+/// Number of packed bytes printed on each line of a dump.
+const DUMP_RAW_BYTES: usize = 10;
+
+/// Render a packed buffer as an annotated hexdump.
+///
+/// Each line gives the offset of an element, its packed bytes, its wire type,
+/// its tag and its decoded value. The elements of arrays, and of blocks that
+/// can be parsed as structs, are printed indented after them. Dumping stops at
+/// the first invalid element.
+pub fn dump(data: &[u8]) -> String {
+    let mut out = String::new();
+
+    dump_elements(&mut out, data, 0, 0);
+    out
+}
+
+fn dump_elements(out: &mut String, data: &[u8], base: usize, depth: usize) {
+    let mut reader = BinReader::new(data);
+
+    while reader.remaining() > 0 {
+        let start = data.len() - reader.remaining();
+
+        match read_element(&mut reader, data, base) {
+            Ok(element) => dump_element(out, &element, depth),
+            Err(e) => {
+                dump_line(
+                    out,
+                    base + start,
+                    &data[start..],
+                    depth,
+                    &format!("error: {}", e),
+                );
+                return;
+            }
+        }
+    }
+}
+
+fn dump_element(out: &mut String, element: &Element, depth: usize) {
+    let line = |out: &mut String, raw: &[u8], value: &str| {
+        let desc = format!(
+            "{:<6} tag {}: {}",
+            format!("{:?}", element.wire),
+            element.tag,
+            value
+        );
+
+        dump_line(out, element.offset, raw, depth, &desc);
+    };
+
+    match &element.value {
+        Value::Int(v) if element.wire == Wire::QUAD => {
+            let value = format!("{} (f64 {})", v, f64::from_bits(*v as u64));
+
+            line(out, element.raw, &value);
+        }
+        Value::Int(v) => line(out, element.raw, &v.to_string()),
+        Value::Repeat(items) => {
+            let items_len: usize = items.iter().map(|item| item.raw.len()).sum();
+            let header = &element.raw[..(element.raw.len() - items_len)];
+
+            line(out, header, &format!("array of {}", items.len()));
+            for item in items {
+                dump_element(out, item, depth + 1);
+            }
+        }
+        Value::Block(payload) => {
+            if let Some(s) = element.as_str() {
+                return line(out, element.raw, &format!("{:?}", s));
+            }
+            match element.children() {
+                Some(children) => {
+                    let header = &element.raw[..(element.raw.len() - payload.len())];
+
+                    line(out, header, &format!("block of {}", payload.len()));
+                    for child in &children {
+                        dump_element(out, child, depth + 1);
+                    }
+                }
+                None => line(out, element.raw, &format!("{} bytes", payload.len())),
+            }
+        }
+    }
+}
+
+fn dump_line(out: &mut String, offset: usize, raw: &[u8], depth: usize, desc: &str) {
+    let mut hex = String::new();
+
+    for b in raw.iter().take(DUMP_RAW_BYTES) {
+        let _ = write!(hex, "{:02x} ", b);
+    }
+    if raw.len() > DUMP_RAW_BYTES {
+        hex.push_str("...");
+    }
+    let _ = writeln!(
+        out,
+        "{:06x}  {:width$}  {:indent$}{}",
+        offset,
+        hex,
+        "",
+        desc,
+        width = 3 * DUMP_RAW_BYTES + 3,
+        indent = 2 * depth,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let data = [
+            0x81, 0x05, // INT1 tag 1
+            0x02, 0x03, b'a', b'b', 0x00, // BLK1 tag 2, "ab"
+            0xE3, 0x02, 0x00, 0x00, 0x00, // REPEAT tag 3, 2 elements
+            0x60, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // QUAD tag 0
+            0x00, 0x02, 0x81, 0xFF, // BLK1 tag 0, struct
+        ];
+        let elements = parse(&data).unwrap();
+
+        assert_eq!(elements.len(), 3);
+        assert_eq!(elements[0].offset, 0);
+        assert_eq!((elements[0].wire, elements[0].tag), (Wire::INT1, 1));
+        assert_eq!(elements[0].value, Value::Int(5));
+        assert_eq!(elements[0].raw, &[0x81, 0x05]);
+
+        assert_eq!(elements[1].offset, 2);
+        assert_eq!(elements[1].as_str(), Some("ab"));
+
+        let items = match &elements[2].value {
+            Value::Repeat(items) => items,
+            v => panic!("unexpected value {:?}", v),
+        };
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].value, Value::Int(1));
+        assert_eq!(items[1].offset, 21);
+        assert_eq!(items[1].as_str(), None);
+
+        let children = items[1].children().unwrap();
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].offset, 23);
+        assert_eq!((children[0].tag, &children[0].value), (1, &Value::Int(-1)));
+
+        // "ab" is not a valid sequence of elements
+        assert_eq!(elements[1].children(), None);
+        assert!(parse(&data[..5]).is_err());
+    }
+
+    #[test]
+    fn test_dump() {
+        let data = [
+            0x81, 0x05, // INT1 tag 1
+            0x02, 0x03, b'a', b'b', 0x00, // BLK1 tag 2, "ab"
+            0xE3, 0x02, 0x00, 0x00, 0x00, // REPEAT tag 3, 2 elements
+            0x60, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xF8, 0x3F, // QUAD tag 0
+            0x00, 0x02, 0x81, 0xFF, // BLK1 tag 0, struct
+            0x04, 0x02, 0xFF, 0x01, // BLK1 tag 4, bytes
+            0x45, 0x02, // BLK2 tag 5, truncated
+        ];
+
+        assert_eq!(
+            dump(&data),
+            "\
+000000  81 05                              INT1   tag 1: 5
+000002  02 03 61 62 00                     BLK1   tag 2: \"ab\"
+000007  e3 02 00 00 00                     REPEAT tag 3: array of 2
+00000c  60 00 00 00 00 00 00 f8 3f           QUAD   tag 0: 4609434218613702656 (f64 1.5)
+000015  00 02                                BLK1   tag 0: block of 2
+000017  81 ff                                  INT1   tag 1: -1
+000019  04 02 ff 01                        BLK1   tag 4: 2 bytes
+00001d  45 02                              error: deserializing failed as input is too short
+"
+        );
+    }
+}
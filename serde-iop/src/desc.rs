@@ -0,0 +1,738 @@
+//! Runtime description of IOP types.
+//!
+//! `describe` gives the fields, tags and types of a Rust type, and of all the
+//! structs and unions it contains, as `iop_struct_t` does in C. It traces the
+//! `Deserialize` implementation of the type, so it works for any type
+//! supported by the IOP deserializer, with some limitations:
+//!
+//! * the tracer builds values filled with zeros and empty strings, so types
+//!   rejecting these values in their `Deserialize` implementation, like
+//!   enums without a 0 value, cannot be described.
+//! * recursive structs and unions are described from their first occurrence,
+//!   the recursive occurrences being built with the first variant of unions,
+//!   so this variant must not be recursive.
+//! * class fields cannot be described, as their concrete types are only known
+//!   from the packed data.
+//!
+//! `layout` renders the description as text tables, to be reviewed or
+//! committed alongside interface changes.
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, SeqAccess,
+    VariantAccess, Visitor,
+};
+
+use crate::attr::{FieldAttrs, TypeAttrs};
+use crate::class::CLASS_NAME;
+use crate::error::{Error, Result};
+use crate::wire::Wire;
+
+/// Type of a field or of a union variant.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TypeDesc {
+    Unit,
+    Bool,
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    I64,
+    U64,
+    F32,
+    F64,
+    Char,
+    String,
+    Bytes,
+    Option(Box<TypeDesc>),
+    Array(Box<TypeDesc>),
+    /// Struct, described in `Schema::structs`.
+    Struct(&'static str),
+    /// Union, described in `Schema::unions`.
+    Union(&'static str),
+}
+
+impl TypeDesc {
+    /// Wire types with which values of this type can be packed.
+    pub fn wire_types(&self) -> &'static [Wire] {
+        match self {
+            // only packed as an optional value or as a union member
+            TypeDesc::Unit => &[Wire::BLK1],
+            TypeDesc::Bool | TypeDesc::I8 => &[Wire::INT1],
+            TypeDesc::U8 | TypeDesc::I16 | TypeDesc::U16 | TypeDesc::I32 | TypeDesc::Char => {
+                &[Wire::INT1, Wire::INT2, Wire::INT4]
+            }
+            TypeDesc::U32 | TypeDesc::I64 | TypeDesc::U64 => {
+                &[Wire::INT1, Wire::INT2, Wire::INT4, Wire::QUAD]
+            }
+            TypeDesc::F32 => &[Wire::INT4],
+            TypeDesc::F64 => &[Wire::QUAD],
+            TypeDesc::String | TypeDesc::Bytes | TypeDesc::Struct(_) | TypeDesc::Union(_) => {
+                &[Wire::BLK1, Wire::BLK2, Wire::BLK4]
+            }
+            TypeDesc::Option(ty) => ty.wire_types(),
+            TypeDesc::Array(_) => &[Wire::REPEAT],
+        }
+    }
+
+    /// Range of the values of an integer type.
+    pub(crate) fn int_range(&self) -> Option<(i128, i128)> {
+        Some(match self {
+            TypeDesc::I8 => (i8::MIN.into(), i8::MAX.into()),
+            TypeDesc::U8 => (0, u8::MAX.into()),
+            TypeDesc::I16 => (i16::MIN.into(), i16::MAX.into()),
+            TypeDesc::U16 => (0, u16::MAX.into()),
+            TypeDesc::I32 => (i32::MIN.into(), i32::MAX.into()),
+            TypeDesc::U32 => (0, u32::MAX.into()),
+            TypeDesc::I64 => (i64::MIN.into(), i64::MAX.into()),
+            TypeDesc::U64 => (0, u64::MAX.into()),
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for TypeDesc {
+    /// Name of the type in IOP.
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            TypeDesc::Unit => "void",
+            TypeDesc::Bool => "bool",
+            TypeDesc::I8 => "byte",
+            TypeDesc::U8 => "ubyte",
+            TypeDesc::I16 => "short",
+            TypeDesc::U16 => "ushort",
+            TypeDesc::I32 => "int",
+            TypeDesc::U32 => "uint",
+            TypeDesc::I64 => "long",
+            TypeDesc::U64 => "ulong",
+            TypeDesc::F32 => "float",
+            TypeDesc::F64 => "double",
+            TypeDesc::Char => "char",
+            TypeDesc::String => "string",
+            TypeDesc::Bytes => "bytes",
+            TypeDesc::Option(ty) => return write!(fmt, "{}?", ty),
+            TypeDesc::Array(ty) => return write!(fmt, "{}[]", ty),
+            TypeDesc::Struct(name) | TypeDesc::Union(name) => name,
+        };
+        fmt.write_str(name)
+    }
+}
+
+/// Field of a struct, or variant of a union.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldDesc {
+    pub name: &'static str,
+    /// Former names, given with `#[iop(alias = "...")]`.
+    pub aliases: Vec<&'static str>,
+    pub tag: u16,
+    pub ty: TypeDesc,
+}
+
+impl FieldDesc {
+    /// Whether `name` is the name of the field, or one of its aliases.
+    pub fn is_named(&self, name: &str) -> bool {
+        self.name == name || self.aliases.contains(&name)
+    }
+
+    pub fn is_optional(&self) -> bool {
+        matches!(self.ty, TypeDesc::Option(_))
+    }
+
+    pub fn is_repeated(&self) -> bool {
+        matches!(self.ty, TypeDesc::Array(_))
+    }
+}
+
+/// Description of a type and of the structs and unions it uses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schema {
+    pub root: TypeDesc,
+    /// Fields of the structs, by name. The fields of inlined structs are
+    /// given in the structs containing them.
+    pub structs: BTreeMap<&'static str, Vec<FieldDesc>>,
+    /// Variants of the unions, by name.
+    pub unions: BTreeMap<&'static str, Vec<FieldDesc>>,
+}
+
+/// Describe a type.
+pub fn describe<T: DeserializeOwned>() -> Result<Schema> {
+    let mut unions = HashMap::new();
+
+    // the tracer explores one variant of each union per value it builds, so
+    // types with unions are traced until all their variants are explored
+    loop {
+        let explored = count_explored(&unions);
+        let mut tracer = Tracer::new(&mut unions);
+
+        T::deserialize(&mut tracer)?;
+        let root = tracer.take_ty();
+        let structs = tracer.structs;
+
+        if count_explored(&unions) == explored {
+            let mut schema = Schema {
+                root,
+                structs,
+                unions: BTreeMap::new(),
+            };
+
+            for (name, (attrs, variants, types)) in unions {
+                let mut descs = Vec::new();
+
+                for (index, (variant, ty)) in variants.iter().zip(types).enumerate() {
+                    let attrs = attrs.field(variant);
+                    let ty = ty.ok_or_else(|| {
+                        Error::Custom(format!(
+                            "cannot describe variant `{}` of union `{}`",
+                            attrs.name, name
+                        ))
+                    })?;
+
+                    descs.push(FieldDesc {
+                        name: attrs.name,
+                        aliases: attrs.aliases().collect(),
+                        tag: attrs.tag.unwrap_or(index as u16 + 1),
+                        ty,
+                    });
+                }
+                schema.unions.insert(name, descs);
+            }
+            return Ok(schema);
+        }
+    }
+}
+
+type Unions = HashMap<&'static str, (TypeAttrs, &'static [&'static str], Vec<Option<TypeDesc>>)>;
+
+fn count_explored(unions: &Unions) -> usize {
+    unions
+        .values()
+        .map(|(_, _, types)| types.iter().filter(|ty| ty.is_some()).count())
+        .sum()
+}
+
+/* {{{ Tracer */
+
+/// Fields of a struct being traced.
+struct StructFrame {
+    name: &'static str,
+    fields: Vec<FieldDesc>,
+    next_tag: u16,
+}
+
+struct Tracer<'a> {
+    // type of the last traced value
+    ty: Option<TypeDesc>,
+    // attributes of the field being traced
+    current_field: Option<FieldAttrs>,
+    frames: Vec<StructFrame>,
+    // unions being traced
+    union_stack: Vec<&'static str>,
+    // recursive structs and unions are traced without recording anything,
+    // building values without options and with empty arrays
+    quiet: usize,
+    structs: BTreeMap<&'static str, Vec<FieldDesc>>,
+    unions: &'a mut Unions,
+}
+
+impl<'a> Tracer<'a> {
+    fn new(unions: &'a mut Unions) -> Self {
+        Self {
+            ty: None,
+            current_field: None,
+            frames: Vec::new(),
+            union_stack: Vec::new(),
+            quiet: 0,
+            structs: BTreeMap::new(),
+            unions,
+        }
+    }
+
+    fn take_ty(&mut self) -> TypeDesc {
+        self.ty.take().unwrap_or(TypeDesc::Unit)
+    }
+}
+
+/// Options cannot be packed in arrays, options or unions, see
+/// `Error::NestedOption`.
+fn check_not_optional(field: Option<FieldAttrs>, ty: &TypeDesc) -> Result<()> {
+    match ty {
+        TypeDesc::Option(_) => Err(Error::NestedOption {
+            field: field.map_or("", |f| f.name).to_owned(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+macro_rules! trace_scalar_method {
+    ($method:ident, $desc:ident, $visit:ident, $value:expr) => {
+        fn $method<V>(self, visitor: V) -> Result<V::Value>
+        where
+            V: Visitor<'de>,
+        {
+            self.ty = Some(TypeDesc::$desc);
+            visitor.$visit($value)
+        }
+    };
+}
+
+impl<'de, 'a, 'b> de::Deserializer<'de> for &'b mut Tracer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(Error::Unimplemented("any"))
+    }
+
+    trace_scalar_method!(deserialize_bool, Bool, visit_bool, false);
+    trace_scalar_method!(deserialize_i8, I8, visit_i64, 0);
+    trace_scalar_method!(deserialize_u8, U8, visit_i64, 0);
+    trace_scalar_method!(deserialize_i16, I16, visit_i64, 0);
+    trace_scalar_method!(deserialize_u16, U16, visit_i64, 0);
+    trace_scalar_method!(deserialize_i32, I32, visit_i64, 0);
+    trace_scalar_method!(deserialize_u32, U32, visit_i64, 0);
+    trace_scalar_method!(deserialize_i64, I64, visit_i64, 0);
+    trace_scalar_method!(deserialize_u64, U64, visit_u64, 0);
+    trace_scalar_method!(deserialize_f32, F32, visit_f32, 0.);
+    trace_scalar_method!(deserialize_f64, F64, visit_f64, 0.);
+    trace_scalar_method!(deserialize_char, Char, visit_char, '\0');
+    trace_scalar_method!(deserialize_str, String, visit_borrowed_str, "");
+    trace_scalar_method!(deserialize_string, String, visit_borrowed_str, "");
+    trace_scalar_method!(deserialize_bytes, Bytes, visit_borrowed_bytes, &[]);
+    trace_scalar_method!(deserialize_byte_buf, Bytes, visit_borrowed_bytes, &[]);
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.ty = Some(TypeDesc::Unit);
+        visitor.visit_unit()
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        if self.quiet > 0 {
+            self.ty = Some(TypeDesc::Unit);
+            return visitor.visit_none();
+        }
+        let field = self.current_field;
+        let v = visitor.visit_some(&mut *self)?;
+        let ty = self.take_ty();
+
+        check_not_optional(field, &ty)?;
+        self.ty = Some(TypeDesc::Option(Box::new(ty)));
+        Ok(v)
+    }
+
+    fn deserialize_unit_struct<V>(self, _name: &'static str, _visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(Error::Unimplemented("unit struct"))
+    }
+
+    fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        if name == CLASS_NAME {
+            return Err(Error::Unimplemented("class description"));
+        }
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        if let Some(f) = &mut self.current_field {
+            f.inline = false;
+        }
+        let field = self.current_field;
+        let remaining = if self.quiet > 0 { 0 } else { 1 };
+        let v = visitor.visit_seq(SeqTracer {
+            tracer: &mut *self,
+            remaining,
+        })?;
+        let ty = self.take_ty();
+
+        check_not_optional(field, &ty)?;
+        self.ty = Some(TypeDesc::Array(Box::new(ty)));
+        Ok(v)
+    }
+
+    fn deserialize_tuple<V>(self, _len: usize, _visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(Error::Unimplemented("tuple"))
+    }
+
+    fn deserialize_tuple_struct<V>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        _visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(Error::Unimplemented("tuple struct"))
+    }
+
+    fn deserialize_map<V>(self, _visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(Error::Unimplemented("map"))
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let attrs = TypeAttrs::get(name)?;
+        let name = attrs.name;
+
+        if self.current_field.is_some_and(|f| f.inline) {
+            // the fields are described in the enclosing struct
+            return visitor.visit_seq(StructTracer {
+                tracer: self,
+                attrs,
+                fields,
+            });
+        }
+
+        let recursive = self.frames.iter().any(|f| f.name == name);
+        if recursive {
+            self.quiet += 1;
+        }
+        self.frames.push(StructFrame {
+            name,
+            fields: Vec::new(),
+            next_tag: 1,
+        });
+        let v = visitor.visit_seq(StructTracer {
+            tracer: &mut *self,
+            attrs,
+            fields,
+        });
+        let frame = self.frames.pop().unwrap();
+
+        if recursive {
+            self.quiet -= 1;
+        } else if self.quiet == 0 {
+            self.structs.insert(name, frame.fields);
+        }
+        self.ty = Some(TypeDesc::Struct(name));
+        v
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let attrs = TypeAttrs::get(name)?;
+        let name = attrs.name;
+        let recursive = self.union_stack.contains(&name);
+        if recursive {
+            self.quiet += 1;
+        }
+        let (_, _, types) = self
+            .unions
+            .entry(name)
+            .or_insert_with(|| (attrs, variants, vec![None; variants.len()]));
+        // explore a new variant if any
+        let index = match types.iter().position(|ty| ty.is_none()) {
+            Some(index) if self.quiet == 0 => index,
+            _ => 0,
+        };
+
+        self.union_stack.push(name);
+        let v = visitor.visit_enum(UnionTracer {
+            tracer: &mut *self,
+            name,
+            attrs,
+            variants,
+            index,
+        });
+        self.union_stack.pop();
+        if recursive {
+            self.quiet -= 1;
+        }
+        self.ty = Some(TypeDesc::Union(name));
+        v
+    }
+
+    fn deserialize_identifier<V>(self, _visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(Error::Unimplemented("identifier"))
+    }
+
+    fn deserialize_ignored_any<V>(self, _visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(Error::Unimplemented("ignored any"))
+    }
+}
+
+/* }}} */
+/* {{{ Seq */
+
+struct SeqTracer<'a, 'b> {
+    tracer: &'b mut Tracer<'a>,
+    remaining: usize,
+}
+
+impl<'de, 'a, 'b> SeqAccess<'de> for SeqTracer<'a, 'b> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.tracer).map(Some)
+    }
+}
+
+/* }}} */
+/* {{{ Struct */
+
+struct StructTracer<'a, 'b> {
+    tracer: &'b mut Tracer<'a>,
+    attrs: TypeAttrs,
+    fields: &'static [&'static str],
+}
+
+impl<'de, 'a, 'b> SeqAccess<'de> for StructTracer<'a, 'b> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        let (field, fields) = match self.fields.split_first() {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let attrs = self.attrs.field(field);
+        let frame = self.tracer.frames.last_mut().ok_or(Error::MissingTag)?;
+        let tag = attrs.tag.unwrap_or(frame.next_tag);
+
+        self.fields = fields;
+        self.tracer.current_field = Some(attrs);
+        if attrs.inline {
+            // the fields of the inlined struct follow the tag of the field
+            frame.next_tag = tag;
+            return seed.deserialize(&mut *self.tracer).map(Some);
+        }
+        frame.next_tag = tag.saturating_add(1);
+
+        let v = seed.deserialize(&mut *self.tracer)?;
+        let ty = self.tracer.take_ty();
+        if let Some(frame) = self.tracer.frames.last_mut() {
+            frame.fields.push(FieldDesc {
+                name: attrs.name,
+                aliases: attrs.aliases().collect(),
+                tag,
+                ty,
+            });
+        }
+        Ok(Some(v))
+    }
+}
+
+/* }}} */
+/* {{{ Union */
+
+struct UnionTracer<'a, 'b> {
+    tracer: &'b mut Tracer<'a>,
+    name: &'static str,
+    attrs: TypeAttrs,
+    variants: &'static [&'static str],
+    index: usize,
+}
+
+impl<'de, 'a, 'b> EnumAccess<'de> for UnionTracer<'a, 'b> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self::Variant)>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let index = self.index as u32;
+        let v = seed.deserialize(index.into_deserializer())?;
+
+        Ok((v, self))
+    }
+}
+
+impl<'de, 'a, 'b> VariantAccess<'de> for UnionTracer<'a, 'b> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        if self.tracer.quiet == 0 {
+            if let Some((_, _, types)) = self.tracer.unions.get_mut(self.name) {
+                types[self.index] = Some(TypeDesc::Unit);
+            }
+        }
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        let attrs = self.attrs.field(self.variants[self.index]);
+
+        self.tracer.current_field = Some(attrs);
+        let v = seed.deserialize(&mut *self.tracer)?;
+        let ty = self.tracer.take_ty();
+
+        check_not_optional(Some(attrs), &ty)?;
+        if self.tracer.quiet == 0 {
+            if let Some((_, _, types)) = self.tracer.unions.get_mut(self.name) {
+                types[self.index] = Some(ty);
+            }
+        }
+        Ok(v)
+    }
+
+    fn tuple_variant<V>(self, _len: usize, _visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(Error::Unimplemented("tuple variant"))
+    }
+
+    fn struct_variant<V>(self, _fields: &'static [&'static str], _visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(Error::Unimplemented("struct variant"))
+    }
+}
+
+/* }}} */
+/* {{{ Layout */
+
+/// Render the wire layout of a type.
+///
+/// Each struct and union used by the type is rendered as a table giving the
+/// tags, names, types, wire types and presence of its fields:
+///
+/// ```text
+/// struct User
+///   tag  name                  type      wire                 presence
+///   1    id                    uint      INT1 INT2 INT4 QUAD  mandatory
+///   2    name (was user_name)  string    BLK1 BLK2 BLK4       mandatory
+///   3    emails                string[]  REPEAT               repeated
+/// ```
+pub fn layout<T: DeserializeOwned>() -> Result<String> {
+    Ok(describe::<T>()?.to_string())
+}
+
+impl fmt::Display for Schema {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(fmt, "root: {}", self.root)?;
+        for (name, fields) in &self.structs {
+            writeln!(fmt, "\nstruct {}", name)?;
+            write_table(fmt, fields, true)?;
+        }
+        for (name, variants) in &self.unions {
+            writeln!(fmt, "\nunion {}", name)?;
+            write_table(fmt, variants, false)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_table(fmt: &mut fmt::Formatter, fields: &[FieldDesc], is_struct: bool) -> fmt::Result {
+    let mut rows = vec![vec![
+        "tag".to_owned(),
+        "name".to_owned(),
+        "type".to_owned(),
+        "wire".to_owned(),
+    ]];
+    if is_struct {
+        rows[0].push("presence".to_owned());
+    }
+
+    for field in fields {
+        let mut name = field.name.to_owned();
+        if !field.aliases.is_empty() {
+            name = format!("{} (was {})", name, field.aliases.join(", "));
+        }
+        let wire: Vec<_> = field
+            .ty
+            .wire_types()
+            .iter()
+            .map(|w| format!("{:?}", w))
+            .collect();
+        let mut row = vec![
+            field.tag.to_string(),
+            name,
+            field.ty.to_string(),
+            wire.join(" "),
+        ];
+
+        if is_struct {
+            let presence = if field.is_optional() {
+                "optional"
+            } else if field.is_repeated() {
+                "repeated"
+            } else if field.ty == TypeDesc::Unit {
+                // void fields are not packed in structs
+                "not packed"
+            } else {
+                "mandatory"
+            };
+            row.push(presence.to_owned());
+        }
+        rows.push(row);
+    }
+
+    let widths: Vec<_> = (0..rows[0].len())
+        .map(|i| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    for row in rows {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        writeln!(fmt, "  {}", cells.join("  ").trim_end())?;
+    }
+    Ok(())
+}
+
+/* }}} */
@@ -9,6 +9,24 @@ pub enum Error {
     InputTooShort,
     InvalidEncoding,
     TrailingCharacters,
+    LengthOverflow(usize),
+    UnknownClass(u16),
+    InvalidUtf8 {
+        field: String,
+    },
+    /// A mandatory field absent from the input.
+    MissingField {
+        field: String,
+    },
+    /// An optional value in an array, in an option or in a union, where its
+    /// absence cannot be packed.
+    NestedOption {
+        field: String,
+    },
+    ConstraintViolation {
+        field: String,
+        constraint: String,
+    },
     Custom(String),
 }
 pub type Result<T> = std::result::Result<T, Error>;
@@ -17,11 +35,23 @@ impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::Unimplemented(name) => write!(fmt, "serialization of {} not implemented", name),
-            Error::MissingTag => write!(fmt, "{}", self),
-            Error::UnknownLen => write!(fmt, "{}", self),
-            Error::InputTooShort => write!(fmt, "{}", self),
-            Error::InvalidEncoding => write!(fmt, "{}", self),
-            Error::TrailingCharacters => write!(fmt, "{}", self),
+            Error::MissingTag => write!(fmt, "tag is missing, only structs can be serialized"),
+            Error::UnknownLen => write!(fmt, "cannot pack a sequence of unknown len"),
+            Error::InputTooShort => write!(fmt, "deserializing failed as input is too short"),
+            Error::InvalidEncoding => write!(fmt, "binary encoding invalid"),
+            Error::TrailingCharacters => write!(fmt, "trailing characters after unpacking"),
+            Error::LengthOverflow(len) => write!(fmt, "length {} too big to be packed", len),
+            Error::UnknownClass(id) => write!(fmt, "unknown class id {}", id),
+            Error::InvalidUtf8 { field } => write!(fmt, "field `{}` is not valid UTF-8", field),
+            Error::MissingField { field } => write!(fmt, "field `{}` is missing", field),
+            Error::NestedOption { field } => write!(
+                fmt,
+                "field `{}` has optional values in an array, an option or a union",
+                field
+            ),
+            Error::ConstraintViolation { field, constraint } => {
+                write!(fmt, "field `{}` violates constraint {}", field, constraint)
+            }
             Error::Custom(msg) => msg.fmt(fmt),
         }
     }
@@ -36,6 +66,12 @@ impl StdError for Error {
             Error::InputTooShort => "deserializing failed as input is too short",
             Error::InvalidEncoding => "binary encoding invalid",
             Error::TrailingCharacters => "trailing characters after unpacking",
+            Error::LengthOverflow(_) => "length too big to be packed",
+            Error::UnknownClass(_) => "unknown class id",
+            Error::InvalidUtf8 { .. } => "string is not valid UTF-8",
+            Error::MissingField { .. } => "mandatory field is missing",
+            Error::NestedOption { .. } => "optional value in an array, an option or a union",
+            Error::ConstraintViolation { .. } => "value does not respect a constraint",
             Error::Custom(msg) => msg,
         }
     }
@@ -0,0 +1,169 @@
+//! Framing of packed structs in a stream.
+//!
+//! Packed structs do not give their own length, so streams of structs sent
+//! over plain sockets are framed: each struct is prefixed by its length, as a
+//! little-endian u32.
+//!
+//! ```ignore
+//! let mut output = Vec::new();
+//! FrameEncoder::new().encode(&value, &mut output)?;
+//! stream.write_all(&output)?;
+//!
+//! let mut decoder = FrameDecoder::new();
+//! loop {
+//!     if decoder.read_from(&mut stream)? == 0 {
+//!         break;
+//!     }
+//!     while let Some(value) = decoder.decode::<Value>()? {
+//!         ...
+//!     }
+//! }
+//! ```
+use std::convert::TryInto;
+use std::io::{self, Read};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+use crate::{from_bytes, to_buffer, Output};
+
+/// Size of the length prefix of frames.
+const HEADER_LEN: usize = 4;
+
+/// Default maximum length of a frame, without its length prefix.
+pub const DEFAULT_MAX_LEN: usize = 64 << 20;
+
+/// Pack structs with a length prefix.
+#[derive(Clone, Debug)]
+pub struct FrameEncoder {
+    max_len: usize,
+}
+
+impl Default for FrameEncoder {
+    fn default() -> Self {
+        Self {
+            max_len: DEFAULT_MAX_LEN,
+        }
+    }
+}
+
+impl FrameEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the maximum length of the packed structs.
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len.min(u32::MAX as usize);
+        self
+    }
+
+    /// Pack a frame at the end of a buffer.
+    ///
+    /// On error, the buffer is left unchanged.
+    pub fn encode<T, O>(&self, value: &T, output: &mut O) -> Result<()>
+    where
+        T: Serialize,
+        O: Output,
+    {
+        let pos = output.len();
+
+        output.resize(pos + HEADER_LEN, 0);
+        let len = match to_buffer(value, &mut *output) {
+            Ok(()) => output.len() - pos - HEADER_LEN,
+            Err(e) => {
+                output.resize(pos, 0);
+                return Err(e);
+            }
+        };
+        if len > self.max_len {
+            output.resize(pos, 0);
+            return Err(Error::LengthOverflow(len));
+        }
+        output.as_mut_slice()[pos..(pos + HEADER_LEN)].copy_from_slice(&(len as u32).to_le_bytes());
+        Ok(())
+    }
+}
+
+/// Unpack length-prefixed structs, from data received in arbitrary chunks.
+#[derive(Clone, Debug)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    // position of the first frame not decoded in the buffer
+    pos: usize,
+    max_len: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self {
+            buffer: Vec::new(),
+            pos: 0,
+            max_len: DEFAULT_MAX_LEN,
+        }
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the maximum length of the frames.
+    ///
+    /// Larger frames are rejected before being received, to protect against
+    /// corrupted or malicious length prefixes.
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Number of bytes received and not decoded yet.
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.len() - self.pos
+    }
+
+    /// Add received data.
+    pub fn extend(&mut self, data: &[u8]) {
+        if self.pos > 0 {
+            self.buffer.drain(..self.pos);
+            self.pos = 0;
+        }
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Read data once from a reader, returning the number of bytes read.
+    ///
+    /// As with `Read::read`, 0 means the end of the stream.
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> io::Result<usize> {
+        let mut chunk = [0; 8192];
+        let len = reader.read(&mut chunk)?;
+
+        self.extend(&chunk[..len]);
+        Ok(len)
+    }
+
+    /// Unpack the next frame, None if it is not fully received yet.
+    ///
+    /// A frame which cannot be unpacked is skipped, so that decoding can go on
+    /// with the next one. An error on a frame too long is not recoverable.
+    pub fn decode<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        let data = &self.buffer[self.pos..];
+        if data.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let len = u32::from_le_bytes(data[..HEADER_LEN].try_into().unwrap()) as usize;
+        if len > self.max_len {
+            return Err(Error::LengthOverflow(len));
+        }
+        let frame = match data.get(HEADER_LEN..(HEADER_LEN + len)) {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+
+        self.pos += HEADER_LEN + len;
+        from_bytes(frame).map(Some)
+    }
+}
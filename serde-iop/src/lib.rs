@@ -1,10 +1,30 @@
+mod attr;
+pub mod class;
+pub mod compat;
+mod constraints;
 mod de;
+pub mod debug;
+pub mod desc;
 mod error;
+pub mod frame;
 mod ser;
+pub mod time;
+pub mod transcode;
 mod wire;
 
-pub use de::from_bytes;
-pub use ser::to_bytes;
+pub use de::{from_bytes, from_bytes_strict, Deserializer};
+pub use error::Error;
+pub use ser::{to_buffer, to_bytes, Output};
+#[cfg(feature = "bumpalo")]
+pub use ser::to_bytes_in_arena;
+pub use serde_iop_derive::iop;
+pub use wire::Wire;
 
 pub use serde::de::DeserializeOwned;
 pub use serde::{Deserialize, Serialize};
+pub use serde_bytes;
+#[cfg(feature = "bumpalo")]
+pub use bumpalo;
+
+#[doc(hidden)]
+pub use serde as __serde;
@@ -1,34 +1,141 @@
-mod pack;
+mod output;
+pub(crate) mod pack;
 
+pub use output::Output;
+
+use super::attr::{FieldAttrs, TypeAttrs};
+use super::class::CLASS_NAME;
 use super::error::{Error, Result};
 use serde::{ser, Serialize};
 
-pub struct Serializer {
-    output: Vec<u8>,
+pub struct Serializer<O> {
+    output: O,
     current_tag: Option<u16>,
+    // attributes of the field being packed
+    current_field: Option<FieldAttrs>,
+    // the bytes being packed are the content of a class
+    class_content: bool,
+    // the value packed now is an optional value or a union member: it must
+    // be present, so a unit is packed as an empty block, and options are
+    // rejected
+    void_packed: bool,
 }
 
 pub fn to_bytes<T>(value: &T) -> Result<Vec<u8>>
 where
     T: Serialize,
+{
+    let mut output = Vec::new();
+
+    to_buffer(value, &mut output)?;
+    Ok(output)
+}
+
+/// Pack a value in an arena.
+///
+/// Temporary buffers, like the ones of replies, are then released all at
+/// once when the arena is reset, without going through the global allocator.
+#[cfg(feature = "bumpalo")]
+pub fn to_bytes_in_arena<'bump, T>(
+    value: &T,
+    bump: &'bump bumpalo::Bump,
+) -> Result<bumpalo::collections::Vec<'bump, u8>>
+where
+    T: Serialize,
+{
+    let mut output = bumpalo::collections::Vec::new_in(bump);
+
+    to_buffer(value, &mut output)?;
+    Ok(output)
+}
+
+/// Pack a value at the end of a buffer.
+///
+/// This can be used with a `SmallVec` to pack small values without heap
+/// allocation, or to pack a value after a header.
+pub fn to_buffer<T, O>(value: &T, output: &mut O) -> Result<()>
+where
+    T: Serialize,
+    O: Output,
 {
     let mut serializer = Serializer {
-        output: Vec::new(),
+        output,
         current_tag: None,
+        current_field: None,
+        class_content: false,
+        void_packed: false,
     };
-    value.serialize(&mut serializer)?;
-    Ok(serializer.output)
+    value.serialize(&mut serializer)
 }
 
 // {{{ Serializer
 
-impl Serializer {
+impl<O: Output> Serializer<O> {
+    /// Serializer packing a single value with the given tag.
+    pub(crate) fn for_field(output: O, tag: u16) -> Self {
+        Self {
+            output,
+            current_tag: Some(tag),
+            current_field: None,
+            class_content: false,
+            void_packed: false,
+        }
+    }
+
     fn get_tag(&mut self) -> Result<u16> {
         self.current_tag.ok_or(Error::MissingTag)
     }
+
+    fn check_int(&self, v: i128) -> Result<()> {
+        match &self.current_field {
+            Some(f) => f.constraints.check_int(f.name, v),
+            None => Ok(()),
+        }
+    }
+
+    /// The absence of an optional value is not packed, so it is ambiguous in
+    /// arrays, in options and in unions.
+    fn check_option(&self) -> Result<()> {
+        if self.void_packed || self.current_tag == Some(0) {
+            Err(Error::NestedOption {
+                field: self.current_field.map_or("", |f| f.name).to_owned(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_float(&self, v: f64) -> Result<()> {
+        match &self.current_field {
+            Some(f) => f.constraints.check_float(f.name, v),
+            None => Ok(()),
+        }
+    }
+
+    fn pack_i32(&mut self, v: i32) -> Result<()> {
+        let tag = self.get_tag()?;
+
+        if v == 0 {
+            pack::push_byte(tag, 0, &mut self.output);
+        } else {
+            pack::push_i32(tag, v, &mut self.output);
+        }
+        Ok(())
+    }
+
+    fn pack_i64(&mut self, v: i64) -> Result<()> {
+        let tag = self.get_tag()?;
+
+        if v >= (i32::MIN as i64) && v <= (i32::MAX as i64) {
+            pack::push_i32(tag, v as i32, &mut self.output);
+        } else {
+            pack::push_quad(tag, v as u64, &mut self.output);
+        }
+        Ok(())
+    }
 }
 
-impl<'a> ser::Serializer for &'a mut Serializer {
+impl<'a, O: Output> ser::Serializer for &'a mut Serializer<O> {
     type Ok = ();
     type Error = Error;
 
@@ -37,7 +144,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     type SerializeTupleStruct = Self;
     type SerializeTupleVariant = Self;
     type SerializeMap = Self;
-    type SerializeStruct = StructSerializer<'a>;
+    type SerializeStruct = StructSerializer<'a, O>;
     type SerializeStructVariant = Self;
 
     fn serialize_bool(self, v: bool) -> Result<()> {
@@ -47,55 +154,50 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     fn serialize_i8(self, v: i8) -> Result<()> {
         let tag = self.get_tag()?;
 
+        self.check_int(v as i128)?;
         pack::push_byte(tag, v as u8, &mut self.output);
         Ok(())
     }
 
     fn serialize_u8(self, v: u8) -> Result<()> {
-        self.serialize_i32(v as i32)
+        self.check_int(v as i128)?;
+        self.pack_i32(v as i32)
     }
 
     fn serialize_i16(self, v: i16) -> Result<()> {
-        self.serialize_i32(v as i32)
+        self.check_int(v as i128)?;
+        self.pack_i32(v as i32)
     }
 
     fn serialize_u16(self, v: u16) -> Result<()> {
-        self.serialize_i32(v as i32)
+        self.check_int(v as i128)?;
+        self.pack_i32(v as i32)
     }
 
     fn serialize_i32(self, v: i32) -> Result<()> {
-        let tag = self.get_tag()?;
-
-        if v == 0 {
-            pack::push_byte(tag, 0, &mut self.output);
-        } else {
-            pack::push_i32(tag, v, &mut self.output);
-        }
-        Ok(())
+        self.check_int(v as i128)?;
+        self.pack_i32(v)
     }
 
     fn serialize_u32(self, v: u32) -> Result<()> {
-        self.serialize_i64(v as i64)
+        self.check_int(v as i128)?;
+        self.pack_i64(v as i64)
     }
 
     fn serialize_i64(self, v: i64) -> Result<()> {
-        let tag = self.get_tag()?;
-
-        if v <= (std::i32::MAX as i64) {
-            pack::push_i32(tag, v as i32, &mut self.output);
-        } else {
-            pack::push_quad(tag, v as u64, &mut self.output);
-        }
-        Ok(())
+        self.check_int(v as i128)?;
+        self.pack_i64(v)
     }
 
     fn serialize_u64(self, v: u64) -> Result<()> {
-        self.serialize_i64(v as i64)
+        self.check_int(v as i128)?;
+        self.pack_i64(v as i64)
     }
 
     fn serialize_f32(self, v: f32) -> Result<()> {
         let tag = self.get_tag()?;
 
+        self.check_float(v as f64)?;
         pack::push_f32(tag, v, &mut self.output);
         Ok(())
     }
@@ -103,6 +205,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     fn serialize_f64(self, v: f64) -> Result<()> {
         let tag = self.get_tag()?;
 
+        self.check_float(v)?;
         pack::push_f64(tag, v, &mut self.output);
         Ok(())
     }
@@ -114,30 +217,50 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     fn serialize_str(self, v: &str) -> Result<()> {
         let tag = self.get_tag()?;
 
-        pack::push_bytes(tag, v.as_bytes(), &mut self.output);
-        Ok(())
+        if let Some(f) = &self.current_field {
+            f.constraints.check_str(f.name, v)?;
+        }
+        pack::push_bytes(tag, v.as_bytes(), &mut self.output)
     }
 
     fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        if self.class_content {
+            // packed as a struct, at the top level or in a field
+            self.class_content = false;
+            if let Some(tag) = self.current_tag {
+                let slice = pack::get_mut_slice(&mut self.output, pack::tag_len(tag) + 1 + 4);
+                pack::set_len32(tag, v.len(), slice)?;
+            }
+            self.output.extend_from_slice(v);
+            return Ok(());
+        }
         let tag = self.get_tag()?;
 
-        pack::push_bytes(tag, v, &mut self.output);
-        Ok(())
+        if let Some(f) = &self.current_field {
+            f.constraints.check_bytes(f.name, v)?;
+        }
+        pack::push_bytes(tag, v, &mut self.output)
     }
 
     fn serialize_none(self) -> Result<()> {
-        Ok(())
+        self.check_option()
     }
 
     fn serialize_some<T>(self, value: &T) -> Result<()>
     where
         T: ?Sized + Serialize,
     {
+        self.check_option()?;
+        self.void_packed = true;
         value.serialize(self)
     }
 
     fn serialize_unit(self) -> Result<()> {
-        // FIXME: must fill output if boxed
+        if std::mem::take(&mut self.void_packed) {
+            let tag = self.get_tag()?;
+
+            pack::push_len(tag, 0, &mut self.output)?;
+        }
         Ok(())
     }
 
@@ -147,25 +270,29 @@ impl<'a> ser::Serializer for &'a mut Serializer {
 
     fn serialize_unit_variant(
         self,
-        _name: &'static str,
-        _variant_index: u32,
-        _variant: &'static str,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
     ) -> Result<()> {
-        Err(Error::Unimplemented("unit variant"))
+        // a void member of the union
+        self.serialize_newtype_variant(name, variant_index, variant, &())
     }
 
-    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<()>
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<()>
     where
         T: ?Sized + Serialize,
     {
+        if name == CLASS_NAME {
+            self.class_content = true;
+        }
         value.serialize(self)
     }
 
     fn serialize_newtype_variant<T>(
         self,
-        _name: &'static str,
+        name: &'static str,
         variant_index: u32,
-        _variant: &'static str,
+        variant: &'static str,
         value: &T,
     ) -> Result<()>
     where
@@ -178,23 +305,30 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         let slice_len = pack::tag_len(tag) + 1 + 4;
         pack::get_mut_slice(&mut self.output, slice_len);
 
-        /* use tag for variant index, and pack value. */
-        self.current_tag = Some(variant_index as u16);
+        /* pack value with the tag of the variant. */
+        let attrs = TypeAttrs::get(name)?.field(variant);
+        self.current_tag = Some(attrs.tag.unwrap_or(variant_index as u16 + 1));
+        self.current_field = Some(attrs);
+        self.void_packed = true;
         value.serialize(&mut *self)?;
         self.current_tag = Some(tag);
 
         /* then write length */
         let len = self.output.len() - pos - slice_len;
-        let slice = &mut self.output[pos..(pos + slice_len)];
-        pack::set_len32(tag, len, slice);
-        Ok(())
+        let slice = &mut self.output.as_mut_slice()[pos..(pos + slice_len)];
+        pack::set_len32(tag, len, slice)
     }
 
     fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq> {
         let tag = self.get_tag()?;
 
         let len = len.ok_or(Error::UnknownLen)?;
-        pack::push_repeated_len(tag, len, &mut self.output);
+        if let Some(f) = &mut self.current_field {
+            f.constraints.check_len(f.name, len)?;
+            f.constraints = f.constraints.elements();
+            f.inline = false;
+        }
+        pack::push_repeated_len(tag, len, &mut self.output)?;
         Ok(self)
     }
 
@@ -224,7 +358,23 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         Err(Error::Unimplemented("map"))
     }
 
-    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+    fn serialize_struct(self, name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        let attrs = TypeAttrs::get(name)?;
+
+        if self.current_field.is_some_and(|f| f.inline) {
+            // the fields are packed in the enclosing struct, following its
+            // tags
+            let tag = self.get_tag()?;
+
+            return Ok(StructSerializer {
+                ser: self,
+                attrs,
+                tag,
+                struct_pos: None,
+                struct_tag: 0,
+                inline: true,
+            });
+        }
         match self.get_tag() {
             Ok(tag) => {
                 let pos = self.output.len();
@@ -232,16 +382,20 @@ impl<'a> ser::Serializer for &'a mut Serializer {
 
                 Ok(StructSerializer {
                     ser: self,
+                    attrs,
                     tag: 1,
-                    struct_pos: pos,
+                    struct_pos: Some(pos),
                     struct_tag: tag,
+                    inline: false,
                 })
             }
             Err(_) => Ok(StructSerializer {
                 ser: self,
+                attrs,
                 tag: 1,
-                struct_pos: 0,
+                struct_pos: None,
                 struct_tag: 0,
+                inline: false,
             }),
         }
     }
@@ -260,7 +414,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
 // }}}
 // {{{ Seq
 
-impl<'a> ser::SerializeSeq for &'a mut Serializer {
+impl<O: Output> ser::SerializeSeq for &mut Serializer<O> {
     type Ok = ();
     type Error = Error;
 
@@ -269,6 +423,7 @@ impl<'a> ser::SerializeSeq for &'a mut Serializer {
         T: ?Sized + Serialize,
     {
         self.current_tag.replace(0);
+        self.void_packed = false;
         value.serialize(&mut **self)
     }
 
@@ -280,7 +435,7 @@ impl<'a> ser::SerializeSeq for &'a mut Serializer {
 // }}}
 // {{{ Tuple
 
-impl<'a> ser::SerializeTuple for &'a mut Serializer {
+impl<O: Output> ser::SerializeTuple for &mut Serializer<O> {
     type Ok = ();
     type Error = Error;
 
@@ -299,7 +454,7 @@ impl<'a> ser::SerializeTuple for &'a mut Serializer {
 // }}}
 // {{{ Tuple Struct
 
-impl<'a> ser::SerializeTupleStruct for &'a mut Serializer {
+impl<O: Output> ser::SerializeTupleStruct for &mut Serializer<O> {
     type Ok = ();
     type Error = Error;
 
@@ -318,7 +473,7 @@ impl<'a> ser::SerializeTupleStruct for &'a mut Serializer {
 // }}}
 // {{{ Tuple Variant
 
-impl<'a> ser::SerializeTupleVariant for &'a mut Serializer {
+impl<O: Output> ser::SerializeTupleVariant for &mut Serializer<O> {
     type Ok = ();
     type Error = Error;
 
@@ -337,7 +492,7 @@ impl<'a> ser::SerializeTupleVariant for &'a mut Serializer {
 // }}}
 // {{{ Map
 
-impl<'a> ser::SerializeMap for &'a mut Serializer {
+impl<O: Output> ser::SerializeMap for &mut Serializer<O> {
     type Ok = ();
     type Error = Error;
 
@@ -363,33 +518,51 @@ impl<'a> ser::SerializeMap for &'a mut Serializer {
 // }}}
 // {{{ Struct
 
-pub struct StructSerializer<'a> {
-    ser: &'a mut Serializer,
+pub struct StructSerializer<'a, O> {
+    ser: &'a mut Serializer<O>,
+    attrs: TypeAttrs,
     tag: u16,
-    struct_pos: usize,
+    // position of the struct header, if the struct is packed in a field
+    struct_pos: Option<usize>,
     struct_tag: u16,
+    // the fields are packed in the enclosing struct
+    inline: bool,
 }
 
-impl<'a> ser::SerializeStruct for StructSerializer<'a> {
+impl<'a, O: Output> ser::SerializeStruct for StructSerializer<'a, O> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T>(&mut self, _key: &'static str, value: &T) -> Result<()>
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
     where
         T: ?Sized + Serialize,
     {
-        self.ser.current_tag.replace(self.tag);
-        self.tag += 1;
+        let attrs = self.attrs.field(key);
+        let tag = attrs.tag.unwrap_or(self.tag);
+
+        self.ser.current_tag.replace(tag);
+        self.ser.current_field = Some(attrs);
+        self.ser.void_packed = false;
+        if attrs.inline {
+            value.serialize(&mut *self.ser)?;
+            // the inlined struct gives back the tag following its fields
+            self.tag = self.ser.get_tag()?;
+            return Ok(());
+        }
+        self.tag = tag.saturating_add(1);
         value.serialize(&mut *self.ser)
     }
 
     fn end(self) -> Result<()> {
-        if self.struct_pos != 0 {
+        if self.inline {
+            self.ser.current_tag = Some(self.tag);
+        }
+        if let Some(pos) = self.struct_pos {
             let slice_len = pack::tag_len(self.struct_tag) + 1 + 4;
-            let struct_len = self.ser.output.len() - self.struct_pos - slice_len;
-            let slice = &mut self.ser.output[self.struct_pos..(self.struct_pos + slice_len)];
+            let struct_len = self.ser.output.len() - pos - slice_len;
+            let slice = &mut self.ser.output.as_mut_slice()[pos..(pos + slice_len)];
 
-            pack::set_len32(self.struct_tag, struct_len, slice);
+            pack::set_len32(self.struct_tag, struct_len, slice)?;
         }
         Ok(())
     }
@@ -398,7 +571,7 @@ impl<'a> ser::SerializeStruct for StructSerializer<'a> {
 // }}}
 // {{{ Struct Variant
 
-impl<'a> ser::SerializeStructVariant for &'a mut Serializer {
+impl<O: Output> ser::SerializeStructVariant for &mut Serializer<O> {
     type Ok = ();
     type Error = Error;
 
@@ -0,0 +1,124 @@
+use smallvec::{Array, SmallVec};
+
+/// Buffer in which values are packed.
+///
+/// It is implemented for `Vec<u8>`, for `SmallVec<[u8; N]>` to pack small
+/// values without heap allocation, and with the `bumpalo` feature for
+/// `bumpalo::collections::Vec<u8>`, to pack values in an arena.
+pub trait Output {
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn push(&mut self, value: u8);
+    fn extend_from_slice(&mut self, values: &[u8]);
+    /// Reserve capacity for at least `additional` more bytes.
+    fn reserve(&mut self, additional: usize);
+    fn resize(&mut self, len: usize, value: u8);
+    fn as_mut_slice(&mut self) -> &mut [u8];
+}
+
+impl Output for Vec<u8> {
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    fn push(&mut self, value: u8) {
+        Vec::push(self, value)
+    }
+
+    fn extend_from_slice(&mut self, values: &[u8]) {
+        Vec::extend_from_slice(self, values)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        Vec::reserve(self, additional)
+    }
+
+    fn resize(&mut self, len: usize, value: u8) {
+        Vec::resize(self, len, value)
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl<A: Array<Item = u8>> Output for SmallVec<A> {
+    fn len(&self) -> usize {
+        SmallVec::len(self)
+    }
+
+    fn push(&mut self, value: u8) {
+        SmallVec::push(self, value)
+    }
+
+    fn extend_from_slice(&mut self, values: &[u8]) {
+        SmallVec::extend_from_slice(self, values)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        SmallVec::reserve(self, additional)
+    }
+
+    fn resize(&mut self, len: usize, value: u8) {
+        SmallVec::resize(self, len, value)
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+#[cfg(feature = "bumpalo")]
+impl<'bump> Output for bumpalo::collections::Vec<'bump, u8> {
+    fn len(&self) -> usize {
+        bumpalo::collections::Vec::len(self)
+    }
+
+    fn push(&mut self, value: u8) {
+        bumpalo::collections::Vec::push(self, value)
+    }
+
+    fn extend_from_slice(&mut self, values: &[u8]) {
+        bumpalo::collections::Vec::extend_from_slice(self, values)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        bumpalo::collections::Vec::reserve(self, additional)
+    }
+
+    fn resize(&mut self, len: usize, value: u8) {
+        bumpalo::collections::Vec::resize(self, len, value)
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl<O: Output + ?Sized> Output for &mut O {
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+
+    fn push(&mut self, value: u8) {
+        (**self).push(value)
+    }
+
+    fn extend_from_slice(&mut self, values: &[u8]) {
+        (**self).extend_from_slice(values)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        (**self).reserve(additional)
+    }
+
+    fn resize(&mut self, len: usize, value: u8) {
+        (**self).resize(len, value)
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        (**self).as_mut_slice()
+    }
+}
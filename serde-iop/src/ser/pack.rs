@@ -1,3 +1,5 @@
+use super::output::Output;
+use crate::error::{Error, Result};
 use crate::wire::Wire;
 
 // FIXME: use proc ctz
@@ -17,18 +19,18 @@ fn required_space_for_i32(value: i32) -> u8 {
     cnt
 }
 
-pub fn get_mut_slice(out: &mut Vec<u8>, size: usize) -> &mut [u8] {
+pub fn get_mut_slice(out: &mut impl Output, size: usize) -> &mut [u8] {
     let len = out.len();
     out.resize(len + size, 0);
-    &mut out[len..(len + size)]
+    &mut out.as_mut_slice()[len..(len + size)]
 }
 
-pub fn push_byte(tag: u16, value: u8, out: &mut Vec<u8>) {
+pub fn push_byte(tag: u16, value: u8, out: &mut impl Output) {
     push_tag(Wire::INT1, tag, out);
     out.push(value);
 }
 
-pub fn push_i32(tag: u16, value: i32, out: &mut Vec<u8>) {
+pub fn push_i32(tag: u16, value: i32, out: &mut impl Output) {
     let space = required_space_for_i32(value);
 
     match space {
@@ -47,56 +49,58 @@ pub fn push_i32(tag: u16, value: i32, out: &mut Vec<u8>) {
     }
 }
 
-pub fn push_quad(tag: u16, value: u64, out: &mut Vec<u8>) {
+pub fn push_quad(tag: u16, value: u64, out: &mut impl Output) {
     push_tag(Wire::QUAD, tag, out);
     out.extend_from_slice(&value.to_le_bytes());
 }
 
-pub fn push_f32(tag: u16, value: f32, out: &mut Vec<u8>) {
+pub fn push_f32(tag: u16, value: f32, out: &mut impl Output) {
     push_tag(Wire::INT4, tag, out);
     out.extend_from_slice(&value.to_le_bytes());
 }
 
-pub fn push_f64(tag: u16, value: f64, out: &mut Vec<u8>) {
+pub fn push_f64(tag: u16, value: f64, out: &mut impl Output) {
     push_tag(Wire::QUAD, tag, out);
     out.extend_from_slice(&value.to_le_bytes());
 }
 
-pub fn push_bytes(tag: u16, bytes: &[u8], out: &mut Vec<u8>) {
-    push_len(tag, bytes.len() + 1, out);
+pub fn push_bytes(tag: u16, bytes: &[u8], out: &mut impl Output) -> Result<()> {
+    push_len(tag, bytes.len() + 1, out)?;
+    // reserve the trailing \0 with the data, so that pushing it cannot
+    // reallocate, and copy, large payloads a second time
     out.reserve(bytes.len() + 1);
-    for b in bytes {
-        out.push(*b);
-    }
-    // pack a trailing \0
+    out.extend_from_slice(bytes);
     out.push(0);
+    Ok(())
 }
 
-pub fn push_repeated_len(tag: u16, len: usize, out: &mut Vec<u8>) {
+pub fn push_repeated_len(tag: u16, len: usize, out: &mut impl Output) -> Result<()> {
+    if len > u32::MAX as usize {
+        return Err(Error::LengthOverflow(len));
+    }
     push_tag(Wire::REPEAT, tag, out);
-    /* TODO: properly handle overflow */
-    assert!(len < std::u32::MAX as usize);
     push_le32(len as u32, out);
+    Ok(())
 }
 
-fn push_le32(v: u32, out: &mut Vec<u8>) {
+fn push_le32(v: u32, out: &mut impl Output) {
     out.extend_from_slice(&v.to_le_bytes());
 }
 
-pub fn push_len(tag: u16, len: usize, out: &mut Vec<u8>) {
-    if len <= std::u8::MAX as usize {
+pub fn push_len(tag: u16, len: usize, out: &mut impl Output) -> Result<()> {
+    if len <= u8::MAX as usize {
         push_tag(Wire::BLK1, tag, out);
         out.push(len as u8);
-    } else if len <= std::u16::MAX as usize {
+    } else if len <= u16::MAX as usize {
         push_tag(Wire::BLK2, tag, out);
         out.extend_from_slice(&(len as u16).to_le_bytes());
-    } else {
-        /* TODO: properly handle overflow */
-        assert!(len <= std::u32::MAX as usize);
-
+    } else if len <= u32::MAX as usize {
         push_tag(Wire::BLK4, tag, out);
         push_le32(len as u32, out);
+    } else {
+        return Err(Error::LengthOverflow(len));
     }
+    Ok(())
 }
 
 pub fn tag_len(tag: u16) -> usize {
@@ -109,13 +113,17 @@ pub fn tag_len(tag: u16) -> usize {
     }
 }
 
-fn push_tag(wiretype: Wire, tag: u16, out: &mut Vec<u8>) {
+fn push_tag(wiretype: Wire, tag: u16, out: &mut impl Output) {
     set_tag(wiretype, tag, get_mut_slice(out, tag_len(tag) + 1));
 }
 
-pub fn set_len32(tag: u16, len: usize, out: &mut [u8]) {
+pub fn set_len32(tag: u16, len: usize, out: &mut [u8]) -> Result<()> {
+    if len > u32::MAX as usize {
+        return Err(Error::LengthOverflow(len));
+    }
     let out = set_tag(Wire::BLK4, tag, out);
     out.copy_from_slice(&(len as u32).to_le_bytes());
+    Ok(())
 }
 
 fn set_tag(wiretype: Wire, tag: u16, out: &mut [u8]) -> &mut [u8] {
@@ -259,7 +267,7 @@ mod tests {
         fn test(tag: u16, len: usize, expected: &[u8]) {
             let mut vec = Vec::new();
 
-            push_len(tag, len, &mut vec);
+            push_len(tag, len, &mut vec).unwrap();
             assert_eq!(vec, expected);
         }
 
@@ -270,6 +278,18 @@ mod tests {
         test(5, 65535, &[0x25, 0xFF, 0xFF]); // BLK2 | 5, 65536
         test(5, 65536, &[0x45, 0x00, 0x00, 0x01, 0x00]); // BLK4 | 5, 65537
         test(5, std::u32::MAX as usize, &[0x45, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+        if let Some(len) = (u32::MAX as usize).checked_add(1) {
+            let mut vec = Vec::new();
+
+            assert_eq!(push_len(5, len, &mut vec), Err(Error::LengthOverflow(len)));
+            assert!(vec.is_empty());
+            assert_eq!(
+                push_repeated_len(5, len, &mut vec),
+                Err(Error::LengthOverflow(len))
+            );
+            assert!(vec.is_empty());
+        }
     }
 
     #[test]
@@ -277,7 +297,7 @@ mod tests {
         fn test(tag: u16, len: usize, expected: &[u8]) {
             let mut vec = Vec::new();
 
-            push_repeated_len(tag, len, &mut vec);
+            push_repeated_len(tag, len, &mut vec).unwrap();
             assert_eq!(vec, expected);
         }
 
@@ -291,7 +311,7 @@ mod tests {
         fn test(tag: u16, inp: &[u8], expected: &[u8]) {
             let mut vec = Vec::new();
 
-            push_bytes(tag, inp, &mut vec);
+            push_bytes(tag, inp, &mut vec).unwrap();
             assert_eq!(vec, expected);
         }
 
@@ -0,0 +1,178 @@
+//! Timestamps adapters.
+//!
+//! IOP timestamps are integers, counting seconds or milliseconds since the
+//! Unix epoch. These modules are meant to be used with `#[serde(with)]`:
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! struct Event {
+//!     #[serde(with = "serde_iop::time::seconds")]
+//!     date: SystemTime,
+//!     #[serde(with = "serde_iop::time::milliseconds")]
+//!     precise_date: chrono::DateTime<chrono::Utc>,
+//! }
+//! ```
+//!
+//! Durations are integers as well, the adapters of the `duration` module
+//! pack `std::time::Duration` as a number of seconds or milliseconds:
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! struct Query {
+//!     #[serde(with = "serde_iop::time::duration::milliseconds")]
+//!     timeout: Duration,
+//! }
+//! ```
+//!
+//! `chrono::DateTime<Utc>` and `chrono::Duration` are supported with the
+//! `chrono` feature.
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A type which can be converted from and to a timestamp.
+pub trait Timestamp: Sized {
+    /// Number of milliseconds since the Unix epoch, None if it does not fit
+    /// in an i64.
+    fn to_millis(&self) -> Option<i64>;
+
+    /// Build from a number of milliseconds since the Unix epoch, None if out
+    /// of range.
+    fn from_millis(millis: i64) -> Option<Self>;
+}
+
+/// A type which can be converted from and to a duration.
+pub trait Interval: Sized {
+    /// Number of milliseconds, None if it does not fit in an i64.
+    fn to_millis(&self) -> Option<i64>;
+
+    /// Build from a number of milliseconds, None if out of range.
+    fn from_millis(millis: i64) -> Option<Self>;
+}
+
+impl Timestamp for SystemTime {
+    fn to_millis(&self) -> Option<i64> {
+        match self.duration_since(UNIX_EPOCH) {
+            Ok(d) => i64::try_from(d.as_millis()).ok(),
+            Err(e) => i64::try_from(e.duration().as_millis()).ok().map(|v| -v),
+        }
+    }
+
+    fn from_millis(millis: i64) -> Option<Self> {
+        let d = Duration::from_millis(millis.unsigned_abs());
+
+        if millis >= 0 {
+            UNIX_EPOCH.checked_add(d)
+        } else {
+            UNIX_EPOCH.checked_sub(d)
+        }
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl Timestamp for chrono::DateTime<chrono::Utc> {
+    fn to_millis(&self) -> Option<i64> {
+        Some(self.timestamp_millis())
+    }
+
+    fn from_millis(millis: i64) -> Option<Self> {
+        use chrono::TimeZone;
+
+        let secs = millis.div_euclid(1000);
+        let nsecs = millis.rem_euclid(1000) as u32 * 1_000_000;
+        chrono::Utc.timestamp_opt(secs, nsecs).single()
+    }
+}
+
+impl Interval for Duration {
+    fn to_millis(&self) -> Option<i64> {
+        i64::try_from(self.as_millis()).ok()
+    }
+
+    fn from_millis(millis: i64) -> Option<Self> {
+        // negative durations cannot be represented
+        u64::try_from(millis).ok().map(Duration::from_millis)
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl Interval for chrono::Duration {
+    fn to_millis(&self) -> Option<i64> {
+        Some(self.num_milliseconds())
+    }
+
+    fn from_millis(millis: i64) -> Option<Self> {
+        chrono::Duration::try_milliseconds(millis)
+    }
+}
+
+macro_rules! adapter_module {
+    ($name:ident, $trait:ident, $what:literal, $doc:expr, $factor:expr) => {
+        #[doc = $doc]
+        pub mod $name {
+            use super::$trait;
+            use serde::de::{Deserialize, Deserializer, Error as _};
+            use serde::ser::{Error as _, Serializer};
+
+            pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                T: $trait,
+                S: Serializer,
+            {
+                match value.to_millis() {
+                    Some(millis) => serializer.serialize_i64(millis.div_euclid($factor)),
+                    None => Err(S::Error::custom(concat!($what, " out of range"))),
+                }
+            }
+
+            pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+            where
+                T: $trait,
+                D: Deserializer<'de>,
+            {
+                let v = i64::deserialize(deserializer)?;
+
+                v.checked_mul($factor)
+                    .and_then(T::from_millis)
+                    .ok_or_else(|| D::Error::custom(concat!($what, " out of range")))
+            }
+        }
+    };
+}
+
+adapter_module!(
+    seconds,
+    Timestamp,
+    "timestamp",
+    "Pack a timestamp as a number of seconds since the Unix epoch.",
+    1000
+);
+adapter_module!(
+    milliseconds,
+    Timestamp,
+    "timestamp",
+    "Pack a timestamp as a number of milliseconds since the Unix epoch.",
+    1
+);
+
+/// Durations adapters.
+///
+/// Sub-unit precision is lost: durations are rounded down when packed as
+/// seconds.
+pub mod duration {
+    use super::Interval;
+
+    adapter_module!(
+        seconds,
+        Interval,
+        "duration",
+        "Pack a duration as a number of seconds.",
+        1000
+    );
+    adapter_module!(
+        milliseconds,
+        Interval,
+        "duration",
+        "Pack a duration as a number of milliseconds.",
+        1
+    );
+}
@@ -0,0 +1,572 @@
+//! Transcoding between packed IOP values and other serde formats.
+//!
+//! With the schema of a type, given by `desc::describe`, packed values can be
+//! converted to any serde format and back, without the Rust type:
+//!
+//! ```ignore
+//! let schema = serde_iop::desc::describe::<Query>()?;
+//!
+//! let mut json = Vec::new();
+//! transcode(&packed, &schema, &mut serde_json::Serializer::new(&mut json))?;
+//!
+//! let mut de = serde_json::Deserializer::from_slice(&json);
+//! let packed = transcode_from(&mut de, &schema)?;
+//! ```
+//!
+//! Structs are transcoded as maps of their fields, and unions as enums, which
+//! most formats represent as a map with a single entry named after the
+//! variant. Present optional voids are lost in formats like JSON, which
+//! cannot tell them apart from absent values. Fields and variants are also
+//! accepted under their aliases.
+//!
+//! Without a schema, the IOP `Deserializer` can still be transcoded, with
+//! `serde_transcode` for example, but the types of the values are guessed
+//! from the wire types and the fields are only known by their tags.
+use std::fmt;
+
+use serde::de::{self, DeserializeSeed, Deserializer, EnumAccess, VariantAccess, Visitor};
+use serde::ser::{self, Error as _, SerializeSeq, SerializeStruct, Serializer};
+use serde::Serialize;
+
+use crate::debug::{self, Element, Value};
+use crate::desc::{FieldDesc, Schema, TypeDesc};
+use crate::error::Error;
+use crate::ser::pack;
+
+/* {{{ IOP to serde */
+
+/// Transcode a packed value into a serde format.
+pub fn transcode<S: Serializer>(
+    data: &[u8],
+    schema: &Schema,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    let elements = debug::parse(data).map_err(S::Error::custom)?;
+
+    match schema.root {
+        TypeDesc::Struct(name) => Struct {
+            schema,
+            name,
+            elements: &elements,
+        }
+        .serialize(serializer),
+        TypeDesc::Union(name) => Union {
+            schema,
+            name,
+            elements: &elements,
+        }
+        .serialize(serializer),
+        _ => Err(S::Error::custom(
+            "only structs and unions can be transcoded",
+        )),
+    }
+}
+
+fn get_fields<'a, E: ser::Error>(
+    fields: &'a std::collections::BTreeMap<&'static str, Vec<FieldDesc>>,
+    name: &str,
+) -> std::result::Result<&'a [FieldDesc], E> {
+    fields
+        .get(name)
+        .map(Vec::as_slice)
+        .ok_or_else(|| E::custom(format!("`{}` is not described in the schema", name)))
+}
+
+/// Content of a packed string or bytes.
+fn block_content<E: ser::Error>(payload: &[u8]) -> std::result::Result<&[u8], E> {
+    match payload.split_last() {
+        Some((0, content)) => Ok(content),
+        _ => Err(E::custom(Error::InvalidEncoding)),
+    }
+}
+
+/// An element typed by the schema.
+struct Typed<'a, 'b> {
+    schema: &'a Schema,
+    ty: &'a TypeDesc,
+    element: &'b Element<'b>,
+}
+
+impl<'a, 'b> Typed<'a, 'b> {
+    fn with_type(&self, ty: &'a TypeDesc, element: &'b Element<'b>) -> Self {
+        Self {
+            schema: self.schema,
+            ty,
+            element,
+        }
+    }
+}
+
+impl<'a, 'b> Serialize for Typed<'a, 'b> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let unexpected = || {
+            S::Error::custom(format!(
+                "unexpected {:?} element with tag {} for a `{:?}`",
+                self.element.wire, self.element.tag, self.ty
+            ))
+        };
+
+        match (self.ty, &self.element.value) {
+            (TypeDesc::Option(ty), _) => {
+                serializer.serialize_some(&self.with_type(ty, self.element))
+            }
+            (TypeDesc::Unit, _) => serializer.serialize_unit(),
+            (TypeDesc::Array(ty), Value::Repeat(items)) => {
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+
+                for item in items {
+                    seq.serialize_element(&self.with_type(ty, item))?;
+                }
+                seq.end()
+            }
+            (TypeDesc::String, Value::Block(payload)) => {
+                let content = block_content(payload)?;
+
+                serializer.serialize_str(std::str::from_utf8(content).map_err(S::Error::custom)?)
+            }
+            (TypeDesc::Bytes, Value::Block(payload)) => {
+                serializer.serialize_bytes(block_content(payload)?)
+            }
+            (TypeDesc::Struct(name), Value::Block(_)) => {
+                let children = self.element.children().ok_or_else(unexpected)?;
+
+                Struct {
+                    schema: self.schema,
+                    name,
+                    elements: &children,
+                }
+                .serialize(serializer)
+            }
+            (TypeDesc::Union(name), Value::Block(_)) => {
+                let children = self.element.children().ok_or_else(unexpected)?;
+
+                Union {
+                    schema: self.schema,
+                    name,
+                    elements: &children,
+                }
+                .serialize(serializer)
+            }
+            (ty, Value::Int(v)) => {
+                let v = *v;
+
+                match ty {
+                    TypeDesc::Bool => serializer.serialize_bool(v != 0),
+                    TypeDesc::I8 => serializer.serialize_i8(v as i8),
+                    TypeDesc::U8 => serializer.serialize_u8(v as u8),
+                    TypeDesc::I16 => serializer.serialize_i16(v as i16),
+                    TypeDesc::U16 => serializer.serialize_u16(v as u16),
+                    TypeDesc::I32 => serializer.serialize_i32(v as i32),
+                    TypeDesc::U32 => serializer.serialize_u32(v as u32),
+                    TypeDesc::I64 => serializer.serialize_i64(v),
+                    TypeDesc::U64 => serializer.serialize_u64(v as u64),
+                    TypeDesc::F32 => serializer.serialize_f32(f32::from_bits(v as u32)),
+                    TypeDesc::F64 => serializer.serialize_f64(f64::from_bits(v as u64)),
+                    TypeDesc::Char => match std::char::from_u32(v as u32) {
+                        Some(c) => serializer.serialize_char(c),
+                        None => Err(S::Error::custom(Error::InvalidEncoding)),
+                    },
+                    _ => Err(unexpected()),
+                }
+            }
+            _ => Err(unexpected()),
+        }
+    }
+}
+
+/// Packed fields of a struct.
+struct Struct<'a, 'b> {
+    schema: &'a Schema,
+    name: &'static str,
+    elements: &'b [Element<'b>],
+}
+
+impl<'a, 'b> Serialize for Struct<'a, 'b> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let fields = get_fields(&self.schema.structs, self.name)?;
+        let mut state = serializer.serialize_struct(self.name, fields.len())?;
+
+        for field in fields {
+            let element = self.elements.iter().find(|e| e.tag == field.tag);
+
+            match (element, &field.ty) {
+                (Some(element), ty) => {
+                    let typed = Typed {
+                        schema: self.schema,
+                        ty,
+                        element,
+                    };
+
+                    state.serialize_field(field.name, &typed)?;
+                }
+                (None, TypeDesc::Option(_)) => state.serialize_field(field.name, &None::<()>)?,
+                // empty arrays are not packed by all implementations
+                (None, TypeDesc::Array(_)) => state.serialize_field(field.name, &[(); 0])?,
+                (None, TypeDesc::Unit) => state.serialize_field(field.name, &())?,
+                (None, _) => {
+                    return Err(S::Error::custom(format!(
+                        "missing field `{}` in `{}`",
+                        field.name, self.name
+                    )));
+                }
+            }
+        }
+        state.end()
+    }
+}
+
+/// Packed member of a union.
+struct Union<'a, 'b> {
+    schema: &'a Schema,
+    name: &'static str,
+    elements: &'b [Element<'b>],
+}
+
+impl<'a, 'b> Serialize for Union<'a, 'b> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let variants = get_fields(&self.schema.unions, self.name)?;
+        let element = match self.elements {
+            [element] => element,
+            _ => return Err(S::Error::custom(Error::InvalidEncoding)),
+        };
+        let (index, variant) = variants
+            .iter()
+            .enumerate()
+            .find(|(_, v)| v.tag == element.tag)
+            .ok_or_else(|| S::Error::custom(Error::InvalidEncoding))?;
+
+        if variant.ty == TypeDesc::Unit {
+            return serializer.serialize_unit_variant(self.name, index as u32, variant.name);
+        }
+        let typed = Typed {
+            schema: self.schema,
+            ty: &variant.ty,
+            element,
+        };
+        serializer.serialize_newtype_variant(self.name, index as u32, variant.name, &typed)
+    }
+}
+
+/* }}} */
+/* {{{ serde to IOP */
+
+/// Transcode a value from a serde format into a packed value.
+pub fn transcode_from<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    schema: &Schema,
+) -> std::result::Result<Vec<u8>, D::Error> {
+    match schema.root {
+        TypeDesc::Struct(_) | TypeDesc::Union(_) => (),
+        _ => {
+            return Err(de::Error::custom(
+                "only structs and unions can be transcoded",
+            ))
+        }
+    }
+
+    let mut output = Vec::new();
+    Packer {
+        schema,
+        ty: &schema.root,
+        tag: None,
+        output: &mut output,
+    }
+    .deserialize(deserializer)?;
+    Ok(output)
+}
+
+/// Pack a value of the given type, with the given tag, or without header at
+/// the top level.
+struct Packer<'a> {
+    schema: &'a Schema,
+    ty: &'a TypeDesc,
+    tag: Option<u16>,
+    output: &'a mut Vec<u8>,
+}
+
+impl<'a> Packer<'a> {
+    fn pack<T: ?Sized + Serialize, E: de::Error>(self, v: &T) -> std::result::Result<(), E> {
+        let tag = self.tag.ok_or_else(|| E::custom(Error::MissingTag))?;
+
+        v.serialize(&mut crate::ser::Serializer::for_field(self.output, tag))
+            .map_err(E::custom)
+    }
+
+    /// Pack a struct or a union, from its packed content.
+    fn pack_block<E: de::Error>(self, content: &[u8]) -> std::result::Result<(), E> {
+        if let Some(tag) = self.tag {
+            // with a 4 bytes length, as the serializer does
+            let slice = pack::get_mut_slice(self.output, pack::tag_len(tag) + 1 + 4);
+
+            pack::set_len32(tag, content.len(), slice).map_err(E::custom)?;
+        }
+        self.output.extend_from_slice(content);
+        Ok(())
+    }
+
+    fn invalid<E: de::Error>(&self, what: de::Unexpected) -> E {
+        E::invalid_type(what, self)
+    }
+
+    fn pack_int<E: de::Error>(self, v: i128) -> std::result::Result<(), E> {
+        match self.ty {
+            TypeDesc::F32 => self.pack(&(v as f32)),
+            TypeDesc::F64 => self.pack(&(v as f64)),
+            ty => match ty.int_range() {
+                Some((min, max)) if min <= v && v <= max => {
+                    if v > i64::MAX as i128 {
+                        self.pack(&(v as u64))
+                    } else {
+                        self.pack(&(v as i64))
+                    }
+                }
+                Some(_) => Err(E::custom(format!("{} is out of range for `{:?}`", v, ty))),
+                None => Err(self.invalid(de::Unexpected::Other("integer"))),
+            },
+        }
+    }
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for Packer<'a> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<(), D::Error> {
+        match self.ty {
+            TypeDesc::Unit => deserializer.deserialize_unit(self),
+            TypeDesc::Bool => deserializer.deserialize_bool(self),
+            TypeDesc::I8 | TypeDesc::I16 | TypeDesc::I32 | TypeDesc::I64 => {
+                deserializer.deserialize_i64(self)
+            }
+            TypeDesc::U8 | TypeDesc::U16 | TypeDesc::U32 | TypeDesc::U64 => {
+                deserializer.deserialize_u64(self)
+            }
+            TypeDesc::F32 | TypeDesc::F64 => deserializer.deserialize_f64(self),
+            TypeDesc::Char => deserializer.deserialize_char(self),
+            TypeDesc::String => deserializer.deserialize_str(self),
+            TypeDesc::Bytes => deserializer.deserialize_bytes(self),
+            TypeDesc::Option(_) => deserializer.deserialize_option(self),
+            TypeDesc::Array(_) => deserializer.deserialize_seq(self),
+            TypeDesc::Struct(name) => deserializer.deserialize_struct(name, &[], self),
+            TypeDesc::Union(name) => deserializer.deserialize_enum(name, &[], self),
+        }
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for Packer<'a> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a value of type `{:?}`", self.ty)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> std::result::Result<(), E> {
+        match self.ty {
+            // only packed as an optional value or as a union member
+            TypeDesc::Unit => {
+                let tag = self.tag.ok_or_else(|| E::custom(Error::MissingTag))?;
+
+                pack::push_len(tag, 0, self.output).map_err(E::custom)
+            }
+            TypeDesc::Option(_) => Ok(()),
+            _ => Err(self.invalid(de::Unexpected::Unit)),
+        }
+    }
+
+    fn visit_none<E: de::Error>(self) -> std::result::Result<(), E> {
+        match self.ty {
+            TypeDesc::Option(_) => Ok(()),
+            _ => Err(self.invalid(de::Unexpected::Option)),
+        }
+    }
+
+    fn visit_some<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<(), D::Error> {
+        match self.ty {
+            TypeDesc::Option(ty) => Packer { ty, ..self }.deserialize(deserializer),
+            _ => Err(self.invalid(de::Unexpected::Option)),
+        }
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> std::result::Result<(), E> {
+        match self.ty {
+            TypeDesc::Bool => self.pack(&v),
+            _ => Err(self.invalid(de::Unexpected::Bool(v))),
+        }
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<(), E> {
+        self.pack_int(v.into())
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<(), E> {
+        self.pack_int(v.into())
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> std::result::Result<(), E> {
+        match self.ty {
+            TypeDesc::F32 => self.pack(&(v as f32)),
+            TypeDesc::F64 => self.pack(&v),
+            _ => Err(self.invalid(de::Unexpected::Float(v))),
+        }
+    }
+
+    fn visit_char<E: de::Error>(self, v: char) -> std::result::Result<(), E> {
+        match self.ty {
+            TypeDesc::Char => self.pack(&v),
+            _ => self.visit_str(v.encode_utf8(&mut [0; 4])),
+        }
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<(), E> {
+        let mut chars = v.chars();
+
+        match (self.ty, chars.next(), chars.next()) {
+            (TypeDesc::String, _, _) => self.pack(v),
+            (TypeDesc::Bytes, _, _) => self.pack(serde_bytes::Bytes::new(v.as_bytes())),
+            (TypeDesc::Char, Some(c), None) => self.pack(&c),
+            _ => Err(self.invalid(de::Unexpected::Str(v))),
+        }
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> std::result::Result<(), E> {
+        match self.ty {
+            TypeDesc::Bytes => self.pack(serde_bytes::Bytes::new(v)),
+            TypeDesc::String => match std::str::from_utf8(v) {
+                Ok(s) => self.pack(s),
+                Err(_) => Err(self.invalid(de::Unexpected::Bytes(v))),
+            },
+            _ => Err(self.invalid(de::Unexpected::Bytes(v))),
+        }
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<(), A::Error> {
+        match self.ty {
+            TypeDesc::Array(ty) => {
+                let tag = self
+                    .tag
+                    .ok_or_else(|| de::Error::custom(Error::MissingTag))?;
+                let mut items = Vec::new();
+                let mut len = 0;
+
+                loop {
+                    let packer = Packer {
+                        schema: self.schema,
+                        ty,
+                        tag: Some(0),
+                        output: &mut items,
+                    };
+                    if seq.next_element_seed(packer)?.is_none() {
+                        break;
+                    }
+                    len += 1;
+                }
+                pack::push_repeated_len(tag, len, self.output).map_err(de::Error::custom)?;
+                self.output.extend_from_slice(&items);
+                Ok(())
+            }
+            // bytes given as an array of integers
+            TypeDesc::Bytes => {
+                let mut bytes = Vec::new();
+
+                while let Some(b) = seq.next_element::<u8>()? {
+                    bytes.push(b);
+                }
+                self.pack(serde_bytes::Bytes::new(&bytes))
+            }
+            _ => Err(self.invalid(de::Unexpected::Seq)),
+        }
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> std::result::Result<(), A::Error> {
+        let name = match self.ty {
+            TypeDesc::Struct(name) => name,
+            _ => return Err(self.invalid(de::Unexpected::Map)),
+        };
+        let fields = self.schema.structs.get(name).map_or(&[][..], Vec::as_slice);
+        let mut packed: Vec<(u16, Vec<u8>)> = Vec::new();
+
+        while let Some(key) = map.next_key::<String>()? {
+            let field = match fields.iter().find(|f| f.is_named(&key)) {
+                Some(field) => field,
+                None => {
+                    let names: Vec<_> = fields.iter().map(|f| f.name).collect();
+
+                    return Err(de::Error::custom(format!(
+                        "unknown field `{}` in `{}`, expected one of {:?}",
+                        key, name, names
+                    )));
+                }
+            };
+            if packed.iter().any(|(tag, _)| *tag == field.tag) {
+                return Err(de::Error::custom(format!("duplicate field `{}`", key)));
+            }
+            if field.ty == TypeDesc::Unit {
+                // void fields are not packed
+                map.next_value::<de::IgnoredAny>()?;
+                packed.push((field.tag, Vec::new()));
+                continue;
+            }
+
+            let mut output = Vec::new();
+            map.next_value_seed(Packer {
+                schema: self.schema,
+                ty: &field.ty,
+                tag: Some(field.tag),
+                output: &mut output,
+            })?;
+            packed.push((field.tag, output));
+        }
+
+        for field in fields {
+            let absent = !packed.iter().any(|(tag, _)| *tag == field.tag);
+
+            if absent && !(field.is_optional() || field.is_repeated() || field.ty == TypeDesc::Unit)
+            {
+                return Err(de::Error::custom(format!(
+                    "missing field `{}` in `{}`",
+                    field.name, name
+                )));
+            }
+        }
+
+        // fields are packed in the order of their tags
+        packed.sort_by_key(|(tag, _)| *tag);
+        let content: Vec<u8> = packed.into_iter().flat_map(|(_, v)| v).collect();
+        self.pack_block(&content)
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> std::result::Result<(), A::Error> {
+        let name = match self.ty {
+            TypeDesc::Union(name) => name,
+            _ => return Err(self.invalid(de::Unexpected::Enum)),
+        };
+        let variants = self.schema.unions.get(name).map_or(&[][..], Vec::as_slice);
+        let (key, access) = data.variant::<String>()?;
+        let variant = variants
+            .iter()
+            .find(|v| v.is_named(&key))
+            .ok_or_else(|| de::Error::custom(format!("unknown variant `{}` in `{}`", key, name)))?;
+
+        let mut content = Vec::new();
+        let packer = Packer {
+            schema: self.schema,
+            ty: &variant.ty,
+            tag: Some(variant.tag),
+            output: &mut content,
+        };
+        if variant.ty == TypeDesc::Unit {
+            access.unit_variant()?;
+            packer.visit_unit::<A::Error>()?;
+        } else {
+            access.newtype_variant_seed(packer)?;
+        }
+        self.pack_block(&content)
+    }
+}
+
+/* }}} */
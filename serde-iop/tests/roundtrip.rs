@@ -0,0 +1,156 @@
+use proptest::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_iop::{from_bytes, to_bytes};
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+struct Inner {
+    opt_int: Option<i32>,
+    opt_str: Option<String>,
+    data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+enum Union {
+    Int(i64),
+    Str(String),
+    Inner(Inner),
+    Tab(Vec<u16>),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+struct Outer {
+    i8: i8,
+    u8: u8,
+    i16: i16,
+    u16: u16,
+    i32: i32,
+    u32: u32,
+    i64: i64,
+    u64: u64,
+    f32: f32,
+    f64: f64,
+    b: bool,
+    c: char,
+    s: String,
+    opt_inner: Option<Inner>,
+    inner: Inner,
+    union: Union,
+    opt_union: Option<Union>,
+    strs: Vec<String>,
+    inners: Vec<Inner>,
+    unions: Vec<Union>,
+    opt_tab: Option<Vec<i64>>,
+}
+
+fn inner() -> impl Strategy<Value = Inner> {
+    (
+        any::<Option<i32>>(),
+        any::<Option<String>>(),
+        any::<Vec<u8>>(),
+    )
+        .prop_map(|(opt_int, opt_str, data)| Inner {
+            opt_int,
+            opt_str,
+            data,
+        })
+}
+
+fn union() -> impl Strategy<Value = Union> {
+    prop_oneof![
+        any::<i64>().prop_map(Union::Int),
+        any::<String>().prop_map(Union::Str),
+        inner().prop_map(Union::Inner),
+        any::<Vec<u16>>().prop_map(Union::Tab),
+    ]
+}
+
+// NaN are excluded as they are never equal to themselves
+fn outer() -> impl Strategy<Value = Outer> {
+    (
+        (
+            any::<i8>(),
+            any::<u8>(),
+            any::<i16>(),
+            any::<u16>(),
+            any::<i32>(),
+            any::<u32>(),
+            any::<i64>(),
+            any::<u64>(),
+        ),
+        (
+            any::<f32>().prop_filter("NaN", |v| !v.is_nan()),
+            any::<f64>().prop_filter("NaN", |v| !v.is_nan()),
+            any::<bool>(),
+            any::<char>(),
+            any::<String>(),
+        ),
+        (
+            proptest::option::of(inner()),
+            inner(),
+            union(),
+            proptest::option::of(union()),
+        ),
+        (
+            any::<Vec<String>>(),
+            proptest::collection::vec(inner(), 0..5),
+            proptest::collection::vec(union(), 0..5),
+            any::<Option<Vec<i64>>>(),
+        ),
+    )
+        .prop_map(
+            |(
+                (i8, u8, i16, u16, i32, u32, i64, u64),
+                (f32, f64, b, c, s),
+                (opt_inner, inner, union, opt_union),
+                (strs, inners, unions, opt_tab),
+            )| Outer {
+                i8,
+                u8,
+                i16,
+                u16,
+                i32,
+                u32,
+                i64,
+                u64,
+                f32,
+                f64,
+                b,
+                c,
+                s,
+                opt_inner,
+                inner,
+                union,
+                opt_union,
+                strs,
+                inners,
+                unions,
+                opt_tab,
+            },
+        )
+}
+
+proptest! {
+    #[test]
+    fn roundtrip_inner(v in inner()) {
+        let bytes = to_bytes(&v).unwrap();
+        prop_assert_eq!(from_bytes::<Inner>(&bytes).unwrap(), v);
+    }
+
+    #[test]
+    fn roundtrip_outer(v in outer()) {
+        let bytes = to_bytes(&v).unwrap();
+        prop_assert_eq!(from_bytes::<Outer>(&bytes).unwrap(), v);
+    }
+
+    #[test]
+    fn roundtrip_nested(v in proptest::collection::vec(outer(), 0..3)) {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Nested {
+            tab: Vec<Outer>,
+        }
+
+        let v = Nested { tab: v };
+        let bytes = to_bytes(&v).unwrap();
+        prop_assert_eq!(from_bytes::<Nested>(&bytes).unwrap(), v);
+    }
+}
@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
-use serde_iop::{from_bytes, to_bytes};
+use serde_iop::{from_bytes, from_bytes_strict, to_buffer, to_bytes, Deserializer, Error};
 use serde_repr::{Deserialize_repr, Serialize_repr};
+use smallvec::SmallVec;
 
 #[test]
 fn test_basic() {
@@ -134,3 +135,1496 @@ fn test_all_types() {
     let unpacked = from_bytes(&bytes).unwrap();
     assert_eq!(test, unpacked);
 }
+
+#[test]
+fn test_missing_trailing_fields() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct InnerV1 {
+        a: u32,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct StructV1 {
+        inner: InnerV1,
+        b: String,
+    }
+
+    fn default_d() -> String {
+        "d".to_owned()
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct InnerV2 {
+        a: u32,
+        opt: Option<u32>,
+        #[serde(default)]
+        c: u32,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct StructV2 {
+        inner: InnerV2,
+        b: String,
+        #[serde(default = "default_d")]
+        d: String,
+        #[serde(default)]
+        tab: Vec<i8>,
+    }
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct StructV3 {
+        inner: InnerV2,
+        b: String,
+        mandatory: u32,
+    }
+
+    let v1 = StructV1 {
+        inner: InnerV1 { a: 12 },
+        b: "b".to_owned(),
+    };
+    let bytes = to_bytes(&v1).unwrap();
+
+    let v2: StructV2 = from_bytes(&bytes).unwrap();
+    assert_eq!(
+        v2,
+        StructV2 {
+            inner: InnerV2 {
+                a: 12,
+                opt: None,
+                c: 0,
+            },
+            b: "b".to_owned(),
+            d: "d".to_owned(),
+            tab: Vec::new(),
+        }
+    );
+    assert!(from_bytes::<StructV3>(&bytes).is_err());
+
+    // in strict mode, defaults are not used
+    assert_eq!(
+        from_bytes_strict::<StructV2>(&bytes).unwrap_err(),
+        Error::MissingField {
+            field: "c".to_owned()
+        }
+    );
+    assert_eq!(from_bytes_strict::<StructV1>(&bytes).unwrap(), v1);
+
+    // the other errors of the missing fields are not hidden by the defaults
+    #[serde_iop::iop]
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct StructV4 {
+        inner: InnerV2,
+        b: String,
+        #[serde(default)]
+        #[iop(min_occurs = 1)]
+        tab: Vec<i8>,
+    }
+    assert!(matches!(
+        from_bytes::<StructV4>(&bytes),
+        Err(Error::ConstraintViolation { .. })
+    ));
+}
+
+#[test]
+fn test_absent_arrays() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct WithoutTab {
+        a: u32,
+    }
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct WithTab {
+        a: u32,
+        tab: Vec<String>,
+    }
+
+    // empty arrays are not packed by all implementations, so absent arrays
+    // are empty, even in strict mode
+    let bytes = to_bytes(&WithoutTab { a: 1 }).unwrap();
+    let expected = WithTab {
+        a: 1,
+        tab: Vec::new(),
+    };
+    assert_eq!(from_bytes::<WithTab>(&bytes).unwrap(), expected);
+    assert_eq!(from_bytes_strict::<WithTab>(&bytes).unwrap(), expected);
+}
+
+#[test]
+fn test_constraints() {
+    #[serde_iop::iop]
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct Constrained {
+        #[iop(min = "-10", max = 10, non_zero)]
+        i: i32,
+        #[iop(max = 1.5)]
+        d: f64,
+        #[iop(non_empty, max_length = 4, pattern = "[a-z]+")]
+        s: String,
+        #[iop(min_occurs = 1, max_occurs = 2, min = 0)]
+        tab: Vec<i8>,
+        #[serde(rename = "renamed")]
+        #[iop(min = 5)]
+        opt: Option<u64>,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct Unconstrained {
+        i: i32,
+        d: f64,
+        s: String,
+        tab: Vec<i8>,
+        opt: Option<u64>,
+    }
+
+    fn violation(field: &str, constraint: &str) -> Error {
+        Error::ConstraintViolation {
+            field: field.to_owned(),
+            constraint: constraint.to_owned(),
+        }
+    }
+
+    let valid = Constrained {
+        i: -10,
+        d: 1.5,
+        s: "abcd".to_owned(),
+        tab: vec![0, 12],
+        opt: None,
+    };
+    let bytes = to_bytes(&valid).unwrap();
+    assert_eq!(from_bytes::<Constrained>(&bytes).unwrap(), valid);
+
+    let checks = [
+        (
+            Constrained {
+                i: 0,
+                ..valid.clone()
+            },
+            violation("i", "@nonZero"),
+        ),
+        (
+            Constrained {
+                i: 11,
+                ..valid.clone()
+            },
+            violation("i", "@max(10)"),
+        ),
+        (
+            Constrained {
+                d: 1.6,
+                ..valid.clone()
+            },
+            violation("d", "@max(1.5)"),
+        ),
+        (
+            Constrained {
+                d: f64::NAN,
+                ..valid.clone()
+            },
+            violation("d", "@max(1.5)"),
+        ),
+        (
+            Constrained {
+                s: "".to_owned(),
+                ..valid.clone()
+            },
+            violation("s", "@nonEmpty"),
+        ),
+        (
+            Constrained {
+                s: "abcde".to_owned(),
+                ..valid.clone()
+            },
+            violation("s", "@maxLength(4)"),
+        ),
+        (
+            Constrained {
+                s: "aB".to_owned(),
+                ..valid.clone()
+            },
+            violation("s", "@pattern(\"[a-z]+\")"),
+        ),
+        (
+            Constrained {
+                tab: vec![],
+                ..valid.clone()
+            },
+            violation("tab", "@minOccurs(1)"),
+        ),
+        (
+            Constrained {
+                tab: vec![1, 2, 3],
+                ..valid.clone()
+            },
+            violation("tab", "@maxOccurs(2)"),
+        ),
+        (
+            Constrained {
+                tab: vec![1, -1],
+                ..valid.clone()
+            },
+            violation("tab", "@min(0)"),
+        ),
+        (
+            Constrained {
+                opt: Some(4),
+                ..valid.clone()
+            },
+            violation("renamed", "@min(5)"),
+        ),
+    ];
+    for (value, err) in checks.iter() {
+        assert_eq!(to_bytes(value).as_ref(), Err(err));
+
+        // values packed without constraints are rejected when unpacking
+        let unconstrained = Unconstrained {
+            i: value.i,
+            d: value.d,
+            s: value.s.clone(),
+            tab: value.tab.clone(),
+            opt: value.opt,
+        };
+        let bytes = to_bytes(&unconstrained).unwrap();
+        assert_eq!(from_bytes::<Constrained>(&bytes).as_ref(), Err(err));
+    }
+}
+
+#[test]
+fn test_float_special_values() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Floats {
+        d: f64,
+        f: f32,
+    }
+
+    let doubles = [
+        f64::NAN,
+        -f64::NAN,
+        // NaN with a payload
+        f64::from_bits(0x7ff0_0000_dead_beef),
+        f64::INFINITY,
+        f64::NEG_INFINITY,
+        0.0,
+        -0.0,
+        f64::MIN_POSITIVE / 2.0,
+        f64::MAX,
+    ];
+    let floats = [
+        f32::NAN,
+        f32::INFINITY,
+        f32::NEG_INFINITY,
+        -0.0,
+        f32::MIN_POSITIVE / 2.0,
+    ];
+
+    for (d, f) in doubles.iter().zip(floats.iter().cycle()) {
+        let value = Floats { d: *d, f: *f };
+        let bytes = to_bytes(&value).unwrap();
+
+        // the bits are packed as is
+        let mut expected = vec![0x61];
+        expected.extend_from_slice(&d.to_bits().to_le_bytes());
+        expected.push(0xC2);
+        expected.extend_from_slice(&f.to_bits().to_le_bytes());
+        assert_eq!(bytes, expected);
+
+        let unpacked: Floats = from_bytes(&bytes).unwrap();
+        assert_eq!(unpacked.d.to_bits(), d.to_bits());
+        assert_eq!(unpacked.f.to_bits(), f.to_bits());
+    }
+
+    // infinities are checked against bounds like other values
+    #[serde_iop::iop]
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Bounded {
+        #[iop(min = 0)]
+        d: f64,
+    }
+    assert!(to_bytes(&Bounded { d: f64::INFINITY }).is_ok());
+    assert!(to_bytes(&Bounded { d: -0.0 }).is_ok());
+    assert!(to_bytes(&Bounded {
+        d: f64::NEG_INFINITY
+    })
+    .is_err());
+}
+
+#[test]
+fn test_nested_options() {
+    use serde_iop::desc::describe;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Tab {
+        tab: Vec<Option<i32>>,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Opt {
+        opt: Option<Option<i32>>,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum Union {
+        A(Option<i32>),
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct WithUnion {
+        u: Union,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Item {
+        a: Option<i32>,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Valid {
+        opt: Option<Vec<i32>>,
+        tab: Vec<Item>,
+    }
+
+    fn nested(field: &str) -> Error {
+        Error::NestedOption {
+            field: field.to_owned(),
+        }
+    }
+
+    // the absence of the values cannot be packed
+    let tab = Tab {
+        tab: vec![Some(1), None],
+    };
+    assert_eq!(to_bytes(&tab), Err(nested("tab")));
+    let tab = Tab { tab: vec![] };
+    assert!(to_bytes(&tab).is_ok());
+    assert_eq!(to_bytes(&Opt { opt: Some(None) }), Err(nested("opt")));
+    assert_eq!(to_bytes(&Opt { opt: Some(Some(1)) }), Err(nested("opt")));
+    assert_eq!(to_bytes(&Opt { opt: None }), Ok(vec![]));
+    let u = WithUnion { u: Union::A(None) };
+    assert_eq!(to_bytes(&u), Err(nested("A")));
+
+    // nor unpacked
+    #[derive(Serialize, Deserialize)]
+    struct PackedTab {
+        tab: Vec<i32>,
+    }
+    #[derive(Serialize, Deserialize)]
+    struct PackedOpt {
+        opt: Option<i32>,
+    }
+    let bytes = to_bytes(&PackedTab { tab: vec![1] }).unwrap();
+    assert_eq!(from_bytes::<Tab>(&bytes), Err(nested("tab")));
+    let bytes = to_bytes(&PackedOpt { opt: Some(1) }).unwrap();
+    assert_eq!(from_bytes::<Opt>(&bytes), Err(nested("opt")));
+
+    // nor described
+    assert_eq!(describe::<Tab>().unwrap_err(), nested("tab"));
+    assert_eq!(describe::<Opt>().unwrap_err(), nested("opt"));
+    assert_eq!(describe::<WithUnion>().unwrap_err(), nested("A"));
+
+    // options of arrays, and arrays of structs with options are fine
+    let valid = Valid {
+        opt: Some(vec![1]),
+        tab: vec![Item { a: None }, Item { a: Some(2) }],
+    };
+    let bytes = to_bytes(&valid).unwrap();
+    assert_eq!(from_bytes::<Valid>(&bytes).unwrap(), valid);
+    assert!(describe::<Valid>().is_ok());
+}
+
+#[test]
+fn test_union_tags() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum Implicit {
+        A(i8),
+        B(i8),
+    }
+    #[serde_iop::iop]
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    enum Explicit {
+        #[iop(tag = 3)]
+        A(i8),
+        B(String),
+        #[iop(tag = 10, max = 5)]
+        C(i8),
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Test {
+        implicit: Implicit,
+        explicit: Explicit,
+    }
+
+    let test = Test {
+        implicit: Implicit::B(1),
+        explicit: Explicit::C(2),
+    };
+    let expected_bytes = [
+        // implicit:
+        0x41, // BLK4 | 1
+        0x02, 0x00, 0x00, 0x00, // len: 2
+        0x82, // INT1 | 2
+        0x01, // value: 1
+        // explicit:
+        0x42, // BLK4 | 2
+        0x02, 0x00, 0x00, 0x00, // len: 2
+        0x8A, // INT1 | 10
+        0x02, // value: 2
+    ];
+    assert_eq!(to_bytes(&test).unwrap(), expected_bytes);
+    assert_eq!(test, from_bytes(&expected_bytes).unwrap());
+
+    for explicit in [Explicit::A(1), Explicit::B("b".to_owned())].iter() {
+        let test = Test {
+            implicit: Implicit::A(1),
+            explicit: explicit.clone(),
+        };
+        assert_eq!(test, from_bytes(&to_bytes(&test).unwrap()).unwrap());
+    }
+
+    // constraints of the variants are checked
+    let test = Test {
+        implicit: Implicit::A(1),
+        explicit: Explicit::C(6),
+    };
+    assert!(to_bytes(&test).is_err());
+
+    // unknown tag
+    let mut bytes = expected_bytes;
+    bytes[12] = 0x85;
+    assert_eq!(from_bytes::<Test>(&bytes), Err(Error::InvalidEncoding));
+}
+
+#[test]
+fn test_attrs_in_other_formats() {
+    use serde_json::json;
+
+    #[serde_iop::iop]
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum Union {
+        #[iop(tag = 3)]
+        A(i8),
+        #[iop(alias = "c")]
+        B(String),
+    }
+    #[serde_iop::iop]
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    #[serde(rename = "Renamed")]
+    struct Test {
+        #[iop(tag = 2, min = 0, pattern = "[a-z;]+")]
+        a: String,
+        #[serde(rename = "renamed")]
+        #[iop(max = 5)]
+        b: i32,
+        u: Union,
+    }
+
+    // the IOP attributes do not appear in the names of the fields
+    let test = Test {
+        a: "a;b".to_owned(),
+        b: 5,
+        u: Union::B("b".to_owned()),
+    };
+    let value = json!({ "a": "a;b", "renamed": 5, "u": { "B": "b" } });
+    assert_eq!(serde_json::to_value(&test).unwrap(), value);
+    assert_eq!(serde_json::from_value::<Test>(value).unwrap(), test);
+
+    assert_eq!(from_bytes::<Test>(&to_bytes(&test).unwrap()).unwrap(), test);
+    let test = Test { b: 6, ..test };
+    assert!(to_bytes(&test).is_err());
+}
+
+#[test]
+fn test_void() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum Union {
+        A(i8),
+        B(()),
+        C,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Test {
+        _dummy: (),
+        opt: Option<()>,
+        u1: Union,
+        u2: Union,
+        a: u8,
+    }
+
+    let test = Test {
+        _dummy: (),
+        opt: Some(()),
+        u1: Union::B(()),
+        u2: Union::C,
+        a: 1,
+    };
+    let expected_bytes = [
+        // opt:
+        0x02, // BLK1 | 2
+        0x00, // len: 0
+        // u1:
+        0x43, // BLK4 | 3
+        0x02, 0x00, 0x00, 0x00, // len: 2
+        0x02, // BLK1 | 2
+        0x00, // len: 0
+        // u2:
+        0x44, // BLK4 | 4
+        0x02, 0x00, 0x00, 0x00, // len: 2
+        0x03, // BLK1 | 3
+        0x00, // len: 0
+        // a:
+        0x85, // INT1 | 5
+        0x01, // value: 1
+    ];
+    assert_eq!(to_bytes(&test).unwrap(), expected_bytes);
+    assert_eq!(test, from_bytes_strict(&expected_bytes).unwrap());
+
+    let test = Test { opt: None, ..test };
+    assert_eq!(to_bytes(&test).unwrap(), expected_bytes[2..]);
+    assert_eq!(test, from_bytes_strict(&expected_bytes[2..]).unwrap());
+}
+
+#[test]
+fn test_invalid_utf8() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Test {
+        a: String,
+        tab: Vec<String>,
+    }
+
+    let test = Test {
+        a: "a".to_owned(),
+        tab: vec!["bc".to_owned()],
+    };
+    let mut bytes = to_bytes(&test).unwrap();
+    // replace 'c' by a lone continuation byte
+    let pos = bytes.iter().position(|&b| b == b'c').unwrap();
+    bytes[pos] = 0x80;
+
+    assert_eq!(
+        from_bytes::<Test>(&bytes),
+        Err(Error::InvalidUtf8 {
+            field: "tab".to_owned()
+        })
+    );
+
+    let mut de = Deserializer::from_bytes(&bytes).lossy_utf8(true);
+    let lossy = Test::deserialize(&mut de).unwrap();
+    de.end().unwrap();
+    assert_eq!(lossy.tab, ["b\u{FFFD}"]);
+}
+
+#[test]
+fn test_sparse_tags() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Full {
+        a: u32,
+        b: Vec<String>,
+        c: String,
+        d: Vec<u32>,
+        e: u32,
+        f: Vec<u8>,
+    }
+    #[serde_iop::iop]
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Sparse {
+        a: u32,
+        #[iop(tag = 3)]
+        c: String,
+        // follows c
+        d: Vec<u32>,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Test {
+        full: Full,
+        b: u32,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct SparseTest {
+        full: Sparse,
+        b: u32,
+    }
+
+    let full = Test {
+        full: Full {
+            a: 1,
+            b: vec!["b".to_owned()],
+            c: "c".to_owned(),
+            d: vec![4, 5],
+            e: 6,
+            f: vec![7],
+        },
+        b: 2,
+    };
+    let sparse = SparseTest {
+        full: Sparse {
+            a: 1,
+            c: "c".to_owned(),
+            d: vec![4, 5],
+        },
+        b: 2,
+    };
+
+    // undeclared fields are skipped
+    let bytes = to_bytes(&full).unwrap();
+    assert_eq!(from_bytes::<SparseTest>(&bytes).unwrap(), sparse);
+    let bytes = to_bytes(&full.full).unwrap();
+    assert_eq!(from_bytes::<Sparse>(&bytes).unwrap(), sparse.full);
+
+    // tags with gaps are packed with the right tags
+    let bytes = to_bytes(&sparse.full).unwrap();
+    assert_eq!(&bytes[..4], &[0x81, 0x01, 0x03, 0x02]);
+    assert_eq!(
+        from_bytes::<Full>(&bytes).unwrap_err(),
+        Error::Custom("invalid length 4, expected struct Full with 6 elements".to_owned())
+    );
+}
+
+#[test]
+fn test_to_buffer() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Inner {
+        a: u32,
+        s: String,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Test {
+        inner: Inner,
+        tab: Vec<Inner>,
+    }
+
+    let test = Test {
+        inner: Inner {
+            a: 1,
+            s: "a".to_owned(),
+        },
+        tab: vec![Inner {
+            a: 2,
+            s: "b".to_owned(),
+        }],
+    };
+    let bytes = to_bytes(&test).unwrap();
+
+    let mut small = SmallVec::<[u8; 64]>::new();
+    to_buffer(&test, &mut small).unwrap();
+    assert!(!small.spilled());
+    assert_eq!(&small[..], &bytes[..]);
+
+    // values are packed after the existing content
+    let mut small = SmallVec::<[u8; 4]>::from_slice(&[0xFF; 3]);
+    to_buffer(&test, &mut small).unwrap();
+    assert_eq!(&small[..3], &[0xFF; 3]);
+    assert_eq!(&small[3..], &bytes[..]);
+    assert_eq!(from_bytes::<Test>(&small[3..]).unwrap(), test);
+}
+
+#[cfg(feature = "bumpalo")]
+#[test]
+fn test_arena() {
+    use serde_iop::bumpalo::Bump;
+    use serde_iop::to_bytes_in_arena;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Test {
+        a: u32,
+        s: String,
+    }
+
+    let test = Test {
+        a: 1,
+        s: "a".repeat(100),
+    };
+    let mut bump = Bump::new();
+    for _ in 0..2 {
+        let bytes = to_bytes_in_arena(&test, &bump).unwrap();
+
+        assert_eq!(&bytes[..], &to_bytes(&test).unwrap()[..]);
+        assert_eq!(from_bytes::<Test>(&bytes).unwrap(), test);
+        assert!(bump.allocated_bytes() >= bytes.len());
+        drop(bytes);
+        bump.reset();
+    }
+}
+
+#[test]
+fn test_bytes() {
+    #[serde_iop::iop]
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Test {
+        #[iop(bytes)]
+        data: Vec<u8>,
+        #[iop(bytes, max_length = 2)]
+        opt: Option<Vec<u8>>,
+        tab: Vec<u8>,
+    }
+
+    let test = Test {
+        data: vec![1, 2, 3],
+        opt: Some(vec![4]),
+        tab: vec![5],
+    };
+    let bytes = to_bytes(&test).unwrap();
+    assert_eq!(
+        bytes,
+        &[
+            0x01, 0x04, 0x01, 0x02, 0x03, 0x00, // data
+            0x02, 0x02, 0x04, 0x00, // opt
+            0xE3, 0x01, 0x00, 0x00, 0x00, 0x80, 0x05, // tab
+        ]
+    );
+    assert_eq!(from_bytes::<Test>(&bytes).unwrap(), test);
+
+    let test = Test {
+        data: vec![],
+        opt: None,
+        tab: vec![],
+    };
+    let bytes = to_bytes(&test).unwrap();
+    assert_eq!(from_bytes::<Test>(&bytes).unwrap(), test);
+
+    // constraints apply on the whole buffer
+    let test = Test {
+        data: vec![],
+        opt: Some(vec![1, 2, 3]),
+        tab: vec![],
+    };
+    assert_eq!(
+        to_bytes(&test).unwrap_err(),
+        Error::ConstraintViolation {
+            field: "opt".to_owned(),
+            constraint: "@maxLength(2)".to_owned(),
+        }
+    );
+}
+
+#[test]
+fn test_timestamps() {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Test {
+        #[serde(with = "serde_iop::time::seconds")]
+        secs: SystemTime,
+        #[serde(with = "serde_iop::time::milliseconds")]
+        millis: SystemTime,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Raw {
+        secs: i64,
+        millis: i64,
+    }
+
+    let test = Test {
+        secs: UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+        millis: UNIX_EPOCH - Duration::from_millis(1_500),
+    };
+    let bytes = to_bytes(&test).unwrap();
+    assert_eq!(
+        from_bytes::<Raw>(&bytes).unwrap(),
+        Raw {
+            secs: 1_600_000_000,
+            millis: -1_500,
+        }
+    );
+    assert_eq!(from_bytes::<Test>(&bytes).unwrap(), test);
+
+    // sub-second precision is lost when packed as seconds
+    let test = Test {
+        secs: UNIX_EPOCH - Duration::from_millis(1_500),
+        millis: UNIX_EPOCH,
+    };
+    let bytes = to_bytes(&test).unwrap();
+    assert_eq!(from_bytes::<Raw>(&bytes).unwrap().secs, -2);
+
+    let bytes = to_bytes(&Raw {
+        secs: i64::MAX,
+        millis: 0,
+    })
+    .unwrap();
+    assert_eq!(
+        from_bytes::<Test>(&bytes).unwrap_err(),
+        Error::Custom("timestamp out of range".to_owned())
+    );
+}
+
+#[test]
+fn test_durations() {
+    use std::time::Duration;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Test {
+        #[serde(with = "serde_iop::time::duration::seconds")]
+        secs: Duration,
+        #[serde(with = "serde_iop::time::duration::milliseconds")]
+        millis: Duration,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Raw {
+        secs: i64,
+        millis: i64,
+    }
+
+    let test = Test {
+        secs: Duration::from_millis(2_999),
+        millis: Duration::from_millis(1_500),
+    };
+    let bytes = to_bytes(&test).unwrap();
+    assert_eq!(
+        from_bytes::<Raw>(&bytes).unwrap(),
+        Raw {
+            secs: 2,
+            millis: 1_500
+        }
+    );
+    assert_eq!(
+        from_bytes::<Test>(&bytes).unwrap(),
+        Test {
+            secs: Duration::from_secs(2),
+            ..test
+        }
+    );
+
+    // negative durations are rejected
+    let bytes = to_bytes(&Raw {
+        secs: 0,
+        millis: -1,
+    })
+    .unwrap();
+    assert_eq!(
+        from_bytes::<Test>(&bytes).unwrap_err(),
+        Error::Custom("duration out of range".to_owned())
+    );
+}
+
+#[cfg(feature = "chrono")]
+#[test]
+fn test_chrono_timestamps() {
+    use chrono::{DateTime, TimeZone, Utc};
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Test {
+        #[serde(with = "serde_iop::time::seconds")]
+        secs: DateTime<Utc>,
+        #[serde(with = "serde_iop::time::milliseconds")]
+        millis: DateTime<Utc>,
+    }
+
+    let test = Test {
+        secs: Utc.timestamp_opt(1_600_000_000, 0).unwrap(),
+        millis: Utc.timestamp_opt(-2, 500_000_000).unwrap(),
+    };
+    let bytes = to_bytes(&test).unwrap();
+    assert_eq!(from_bytes::<Test>(&bytes).unwrap(), test);
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Durations {
+        #[serde(with = "serde_iop::time::duration::milliseconds")]
+        millis: chrono::Duration,
+    }
+
+    // chrono durations can be negative
+    let test = Durations {
+        millis: chrono::Duration::milliseconds(-1_500),
+    };
+    let bytes = to_bytes(&test).unwrap();
+    assert_eq!(from_bytes::<Durations>(&bytes).unwrap(), test);
+}
+
+#[test]
+fn test_inline() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Flat {
+        a: u32,
+        b: String,
+        c: u32,
+        d: u32,
+        e: u32,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Common {
+        b: String,
+        c: u32,
+    }
+    #[serde_iop::iop]
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Composed {
+        a: u32,
+        #[iop(inline)]
+        common: Common,
+        d: u32,
+        #[iop(tag = 5)]
+        e: u32,
+    }
+    #[serde_iop::iop]
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Outer {
+        #[iop(inline)]
+        composed: Composed,
+        f: Vec<Common>,
+    }
+
+    let flat = Flat {
+        a: 1,
+        b: "b".to_owned(),
+        c: 3,
+        d: 4,
+        e: 5,
+    };
+    let composed = Composed {
+        a: 1,
+        common: Common {
+            b: "b".to_owned(),
+            c: 3,
+        },
+        d: 4,
+        e: 5,
+    };
+    let bytes = to_bytes(&composed).unwrap();
+    assert_eq!(bytes, to_bytes(&flat).unwrap());
+    assert_eq!(from_bytes::<Composed>(&bytes).unwrap(), composed);
+
+    // inlined structs can be nested, and arrays of them are not inlined
+    let outer = Outer {
+        composed,
+        f: vec![Common {
+            b: "f".to_owned(),
+            c: 6,
+        }],
+    };
+    let bytes = to_bytes(&outer).unwrap();
+    assert_eq!(&bytes[..(bytes.len() - 16)], &to_bytes(&flat).unwrap()[..]);
+    assert_eq!(bytes[bytes.len() - 16], 0xE6);
+    assert_eq!(from_bytes::<Outer>(&bytes).unwrap(), outer);
+}
+
+#[test]
+fn test_classes() {
+    use serde_iop::class::{Class, ClassObject};
+
+    trait Animal: ClassObject + std::fmt::Debug {
+        fn name(&self) -> &str;
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Base {
+        name: String,
+    }
+    #[serde_iop::iop]
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Dog {
+        #[iop(inline)]
+        base: Base,
+        good: bool,
+    }
+    #[serde_iop::iop]
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Cat {
+        #[iop(inline)]
+        base: Base,
+        lives: u8,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Bird {
+        name: String,
+    }
+    impl Class for Dog {
+        const CLASS_ID: u16 = 1;
+    }
+    impl Class for Cat {
+        const CLASS_ID: u16 = 2;
+    }
+    impl Class for Bird {
+        const CLASS_ID: u16 = 3;
+    }
+    impl Animal for Dog {
+        fn name(&self) -> &str {
+            &self.base.name
+        }
+    }
+    impl Animal for Cat {
+        fn name(&self) -> &str {
+            &self.base.name
+        }
+    }
+    impl Animal for Bird {
+        fn name(&self) -> &str {
+            &self.name
+        }
+    }
+    serde_iop::class_registry!(Animal { Dog, Cat });
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Farm {
+        main: Box<dyn Animal>,
+        others: Vec<Box<dyn Animal>>,
+    }
+
+    let farm = Farm {
+        main: Box::new(Dog {
+            base: Base {
+                name: "rex".to_owned(),
+            },
+            good: true,
+        }),
+        others: vec![
+            Box::new(Cat {
+                base: Base {
+                    name: "tom".to_owned(),
+                },
+                lives: 9,
+            }),
+            Box::new(Dog {
+                base: Base {
+                    name: "medor".to_owned(),
+                },
+                good: false,
+            }),
+        ],
+    };
+    let bytes = to_bytes(&farm).unwrap();
+    // the class is packed as a struct, starting with the class id
+    assert_eq!(
+        &bytes[..16],
+        &[
+            0x41, 0x0A, 0x00, 0x00, 0x00, 0x80, 0x01, 0x01, 0x04, b'r', b'e', b'x', 0x00, 0x82,
+            0x01, 0xE2
+        ]
+    );
+
+    let unpacked = from_bytes::<Farm>(&bytes).unwrap();
+    assert_eq!(unpacked.main.class_id(), 1);
+    assert_eq!(unpacked.main.name(), "rex");
+    let names: Vec<_> = unpacked.others.iter().map(|a| a.name()).collect();
+    assert_eq!(names, ["tom", "medor"]);
+    assert_eq!(to_bytes(&unpacked).unwrap(), bytes);
+
+    // a class can be packed at the top level
+    let dog: Box<dyn Animal> = Box::new(Dog {
+        base: Base {
+            name: "rex".to_owned(),
+        },
+        good: true,
+    });
+    let bytes = to_bytes(&dog).unwrap();
+    assert_eq!(from_bytes::<Box<dyn Animal>>(&bytes).unwrap().name(), "rex");
+
+    // unregistered classes cannot be unpacked
+    let bird: Box<dyn Animal> = Box::new(Bird {
+        name: "tweety".to_owned(),
+    });
+    let bytes = to_bytes(&bird).unwrap();
+    assert_eq!(
+        from_bytes::<Box<dyn Animal>>(&bytes).unwrap_err(),
+        Error::Custom("unknown class id 3".to_owned())
+    );
+}
+
+#[test]
+fn test_describe() {
+    use serde_iop::desc::{describe, FieldDesc, TypeDesc};
+    use serde_iop::Wire;
+
+    #[derive(Serialize, Deserialize)]
+    struct Common {
+        b: String,
+    }
+    #[serde_iop::iop]
+    #[derive(Serialize, Deserialize)]
+    enum Union {
+        Int(i32),
+        #[iop(tag = 5)]
+        Node(Box<Node>),
+    }
+    #[serde_iop::iop]
+    #[derive(Serialize, Deserialize)]
+    struct Node {
+        a: Option<u64>,
+        #[iop(inline)]
+        common: Common,
+        #[iop(tag = 4, bytes)]
+        data: Vec<u8>,
+        children: Vec<Node>,
+        union: Union,
+    }
+
+    fn field(name: &'static str, tag: u16, ty: TypeDesc) -> FieldDesc {
+        FieldDesc {
+            name,
+            aliases: Vec::new(),
+            tag,
+            ty,
+        }
+    }
+
+    let schema = describe::<Node>().unwrap();
+    assert_eq!(schema.root, TypeDesc::Struct("Node"));
+    assert_eq!(schema.structs.keys().copied().collect::<Vec<_>>(), ["Node"]);
+    assert_eq!(
+        schema.structs["Node"],
+        [
+            field("a", 1, TypeDesc::Option(Box::new(TypeDesc::U64))),
+            field("b", 2, TypeDesc::String),
+            field("data", 4, TypeDesc::Bytes),
+            field(
+                "children",
+                5,
+                TypeDesc::Array(Box::new(TypeDesc::Struct("Node")))
+            ),
+            field("union", 6, TypeDesc::Union("Union")),
+        ]
+    );
+    assert_eq!(
+        schema.unions["Union"],
+        [
+            field("Int", 1, TypeDesc::I32),
+            field("Node", 5, TypeDesc::Struct("Node")),
+        ]
+    );
+
+    let fields = &schema.structs["Node"];
+    assert!(fields[0].is_optional());
+    assert!(!fields[1].is_optional());
+    assert!(fields[3].is_repeated());
+    assert_eq!(
+        fields[0].ty.wire_types(),
+        [Wire::INT1, Wire::INT2, Wire::INT4, Wire::QUAD]
+    );
+    assert_eq!(fields[3].ty.wire_types(), [Wire::REPEAT]);
+
+    assert_eq!(
+        serde_iop::desc::layout::<Node>().unwrap(),
+        "\
+root: Node
+
+struct Node
+  tag  name      type    wire                 presence
+  1    a         ulong?  INT1 INT2 INT4 QUAD  optional
+  2    b         string  BLK1 BLK2 BLK4       mandatory
+  4    data      bytes   BLK1 BLK2 BLK4       mandatory
+  5    children  Node[]  REPEAT               repeated
+  6    union     Union   BLK1 BLK2 BLK4       mandatory
+
+union Union
+  tag  name  type  wire
+  1    Int   int   INT1 INT2 INT4
+  5    Node  Node  BLK1 BLK2 BLK4
+"
+    );
+}
+
+#[test]
+fn test_compat() {
+    use serde_iop::compat::{check, compare};
+
+    mod v1 {
+        use super::*;
+
+        #[derive(Serialize, Deserialize)]
+        pub enum Union {
+            A(i32),
+            B(String),
+        }
+        #[derive(Serialize, Deserialize)]
+        pub struct Test {
+            pub a: u16,
+            pub b: String,
+            pub c: Option<u32>,
+            pub u: Union,
+        }
+    }
+    mod v2 {
+        use super::*;
+
+        #[derive(Serialize, Deserialize)]
+        pub enum Union {
+            A(i64),
+            B(String),
+            C(bool),
+        }
+        #[serde_iop::iop]
+        #[derive(Serialize, Deserialize)]
+        pub struct Test {
+            pub a: u32,
+            #[iop(bytes)]
+            pub b: Vec<u8>,
+            #[iop(tag = 4)]
+            pub u: Union,
+            pub d: Option<bool>,
+            pub e: Vec<u8>,
+            pub f: i8,
+        }
+    }
+
+    let paths = |res: Vec<serde_iop::compat::Incompatibility>| {
+        res.into_iter().map(|i| i.path).collect::<Vec<_>>()
+    };
+
+    // the removed field is skipped, the added ones are optional, except `f`
+    assert_eq!(paths(check::<v1::Test, v2::Test>().unwrap()), ["f"]);
+
+    let forward = check::<v2::Test, v1::Test>().unwrap();
+    assert_eq!(paths(forward.clone()), ["a", "b", "u.A", "u.C"]);
+    assert_eq!(
+        forward[0].to_string(),
+        "a: `U32` cannot be unpacked as `U16`"
+    );
+
+    let compat = compare::<v1::Test, v2::Test>().unwrap();
+    assert_eq!(compat.forward, forward);
+    assert!(!compat.is_compatible());
+    assert!(compare::<v1::Test, v1::Test>().unwrap().is_compatible());
+}
+
+#[test]
+fn test_transcode() {
+    use serde_iop::desc::describe;
+    use serde_iop::transcode::{transcode, transcode_from};
+    use serde_json::json;
+
+    #[derive(Serialize, Deserialize)]
+    struct Inner {
+        b: bool,
+    }
+    #[derive(Serialize, Deserialize)]
+    enum Union {
+        A(i32),
+        B(String),
+        C,
+    }
+    #[serde_iop::iop]
+    #[derive(Serialize, Deserialize)]
+    struct Test {
+        a: u32,
+        s: String,
+        opt: Option<i8>,
+        tab: Vec<Inner>,
+        u1: Union,
+        u2: Union,
+        #[iop(bytes)]
+        data: Vec<u8>,
+        f: f64,
+        void: Option<()>,
+    }
+
+    let test = Test {
+        a: 3_000_000_000,
+        s: "s".to_owned(),
+        opt: None,
+        tab: vec![Inner { b: true }],
+        u1: Union::B("b".to_owned()),
+        u2: Union::C,
+        data: vec![1, 2],
+        f: 0.5,
+        void: None,
+    };
+    let bytes = to_bytes(&test).unwrap();
+    let schema = describe::<Test>().unwrap();
+
+    let value = transcode(&bytes, &schema, serde_json::value::Serializer).unwrap();
+    assert_eq!(value, serde_json::to_value(&test).unwrap());
+    assert_eq!(transcode_from(&value, &schema).unwrap(), bytes);
+
+    // fields can be given in any order, and optional ones omitted
+    let value = json!({
+        "void": null,
+        "data": [],
+        "f": 1,
+        "u2": { "A": -1 },
+        "u1": "C",
+        "s": "",
+        "a": 1,
+    });
+    let packed = transcode_from(&value, &schema).unwrap();
+    let test = from_bytes::<Test>(&packed).unwrap();
+    assert_eq!((test.a, test.f, test.tab.len()), (1, 1., 0));
+    assert!(matches!((test.u1, test.u2), (Union::C, Union::A(-1))));
+
+    let value = json!({ "a": -1 });
+    assert!(transcode_from(&value, &schema).is_err());
+    let value = json!({ "a": 1, "unknown": 1 });
+    assert!(transcode_from(&value, &schema).is_err());
+
+    // without schema, the fields are given by tags
+    let mut de = serde_iop::Deserializer::from_bytes(&bytes);
+    let value = serde_transcode::transcode(&mut de, serde_json::value::Serializer).unwrap();
+    assert_eq!(value["1"], json!(3_000_000_000_i64));
+    assert_eq!(value["2"], json!("s"));
+    assert_eq!(value["4"], json!([{ "1": 1 }]));
+    assert_eq!(value["5"], json!({ "2": "b" }));
+}
+
+#[test]
+fn test_aliases() {
+    use serde_iop::compat::compare;
+    use serde_iop::desc::describe;
+    use serde_iop::transcode::transcode_from;
+    use serde_json::json;
+
+    mod v1 {
+        use super::*;
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        pub enum Kind {
+            Person(i32),
+        }
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        pub struct User {
+            pub id: u32,
+            pub user_name: String,
+            pub mail: Option<String>,
+            pub kind: Kind,
+        }
+    }
+    mod v2 {
+        use super::*;
+
+        #[serde_iop::iop]
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        pub enum Kind {
+            #[iop(alias = "Person")]
+            Human(i32),
+        }
+        #[serde_iop::iop]
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        pub struct User {
+            pub id: u32,
+            #[iop(alias = "user_name")]
+            pub name: String,
+            #[iop(alias = "mail", alias = "email")]
+            pub address: Option<String>,
+            pub kind: Kind,
+        }
+    }
+
+    // renamed fields keep their tags
+    let old = v1::User {
+        id: 1,
+        user_name: "john".to_owned(),
+        mail: Some("john@example.com".to_owned()),
+        kind: v1::Kind::Person(2),
+    };
+    let new = v2::User {
+        id: 1,
+        name: "john".to_owned(),
+        address: Some("john@example.com".to_owned()),
+        kind: v2::Kind::Human(2),
+    };
+    let bytes = to_bytes(&old).unwrap();
+    assert_eq!(to_bytes(&new).unwrap(), bytes);
+    assert_eq!(from_bytes::<v2::User>(&bytes).unwrap(), new);
+    assert!(compare::<v1::User, v2::User>().unwrap().is_compatible());
+
+    let schema = describe::<v2::User>().unwrap();
+    let fields = &schema.structs["User"];
+    assert_eq!(fields[1].aliases, ["user_name"]);
+    assert_eq!(fields[2].aliases, ["mail", "email"]);
+    assert!(fields[2].is_named("email"));
+    assert!(schema
+        .to_string()
+        .contains("  3    address (was mail, email)  string?"));
+
+    // the former names are accepted when transcoding
+    let value = json!({
+        "id": 1,
+        "user_name": "john",
+        "email": "john@example.com",
+        "kind": { "Person": 2 },
+    });
+    assert_eq!(transcode_from(&value, &schema).unwrap(), bytes);
+    let value = json!({ "id": 1, "name": "a", "user_name": "b", "kind": { "Human": 2 } });
+    assert!(transcode_from(&value, &schema).is_err());
+}
+
+#[test]
+fn test_frames() {
+    use serde_iop::frame::{FrameDecoder, FrameEncoder};
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Test {
+        a: u32,
+        s: String,
+    }
+
+    let values: Vec<Test> = (0..3)
+        .map(|i| Test {
+            a: i,
+            s: "a".repeat(i as usize),
+        })
+        .collect();
+    let encoder = FrameEncoder::new();
+    let mut stream = Vec::new();
+    for value in &values {
+        encoder.encode(value, &mut stream).unwrap();
+    }
+    assert_eq!(&stream[..4], &[5, 0, 0, 0]);
+
+    // frames are reassembled from partial reads
+    let mut decoder = FrameDecoder::new();
+    let mut decoded = Vec::new();
+    for chunk in stream.chunks(3) {
+        decoder.extend(chunk);
+        while let Some(value) = decoder.decode::<Test>().unwrap() {
+            decoded.push(value);
+        }
+    }
+    assert_eq!(decoded, values);
+    assert_eq!(decoder.buffered_len(), 0);
+
+    let mut decoder = FrameDecoder::new();
+    assert_eq!(decoder.read_from(&mut &stream[..]).unwrap(), stream.len());
+    assert_eq!(decoder.decode::<Test>().unwrap().as_ref(), Some(&values[0]));
+
+    // too long frames are rejected
+    let mut output = vec![0xAA];
+    let encoder = FrameEncoder::new().max_len(4);
+    assert_eq!(
+        encoder.encode(&values[2], &mut output),
+        Err(Error::LengthOverflow(7))
+    );
+    assert_eq!(output, [0xAA]);
+
+    let mut decoder = FrameDecoder::new().max_len(4);
+    decoder.extend(&[9, 0, 0, 0]);
+    assert_eq!(decoder.decode::<Test>(), Err(Error::LengthOverflow(9)));
+}
+
+#[test]
+fn test_ignored_any() {
+    use serde::de::IgnoredAny;
+
+    #[derive(Serialize)]
+    struct Full {
+        a: u8,
+        s: String,
+        v: Vec<u32>,
+        b: u8,
+    }
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Partial {
+        a: u8,
+        s: IgnoredAny,
+        v: IgnoredAny,
+        b: u8,
+    }
+
+    let bytes = to_bytes(&Full {
+        a: 1,
+        s: "skipped".to_owned(),
+        v: vec![1, 2, 3],
+        b: 2,
+    })
+    .unwrap();
+
+    let partial: Partial = from_bytes(&bytes).unwrap();
+    assert_eq!((partial.a, partial.b), (1, 2));
+
+    // the whole payload can be ignored as well
+    assert!(from_bytes::<IgnoredAny>(&bytes).is_ok());
+}
+
+#[test]
+fn test_void_payload() {
+    #[derive(Serialize)]
+    struct Answer {
+        a: u8,
+        s: String,
+    }
+
+    // void values are packed as nothing
+    assert!(to_bytes(&()).unwrap().is_empty());
+    assert!(from_bytes::<()>(&[]).is_ok());
+
+    // and any payload is accepted for them
+    let bytes = to_bytes(&Answer {
+        a: 1,
+        s: "ignored".to_owned(),
+    })
+    .unwrap();
+    assert!(from_bytes::<()>(&bytes).is_ok());
+}
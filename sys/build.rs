@@ -36,6 +36,11 @@ fn main() {
         .whitelist_function("el_loop")
         .whitelist_function("el_loop_timeout")
         .whitelist_function("el_has_pending_events")
+        .whitelist_function("el_wake_register_d")
+        .whitelist_function("el_wake_fire")
+        .whitelist_function("el_before_register_d")
+        .whitelist_function("el_fd_register_d")
+        .whitelist_function("el_fd_set_mask")
         // For crate 'ic'
         .whitelist_function("ic_get_module")
         // msg
@@ -52,12 +57,33 @@ fn main() {
         .whitelist_function("addr_info")
         .whitelist_function("ic_listento")
         .whitelist_function("ic_spawn")
+        .whitelist_function("el_fd_get_fd")
         // client
         .whitelist_function("ic_init")
         .whitelist_function("ic_connect")
         .whitelist_function("ic_connect_blocking")
         .whitelist_function("ic_disconnect")
         .whitelist_function("ic_wipe")
+        // tls
+        .whitelist_function("ic_set_ssl_ctx")
+        .whitelist_function("TLS_method")
+        .whitelist_function("SSL_CTX_new")
+        .whitelist_function("SSL_CTX_free")
+        .whitelist_function("SSL_CTX_use_certificate_chain_file")
+        .whitelist_function("SSL_CTX_use_PrivateKey_file")
+        .whitelist_function("SSL_CTX_check_private_key")
+        .whitelist_function("SSL_CTX_load_verify_locations")
+        .whitelist_function("SSL_CTX_set_verify")
+        .whitelist_function("SSL_get1_peer_certificate")
+        .whitelist_function("SSL_get_verify_result")
+        .whitelist_function("X509_free")
+        .whitelist_function("X509_get_subject_name")
+        .whitelist_function("X509_NAME_oneline")
+        .whitelist_function("X509_NAME_get_text_by_NID")
+        .whitelist_var("NID_commonName")
+        .whitelist_var("X509_V_OK")
+        .whitelist_var("SSL_FILETYPE_PEM")
+        .whitelist_var("SSL_VERIFY_.*")
         // Doctests are otherwise generated, which fails due to
         // possibly invalid doxygen comments.
         .generate_comments(false)
@@ -83,6 +109,8 @@ fn main() {
 #[link(name=\"libcommon-iop\", kind=\"static\")]
 #[link(name=\"libcommon-minimal\", kind=\"static\")]
 extern \"C\" {}
+mod lstr;
+pub use lstr::{from_lstr, from_lstr_str, to_lstr, LStr};
     ",
         )
         .unwrap();
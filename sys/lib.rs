@@ -6,6 +6,8 @@
 #[link(name="libcommon-iop", kind="static")]
 #[link(name="libcommon-minimal", kind="static")]
 extern "C" {}
+mod lstr;
+pub use lstr::{from_lstr, from_lstr_str, to_lstr, LStr};
     /* automatically generated by rust-bindgen */
 
 #[repr(C)]
@@ -819,6 +821,12 @@ extern "C" {
 extern "C" {
     pub fn el_has_pending_events() -> bool;
 }
+extern "C" {
+    pub fn el_wake_register_d(arg1: el_cb_f, arg2: data_t) -> el_t;
+}
+extern "C" {
+    pub fn el_wake_fire(arg1: el_t);
+}
 pub type in_addr_t = u32;
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -3432,6 +3440,50 @@ pub struct ssl_st {
     _unused: [u8; 0],
 }
 pub type SSL = ssl_st;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ssl_ctx_st {
+    _unused: [u8; 0],
+}
+pub type SSL_CTX = ssl_ctx_st;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ssl_method_st {
+    _unused: [u8; 0],
+}
+pub type SSL_METHOD = ssl_method_st;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct x509_store_ctx_st {
+    _unused: [u8; 0],
+}
+pub type X509_STORE_CTX = x509_store_ctx_st;
+pub type SSL_verify_cb = ::std::option::Option<
+    unsafe extern "C" fn(
+        preverify_ok: ::std::os::raw::c_int,
+        x509_ctx: *mut X509_STORE_CTX,
+    ) -> ::std::os::raw::c_int,
+>;
+pub const SSL_FILETYPE_PEM: u32 = 1;
+pub const SSL_VERIFY_NONE: u32 = 0;
+pub const SSL_VERIFY_PEER: u32 = 1;
+pub const SSL_VERIFY_FAIL_IF_NO_PEER_CERT: u32 = 2;
+pub const SSL_VERIFY_CLIENT_ONCE: u32 = 4;
+pub const SSL_VERIFY_POST_HANDSHAKE: u32 = 8;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct x509_st {
+    _unused: [u8; 0],
+}
+pub type X509 = x509_st;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct X509_name_st {
+    _unused: [u8; 0],
+}
+pub type X509_NAME = X509_name_st;
+pub const NID_commonName: u32 = 13;
+pub const X509_V_OK: u32 = 0;
 pub const ic__ic_priority__t_IC_PRIORITY_LOW: ic__ic_priority__t = 0;
 pub const ic__ic_priority__t_IC_PRIORITY_NORMAL: ic__ic_priority__t = 1;
 pub const ic__ic_priority__t_IC_PRIORITY_HIGH: ic__ic_priority__t = 2;
@@ -5732,6 +5784,9 @@ extern "C" {
 extern "C" {
     pub fn ic_spawn(ic: *mut ichannel_t, fd: ::std::os::raw::c_int, fn_: ic_creds_f);
 }
+extern "C" {
+    pub fn el_fd_get_fd(ev: el_t) -> ::std::os::raw::c_int;
+}
 extern "C" {
     pub fn ic_listento(
         su: *const sockunion_t,
@@ -5753,3 +5808,72 @@ extern "C" {
 extern "C" {
     pub fn __ic_query(arg1: *mut ichannel_t, arg2: *mut ic_msg_t);
 }
+extern "C" {
+    pub fn ic_set_ssl_ctx(ctx: *mut SSL_CTX);
+}
+extern "C" {
+    pub fn TLS_method() -> *const SSL_METHOD;
+}
+extern "C" {
+    pub fn SSL_CTX_new(meth: *const SSL_METHOD) -> *mut SSL_CTX;
+}
+extern "C" {
+    pub fn SSL_CTX_free(arg1: *mut SSL_CTX);
+}
+extern "C" {
+    pub fn SSL_CTX_use_certificate_chain_file(
+        ctx: *mut SSL_CTX,
+        file: *const ::std::os::raw::c_char,
+    ) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn SSL_CTX_use_PrivateKey_file(
+        ctx: *mut SSL_CTX,
+        file: *const ::std::os::raw::c_char,
+        type_: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn SSL_CTX_check_private_key(ctx: *const SSL_CTX) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn SSL_CTX_load_verify_locations(
+        ctx: *mut SSL_CTX,
+        CAfile: *const ::std::os::raw::c_char,
+        CApath: *const ::std::os::raw::c_char,
+    ) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn SSL_CTX_set_verify(
+        ctx: *mut SSL_CTX,
+        mode: ::std::os::raw::c_int,
+        callback: SSL_verify_cb,
+    );
+}
+extern "C" {
+    pub fn SSL_get1_peer_certificate(s: *const SSL) -> *mut X509;
+}
+extern "C" {
+    pub fn SSL_get_verify_result(ssl: *const SSL) -> ::std::os::raw::c_long;
+}
+extern "C" {
+    pub fn X509_free(a: *mut X509);
+}
+extern "C" {
+    pub fn X509_get_subject_name(a: *const X509) -> *mut X509_NAME;
+}
+extern "C" {
+    pub fn X509_NAME_oneline(
+        a: *const X509_NAME,
+        buf: *mut ::std::os::raw::c_char,
+        size: ::std::os::raw::c_int,
+    ) -> *mut ::std::os::raw::c_char;
+}
+extern "C" {
+    pub fn X509_NAME_get_text_by_NID(
+        name: *const X509_NAME,
+        nid: ::std::os::raw::c_int,
+        buf: *mut ::std::os::raw::c_char,
+        len: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+}
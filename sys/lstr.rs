@@ -0,0 +1,79 @@
+//! Conversions between Rust buffers and `lstr_t`.
+//!
+//! These helpers should be used instead of building or reading the fields of
+//! the generated `lstr_t` by hand.
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::os::raw::c_char;
+
+use crate::{lstr_t, lstr_t__bindgen_ty_1, mem_flags_t};
+
+/// Memory pool of strings which are not owned by the `lstr_t`.
+const MEM_STATIC: mem_flags_t = 0;
+
+/// An `lstr_t` borrowing a Rust buffer.
+///
+/// The C library never frees static strings, so the buffer only needs to
+/// outlive the `LStr`.
+#[derive(Clone, Copy)]
+pub struct LStr<'a> {
+    raw: lstr_t,
+    marker: PhantomData<&'a [u8]>,
+}
+
+impl<'a> LStr<'a> {
+    /// Get the `lstr_t`, to pass it to a C function.
+    ///
+    /// The C function must not keep it after the end of the borrow.
+    pub fn as_raw(&self) -> lstr_t {
+        self.raw
+    }
+}
+
+/// Borrow a buffer, or a string, as an `lstr_t`.
+///
+/// # Panics
+///
+/// Panics if the buffer is longer than `i32::MAX` bytes.
+pub fn to_lstr<T: AsRef<[u8]> + ?Sized>(data: &T) -> LStr<'_> {
+    let data = data.as_ref();
+    let len = i32::try_from(data.len()).expect("buffer too long for an lstr_t");
+
+    LStr {
+        raw: lstr_t {
+            __bindgen_anon_1: lstr_t__bindgen_ty_1 {
+                s: data.as_ptr() as *const c_char,
+            },
+            len,
+            mem_pool: MEM_STATIC,
+        },
+        marker: PhantomData,
+    }
+}
+
+/// Borrow the content of an `lstr_t`.
+///
+/// Copy it with `to_vec` to keep it after the string is released by the C
+/// library.
+///
+/// # Safety
+///
+/// The string must be valid for its length, and not be modified nor released
+/// during the lifetime `'a`.
+pub unsafe fn from_lstr<'a>(s: &lstr_t) -> &'a [u8] {
+    if s.len <= 0 || s.__bindgen_anon_1.s.is_null() {
+        // a null pointer is not a valid empty slice
+        &[]
+    } else {
+        std::slice::from_raw_parts(s.__bindgen_anon_1.s as *const u8, s.len as usize)
+    }
+}
+
+/// Borrow the content of an `lstr_t` as a string.
+///
+/// # Safety
+///
+/// See `from_lstr`.
+pub unsafe fn from_lstr_str<'a>(s: &lstr_t) -> Result<&'a str, std::str::Utf8Error> {
+    std::str::from_utf8(from_lstr(s))
+}
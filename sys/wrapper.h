@@ -6,6 +6,7 @@
 
 /* For crate 'ic' */
 #include "lib-common/iop-rpc.h"
+#include <openssl/ssl.h>
 
 /* For crate 'el' */
 #include "lib-common/el.h"